@@ -0,0 +1,80 @@
+//! Audit log of the administrative actions. Every action is appended to the log file as one
+//! JSON line, so the file can be inspected with the usual line based tools.
+
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::{fs, io::AsyncWriteExt};
+
+/// One administrative action
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+  /// RFC 3339 time in the configured timezone
+  pub time: String,
+  /// The user who did the action
+  pub user: u64,
+  /// Short description of the action
+  pub action: String,
+}
+
+/// Read the path of the audit log from `NOTIFY_BOT_AUDIT_LOG`, default to `audit.log`
+pub fn path_from_env() -> PathBuf {
+  std::env::var("NOTIFY_BOT_AUDIT_LOG")
+    .map(PathBuf::from)
+    .unwrap_or_else(|_| PathBuf::from("audit.log"))
+}
+
+/// Append the entry to the end of the log file. The file is created if it doesn't exist.
+pub async fn append(path: &Path, entry: &AuditEntry) -> Result<()> {
+  let mut line = serde_json::to_string(entry)?;
+  line.push('\n');
+
+  let mut file = fs::OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(path)
+    .await?;
+  // write the whole line at once, so concurrent writers don't interleave
+  file.write_all(line.as_bytes()).await?;
+  Ok(())
+}
+
+/// Read the last `n` entries from the log file, the oldest first. Broken lines are skipped.
+pub async fn read_last(path: &Path, n: usize) -> Result<Vec<AuditEntry>> {
+  let content = match fs::read_to_string(path).await {
+    Ok(content) => content,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+    Err(e) => return Err(e.into()),
+  };
+
+  Ok(parse_last(&content, n))
+}
+
+fn parse_last(content: &str, n: usize) -> Vec<AuditEntry> {
+  let mut entries = content
+    .lines()
+    .rev()
+    .filter_map(|line| serde_json::from_str(line).ok())
+    .take(n)
+    .collect::<Vec<AuditEntry>>();
+  entries.reverse();
+  entries
+}
+
+#[test]
+fn parse_last_test() {
+  let entry = |user: u64| AuditEntry {
+    time: "2022-06-01T10:00:00+00:00".to_string(),
+    user,
+    action: "删除任务 1".to_string(),
+  };
+  let content = [1, 2, 3]
+    .iter()
+    .map(|&u| serde_json::to_string(&entry(u)).unwrap())
+    .chain(std::iter::once("broken".to_string()))
+    .collect::<Vec<String>>()
+    .join("\n");
+
+  assert_eq!(parse_last(&content, 2), vec![entry(2), entry(3)]);
+  assert_eq!(parse_last(&content, 10).len(), 3);
+}
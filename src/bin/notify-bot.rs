@@ -1,7 +1,31 @@
 use anyhow::Result;
-use notify_bot::{handler::*, BotRuntime, Whitelist};
-use teloxide::{dispatching::dialogue::InMemStorage, prelude::*};
-use tracing::info;
+use notify_bot::{
+  handler::*, throttle_limits_from_env, BotRuntime, Config, JsonFileStore, Whitelist,
+};
+#[cfg(feature = "sqlite")]
+use notify_bot::{TaskStore, WhitelistStore};
+use std::{path::PathBuf, sync::Arc};
+use teloxide::prelude::*;
+use tracing::{debug, info, warn};
+
+/// Read the config file path from the `--config <path>` or `--config=<path>` argument
+fn config_path_from_args() -> Result<Option<PathBuf>> {
+  let mut args = std::env::args().skip(1);
+  let mut path = None;
+  while let Some(arg) = args.next() {
+    if arg == "--config" {
+      let val = args
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("--config requires a file path"))?;
+      path = Some(PathBuf::from(val));
+    } else if let Some(val) = arg.strip_prefix("--config=") {
+      path = Some(PathBuf::from(val));
+    } else {
+      anyhow::bail!("Unknown argument {arg}, usage: notify-bot [--config path/to/config.toml]");
+    }
+  }
+  Ok(path)
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -9,32 +33,149 @@ async fn main() -> Result<()> {
   info!("Bot initializing...");
   dotenv::dotenv().ok();
 
-  let bot = Bot::from_env().auto_send();
+  info!("Parsing config...");
+  let config_path = config_path_from_args()?;
+  if let Some(path) = &config_path {
+    info!("Loading config from {}", path.display());
+  }
+  // the environment variables override the file
+  let config = Config::load(config_path.as_deref())?;
+  config.check_required()?;
+  config.apply_to_env();
 
-  let username = bot.get_me().await?.username().to_string();
-  info!("Bot {} start running", username);
+  #[cfg(feature = "sqlite")]
+  let database = match &config.runtime.database {
+    Some(path) => {
+      info!("Opening database {path}");
+      Some(Arc::new(notify_bot::SqliteStore::open(path)?))
+    }
+    None => None,
+  };
+  #[cfg(feature = "sqlite")]
+  let stored = match &database {
+    Some(db) => db.load_whitelist().await?,
+    None => None,
+  };
+  #[cfg(not(feature = "sqlite"))]
+  let stored: Option<Whitelist> = None;
+  #[cfg(not(feature = "sqlite"))]
+  if config.runtime.database.is_some() {
+    warn!("NOTIFY_BOT_DATABASE is ignored, the bot is built without the sqlite feature");
+  }
 
-  info!("Parsing config...");
+  // prefer the database and then the whitelist file, the config is only used for the first run
+  let whitelist_path = config.whitelist_path();
+  let whitelist = if let Some(whitelist) = stored {
+    info!("Loading whitelist from database");
+    whitelist
+  } else if whitelist_path.exists() {
+    info!("Loading whitelist from {}", whitelist_path.display());
+    Whitelist::load_from(&whitelist_path).await?
+  } else {
+    info!(
+      "{} not found, building whitelist from config",
+      whitelist_path.display()
+    );
+    Whitelist::from(&config)
+  };
+
+  if whitelist.maintainers.is_empty() {
+    anyhow::bail!(
+      "No maintainer is configured, refuse to start. Set NOTIFY_BOT_MAINTAINERS to your user \
+      id, multiple id are separated by `,`, for example `export NOTIFY_BOT_MAINTAINERS=12345678`. \
+      You can get your user id from @userinfobot."
+    );
+  }
+  info!("Current whitelist: {}", whitelist.summary());
+  debug!("Whitelist detail: {}", &whitelist);
 
-  let whitelist = Whitelist::new()
-    .parse_admins()
-    .parse_groups()
-    .parse_maintainers();
+  let limits = throttle_limits_from_env();
+  info!("Throttle limits: {:?}", limits);
+  let token = config.bot.token.clone().unwrap_or_default();
+  let bot = Bot::new(token).throttle(limits).auto_send();
+
+  let username = bot.get_me().await?.username().to_string();
+  info!("Bot {} start running", username);
+  register_commands(&bot, &whitelist.maintainers).await;
 
-  info!("Current configuration: {}", &whitelist);
   // setup bot runtime
-  let runtime = BotRuntime::new(bot.clone()).whitelist(whitelist);
+  let tasks_path = config.tasks_path();
+  let mut runtime = BotRuntime::new(bot.clone())
+    .whitelist(whitelist)
+    .whitelist_path(whitelist_path)
+    .config_path(config_path)
+    .task_store(Arc::new(JsonFileStore::new(&tasks_path)));
+  let tasks_source = tasks_path.display().to_string();
+  #[cfg(feature = "sqlite")]
+  let tasks_source = match database {
+    Some(db) => {
+      // move the data of the legacy files or the config into the database on the first run
+      if db.load_all().await?.is_empty() {
+        let legacy = JsonFileStore::new(&tasks_path).load_all().await?;
+        if !legacy.is_empty() {
+          info!(
+            "Importing {} tasks from {}",
+            legacy.len(),
+            tasks_path.display()
+          );
+          db.save_all(&legacy).await?;
+        }
+      }
+      runtime = runtime.task_store(db.clone()).whitelist_store(db);
+      runtime.save_whitelist().await?;
+      config.runtime.database.clone().unwrap_or_default()
+    }
+    None => tasks_source,
+  };
+  let restored = runtime.task_pool.restore().await?;
+  info!("Restored {} tasks from {}", restored, tasks_source);
+
+  #[cfg(feature = "sqlite")]
+  let dialogue_storage = match &config.runtime.dialogue_storage {
+    Some(path) => {
+      info!("Keeping dialogues in {path}");
+      DialogueStorage::sqlite(path).await?
+    }
+    None => DialogueStorage::memory(),
+  };
+  #[cfg(not(feature = "sqlite"))]
+  let dialogue_storage = {
+    if config.runtime.dialogue_storage.is_some() {
+      warn!("NOTIFY_BOT_DIALOGUE_STORAGE is ignored, the bot is built without the sqlite feature");
+    }
+    DialogueStorage::memory()
+  };
 
   // setup handler
-  Dispatcher::builder(bot.clone(), handler_schema())
-    .dependencies(dptree::deps![
-      runtime,
-      InMemStorage::<AddTaskDialogueCurrentState>::new()
-    ])
-    .build()
-    .setup_ctrlc_handler()
-    .dispatch()
+  let dialogue_storage = Arc::new(dialogue_storage.timeout(config.dialogue_timeout()));
+  let mut dispatcher = Dispatcher::builder(bot.clone(), handler_schema())
+    .dependencies(dptree::deps![runtime.clone(), dialogue_storage])
+    .build();
+  if config.runtime.startup_notify.unwrap_or(true) {
+    runtime.notify_startup(&bot, &username).await;
+  }
+
+  // stop receiving updates on Ctrl-C or SIGTERM
+  let shutdown_token = dispatcher.shutdown_token();
+  let signal_rt = runtime.clone();
+  tokio::spawn(async move {
+    signal_rt.wait_shutdown_sig().await;
+    info!("Shutting down, stop receiving updates...");
+    match shutdown_token.shutdown() {
+      Ok(stopped) => stopped.await,
+      Err(e) => warn!("Fail to stop the dispatcher: {e:?}"),
+    }
+  });
+  dispatcher.dispatch().await;
+
+  runtime
+    .shutdown(
+      &bot,
+      config.shutdown_timeout(),
+      config.runtime.shutdown_notify.unwrap_or(true),
+    )
     .await;
+  info!("Bot is stopped");
 
   Ok(())
 }
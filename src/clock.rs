@@ -0,0 +1,24 @@
+//! Wall clock time in the configured timezone. All the time formatting and comparison should
+//! go through this module instead of the system local time.
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+
+lazy_static::lazy_static!(
+    /// Timezone of all the wall clock time. It is read from `NOTIFY_BOT_TIMEZONE`, and default
+    /// to UTC when the variable is not set.
+    pub static ref TIMEZONE: Tz = match std::env::var("NOTIFY_BOT_TIMEZONE") {
+        Ok(name) => name.trim().parse().unwrap_or_else(|e| {
+            panic!(
+                "Invalid NOTIFY_BOT_TIMEZONE `{name}`: {e}. \
+                Expect a IANA timezone name like `Asia/Shanghai` or `UTC`"
+            )
+        }),
+        Err(_) => Tz::UTC,
+    };
+);
+
+/// Return the current time in the configured timezone
+pub fn now() -> DateTime<Tz> {
+  Utc::now().with_timezone(&*TIMEZONE)
+}
@@ -0,0 +1,632 @@
+//! Configuration of the bot. The settings are read from an optional TOML file with the `bot`,
+//! `whitelist` and `runtime` sections, then from the environment variables. Every value in the
+//! file has a matching environment variable, which overrides the file, so the deployments
+//! configured by the environment variables keep working.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{
+  collections::BTreeMap,
+  env,
+  path::{Path, PathBuf},
+  str::FromStr,
+  time::Duration,
+};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct BotConfig {
+  /// Telegram bot token, `TELOXIDE_TOKEN`
+  pub token: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct WhitelistConfig {
+  /// `NOTIFY_BOT_MAINTAINERS`
+  pub maintainers: Option<Vec<u64>>,
+  /// `NOTIFY_BOT_ADMINS`
+  pub admins: Option<Vec<u64>>,
+  /// `NOTIFY_BOT_GROUPS`, the group can be written as `"-100123:42"` to send into a topic
+  pub groups: Option<Vec<GroupEntry>>,
+  /// `NOTIFY_BOT_BANNED`
+  pub banned: Option<Vec<u64>>,
+  /// `NOTIFY_BOT_ADMIN_SCOPES`
+  pub admin_scopes: Option<Vec<AdminScope>>,
+  /// `NOTIFY_BOT_WHITELIST`
+  pub file: Option<String>,
+}
+
+/// A group with the optional forum topic, written as the chat id or the `chat_id:thread_id`
+/// string
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RawGroupEntry")]
+pub struct GroupEntry {
+  pub id: i64,
+  pub thread: Option<i32>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawGroupEntry {
+  Id(i64),
+  Text(String),
+}
+
+impl TryFrom<RawGroupEntry> for GroupEntry {
+  type Error = String;
+
+  fn try_from(raw: RawGroupEntry) -> Result<Self, Self::Error> {
+    match raw {
+      RawGroupEntry::Id(id) => Ok(Self { id, thread: None }),
+      RawGroupEntry::Text(s) => s.parse(),
+    }
+  }
+}
+
+impl FromStr for GroupEntry {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || format!("{s} is not a valid group");
+    let (id, thread) = match s.trim().split_once(':') {
+      Some((id, thread)) => (id, Some(thread.trim().parse().map_err(|_| invalid())?)),
+      None => (s.trim(), None),
+    };
+    let id = id.trim().parse().map_err(|_| invalid())?;
+    Ok(Self { id, thread })
+  }
+}
+
+impl std::fmt::Display for GroupEntry {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.thread {
+      Some(thread) => write!(f, "{}:{thread}", self.id),
+      None => write!(f, "{}", self.id),
+    }
+  }
+}
+
+/// The groups an admin is allowed to manage. In the environment variable it is written as
+/// `admin:group|group`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct AdminScope {
+  pub admin: u64,
+  pub groups: Vec<i64>,
+}
+
+impl FromStr for AdminScope {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || format!("{s} is not a valid admin scope");
+    let (admin, groups) = s.trim().split_once(':').ok_or_else(invalid)?;
+    let admin = admin.trim().parse().map_err(|_| invalid())?;
+    let groups = groups
+      .split('|')
+      .map(|g| g.trim().parse().map_err(|_| invalid()))
+      .collect::<Result<Vec<i64>, String>>()?;
+    Ok(Self { admin, groups })
+  }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct RuntimeConfig {
+  /// `NOTIFY_BOT_TIMEZONE`
+  pub timezone: Option<String>,
+  /// `NOTIFY_BOT_QUIET_HOURS`
+  pub quiet_hours: Option<String>,
+  /// `NOTIFY_BOT_MIN_INTERVAL`
+  pub min_interval: Option<String>,
+  /// `NOTIFY_BOT_MAX_INTERVAL`
+  pub max_interval: Option<String>,
+  /// `NOTIFY_BOT_MAX_TEXT_LENGTH`
+  pub max_text_length: Option<usize>,
+  /// `NOTIFY_BOT_BUTTON_SCHEMES`
+  pub button_schemes: Option<Vec<String>>,
+  /// `NOTIFY_BOT_SEND_RETRIES`
+  pub send_retries: Option<u32>,
+  /// `NOTIFY_BOT_MAX_FAILURES`
+  pub max_failures: Option<u32>,
+  /// `NOTIFY_BOT_SEND_DELAY_MS`
+  pub send_delay_ms: Option<u64>,
+  /// `NOTIFY_BOT_MESSAGES_PER_SEC`
+  pub messages_per_sec: Option<u32>,
+  /// `NOTIFY_BOT_MESSAGES_PER_MIN_CHAT`
+  pub messages_per_min_chat: Option<u32>,
+  /// `NOTIFY_BOT_AUDIT_LOG`
+  pub audit_log: Option<String>,
+  /// `NOTIFY_BOT_TEMPLATE_FILE`
+  pub template_file: Option<String>,
+  /// `NOTIFY_BOT_STARTUP_NOTIFY`, send the maintainers a message when the bot starts, default
+  /// to true
+  pub startup_notify: Option<bool>,
+  /// `NOTIFY_BOT_SHUTDOWN_NOTIFY`, send the maintainers a message when the bot is going down,
+  /// default to true
+  pub shutdown_notify: Option<bool>,
+  /// `NOTIFY_BOT_SHUTDOWN_TIMEOUT`, seconds to wait for the sends in flight on shutdown,
+  /// default to 30
+  pub shutdown_timeout: Option<u64>,
+  /// `NOTIFY_BOT_TASKS_FILE`, where the tasks are saved, default to `tasks.json`
+  pub tasks_file: Option<String>,
+  /// `NOTIFY_BOT_DATABASE`, the SQLite database keeping the tasks and the whitelist instead of
+  /// the files. Require the `sqlite` feature.
+  pub database: Option<String>,
+  /// `NOTIFY_BOT_DIALOGUE_STORAGE`, the SQLite database keeping the unfinished dialogues, so
+  /// they survive restarts. The dialogues are kept in memory if unset. Require the `sqlite`
+  /// feature.
+  pub dialogue_storage: Option<String>,
+  /// `NOTIFY_BOT_DIALOGUE_TIMEOUT`, seconds before the idle dialogue is reset, default to 1800
+  pub dialogue_timeout: Option<u64>,
+}
+
+/// All the settings of the bot, merged from the config file and the environment variables
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Config {
+  pub bot: BotConfig,
+  pub whitelist: WhitelistConfig,
+  pub runtime: RuntimeConfig,
+}
+
+/// Known keys of each section, used to report the unknown keys
+const KNOWN_KEYS: &[(&str, &[&str])] = &[
+  ("bot", &["token"]),
+  (
+    "whitelist",
+    &[
+      "maintainers",
+      "admins",
+      "groups",
+      "banned",
+      "admin_scopes",
+      "file",
+    ],
+  ),
+  (
+    "runtime",
+    &[
+      "timezone",
+      "quiet_hours",
+      "min_interval",
+      "max_interval",
+      "max_text_length",
+      "button_schemes",
+      "send_retries",
+      "max_failures",
+      "send_delay_ms",
+      "messages_per_sec",
+      "messages_per_min_chat",
+      "audit_log",
+      "template_file",
+      "startup_notify",
+      "shutdown_notify",
+      "shutdown_timeout",
+      "tasks_file",
+      "database",
+      "dialogue_storage",
+      "dialogue_timeout",
+    ],
+  ),
+];
+
+/// Malformed value in a list environment variable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+  /// Name of the environment variable
+  pub var: String,
+  /// The segment that can't be parsed
+  pub token: String,
+  /// 1-based position of the segment in the comma separated list
+  pub position: usize,
+  pub reason: String,
+}
+
+impl std::fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "Invalid value `{}` at position {} in {}: {}",
+      self.token, self.position, self.var, self.reason
+    )
+  }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Parse the comma separated list. Empty segments, for example from a trailing comma, are
+/// skipped.
+fn parse_list<T: FromStr>(var: &str, val: &str) -> Result<Vec<T>, ConfigError>
+where
+  <T as FromStr>::Err: std::fmt::Display,
+{
+  val
+    .split(',')
+    .enumerate()
+    .map(|(i, x)| (i + 1, x.trim()))
+    .filter(|(_, x)| !x.is_empty())
+    .map(|(position, x)| {
+      x.parse::<T>().map_err(|e| ConfigError {
+        var: var.to_string(),
+        token: x.to_string(),
+        position,
+        reason: e.to_string(),
+      })
+    })
+    .collect()
+}
+
+/// Parse the boolean environment variable, `None` if it is not set
+fn env_bool(var: &str) -> Result<Option<bool>, ConfigError> {
+  let val = match env::var(var) {
+    Ok(val) => val,
+    Err(_) => return Ok(None),
+  };
+  match val.trim().to_lowercase().as_str() {
+    "" => Ok(None),
+    "true" | "1" | "yes" => Ok(Some(true)),
+    "false" | "0" | "no" => Ok(Some(false)),
+    token => Err(ConfigError {
+      var: var.to_string(),
+      token: token.to_string(),
+      position: 1,
+      reason: "expect true or false".to_string(),
+    }),
+  }
+}
+
+/// Parse the environment variable holding a single value, `None` if it is not set
+fn env_value<T: FromStr>(var: &str) -> Result<Option<T>, ConfigError>
+where
+  <T as FromStr>::Err: std::fmt::Display,
+{
+  match env::var(var) {
+    Ok(val) => parse_list(var, &val).map(|list| list.into_iter().next()),
+    Err(_) => Ok(None),
+  }
+}
+
+/// Parse the comma separated list in the environment variable, `None` if it is not set
+fn env_list<T: FromStr>(var: &str) -> Result<Option<Vec<T>>, ConfigError>
+where
+  <T as FromStr>::Err: std::fmt::Display,
+{
+  match env::var(var) {
+    Ok(val) => parse_list(var, &val).map(Some),
+    Err(_) => Ok(None),
+  }
+}
+
+impl Config {
+  /// Read the config file if given, then override it with the environment variables
+  pub fn load(path: Option<&Path>) -> Result<Self> {
+    let file = match path {
+      Some(path) => Self::from_file(path)?,
+      None => Self::default(),
+    };
+    Ok(file.merge(Self::from_env()?))
+  }
+
+  /// Read the bot and whitelist settings from the environment variables. Most of the runtime
+  /// settings are still read by their own modules, see [`Config::apply_to_env`].
+  pub fn from_env() -> Result<Self, ConfigError> {
+    Ok(Self {
+      bot: BotConfig {
+        token: env::var("TELOXIDE_TOKEN").ok(),
+      },
+      whitelist: WhitelistConfig {
+        maintainers: env_list("NOTIFY_BOT_MAINTAINERS")?,
+        admins: env_list("NOTIFY_BOT_ADMINS")?,
+        groups: env_list("NOTIFY_BOT_GROUPS")?,
+        banned: env_list("NOTIFY_BOT_BANNED")?,
+        admin_scopes: env_list("NOTIFY_BOT_ADMIN_SCOPES")?,
+        file: env::var("NOTIFY_BOT_WHITELIST").ok(),
+      },
+      runtime: RuntimeConfig {
+        startup_notify: env_bool("NOTIFY_BOT_STARTUP_NOTIFY")?,
+        shutdown_notify: env_bool("NOTIFY_BOT_SHUTDOWN_NOTIFY")?,
+        shutdown_timeout: env_value("NOTIFY_BOT_SHUTDOWN_TIMEOUT")?,
+        tasks_file: env::var("NOTIFY_BOT_TASKS_FILE").ok(),
+        database: env::var("NOTIFY_BOT_DATABASE").ok(),
+        dialogue_storage: env::var("NOTIFY_BOT_DIALOGUE_STORAGE").ok(),
+        dialogue_timeout: env_value("NOTIFY_BOT_DIALOGUE_TIMEOUT")?,
+        ..Default::default()
+      },
+    })
+  }
+
+  /// Merge two configs, the values set in `other` take precedence
+  pub fn merge(self, other: Self) -> Self {
+    let (wt, other_wt) = (self.whitelist, other.whitelist);
+    Self {
+      bot: BotConfig {
+        token: other.bot.token.or(self.bot.token),
+      },
+      whitelist: WhitelistConfig {
+        maintainers: other_wt.maintainers.or(wt.maintainers),
+        admins: other_wt.admins.or(wt.admins),
+        groups: other_wt.groups.or(wt.groups),
+        banned: other_wt.banned.or(wt.banned),
+        admin_scopes: other_wt.admin_scopes.or(wt.admin_scopes),
+        file: other_wt.file.or(wt.file),
+      },
+      runtime: RuntimeConfig {
+        startup_notify: other.runtime.startup_notify.or(self.runtime.startup_notify),
+        shutdown_notify: other
+          .runtime
+          .shutdown_notify
+          .or(self.runtime.shutdown_notify),
+        shutdown_timeout: other
+          .runtime
+          .shutdown_timeout
+          .or(self.runtime.shutdown_timeout),
+        tasks_file: other.runtime.tasks_file.or(self.runtime.tasks_file),
+        database: other.runtime.database.or(self.runtime.database),
+        dialogue_storage: other
+          .runtime
+          .dialogue_storage
+          .or(self.runtime.dialogue_storage),
+        dialogue_timeout: other
+          .runtime
+          .dialogue_timeout
+          .or(self.runtime.dialogue_timeout),
+        ..self.runtime
+      },
+    }
+  }
+
+  /// Time to wait for the sends in flight on shutdown
+  pub fn shutdown_timeout(&self) -> Duration {
+    Duration::from_secs(self.runtime.shutdown_timeout.unwrap_or(30))
+  }
+
+  /// Time before the idle dialogue is reset
+  pub fn dialogue_timeout(&self) -> Duration {
+    Duration::from_secs(self.runtime.dialogue_timeout.unwrap_or(30 * 60))
+  }
+
+  /// Path of the file the tasks are saved to, default to `tasks.json`
+  pub fn tasks_path(&self) -> PathBuf {
+    PathBuf::from(self.runtime.tasks_file.as_deref().unwrap_or("tasks.json"))
+  }
+
+  /// Path of the whitelist file, default to `whitelist.json`
+  pub fn whitelist_path(&self) -> PathBuf {
+    PathBuf::from(self.whitelist.file.as_deref().unwrap_or("whitelist.json"))
+  }
+
+  /// Read and parse the config file. Unknown keys are logged as warning.
+  pub fn from_file(path: &Path) -> Result<Self> {
+    let content = std::fs::read_to_string(path)
+      .with_context(|| format!("Fail to read config file {}", path.display()))?;
+    let (config, unknown) =
+      Self::parse(&content).with_context(|| format!("Invalid config file {}", path.display()))?;
+    if !unknown.is_empty() {
+      tracing::warn!(
+        "Unknown keys in config file {}: {}",
+        path.display(),
+        unknown.join(", ")
+      );
+    }
+    Ok(config)
+  }
+
+  /// Parse the TOML document, return the config and the unknown keys
+  fn parse(content: &str) -> Result<(Self, Vec<String>)> {
+    let value: toml::Value = toml::from_str(content)?;
+    let unknown = Self::unknown_keys(&value);
+    Ok((value.try_into()?, unknown))
+  }
+
+  fn unknown_keys(value: &toml::Value) -> Vec<String> {
+    let table = match value.as_table() {
+      Some(table) => table,
+      None => return Vec::new(),
+    };
+
+    let mut unknown = Vec::new();
+    for (section, value) in table {
+      let known = match KNOWN_KEYS.iter().find(|(name, _)| name == section) {
+        Some((_, known)) => known,
+        None => {
+          unknown.push(section.clone());
+          continue;
+        }
+      };
+      if let Some(table) = value.as_table() {
+        unknown.extend(
+          table
+            .keys()
+            .filter(|key| !known.contains(&key.as_str()))
+            .map(|key| format!("{section}.{key}")),
+        );
+      }
+    }
+    unknown
+  }
+
+  /// Map the runtime values to the environment variables
+  fn to_env_vars(&self) -> BTreeMap<&'static str, String> {
+    let mut vars = BTreeMap::new();
+    let mut set = |key: &'static str, val: Option<String>| {
+      if let Some(val) = val {
+        vars.insert(key, val);
+      }
+    };
+
+    let rt = &self.runtime;
+    set("NOTIFY_BOT_TIMEZONE", rt.timezone.clone());
+    set("NOTIFY_BOT_QUIET_HOURS", rt.quiet_hours.clone());
+    set("NOTIFY_BOT_MIN_INTERVAL", rt.min_interval.clone());
+    set("NOTIFY_BOT_MAX_INTERVAL", rt.max_interval.clone());
+    set(
+      "NOTIFY_BOT_MAX_TEXT_LENGTH",
+      rt.max_text_length.map(|n| n.to_string()),
+    );
+    set(
+      "NOTIFY_BOT_BUTTON_SCHEMES",
+      rt.button_schemes.as_ref().map(|s| s.join(",")),
+    );
+    set(
+      "NOTIFY_BOT_SEND_RETRIES",
+      rt.send_retries.map(|n| n.to_string()),
+    );
+    set(
+      "NOTIFY_BOT_MAX_FAILURES",
+      rt.max_failures.map(|n| n.to_string()),
+    );
+    set(
+      "NOTIFY_BOT_SEND_DELAY_MS",
+      rt.send_delay_ms.map(|n| n.to_string()),
+    );
+    set(
+      "NOTIFY_BOT_MESSAGES_PER_SEC",
+      rt.messages_per_sec.map(|n| n.to_string()),
+    );
+    set(
+      "NOTIFY_BOT_MESSAGES_PER_MIN_CHAT",
+      rt.messages_per_min_chat.map(|n| n.to_string()),
+    );
+    set("NOTIFY_BOT_AUDIT_LOG", rt.audit_log.clone());
+    set("NOTIFY_BOT_TEMPLATE_FILE", rt.template_file.clone());
+
+    vars
+  }
+
+  /// Export the runtime values as environment variables, which are read by the scheduler and
+  /// the clock. Variables already set in the environment are kept, so they override the file.
+  pub fn apply_to_env(&self) {
+    for (key, val) in self.to_env_vars() {
+      if env::var_os(key).is_none() {
+        env::set_var(key, val);
+      }
+    }
+  }
+
+  /// Check that the required values are set in either the file or the environment. The
+  /// maintainers are checked after the whitelist is loaded, as they might come from the
+  /// whitelist file.
+  pub fn check_required(&self) -> Result<()> {
+    if self
+      .bot
+      .token
+      .as_deref()
+      .map_or(true, |v| v.trim().is_empty())
+    {
+      anyhow::bail!(
+        "Missing required config `bot.token`, set it in the config file or set the environment \
+        variable TELOXIDE_TOKEN"
+      );
+    }
+    Ok(())
+  }
+}
+
+#[test]
+fn parse_config_test() {
+  let (config, unknown) = Config::parse(
+    r#"
+      [bot]
+      token = "abc:123"
+
+      [whitelist]
+      maintainers = [1, 2]
+      groups = ["-100123", "-100456:42"]
+      admin = [3]
+
+      [runtime]
+      timezone = "Asia/Shanghai"
+      send_retries = 5
+      max_text_length = 2000
+      button_schemes = ["https", "tg"]
+
+      [extra]
+      foo = 1
+    "#,
+  )
+  .unwrap();
+
+  assert_eq!(config.bot.token.as_deref(), Some("abc:123"));
+  assert_eq!(unknown, vec!["extra", "whitelist.admin"]);
+
+  assert_eq!(config.whitelist.maintainers, Some(vec![1, 2]));
+  assert_eq!(
+    config.whitelist.groups,
+    Some(vec![
+      GroupEntry {
+        id: -100123,
+        thread: None
+      },
+      GroupEntry {
+        id: -100456,
+        thread: Some(42)
+      },
+    ])
+  );
+
+  let vars = config.to_env_vars();
+  assert_eq!(vars["NOTIFY_BOT_TIMEZONE"], "Asia/Shanghai");
+  assert_eq!(vars["NOTIFY_BOT_SEND_RETRIES"], "5");
+  assert_eq!(vars["NOTIFY_BOT_MAX_TEXT_LENGTH"], "2000");
+  assert_eq!(vars["NOTIFY_BOT_BUTTON_SCHEMES"], "https,tg");
+  assert!(!vars.contains_key("NOTIFY_BOT_MAINTAINERS"));
+
+  // wrong type is a error
+  assert!(Config::parse("[runtime]\nsend_retries = \"five\"").is_err());
+  assert!(Config::parse("[whitelist]\ngroups = [\"-100123:abc\"]").is_err());
+}
+
+#[test]
+fn parse_list_test() {
+  assert_eq!(parse_list::<u64>("A", "1,2,3"), Ok(vec![1, 2, 3]));
+  assert_eq!(parse_list::<u64>("A", " 1 ,  2 "), Ok(vec![1, 2]));
+  assert_eq!(parse_list::<u64>("A", "1,,2,"), Ok(vec![1, 2]));
+  assert_eq!(parse_list::<u64>("A", ""), Ok(vec![]));
+
+  let err = parse_list::<i64>("NOTIFY_BOT_GROUPS", "-100123,, abc").unwrap_err();
+  assert_eq!(err.var, "NOTIFY_BOT_GROUPS");
+  assert_eq!(err.token, "abc");
+  assert_eq!(err.position, 3);
+
+  let groups = parse_list::<GroupEntry>("A", "-100123:42,").unwrap();
+  assert_eq!(groups[0].thread, Some(42));
+  assert!(parse_list::<GroupEntry>("A", "-100123:").is_err());
+  assert!(parse_list::<AdminScope>("A", "1:-100|x").is_err());
+}
+
+#[test]
+fn merge_config_test() {
+  let (file, _) = Config::parse(
+    r#"
+      [bot]
+      token = "file"
+
+      [whitelist]
+      maintainers = [1]
+      admins = [2]
+    "#,
+  )
+  .unwrap();
+  let env = Config {
+    whitelist: WhitelistConfig {
+      admins: Some(vec![3]),
+      admin_scopes: Some(vec!["3:-100123|-100456".parse().unwrap()]),
+      ..Default::default()
+    },
+    ..Default::default()
+  };
+
+  let config = file.merge(env);
+  assert_eq!(config.bot.token.as_deref(), Some("file"));
+  assert_eq!(config.whitelist.maintainers, Some(vec![1]));
+  assert_eq!(config.whitelist.admins, Some(vec![3]));
+  assert_eq!(
+    config.whitelist.admin_scopes,
+    Some(vec![AdminScope {
+      admin: 3,
+      groups: vec![-100123, -100456]
+    }])
+  );
+  assert_eq!(config.whitelist_path(), PathBuf::from("whitelist.json"));
+}
@@ -0,0 +1,129 @@
+//! Errors returned by the task pool, the runtime and the whitelist. The handlers and the binary
+//! convert them into `anyhow::Error` at the edge, while the text shown to the Telegram users is
+//! made from the variants by [`Error::user_message`].
+
+use std::path::PathBuf;
+use teloxide::{
+  types::{ChatId, UserId},
+  RequestError,
+};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error("no task found with id {0}")]
+  TaskNotFound(u32),
+  #[error("task name {0} is already used")]
+  TaskNameUsed(String),
+  #[error("template name {0} is already used")]
+  TemplateNameUsed(String),
+  #[error("no template found with name {0}")]
+  TemplateNotFound(String),
+  #[error("invalid template name {0}")]
+  InvalidTemplateName(String),
+  #[error("task {0} has a unexpected closed edit channel")]
+  TaskChannelClosed(u32),
+  #[error("task {0} is not stopped in time, aborted")]
+  TaskStopTimeout(u32),
+  #[error("interval should be greater than zero, and the time should be in the future")]
+  InvalidSchedule,
+  #[error("the end time should be in the future")]
+  InvalidEndTime,
+  #[error("the one-shot time is in the quiet hours {0}")]
+  InQuietHours(crate::schedule::QuietHours),
+  #[error("invalid notification index {index}, available index: 0 ~ {max}")]
+  NotificationIndex { index: usize, max: usize },
+  #[error("can't remove the last notification of the task")]
+  LastNotification,
+  #[error("task require at least one notification")]
+  NoNotification,
+  #[error("invalid notification weight {0}")]
+  InvalidWeight(u32),
+  #[error("task require at least one group")]
+  NoGroup,
+  #[error("group {group} is not a target of task {id}")]
+  GroupNotInTask { id: u32, group: ChatId },
+  #[error("task {id} has no override for group {group}")]
+  OverrideNotFound { id: u32, group: ChatId },
+  #[error("user {0} is not an admin")]
+  AdminNotFound(UserId),
+  #[error("user {0} is banned")]
+  UserBanned(UserId),
+  #[error("user {0} is not banned")]
+  UserNotBanned(UserId),
+  #[error("group {0} is not in the whitelist")]
+  GroupNotFound(ChatId),
+  #[error("group {0} is not disabled")]
+  GroupNotDisabled(ChatId),
+  #[error("no maintainer is configured")]
+  NoMaintainer,
+  #[error("{} is empty", .0.display())]
+  EmptyFile(PathBuf),
+  #[error("fail to load config: {0:#}")]
+  Config(anyhow::Error),
+  #[error("storage error: {0:#}")]
+  Storage(anyhow::Error),
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error(transparent)]
+  Json(#[from] serde_json::Error),
+  #[error(transparent)]
+  Telegram(#[from] RequestError),
+}
+
+impl Error {
+  /// The text shown to the Telegram users
+  pub fn user_message(&self) -> String {
+    match self {
+      Self::TaskNotFound(id) => format!("任务 {id} 不存在"),
+      Self::TaskNameUsed(name) => format!("任务名 {name} 已被使用"),
+      Self::TemplateNameUsed(name) => format!("模板名 {name} 已被使用"),
+      Self::TemplateNotFound(name) => format!("模板 {name} 不存在"),
+      Self::InvalidTemplateName(_) => format!(
+        "模板名需要是 1 到 {} 个字符，且不能包含空格",
+        crate::template::NAME_MAX_LEN
+      ),
+      Self::TaskChannelClosed(id) => format!("任务 {id} 已停止运行"),
+      Self::TaskStopTimeout(id) => format!("任务 {id} 停止超时，已强制结束"),
+      Self::InvalidSchedule => "发送间隔必须大于零，且发送时间必须在未来".to_string(),
+      Self::InvalidEndTime => "结束时间必须在未来".to_string(),
+      Self::InQuietHours(quiet) => {
+        format!("发送时间在免打扰时段 {quiet} 内，一次性任务不会被发送，请换一个时间")
+      }
+      Self::NotificationIndex { index, max } => {
+        format!("通知序号 {index} 不存在，可用的序号：0 ~ {max}")
+      }
+      Self::LastNotification => "任务至少需要保留一条通知".to_string(),
+      Self::NoNotification => "任务至少需要一条通知".to_string(),
+      Self::InvalidWeight(_) => format!(
+        "权重需要是 1 到 {} 的整数",
+        crate::schedule::MAX_NOTIFICATION_WEIGHT
+      ),
+      Self::NoGroup => "任务至少需要一个群组".to_string(),
+      Self::GroupNotInTask { id, group } => format!("群组 {group} 不是任务 {id} 的通知群组"),
+      Self::OverrideNotFound { id, group } => {
+        format!("任务 {id} 没有为群组 {group} 设置单独的通知")
+      }
+      Self::AdminNotFound(id) => format!("用户 {id} 不是管理员"),
+      Self::UserBanned(id) => format!("用户 {id} 已被封禁，请先使用 /unban {id} 解除封禁"),
+      Self::UserNotBanned(id) => format!("用户 {id} 没有被封禁"),
+      Self::GroupNotFound(id) => format!("群组 {id} 不在白名单中"),
+      Self::GroupNotDisabled(id) => format!("群组 {id} 没有被停用"),
+      Self::NoMaintainer => "没有配置任何维护者".to_string(),
+      Self::EmptyFile(_) | Self::Io(_) | Self::Json(_) => format!("读写文件失败：{self}"),
+      Self::Config(e) => format!("配置有误：{e:#}"),
+      Self::Storage(e) => format!("读写存储失败：{e:#}"),
+      Self::Telegram(e) => format!("Telegram 请求失败：{e}"),
+    }
+  }
+}
+
+#[test]
+fn user_message_test() {
+  assert_eq!(Error::TaskNotFound(3).user_message(), "任务 3 不存在");
+  assert_eq!(
+    Error::GroupNotDisabled(ChatId(-100)).user_message(),
+    "群组 -100 没有被停用"
+  );
+}
@@ -1,17 +1,51 @@
-use std::str::FromStr;
+use std::{
+  collections::HashMap,
+  future::Future,
+  str::FromStr,
+  sync::Arc,
+  time::{Duration, Instant},
+};
 
-use crate::{schedule::ScheduleTask, BotRuntime};
+use crate::{
+  clock,
+  error::Error,
+  runtime::ConsumedNonces,
+  schedule::{
+    alert_maintainers, bind_counter_buttons, format_duration, parse_counter_button, parse_duration,
+    send_notification, validate_weight, GroupOverride, ImportOutcome, IntervalBounds, Messenger,
+    Notification, RotationMode, Schedule, ScheduleTask, SendOptions, TaskInfo, TemplateVars,
+    WeekdaySet, COUNTER_BUTTON_PREFIX,
+  },
+  store::{export_tasks, parse_exported_tasks},
+  template::MessageTemplate,
+  BotRuntime, NotifyBot,
+};
 use anyhow::Result;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use chrono_tz::Tz;
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "sqlite")]
+use teloxide::dispatching::dialogue::{serializer::Json, SqliteStorage};
 use teloxide::{
   dispatching::{
-    dialogue::{self, InMemStorage},
+    dialogue::{self, InMemStorage, Storage},
     UpdateFilterExt, UpdateHandler,
   },
-  payloads::SendMessageSetters,
+  net::Download,
+  payloads::{
+    AnswerCallbackQuerySetters, EditMessageReplyMarkupSetters, EditMessageTextSetters,
+    SendDocumentSetters, SendMessageSetters, SetMyCommandsSetters,
+  },
   prelude::*,
-  types::{ChatId, InlineKeyboardButton, InlineKeyboardMarkup},
+  types::{
+    BotCommand, BotCommandScope, Chat, ChatId, Document, InlineKeyboardButton,
+    InlineKeyboardButtonKind, InlineKeyboardMarkup, InputFile, ParseMode, Recipient, UserId,
+  },
   utils::command::BotCommands,
+  ApiError, RequestError,
 };
 
 lazy_static::lazy_static!(
@@ -26,6 +60,24 @@ lazy_static::lazy_static!(
     ).unwrap();
 );
 
+/// Help message for the button definition syntax
+const BUTTON_FORMAT_HELP: &str = "=================================
+格式: [按钮文本|链接] （这里是半角的括号）
+示例：[注册|https://example.com]
+如果需要给按钮分不同的行，只需要在新的一行重现写按钮就行：
+示例：
+[注册|https://example.com/register] [登录|https://example.com/login]
+[下载|https://example.com/download] [反馈|https://example.com/feedback]
+链接默认支持 http、https 和 tg 开头的地址，例如：
+[加入群组|https://t.me/+AbCdEf] [打开机器人|tg://resolve?domain=username]
+用 cb: 开头代替链接可以创建计数按钮，群成员点击后按钮上会显示点击次数，例如：
+[报名|cb:signup] [请假|cb:leave]
+用 share: 开头代替链接可以创建分享按钮，点击后选择聊天并预填后面的文本，例如：
+[分享给朋友|share:快来看看这个活动]
+每行最多 8 个按钮，总共最多 100 个，按钮文本最多 30 个字符
+=================================
+";
+
 /// parse_button can parse multiple button and extract their context into a vector
 fn parse_button(text: &str) -> Option<Vec<String>> {
   let mut v = Vec::with_capacity(4);
@@ -39,19 +91,339 @@ fn parse_button(text: &str) -> Option<Vec<String>> {
   }
 }
 
+/// What the button does when it is pressed
+#[derive(Debug, PartialEq)]
+enum ButtonTarget {
+  /// Open the link
+  Url(url::Url),
+  /// Count the press, written as `cb:<action>`
+  Callback(String),
+  /// Open the chat picker with the prefilled text, written as `share:<text>`
+  Share(String),
+}
+
 /// parse_button_content parse *single* button context to two part.
 /// This function is used to split button contents, it is not used for parsing the button.
 /// So call `parse_button` for the raw button definition.
 /// This function will also validate URL correctness.
 /// It can split word|http link, word |http link, word | http link.
+/// The link can be replaced by `cb:<action>` or `share:<text>`, the shared text can contain
+/// spaces.
 /// Return `None` if the button text is not construct with normal word character,
 /// or link is not a valid URL.
-fn parse_button_content(text: &str) -> Option<(String, url::Url)> {
+fn parse_button_content(text: &str) -> Option<(String, ButtonTarget)> {
   // early return `None` if no match found
   let cap = BUT_CONTENT_REGEX.captures(text)?;
-  let url = url::Url::parse(cap.get(2)?.as_str()).ok()?;
   // early return `None` when any capture group doesn't matched
-  Some((cap.get(1)?.as_str().to_string(), url))
+  let label = cap.get(1)?.as_str().to_string();
+  let link = cap.get(2)?;
+  if let Some(query) = text[link.start()..].strip_prefix(SHARE_BUTTON_PREFIX) {
+    return Some((label, ButtonTarget::Share(query.trim().to_string())));
+  }
+  if let Some(action) = link.as_str().strip_prefix(COUNTER_BUTTON_PREFIX) {
+    return Some((label, ButtonTarget::Callback(action.to_string())));
+  }
+  let url = url::Url::parse(link.as_str()).ok()?;
+  Some((label, ButtonTarget::Url(url)))
+}
+
+/// Prefix of the share button in the button definition
+const SHARE_BUTTON_PREFIX: &str = "share:";
+
+/// Error about parsing the keyboard definition
+#[derive(Debug)]
+enum KeyboardParseError {
+  /// The line doesn't contains any `[...]` button definition
+  InvalidDefinition(String),
+  /// The content inside of the `[...]` is not `text|link`
+  InvalidContent(String),
+  /// The action of the counter button is empty, too long or has other characters than
+  /// letters, digits, `_` and `-`
+  InvalidAction(String),
+  /// The scheme of the link is not in the `allowed` list
+  SchemeNotAllowed {
+    scheme: String,
+    allowed: Vec<String>,
+  },
+  /// The row has more buttons than Telegram accept. Rows and buttons are counted from 1.
+  RowTooWide { row: usize, count: usize },
+  /// The keyboard has more buttons than Telegram accept
+  TooManyButtons(usize),
+  /// The label of the button is blank
+  EmptyLabel { row: usize, column: usize },
+  /// The label of the button is too long to display
+  LabelTooLong {
+    row: usize,
+    column: usize,
+    label: String,
+  },
+}
+
+impl KeyboardParseError {
+  /// Return a message to tell user what is wrong
+  fn hint(&self) -> String {
+    match self {
+      Self::InvalidDefinition(_) => "错误的链接定义！请参照上面的格式重新输入！".to_string(),
+      Self::InvalidContent(_) => "按钮的内容定义有问题！请重新输入！".to_string(),
+      Self::InvalidAction(action) => format!(
+        "计数按钮的名称“{action}”有问题，只能使用字母、数字、_ 和 -，最多 {COUNTER_ACTION_MAX_LEN} 个字符！请重新输入！"
+      ),
+      Self::SchemeNotAllowed { scheme, allowed } => format!(
+        "不支持 {scheme} 链接，按钮只能使用 {} 链接！请重新输入！",
+        allowed.join("、")
+      ),
+      Self::RowTooWide { row, count } => format!(
+        "第 {row} 行有 {count} 个按钮，每行最多 {KEYBOARD_ROW_MAX} 个！请拆分这一行后重新输入！"
+      ),
+      Self::TooManyButtons(count) => format!(
+        "一共有 {count} 个按钮，最多 {KEYBOARD_BUTTONS_MAX} 个！请删减后重新输入！"
+      ),
+      Self::EmptyLabel { row, column } => {
+        format!("第 {row} 行第 {column} 个按钮的文本为空！请修改后重新输入！")
+      }
+      Self::LabelTooLong { row, column, label } => format!(
+        "第 {row} 行第 {column} 个按钮“{label}”太长了，最多 {BUTTON_LABEL_MAX_LEN} 个字符！请修改后重新输入！"
+      ),
+    }
+  }
+}
+
+impl std::fmt::Display for KeyboardParseError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::InvalidDefinition(line) => write!(f, "invalid button definition: {line}"),
+      Self::InvalidContent(but) => write!(f, "invalid button contents: {but}"),
+      Self::InvalidAction(action) => write!(f, "invalid counter button action: {action}"),
+      Self::SchemeNotAllowed { scheme, .. } => write!(f, "link scheme {scheme} is not allowed"),
+      Self::RowTooWide { row, count } => write!(f, "row {row} has {count} buttons"),
+      Self::TooManyButtons(count) => write!(f, "keyboard has {count} buttons"),
+      Self::EmptyLabel { row, column } => write!(f, "button {row}:{column} has empty label"),
+      Self::LabelTooLong { row, column, label } => {
+        write!(f, "button {row}:{column} has too long label: {label}")
+      }
+    }
+  }
+}
+
+/// The callback data is limited to 64 bytes, and the sent counter button carries the prefix
+/// and the task id besides the action
+const COUNTER_ACTION_MAX_LEN: usize = 48;
+
+/// Telegram accept at most 8 buttons in a row
+const KEYBOARD_ROW_MAX: usize = 8;
+
+/// Telegram accept at most 100 buttons in a keyboard
+const KEYBOARD_BUTTONS_MAX: usize = 100;
+
+/// Longer label is cut off by the clients
+const BUTTON_LABEL_MAX_LEN: usize = 30;
+
+/// Check the keyboard against the Telegram limits, the first offending row or button is
+/// reported.
+fn validate_keyboard(keyboard: &[Vec<InlineKeyboardButton>]) -> Result<(), KeyboardParseError> {
+  for (i, row) in keyboard.iter().enumerate() {
+    if row.len() > KEYBOARD_ROW_MAX {
+      return Err(KeyboardParseError::RowTooWide {
+        row: i + 1,
+        count: row.len(),
+      });
+    }
+    for (j, button) in row.iter().enumerate() {
+      let label = button.text.trim();
+      if label.is_empty() {
+        return Err(KeyboardParseError::EmptyLabel {
+          row: i + 1,
+          column: j + 1,
+        });
+      }
+      if label.chars().count() > BUTTON_LABEL_MAX_LEN {
+        return Err(KeyboardParseError::LabelTooLong {
+          row: i + 1,
+          column: j + 1,
+          label: label.to_string(),
+        });
+      }
+    }
+  }
+
+  let count = keyboard.iter().map(Vec::len).sum();
+  if count > KEYBOARD_BUTTONS_MAX {
+    return Err(KeyboardParseError::TooManyButtons(count));
+  }
+  Ok(())
+}
+
+#[test]
+fn parse_keyboard_scheme_test() {
+  use crate::runtime::DEFAULT_BUTTON_SCHEMES;
+
+  let schemes = DEFAULT_BUTTON_SCHEMES
+    .iter()
+    .map(|s| s.to_string())
+    .collect::<Vec<_>>();
+  let link = |text: &str| match parse_keyboard(text, &schemes) {
+    Ok(keyboard) => match &keyboard.inline_keyboard[0][0].kind {
+      InlineKeyboardButtonKind::Url(url) => Ok(url.to_string()),
+      _ => unreachable!(),
+    },
+    Err(e) => Err(e),
+  };
+
+  assert_eq!(
+    link("[打开|tg://resolve?domain=notify_bot]").unwrap(),
+    "tg://resolve?domain=notify_bot"
+  );
+  // the invite links keep the `+` in the path
+  assert_eq!(
+    link("[加入|https://t.me/+AbCd-Ef_12]").unwrap(),
+    "https://t.me/+AbCd-Ef_12"
+  );
+  assert_eq!(
+    link("[加入|https://t.me/joinchat/AbCdEf]").unwrap(),
+    "https://t.me/joinchat/AbCdEf"
+  );
+  assert!(link("[网站|http://example.com]").is_ok());
+
+  let err = link("[点我|javascript:alert(1)]").unwrap_err();
+  assert!(matches!(
+    &err,
+    KeyboardParseError::SchemeNotAllowed { scheme, .. } if scheme == "javascript"
+  ));
+  assert!(err.hint().contains("javascript"));
+  assert!(link("[邮件|mailto:admin@example.com]").is_err());
+
+  // the allowed schemes are configurable
+  let mail = vec!["mailto".to_string()];
+  assert!(parse_keyboard("[邮件|mailto:admin@example.com]", &mail).is_ok());
+  assert!(parse_keyboard("[网站|https://example.com]", &mail).is_err());
+}
+
+#[test]
+fn parse_counter_button_test() {
+  let keyboard = parse_keyboard(
+    "[报名|cb:signup] [官网|https://example.com]",
+    &["https".to_string()],
+  )
+  .unwrap();
+  let row = &keyboard.inline_keyboard[0];
+  assert!(matches!(
+    &row[0].kind,
+    InlineKeyboardButtonKind::CallbackData(data) if data == "cb:signup"
+  ));
+  assert!(matches!(&row[1].kind, InlineKeyboardButtonKind::Url(_)));
+  // the definition is kept when editing the task
+  assert_eq!(
+    format_keyboard(&keyboard),
+    "[报名|cb:signup] [官网|https://example.com/]"
+  );
+
+  let schemes = vec!["https".to_string()];
+  for invalid in ["[报名|cb:]", "[报名|cb:sign:up]", "[报名|cb:sign%20up]"] {
+    assert!(matches!(
+      parse_keyboard(invalid, &schemes),
+      Err(KeyboardParseError::InvalidAction(_))
+    ));
+  }
+  let long = format!("[报名|cb:{}]", "a".repeat(COUNTER_ACTION_MAX_LEN + 1));
+  assert!(parse_keyboard(&long, &schemes).is_err());
+}
+
+#[test]
+fn validate_keyboard_test() {
+  let button = |label: &str| {
+    InlineKeyboardButton::url(label.to_string(), "https://example.com".parse().unwrap())
+  };
+  let row = |n: usize| {
+    (0..n)
+      .map(|i| button(&format!("按钮{i}")))
+      .collect::<Vec<_>>()
+  };
+
+  assert!(validate_keyboard(&[]).is_ok());
+  assert!(validate_keyboard(&[row(KEYBOARD_ROW_MAX), row(1)]).is_ok());
+  assert!(matches!(
+    validate_keyboard(&[row(1), row(KEYBOARD_ROW_MAX + 1)]),
+    Err(KeyboardParseError::RowTooWide { row: 2, count: 9 })
+  ));
+
+  let mut keyboard = vec![row(KEYBOARD_ROW_MAX); KEYBOARD_BUTTONS_MAX / KEYBOARD_ROW_MAX];
+  keyboard.push(row(KEYBOARD_BUTTONS_MAX % KEYBOARD_ROW_MAX));
+  assert!(validate_keyboard(&keyboard).is_ok());
+  keyboard.push(row(1));
+  assert!(matches!(
+    validate_keyboard(&keyboard),
+    Err(KeyboardParseError::TooManyButtons(101))
+  ));
+
+  assert!(matches!(
+    validate_keyboard(&[row(2), vec![button("好"), button("  ")]]),
+    Err(KeyboardParseError::EmptyLabel { row: 2, column: 2 })
+  ));
+  let long = "长".repeat(BUTTON_LABEL_MAX_LEN + 1);
+  assert!(validate_keyboard(&[vec![button(&"长".repeat(BUTTON_LABEL_MAX_LEN))]]).is_ok());
+  let err = validate_keyboard(&[vec![button("好"), button(&long)]]).unwrap_err();
+  assert!(matches!(
+    err,
+    KeyboardParseError::LabelTooLong {
+      row: 1,
+      column: 2,
+      ..
+    }
+  ));
+  assert!(err.hint().contains("第 1 行第 2 个按钮"));
+}
+
+/// parse_keyboard parse the whole keyboard definition. Each line of the text is a row
+/// of buttons. Links with the scheme not in `schemes` are refused.
+fn parse_keyboard(
+  text: &str,
+  schemes: &[String],
+) -> Result<InlineKeyboardMarkup, KeyboardParseError> {
+  // the final result
+  let mut keyboard: Vec<Vec<InlineKeyboardButton>> = vec![];
+  // parse buttons line by line
+  for line in text.lines() {
+    // create a row of button collection
+    let mut row = Vec::new();
+    // first parse the buttons in current line
+    let buttons =
+      parse_button(line).ok_or_else(|| KeyboardParseError::InvalidDefinition(line.to_string()))?;
+    // then parse the contents inside of the buttons definition
+    for but in buttons {
+      let (label, target) = parse_button_content(&but)
+        .ok_or_else(|| KeyboardParseError::InvalidContent(but.to_string()))?;
+      // finally create a new button and push into row
+      let button = match target {
+        ButtonTarget::Url(url) => {
+          if !schemes.iter().any(|s| s == url.scheme()) {
+            return Err(KeyboardParseError::SchemeNotAllowed {
+              scheme: url.scheme().to_string(),
+              allowed: schemes.to_vec(),
+            });
+          }
+          InlineKeyboardButton::url(label, url)
+        }
+        ButtonTarget::Callback(action) => {
+          let valid = !action.is_empty()
+            && action.len() <= COUNTER_ACTION_MAX_LEN
+            && action
+              .chars()
+              .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+          if !valid {
+            return Err(KeyboardParseError::InvalidAction(action));
+          }
+          InlineKeyboardButton::callback(label, format!("{COUNTER_BUTTON_PREFIX}{action}"))
+        }
+        ButtonTarget::Share(query) => InlineKeyboardButton::switch_inline_query(label, query),
+      };
+      row.push(button);
+    }
+    // push the new row into final results
+    keyboard.push(row);
+  }
+
+  validate_keyboard(&keyboard)?;
+  Ok(InlineKeyboardMarkup::new(keyboard))
 }
 
 #[test]
@@ -90,7 +462,7 @@ fn parse_button_content_test() {
     parse_button_content(text),
     Some((
       "Button".to_string(),
-      url::Url::parse("https://example.com").unwrap()
+      ButtonTarget::Url(url::Url::parse("https://example.com").unwrap())
     ))
   );
 
@@ -100,29 +472,212 @@ fn parse_button_content_test() {
     parse_button_content(text),
     Some((
       "按钮".to_string(),
-      url::Url::parse("https://example.com").unwrap()
+      ButtonTarget::Url(url::Url::parse("https://example.com").unwrap())
+    ))
+  );
+
+  // test counter and share buttons
+  assert_eq!(
+    parse_button_content("报名|cb:signup"),
+    Some((
+      "报名".to_string(),
+      ButtonTarget::Callback("signup".to_string())
+    ))
+  );
+  assert_eq!(
+    parse_button_content("分享 | share:快来 看看"),
+    Some((
+      "分享".to_string(),
+      ButtonTarget::Share("快来 看看".to_string())
     ))
   );
 }
 
-#[derive(Clone)]
+#[test]
+fn parse_mixed_buttons_test() {
+  let schemes = vec!["https".to_string()];
+  let keyboard = parse_keyboard(
+    "[官网|https://example.com] [报名|cb:signup] [分享|share:一起来报名吧]",
+    &schemes,
+  )
+  .unwrap();
+  let row = &keyboard.inline_keyboard[0];
+  assert_eq!(row.len(), 3);
+  assert!(matches!(&row[0].kind, InlineKeyboardButtonKind::Url(_)));
+  assert!(matches!(
+    &row[1].kind,
+    InlineKeyboardButtonKind::CallbackData(data) if data == "cb:signup"
+  ));
+  assert_eq!(row[2].text, "分享");
+  assert!(matches!(
+    &row[2].kind,
+    InlineKeyboardButtonKind::SwitchInlineQuery(query) if query == "一起来报名吧"
+  ));
+  assert_eq!(
+    format_keyboard(&keyboard),
+    "[官网|https://example.com/] [报名|cb:signup] [分享|share:一起来报名吧]"
+  );
+
+  // the share button is not a link, so it is not limited by the schemes
+  let keyboard = parse_keyboard("[分享|share:]\n[报名|cb:signup]", &[]).unwrap();
+  assert_eq!(keyboard.inline_keyboard.len(), 2);
+  assert!(matches!(
+    &keyboard.inline_keyboard[0][0].kind,
+    InlineKeyboardButtonKind::SwitchInlineQuery(query) if query.is_empty()
+  ));
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 /// AddTaskDialogueCurrentState describe current add task dialogue progress.
 pub enum AddTaskDialogueCurrentState {
   /// None describe that there is no add task dialogue
   None,
-  /// RequestNotifyText describe that current status bot require notification text
-  RequestNotifyText,
+  /// RequestTemplate describe that in current status, bot require the admin to choose a saved
+  /// template to start from, or not to use any template.
+  RequestTemplate { name: Option<String> },
+  /// RequestTemplateSchedule describe that in current status, bot require the schedule of the
+  /// task made from the template. The text and the buttons come from the template.
+  RequestTemplateSchedule {
+    name: Option<String>,
+    template: MessageTemplate,
+  },
+  /// RequestNotifyText describe that current status bot require notification text.
+  /// `name` is the optional task name given by the /addtask argument.
+  RequestNotifyText { name: Option<String> },
+  /// RequestParseMode describe that in current status, bot require the admin to choose how
+  /// the notification text is formatted.
+  RequestParseMode {
+    name: Option<String>,
+    content: Notification,
+  },
+  /// RequestPollType describe that in current status, bot require the admin to choose whether
+  /// the poll is anonymous and allow multiple answers.
+  RequestPollType {
+    name: Option<String>,
+    content: Notification,
+  },
   /// RequestRepeatInterval describe that in curret status, bot require notification
-  /// repeat interval settings
-  RequestRepeatInterval { text: String },
+  /// repeat interval or cron expression
+  RequestRepeatInterval {
+    name: Option<String>,
+    content: Notification,
+    parse_mode: Option<ParseMode>,
+  },
+  /// RequestWeekdays describe that in current status, bot require the weekdays to send
+  /// notification
+  RequestWeekdays {
+    name: Option<String>,
+    content: Notification,
+    parse_mode: Option<ParseMode>,
+    schedule: Schedule,
+  },
   /// RequestButtons describe that in current status, bot require button definition.
-  RequestButtons { text: String, interval: u64 },
+  RequestButtons {
+    name: Option<String>,
+    content: Notification,
+    parse_mode: Option<ParseMode>,
+    schedule: Schedule,
+    weekdays: WeekdaySet,
+  },
+  /// RequestGroups describe that in current status, bot require the admin to choose the
+  /// target groups from the whitelist.
+  RequestGroups {
+    name: Option<String>,
+    content: Notification,
+    parse_mode: Option<ParseMode>,
+    schedule: Schedule,
+    weekdays: WeekdaySet,
+    buttons: InlineKeyboardMarkup,
+    groups: Vec<ChatId>,
+  },
+  /// RequestFixedText describe that the preview failed to render with the parse mode, bot
+  /// require the admin to send the notification text again.
+  RequestFixedText {
+    name: Option<String>,
+    parse_mode: Option<ParseMode>,
+    schedule: Schedule,
+    weekdays: WeekdaySet,
+    buttons: InlineKeyboardMarkup,
+    groups: Vec<ChatId>,
+  },
   /// RequestConfirmation describe that in current status, bot require final result confirmation.
   RequestConfirmation {
-    text: String,
-    interval: u64,
+    name: Option<String>,
+    content: Notification,
+    parse_mode: Option<ParseMode>,
+    schedule: Schedule,
+    weekdays: WeekdaySet,
     buttons: InlineKeyboardMarkup,
+    groups: Vec<ChatId>,
+    options: NotifyOptions,
+    /// The preview messages, edited when a field is revised
+    preview: Vec<i32>,
+    /// Embedded in the buttons that end the dialogue, so the repeated presses are rejected
+    #[serde(default)]
+    nonce: u32,
+  },
+  /// ReviseTask describe that in current status, bot require the new value of `field` for the
+  /// task waiting for confirmation. `confirmation` is the RequestConfirmation state to go back
+  /// to, and `confirm_msg` is the confirmation message to refresh.
+  ReviseTask {
+    field: ReviseField,
+    confirmation: Box<AddTaskDialogueCurrentState>,
+    confirm_msg: i32,
+  },
+  /// RequestExtraNotifyText describe that in current status, bot require a new notification
+  /// text for the existing task with the given id.
+  RequestExtraNotifyText { id: u32 },
+  /// RequestExtraNotifyWeight describe that in current status, bot require the weight of the
+  /// new notification for the existing task with the given id.
+  RequestExtraNotifyWeight { id: u32, content: Notification },
+  /// RequestReplaceText describe that in current status, bot require a new text to replace the
+  /// notification at `index` of the existing task.
+  RequestReplaceText { id: u32, index: usize },
+  /// RequestNewButtons describe that in current status, bot require new button definition
+  /// for the existing task.
+  RequestNewButtons { id: u32 },
+  /// RequestNewButtonsConfirmation describe that in current status, bot require confirmation
+  /// for replacing the buttons of the existing task.
+  RequestNewButtonsConfirmation {
+    id: u32,
+    buttons: Option<InlineKeyboardMarkup>,
   },
+  /// RequestCloneInterval describe that in current status, bot require the schedule of the
+  /// paused task with the given id, which is just cloned from the task `source`.
+  RequestCloneInterval { id: u32, source: u32 },
+  /// RequestImportFile describe that in current status, bot require the JSON file made by
+  /// /exporttasks.
+  RequestImportFile,
+  /// RequestOverrideText describe that in current status, bot require the content sent to
+  /// `group` instead of the notification of the task.
+  RequestOverrideText { id: u32, group: ChatId },
+  /// RequestOverrideButtons describe that in current status, bot require the buttons attached
+  /// to the override content of `group`.
+  RequestOverrideButtons {
+    id: u32,
+    group: ChatId,
+    content: Notification,
+  },
+  /// EditTaskMenu describe that in current status, bot require admin to choose which part of
+  /// the task to edit. `changes` record the modification made in this edit dialogue.
+  EditTaskMenu { id: u32, changes: Vec<String> },
+  /// EditTaskText describe that in current status, bot require the new notification text
+  EditTaskText { id: u32, changes: Vec<String> },
+  /// EditTaskInterval describe that in current status, bot require the new repeat interval or
+  /// cron expression
+  EditTaskInterval { id: u32, changes: Vec<String> },
+  /// EditTaskButtons describe that in current status, bot require the new button definition
+  EditTaskButtons { id: u32, changes: Vec<String> },
+  /// EditTaskGroups describe that in current status, bot require the new target groups
+  EditTaskGroups { id: u32, changes: Vec<String> },
+}
+
+/// The part of the new task that can be revised from the confirmation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReviseField {
+  Text,
+  Schedule,
+  Buttons,
 }
 
 impl Default for AddTaskDialogueCurrentState {
@@ -131,431 +686,6001 @@ impl Default for AddTaskDialogueCurrentState {
   }
 }
 
-/// An alias type for shorthand, nothing special
-pub type AddTaskDialogue =
-  Dialogue<AddTaskDialogueCurrentState, InMemStorage<AddTaskDialogueCurrentState>>;
+/// Where the dialogue states are kept. The states in memory are lost on restart, so the admin
+/// in the middle of a dialogue has to start over. The dialogues idle for longer than the timeout
+/// are reset by [`expire_dialogue`].
+pub struct DialogueStorage {
+  backend: DialogueBackend,
+  timeout: Duration,
+  /// The time of the last message in each dialogue
+  last_active: Mutex<HashMap<ChatId, Instant>>,
+}
 
-/// Handler for AddTaskDialogueCurrentState::RequestNotifyText status
-/// request_notify_text receive notification text, store in memory, and change status
-/// to AddTaskDialogueCurrentState::RequestRepeatInterval.
-async fn request_notify_text(
-  msg: Message,
-  bot: AutoSend<Bot>,
-  dialogue: AddTaskDialogue,
-) -> Result<()> {
-  match msg.text() {
-    Some(notify) => {
-      bot
-        .send_message(
-          msg.chat.id,
-          "请发送时间间隔，只需要数字即可。（单位：分钟）",
-        )
-        .await?;
-      // Update next status to interval request
-      dialogue
-        .update(AddTaskDialogueCurrentState::RequestRepeatInterval {
-          text: notify.to_string(),
-        })
-        .await?;
-    }
-    None => {
-      bot.send_message(msg.chat.id, "请发送通知的文本").await?;
+enum DialogueBackend {
+  Memory(Arc<InMemStorage<AddTaskDialogueCurrentState>>),
+  /// Keep the states in a SQLite database, so the dialogues survive restarts
+  #[cfg(feature = "sqlite")]
+  Sqlite(Arc<SqliteStorage<Json>>),
+}
+
+impl DialogueStorage {
+  fn new(backend: DialogueBackend) -> Self {
+    Self {
+      backend,
+      timeout: Duration::from_secs(30 * 60),
+      last_active: Mutex::new(HashMap::new()),
     }
   }
 
-  Ok(())
+  pub fn memory() -> Self {
+    Self::new(DialogueBackend::Memory(InMemStorage::new()))
+  }
+
+  /// Open or create the SQLite database at `path`
+  #[cfg(feature = "sqlite")]
+  pub async fn sqlite(path: &str) -> Result<Self> {
+    Ok(Self::new(DialogueBackend::Sqlite(
+      SqliteStorage::open(path, Json).await?,
+    )))
+  }
+
+  /// Reset the dialogues idle for longer than `timeout`, default to 30 minutes
+  pub fn timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = timeout;
+    self
+  }
+
+  /// Record the activity of the dialogue, return whether it was idle for longer than the
+  /// timeout. The dialogue restored after restart starts counting from the first message.
+  fn touch(&self, chat_id: ChatId) -> bool {
+    let now = Instant::now();
+    let last = self.last_active.lock().insert(chat_id, now);
+    last.map_or(false, |last| now.duration_since(last) > self.timeout)
+  }
 }
 
-/// Handler for AddTaskDialogueCurrentState::RequestRepeatInterval status
-/// It parse interval to u64, then update status to RequestButtons.
-async fn request_repeat_interval(
-  msg: Message,
-  bot: AutoSend<Bot>,
-  dialogue: AddTaskDialogue,
-  text: String,
-) -> Result<()> {
-  match msg.text().map(|t| t.parse::<u64>()) {
-    Some(Ok(interval)) => {
-      bot
-        .send_message(
-          msg.chat.id,
-          format!("bot 将会毎 {interval} 分钟发送一次：\n\n{text}"),
-        )
-        .await?;
+impl Storage<AddTaskDialogueCurrentState> for DialogueStorage {
+  type Error = anyhow::Error;
 
-      bot
-        .send_message(
-          msg.chat.id,
-          "接下来请你输入附带在定时通知上的按钮信息:
-=================================
-格式: [按钮文本|链接] （这里是半角的括号）
-示例：[注册|https://example.com]
-如果需要给按钮分不同的行，只需要在新的一行重现写按钮就行：
-示例：
-[注册|https://example.com/register] [登录|https://example.com/login]
-[下载|https://example.com/download] [反馈|https://example.com/feedback]
-=================================
-"
-          .to_string(),
-        )
-        .await?;
-      dialogue
-        .update(AddTaskDialogueCurrentState::RequestButtons { text, interval })
-        .await?;
-    }
-    _ => {
-      bot
-        .send_message(msg.chat.id, "非法输入！请只输入数字")
-        .await?;
-    }
+  fn remove_dialogue(self: Arc<Self>, chat_id: ChatId) -> BoxFuture<'static, Result<()>> {
+    Box::pin(async move {
+      self.last_active.lock().remove(&chat_id);
+      match &self.backend {
+        DialogueBackend::Memory(s) => s.clone().remove_dialogue(chat_id).await?,
+        #[cfg(feature = "sqlite")]
+        DialogueBackend::Sqlite(s) => s.clone().remove_dialogue(chat_id).await?,
+      }
+      Ok(())
+    })
+  }
+
+  fn update_dialogue(
+    self: Arc<Self>,
+    chat_id: ChatId,
+    dialogue: AddTaskDialogueCurrentState,
+  ) -> BoxFuture<'static, Result<()>> {
+    Box::pin(async move {
+      self.touch(chat_id);
+      match &self.backend {
+        DialogueBackend::Memory(s) => s.clone().update_dialogue(chat_id, dialogue).await?,
+        #[cfg(feature = "sqlite")]
+        DialogueBackend::Sqlite(s) => s.clone().update_dialogue(chat_id, dialogue).await?,
+      }
+      Ok(())
+    })
+  }
+
+  fn get_dialogue(
+    self: Arc<Self>,
+    chat_id: ChatId,
+  ) -> BoxFuture<'static, Result<Option<AddTaskDialogueCurrentState>>> {
+    Box::pin(async move {
+      Ok(match &self.backend {
+        DialogueBackend::Memory(s) => s.clone().get_dialogue(chat_id).await?,
+        #[cfg(feature = "sqlite")]
+        DialogueBackend::Sqlite(s) => s.clone().get_dialogue(chat_id).await?,
+      })
+    })
   }
-  Ok(())
 }
 
-/// Handler for AddTaskDialogueCurrentState::RequestButtons status
-/// It parse input to buttons, then update status to RequestConfirmation.
-async fn request_buttons(
-  msg: Message,
-  bot: AutoSend<Bot>,
+/// Reset the dialogue idle for longer than the timeout, so the message is not taken as the input
+/// of an abandoned dialogue. The message is then handled as if there is no dialogue.
+async fn expire_dialogue(
+  state: AddTaskDialogueCurrentState,
   dialogue: AddTaskDialogue,
-  (text, interval): (String, u64),
-) -> Result<()> {
-  if msg.text().is_none() {
-    bot
-      .send_message(msg.chat.id, "bot 需要文字消息！请重新输入！")
-      .await?;
-    anyhow::bail!("invalid message text for parsing buttons");
+  storage: Arc<DialogueStorage>,
+  bot: NotifyBot,
+) -> Option<AddTaskDialogueCurrentState> {
+  if matches!(state, AddTaskDialogueCurrentState::None) || !storage.touch(dialogue.chat_id()) {
+    return Some(state);
   }
 
-  let msg_text = msg.text().unwrap();
-  // the final result
-  let mut keyboard: Vec<Vec<InlineKeyboardButton>> = vec![];
-  // parse buttons line by line
-  for line in msg_text.lines() {
-    // create a row of button collection
-    let mut row = Vec::new();
-    // first parse the buttons in current line
-    let buttons = parse_button(line);
-    if buttons.is_none() {
-      bot
-        .send_message(msg.chat.id, "错误的链接定义！请参照上面的格式重新输入！")
-        .await?;
-      anyhow::bail!("invalid button definition: {}", line);
+  if let Err(e) = dialogue.exit().await {
+    tracing::error!("Fail to reset the dialogue: {e:#}");
+    return Some(state);
+  }
+  let text = match state {
+    AddTaskDialogueCurrentState::EditTaskMenu { .. }
+    | AddTaskDialogueCurrentState::EditTaskText { .. }
+    | AddTaskDialogueCurrentState::EditTaskInterval { .. }
+    | AddTaskDialogueCurrentState::EditTaskButtons { .. }
+    | AddTaskDialogueCurrentState::EditTaskGroups { .. } => "任务编辑已超时，请重新 /edittask",
+    AddTaskDialogueCurrentState::RequestCloneInterval { .. } => {
+      "设置周期已超时，复制的任务保持暂停，请用 /setinterval 和 /resumetask 继续设置"
     }
-    let buttons = buttons.unwrap();
-    // then parse the contents inside of the buttons definition
-    for but in buttons {
-      let pair = parse_button_content(&but);
-      if pair.is_none() {
-        bot
-          .send_message(msg.chat.id, "按钮的内容定义有问题！请重新输入！")
-          .await?;
-        anyhow::bail!("invalid button contents: {}", but);
-      }
-      let pair = pair.unwrap();
-      // finally create a new button and push into row
-      row.push(InlineKeyboardButton::url(pair.0, pair.1));
+    AddTaskDialogueCurrentState::RequestImportFile => "导入已超时，请重新 /importtasks",
+    AddTaskDialogueCurrentState::RequestOverrideText { .. }
+    | AddTaskDialogueCurrentState::RequestOverrideButtons { .. } => {
+      "设置单独通知已超时，请重新 /override"
     }
-    // push the new row into final results
-    keyboard.push(row);
+    _ => "任务创建已超时，请重新 /addtask",
+  };
+  if let Err(e) = bot.send_message(dialogue.chat_id(), text).await {
+    tracing::warn!("Fail to tell the dialogue timeout: {e}");
   }
+  Some(AddTaskDialogueCurrentState::None)
+}
 
-  let buttons = InlineKeyboardMarkup::new(keyboard);
+#[test]
+fn dialogue_timeout_test() {
+  let storage = DialogueStorage::memory().timeout(Duration::from_secs(60));
+  let chat = ChatId(1234);
+  assert!(!storage.touch(chat));
+  assert!(!storage.touch(chat));
 
-  bot
-    .send_message(msg.chat.id, text.to_string())
-    .reply_markup(buttons.clone())
-    .await?;
+  let old = Instant::now() - Duration::from_secs(120);
+  storage.last_active.lock().insert(chat, old);
+  assert!(storage.touch(chat));
+  // the timer restarts after the reset
+  assert!(!storage.touch(chat));
+}
 
-  bot
-    .send_message(
-      msg.chat.id,
-      format!("上面的信息将会每隔 {interval} 分钟重复一次。\n请确认添加这个新的通知："),
-    )
-    .reply_markup(create_add_task_confirm_buttons())
-    .await?;
+/// An alias type for shorthand, nothing special
+pub type AddTaskDialogue = Dialogue<AddTaskDialogueCurrentState, DialogueStorage>;
 
-  dialogue
-    .update(AddTaskDialogueCurrentState::RequestConfirmation {
-      text,
-      interval,
-      buttons,
-    })
-    .await?;
+#[test]
+fn dialogue_state_serde_test() {
+  use AddTaskDialogueCurrentState::*;
 
-  Ok(())
+  let name = Some("早报".to_string());
+  let content = Notification::Text("早上好".to_string());
+  let parse_mode = Some(ParseMode::Html);
+  let schedule = Schedule::Every(Duration::from_secs(3600));
+  let weekdays: WeekdaySet = "一三五".parse().unwrap();
+  let buttons = InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::url(
+    "Button".to_string(),
+    "https://example.com".parse().unwrap(),
+  )]]);
+  let groups = vec![ChatId(-1001)];
+  let changes = vec!["修改了通知内容".to_string()];
+  let states = [
+    AddTaskDialogueCurrentState::None,
+    RequestTemplate { name: name.clone() },
+    RequestTemplateSchedule {
+      name: name.clone(),
+      template: MessageTemplate {
+        content: content.clone(),
+        parse_mode,
+        buttons: Some(buttons.clone()),
+      },
+    },
+    RequestNotifyText { name: name.clone() },
+    RequestParseMode {
+      name: name.clone(),
+      content: content.clone(),
+    },
+    RequestPollType {
+      name: name.clone(),
+      content: content.clone(),
+    },
+    RequestRepeatInterval {
+      name: name.clone(),
+      content: content.clone(),
+      parse_mode,
+    },
+    RequestWeekdays {
+      name: name.clone(),
+      content: content.clone(),
+      parse_mode,
+      schedule: schedule.clone(),
+    },
+    RequestButtons {
+      name: name.clone(),
+      content: content.clone(),
+      parse_mode,
+      schedule: schedule.clone(),
+      weekdays,
+    },
+    RequestGroups {
+      name: name.clone(),
+      content: content.clone(),
+      parse_mode,
+      schedule: schedule.clone(),
+      weekdays,
+      buttons: buttons.clone(),
+      groups: groups.clone(),
+    },
+    RequestFixedText {
+      name: name.clone(),
+      parse_mode,
+      schedule: schedule.clone(),
+      weekdays,
+      buttons: buttons.clone(),
+      groups: groups.clone(),
+    },
+    RequestConfirmation {
+      name: name.clone(),
+      content: content.clone(),
+      parse_mode,
+      schedule: schedule.clone(),
+      weekdays,
+      buttons: buttons.clone(),
+      groups: groups.clone(),
+      options: NotifyOptions::default(),
+      preview: vec![10],
+      nonce: 42,
+    },
+    ReviseTask {
+      field: ReviseField::Schedule,
+      confirmation: Box::new(RequestConfirmation {
+        name,
+        content,
+        parse_mode,
+        schedule,
+        weekdays,
+        buttons: buttons.clone(),
+        groups,
+        options: NotifyOptions::default(),
+        preview: vec![10],
+        nonce: 42,
+      }),
+      confirm_msg: 11,
+    },
+    RequestExtraNotifyText { id: 1 },
+    RequestExtraNotifyWeight {
+      id: 1,
+      content: content.clone(),
+    },
+    RequestReplaceText { id: 1, index: 2 },
+    RequestNewButtons { id: 1 },
+    RequestNewButtonsConfirmation {
+      id: 1,
+      buttons: Some(buttons),
+    },
+    RequestCloneInterval { id: 2, source: 1 },
+    RequestImportFile,
+    RequestOverrideText {
+      id: 1,
+      group: ChatId(-1001),
+    },
+    RequestOverrideButtons {
+      id: 1,
+      group: ChatId(-1001),
+      content: Notification::Text("Good morning".to_string()),
+    },
+    EditTaskMenu {
+      id: 1,
+      changes: changes.clone(),
+    },
+    EditTaskText {
+      id: 1,
+      changes: changes.clone(),
+    },
+    EditTaskInterval {
+      id: 1,
+      changes: changes.clone(),
+    },
+    EditTaskButtons {
+      id: 1,
+      changes: changes.clone(),
+    },
+    EditTaskGroups { id: 1, changes },
+  ];
+  for state in states {
+    let json = serde_json::to_string(&state).unwrap();
+    let back: AddTaskDialogueCurrentState = serde_json::from_str(&json).unwrap();
+    assert_eq!(serde_json::to_string(&back).unwrap(), json);
+  }
 }
 
-/// Create a InlineKeyboardMarkup for confirmation. Callback data is prefixed
-/// by `add_task_confirm_`. Suffix `y` means confirm, `n` means cancel.
-fn create_add_task_confirm_buttons() -> InlineKeyboardMarkup {
-  let buttons = vec![vec![
-    InlineKeyboardButton::callback("确认", "add_task_confirm_y"),
-    InlineKeyboardButton::callback("取消", "add_task_confirm_n"),
-  ]];
-  InlineKeyboardMarkup::new(buttons)
-}
+/// Hint for the notification content input
+const NOTIFICATION_HELP: &str =
+  "请发送通知的文本，或者图片（可以附带说明文字，多张图片请以相册的形式发送），\
+  也可以转发一条频道消息，机器人会定时复制这条消息。\n\
+  文本中可以使用 {date} {time} {weekday} {count} {group_title}，\
+  发送时会替换为日期、时间、星期、第几次发送和群组名称";
 
-/// Callback handler for buttons CallbackQuery.
-async fn button_callback_handler(
-  q: CallbackQuery,
-  bot: AutoSend<Bot>,
-  dialogue: AddTaskDialogue,
-  mut rt: BotRuntime,
-  (text, interval, buttons): (String, u64, InlineKeyboardMarkup),
-) -> Result<()> {
-  // we might create some empty button for dressing
-  if q.data.is_none() {
-    return Ok(());
+/// Extract the notification from the message. Photo message use the largest size of the photo
+/// and the caption, otherwise the text of the message is used.
+fn notification_from_message(msg: &Message) -> Option<Notification> {
+  // message forwarded from a channel can be copied from the source directly
+  if let (Some(chat), Some(message_id)) = (msg.forward_from_chat(), msg.forward_from_message_id()) {
+    return Some(Notification::Copy {
+      from_chat: chat.id,
+      message_id,
+    });
   }
+  if let Some(photo) = msg.photo() {
+    let largest = photo.iter().max_by_key(|p| p.width * p.height)?;
+    return Some(Notification::Photo {
+      file_id: largest.file_id.clone(),
+      caption: msg.caption().map(str::to_string),
+    });
+  }
+  msg.text().map(|text| Notification::Text(text.to_string()))
+}
 
-  let data = q.data.unwrap();
-  let chat_id = q
-    .message
-    .ok_or_else(|| anyhow::anyhow!("A button callback without message can't be handle"))?
-    .chat
-    .id;
+/// How long to wait for the rest messages of an album
+const ALBUM_COLLECT_DELAY: Duration = Duration::from_millis(1500);
 
-  // We will use interval number as minute in release build
-  #[cfg(not(debug_assertions))]
-  let interval = interval * 60;
+/// Telegram accept at most 10 photos in an album
+const ALBUM_MAX_PHOTOS: usize = 10;
 
-  match data.as_str() {
-    "add_task_confirm_y" => {
-      let task = ScheduleTask::new(rt.subscribe_shutdown_sig())
-        .interval(interval)
-        .pending_notification(vec![text])
-        .groups(rt.get_group().to_vec())
-        .msg_buttons(buttons);
-      rt.task_pool.add_task(task);
-      bot.send_message(chat_id, "你已提交了任务！").await?;
-      dialogue.exit().await?;
+/// Convert the messages of an album into notification. The photos are sorted by the message
+/// id, and the first caption is used for the whole album. Return None if there is no photo.
+fn notification_from_album(album: &mut [Message]) -> Option<Notification> {
+  album.sort_unstable_by_key(|msg| msg.id);
+  let mut file_ids = Vec::new();
+  let mut caption = None;
+  for msg in album.iter() {
+    let largest = match msg
+      .photo()
+      .and_then(|p| p.iter().max_by_key(|p| p.width * p.height))
+    {
+      Some(largest) => largest,
+      None => continue,
+    };
+    file_ids.push(largest.file_id.clone());
+    if caption.is_none() {
+      caption = msg.caption().map(str::to_string);
     }
-    "add_task_confirm_n" => {
-      bot.send_message(chat_id, "你已取消了任务！").await?;
-      dialogue.exit().await?;
+  }
+  file_ids.truncate(ALBUM_MAX_PHOTOS);
+
+  match file_ids.len() {
+    0 => None,
+    1 => Some(Notification::Photo {
+      file_id: file_ids.remove(0),
+      caption,
+    }),
+    _ => Some(Notification::Album { file_ids, caption }),
+  }
+}
+
+/// Receive the notification from the message, then handle it with `next`. Telegram deliver an
+/// album as several messages sharing the same media group id, and the messages of one chat are
+/// handled one by one, so the first message of the album wait for the rest in background, and
+/// the others are only buffered.
+async fn receive_notification<F, Fut>(
+  msg: Message,
+  bot: NotifyBot,
+  rt: BotRuntime,
+  next: F,
+) -> Result<()>
+where
+  F: FnOnce(Notification) -> Fut + Send + 'static,
+  Fut: Future<Output = Result<()>> + Send + 'static,
+{
+  let max_text = rt.max_text_length;
+  let group = match msg.media_group_id() {
+    Some(group) => group.to_string(),
+    None => {
+      return match notification_from_message(&msg) {
+        Some(content) => match check_text_length(&content, max_text) {
+          Ok(_) => next(content).await,
+          Err(hint) => {
+            bot.send_message(msg.chat.id, hint).await?;
+            Ok(())
+          }
+        },
+        None => {
+          bot.send_message(msg.chat.id, NOTIFICATION_HELP).await?;
+          Ok(())
+        }
+      };
     }
-    _ => {}
+  };
+
+  let chat_id = msg.chat.id;
+  let first = {
+    let mut albums = rt.albums.lock();
+    let album = albums.entry(group.clone()).or_default();
+    album.push(msg);
+    album.len() == 1
+  };
+  if !first {
+    return Ok(());
   }
 
+  tokio::spawn(async move {
+    tokio::time::sleep(ALBUM_COLLECT_DELAY).await;
+    let mut album = rt.albums.lock().remove(&group).unwrap_or_default();
+    let result = match notification_from_album(&mut album) {
+      Some(content) => match check_text_length(&content, max_text) {
+        Ok(_) => next(content).await,
+        Err(hint) => bot
+          .send_message(chat_id, hint)
+          .await
+          .map(|_| ())
+          .map_err(Into::into),
+      },
+      None => bot
+        .send_message(chat_id, NOTIFICATION_HELP)
+        .await
+        .map(|_| ())
+        .map_err(Into::into),
+    };
+    if let Err(e) = result {
+      tracing::error!("Fail to handle the album {group}: {e}");
+    }
+  });
+
   Ok(())
 }
 
-#[derive(BotCommands, Debug, Clone)]
-#[command(rename = "lowercase", description = "These commands are supported:")]
-enum Command {
-  #[command(description = "显示这条帮助消息")]
-  Help,
-  #[command(description = "显示这条帮助消息")]
-  Start,
-  #[command(description = "添加一个新的播报任务。")]
-  AddTask,
-  #[command(description = "列出当前所有的播报任务")]
-  ListTask,
-  #[command(description = "删除指定的任务。")]
-  DelTask,
-  #[command(description = "添加一个新的 bot 管理员（维护者专用）")]
-  AddAdmin,
-  #[command(description = "删除 bot 管理员（维护者专用）")]
-  DelAdmin,
-  #[command(description = "添加一个新的通知群")]
-  AddGroup,
-  #[command(description = "删除通知群")]
-  DelGroup,
+/// Telegram accept at most 1024 characters in the caption of a photo or an album
+const CAPTION_MAX_LEN: usize = 1024;
+
+/// Length of the text counted as Telegram does, in UTF-16 code units
+fn telegram_len(text: &str) -> usize {
+  text.encode_utf16().count()
 }
 
-/// Response command man page
-async fn help(msg: Message, bot: AutoSend<Bot>) -> Result<()> {
-  bot
-    .send_message(msg.chat.id, Command::descriptions().to_string())
+/// Check the text or the caption of the notification against the limits. `max_text` is the
+/// configured limit of the text, the caption is also kept within Telegram's limit. Return the
+/// hint with the current length and the maximum if it is too long.
+fn check_text_length(content: &Notification, max_text: usize) -> Result<(), String> {
+  let (kind, text, max) = match content {
+    Notification::Text(text) => ("通知文本", text, max_text),
+    Notification::Photo {
+      caption: Some(caption),
+      ..
+    }
+    | Notification::Album {
+      caption: Some(caption),
+      ..
+    } => ("图片说明", caption, max_text.min(CAPTION_MAX_LEN)),
+    _ => return Ok(()),
+  };
+  let len = telegram_len(text);
+  if len > max {
+    return Err(format!(
+      "{kind}过长：当前 {len} 字，最多 {max} 字（emoji 等字符按 2 字计算），请修改后重新发送："
+    ));
+  }
+  Ok(())
+}
+
+#[test]
+fn check_text_length_test() {
+  use crate::runtime::TEXT_MAX_LEN;
+
+  let text = |s: String| Notification::Text(s);
+  assert!(check_text_length(&text("a".repeat(TEXT_MAX_LEN)), TEXT_MAX_LEN).is_ok());
+  assert!(check_text_length(&text("a".repeat(TEXT_MAX_LEN + 1)), TEXT_MAX_LEN).is_err());
+
+  // CJK characters take one unit, although they are three bytes in UTF-8
+  assert_eq!(telegram_len("通知"), 2);
+  assert!(check_text_length(&text("通".repeat(TEXT_MAX_LEN)), TEXT_MAX_LEN).is_ok());
+  assert!(check_text_length(&text("通".repeat(TEXT_MAX_LEN + 1)), TEXT_MAX_LEN).is_err());
+
+  // emoji out of the BMP take two units
+  assert_eq!(telegram_len("🎉"), 2);
+  let half = TEXT_MAX_LEN / 2;
+  assert!(check_text_length(&text("🎉".repeat(half)), TEXT_MAX_LEN).is_ok());
+  let err = check_text_length(&text(format!("{}a", "🎉".repeat(half))), TEXT_MAX_LEN).unwrap_err();
+  assert!(err.contains("当前 4097 字，最多 4096 字"));
+
+  // the stricter limit, and the caption limit
+  assert!(check_text_length(&text("a".repeat(101)), 100).is_err());
+  let photo = |caption: String| Notification::Photo {
+    file_id: "id".to_string(),
+    caption: Some(caption),
+  };
+  assert!(check_text_length(&photo("a".repeat(CAPTION_MAX_LEN)), TEXT_MAX_LEN).is_ok());
+  assert!(check_text_length(&photo("a".repeat(CAPTION_MAX_LEN + 1)), TEXT_MAX_LEN).is_err());
+}
+
+/// Handler for AddTaskDialogueCurrentState::RequestNotifyText status
+/// request_notify_text receive notification text or photo, store in memory, and change status
+/// to AddTaskDialogueCurrentState::RequestParseMode. Forwarded message keep its own format, so
+/// it goes to AddTaskDialogueCurrentState::RequestRepeatInterval directly.
+async fn request_notify_text(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+  name: Option<String>,
+) -> Result<()> {
+  let chat_id = msg.chat.id;
+  receive_notification(msg, bot.clone(), rt, move |content| async move {
+    if let Notification::Copy { .. } = content {
+      bot.send_message(chat_id, SCHEDULE_FORMAT_HELP).await?;
+      dialogue
+        .update(AddTaskDialogueCurrentState::RequestRepeatInterval {
+          name,
+          content,
+          parse_mode: None,
+        })
+        .await?;
+      return Ok(());
+    }
+
+    bot
+      .send_message(chat_id, "请选择通知文本的格式：")
+      .reply_markup(create_parse_mode_buttons())
+      .await?;
+    // Update next status to parse mode request
+    dialogue
+      .update(AddTaskDialogueCurrentState::RequestParseMode { name, content })
+      .await?;
+    Ok(())
+  })
+  .await
+}
+
+/// Toast for the button whose action is already done, or whose dialogue is over
+const CALLBACK_HANDLED: &str = "该操作已处理";
+
+/// Answer the callback query, so the client stops the loading animation on the button. `text`
+/// is shown as a toast.
+async fn answer_callback(bot: &NotifyBot, q: &CallbackQuery, text: Option<&str>) -> Result<()> {
+  let mut req = bot.answer_callback_query(q.id.clone());
+  if let Some(text) = text {
+    req = req.text(text);
+  }
+  req.await?;
+  Ok(())
+}
+
+/// Remove the buttons of the message, so they can't be pressed again. The text is replaced by
+/// `outcome` if given. Failures are logged only, the action behind the button is already done.
+async fn close_keyboard(bot: &NotifyBot, msg: &Message, outcome: Option<String>) {
+  let result = match outcome {
+    Some(text) => bot
+      .edit_message_text(msg.chat.id, msg.id, text)
+      .await
+      .map(|_| ()),
+    None => bot
+      .edit_message_reply_markup(msg.chat.id, msg.id)
+      .await
+      .map(|_| ()),
+  };
+  match result {
+    Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => {}
+    Err(e) => tracing::warn!("Fail to remove the buttons: {e}"),
+  }
+}
+
+/// Count the press on the counter button of the notification, then refresh the counts on the
+/// keyboard. Everyone in the group can press it, even without access to the bot.
+async fn counter_button_callback_handler(
+  q: CallbackQuery,
+  bot: NotifyBot,
+  rt: BotRuntime,
+) -> Result<()> {
+  let (id, action) = match q.data.as_deref().and_then(parse_counter_button) {
+    Some(pressed) => pressed,
+    None => return answer_callback(&bot, &q, Some(CALLBACK_HANDLED)).await,
+  };
+  let counters = match rt.task_pool.press_counter(id, action) {
+    Some(counters) => counters,
+    None => return answer_callback(&bot, &q, Some("这个按钮已经失效了")).await,
+  };
+  let count = counters.get(action).copied().unwrap_or_default();
+  answer_callback(&bot, &q, Some(&format!("已记录，当前共 {count} 次"))).await?;
+
+  let msg = match &q.message {
+    Some(msg) => msg,
+    None => return Ok(()),
+  };
+  if let Some(keyboard) = msg.reply_markup() {
+    let result = bot
+      .edit_message_reply_markup(msg.chat.id, msg.id)
+      .reply_markup(bind_counter_buttons(keyboard, id, &counters))
+      .await;
+    if let Err(e) = result {
+      tracing::warn!("Fail to update the counts of task {id}: {e}");
+    }
+  }
+  Ok(())
+}
+
+/// Answer the button which no handler is waiting for, like the confirmation of a finished
+/// dialogue
+async fn stale_callback_handler(q: CallbackQuery, bot: NotifyBot) -> Result<()> {
+  answer_callback(&bot, &q, Some(CALLBACK_HANDLED)).await
+}
+
+/// Create a InlineKeyboardMarkup for choosing the parse mode. Callback data is prefixed
+/// by `parse_mode_`. Suffix `plain` means plain text, `markdown` means MarkdownV2, `html`
+/// means HTML, `poll` means sending the text as a poll.
+fn create_parse_mode_buttons() -> InlineKeyboardMarkup {
+  let buttons = vec![
+    vec![
+      InlineKeyboardButton::callback("纯文本", "parse_mode_plain"),
+      InlineKeyboardButton::callback("Markdown", "parse_mode_markdown"),
+      InlineKeyboardButton::callback("HTML", "parse_mode_html"),
+    ],
+    vec![InlineKeyboardButton::callback(
+      "作为投票发送",
+      "parse_mode_poll",
+    )],
+  ];
+  InlineKeyboardMarkup::new(buttons)
+}
+
+/// Callback handler for AddTaskDialogueCurrentState::RequestParseMode status
+/// It record the parse mode, then update status to RequestRepeatInterval. If the admin choose
+/// to send a poll, the text is parsed as poll and the status is updated to RequestPollType.
+async fn parse_mode_callback_handler(
+  q: CallbackQuery,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  (name, content): (Option<String>, Notification),
+) -> Result<()> {
+  let message = q
+    .message
+    .as_ref()
+    .ok_or_else(|| anyhow::anyhow!("A button callback without message can't be handle"))?;
+  let chat_id = message.chat.id;
+  if !matches!(
+    q.data.as_deref(),
+    Some("parse_mode_plain" | "parse_mode_markdown" | "parse_mode_html" | "parse_mode_poll")
+  ) {
+    return answer_callback(&bot, &q, Some(CALLBACK_HANDLED)).await;
+  }
+  answer_callback(&bot, &q, None).await?;
+  close_keyboard(&bot, message, None).await;
+
+  let parse_mode = match q.data.as_deref() {
+    Some("parse_mode_plain") => None,
+    Some("parse_mode_markdown") => Some(ParseMode::MarkdownV2),
+    Some("parse_mode_html") => Some(ParseMode::Html),
+    Some("parse_mode_poll") => {
+      let poll = match &content {
+        Notification::Text(text) => parse_poll(text),
+        _ => Err("只有文本可以作为投票发送！".to_string()),
+      };
+      match poll {
+        Ok(content) => {
+          bot
+            .send_message(chat_id, "请选择投票的类型：")
+            .reply_markup(create_poll_type_buttons())
+            .await?;
+          dialogue
+            .update(AddTaskDialogueCurrentState::RequestPollType { name, content })
+            .await?;
+        }
+        Err(e) => {
+          bot
+            .send_message(chat_id, format!("{e}\n{POLL_FORMAT_HELP}"))
+            .await?;
+          dialogue
+            .update(AddTaskDialogueCurrentState::RequestNotifyText { name })
+            .await?;
+        }
+      }
+      return Ok(());
+    }
+    _ => return Ok(()),
+  };
+
+  bot.send_message(chat_id, SCHEDULE_FORMAT_HELP).await?;
+  dialogue
+    .update(AddTaskDialogueCurrentState::RequestRepeatInterval {
+      name,
+      content,
+      parse_mode,
+    })
     .await?;
+
   Ok(())
 }
 
-/// Handler for adding task command. This start the add task dialogue, and change
-/// AddTaskDialogueCurrentState to RequestNotifyText.
-async fn add_task_handler(
+/// Hint for the poll input
+const POLL_FORMAT_HELP: &str = "请重新发送投票：第一行是问题，之后每行一个选项。";
+
+/// Telegram accept at most 300 characters in the poll question
+const POLL_QUESTION_MAX_LEN: usize = 300;
+
+/// Telegram accept at most 100 characters in each poll option
+const POLL_OPTION_MAX_LEN: usize = 100;
+
+/// Telegram accept 2 to 10 options in a poll
+const POLL_OPTIONS_RANGE: std::ops::RangeInclusive<usize> = 2..=10;
+
+/// Parse the text as poll. The first line is the question, and each following line is an
+/// option. Blank lines are ignored. The poll is anonymous and single answer by default.
+fn parse_poll(text: &str) -> Result<Notification, String> {
+  let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+  let question = lines.next().ok_or("投票的问题不能为空！")?;
+  if question.chars().count() > POLL_QUESTION_MAX_LEN {
+    return Err(format!(
+      "投票的问题不能超过 {POLL_QUESTION_MAX_LEN} 个字符！"
+    ));
+  }
+
+  let options: Vec<String> = lines.map(str::to_string).collect();
+  if !POLL_OPTIONS_RANGE.contains(&options.len()) {
+    return Err(format!(
+      "投票需要 {} 到 {} 个选项，当前有 {} 个！",
+      POLL_OPTIONS_RANGE.start(),
+      POLL_OPTIONS_RANGE.end(),
+      options.len()
+    ));
+  }
+  if let Some(option) = options
+    .iter()
+    .find(|option| option.chars().count() > POLL_OPTION_MAX_LEN)
+  {
+    return Err(format!(
+      "选项“{option}”不能超过 {POLL_OPTION_MAX_LEN} 个字符！"
+    ));
+  }
+
+  Ok(Notification::Poll {
+    question: question.to_string(),
+    options,
+    anonymous: true,
+    multiple_answers: false,
+  })
+}
+
+#[test]
+fn parse_poll_limits() {
+  let poll = parse_poll("本周六打球吗？\n去\n\n不去\n").unwrap();
+  assert!(matches!(
+    poll,
+    Notification::Poll { ref question, ref options, .. }
+      if question == "本周六打球吗？" && options == &["去", "不去"]
+  ));
+
+  assert!(parse_poll("").is_err());
+  assert!(parse_poll("本周六打球吗？\n去").is_err());
+  let too_many = format!("问题\n{}", vec!["选项"; 11].join("\n"));
+  assert!(parse_poll(&too_many).is_err());
+  let too_long = format!("问题\n{}\n不去", "去".repeat(101));
+  assert!(parse_poll(&too_long).is_err());
+}
+
+/// Create a InlineKeyboardMarkup for choosing the poll type. Callback data is prefixed by
+/// `poll_type_`, then `public` or `anonymous`, then `single` or `multiple`.
+fn create_poll_type_buttons() -> InlineKeyboardMarkup {
+  let buttons = vec![
+    vec![
+      InlineKeyboardButton::callback("匿名单选", "poll_type_anonymous_single"),
+      InlineKeyboardButton::callback("匿名多选", "poll_type_anonymous_multiple"),
+    ],
+    vec![
+      InlineKeyboardButton::callback("实名单选", "poll_type_public_single"),
+      InlineKeyboardButton::callback("实名多选", "poll_type_public_multiple"),
+    ],
+  ];
+  InlineKeyboardMarkup::new(buttons)
+}
+
+/// Callback handler for AddTaskDialogueCurrentState::RequestPollType status
+/// It record the poll type, then update status to RequestRepeatInterval.
+async fn poll_type_callback_handler(
+  q: CallbackQuery,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  (name, content): (Option<String>, Notification),
+) -> Result<()> {
+  let (is_anonymous, is_multiple) = match q.data.as_deref() {
+    Some("poll_type_anonymous_single") => (true, false),
+    Some("poll_type_anonymous_multiple") => (true, true),
+    Some("poll_type_public_single") => (false, false),
+    Some("poll_type_public_multiple") => (false, true),
+    _ => return answer_callback(&bot, &q, Some(CALLBACK_HANDLED)).await,
+  };
+  let message = q
+    .message
+    .as_ref()
+    .ok_or_else(|| anyhow::anyhow!("A button callback without message can't be handle"))?;
+  answer_callback(&bot, &q, None).await?;
+  close_keyboard(&bot, message, None).await;
+  let content = match content {
+    Notification::Poll {
+      question, options, ..
+    } => Notification::Poll {
+      question,
+      options,
+      anonymous: is_anonymous,
+      multiple_answers: is_multiple,
+    },
+    other => other,
+  };
+
+  let chat_id = message.chat.id;
+  bot.send_message(chat_id, SCHEDULE_FORMAT_HELP).await?;
+  dialogue
+    .update(AddTaskDialogueCurrentState::RequestRepeatInterval {
+      name,
+      content,
+      parse_mode: None,
+    })
+    .await?;
+
+  Ok(())
+}
+
+/// Hint for the schedule input
+const SCHEDULE_FORMAT_HELP: &str = "请发送时间间隔，可以只发送数字（单位：分钟），\
+  也可以发送 90m、2h、1d12h、1h30m 这样的时长。\
+  发送 10:00 或者 每天 10:00 可以在每天的固定时间发送，发送 2024-06-01 20:00 则只在这个时间发送一次。也可以发送 5 段的 cron 表达式（分 时 日 月 星期），例如 30 9 * * 1-5 表示每个工作日的 9:30。";
+
+/// Parse the user input as schedule. Number means the repeat interval in minute unit, `HH:MM`
+/// or `每天 HH:MM` means every day at that time, duration like `1h30m` means the repeat
+/// interval, `YYYY-MM-DD HH:MM` means only once at that
+/// time, otherwise the input should be a 5-field cron
+/// expression. Repeat interval out of the bounds is rejected.
+fn parse_schedule(text: &str, bounds: &IntervalBounds) -> Result<Schedule, String> {
+  let text = text.trim();
+  if let Ok(interval) = text.parse::<u64>() {
+    // We will use interval number as minute in release build
+    #[cfg(not(debug_assertions))]
+    let interval = interval.saturating_mul(60);
+    return check_interval(Duration::from_secs(interval), bounds);
+  }
+
+  if let Ok(at) = NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M") {
+    return match clock::TIMEZONE.from_local_datetime(&at).single() {
+      Some(at) if at > clock::now() => Ok(Schedule::Once(at)),
+      Some(_) => Err(format!("{text} 已经过去了，请输入一个未来的时间")),
+      None => Err(format!("{text} 不是一个有效的本地时间")),
+    };
+  }
+
+  if let Some(duration) = parse_duration(text) {
+    return check_interval(duration, bounds);
+  }
+
+  let daily = text.strip_prefix("每天").unwrap_or(text).trim();
+  if daily.contains(':') {
+    return NaiveTime::parse_from_str(daily, "%H:%M")
+      .map(Schedule::Daily)
+      .map_err(|_| format!("非法的时间 {daily}，请使用 HH:MM 格式，例如 10:00"));
+  }
+
+  Schedule::from_cron(text).map_err(|e| format!("非法的 cron 表达式：{e}\n{SCHEDULE_FORMAT_HELP}"))
+}
+
+/// Reject the repeat interval out of the bounds
+fn check_interval(interval: Duration, bounds: &IntervalBounds) -> Result<Schedule, String> {
+  if interval < bounds.min {
+    return Err(format!(
+      "时间间隔不能小于 {}！",
+      format_duration(bounds.min)
+    ));
+  }
+  if interval > bounds.max {
+    return Err(format!(
+      "时间间隔不能大于 {}！",
+      format_duration(bounds.max)
+    ));
+  }
+  Ok(Schedule::Every(interval))
+}
+
+#[test]
+fn parse_schedule_bounds() {
+  let bounds = IntervalBounds::default();
+  assert!(parse_schedule("0", &bounds).is_err());
+  assert!(parse_schedule("0m", &bounds).is_err());
+  assert!(parse_schedule("30s", &bounds).is_err());
+  assert!(parse_schedule("31d", &bounds).is_err());
+  assert!(matches!(
+    parse_schedule("1h30m", &bounds),
+    Ok(Schedule::Every(d)) if d.as_secs() == 5400
+  ));
+  assert!(matches!(
+    parse_schedule("30d", &bounds),
+    Ok(Schedule::Every(d)) if d.as_secs() == 30 * 86400
+  ));
+}
+
+/// Handler for AddTaskDialogueCurrentState::RequestRepeatInterval status
+/// It parse the input to schedule, then update status to RequestWeekdays.
+async fn request_repeat_interval(
   msg: Message,
-  bot: AutoSend<Bot>,
+  bot: NotifyBot,
   dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+  (name, content, parse_mode): (Option<String>, Notification, Option<ParseMode>),
+) -> Result<()> {
+  match msg
+    .text()
+    .map(|text| parse_schedule(text, &rt.interval_bounds))
+  {
+    Some(Ok(schedule)) => {
+      bot
+        .send_message(
+          msg.chat.id,
+          match schedule {
+            Schedule::Every(_) => format!("bot 将会{schedule}发送一次：\n\n{content}"),
+            _ => format!("bot 将会按照 {schedule} 发送：\n\n{content}"),
+          },
+        )
+        .await?;
+
+      // one-shot task doesn't need weekdays
+      if let Schedule::Once(_) = schedule {
+        return ask_for_buttons(
+          &msg,
+          &bot,
+          &dialogue,
+          (name, content, parse_mode, schedule, WeekdaySet::ALL),
+        )
+        .await;
+      }
+
+      bot.send_message(msg.chat.id, WEEKDAYS_FORMAT_HELP).await?;
+      dialogue
+        .update(AddTaskDialogueCurrentState::RequestWeekdays {
+          name,
+          content,
+          parse_mode,
+          schedule,
+        })
+        .await?;
+    }
+    Some(Err(e)) => {
+      bot.send_message(msg.chat.id, e).await?;
+    }
+    None => {
+      bot.send_message(msg.chat.id, SCHEDULE_FORMAT_HELP).await?;
+    }
+  }
+  Ok(())
+}
+
+/// Hint for the weekdays input
+const WEEKDAYS_FORMAT_HELP: &str =
+  "请输入需要发送通知的星期，例如 一三五 或者 mon,wed,fri。发送“跳过”表示每天都发送。";
+
+/// Handler for AddTaskDialogueCurrentState::RequestWeekdays status
+/// It parse the input to weekdays, then update status to RequestButtons.
+async fn request_weekdays(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  (name, content, parse_mode, schedule): (
+    Option<String>,
+    Notification,
+    Option<ParseMode>,
+    Schedule,
+  ),
+) -> Result<()> {
+  let weekdays = match msg.text().map(str::trim) {
+    Some("跳过" | "skip") => WeekdaySet::ALL,
+    Some(days) => match days.parse::<WeekdaySet>() {
+      Ok(weekdays) => weekdays,
+      Err(e) => {
+        bot
+          .send_message(
+            msg.chat.id,
+            format!("非法输入：{e}\n{WEEKDAYS_FORMAT_HELP}"),
+          )
+          .await?;
+        return Ok(());
+      }
+    },
+    None => {
+      bot.send_message(msg.chat.id, WEEKDAYS_FORMAT_HELP).await?;
+      return Ok(());
+    }
+  };
+
+  ask_for_buttons(
+    &msg,
+    &bot,
+    &dialogue,
+    (name, content, parse_mode, schedule, weekdays),
+  )
+  .await
+}
+
+/// Ask for the button definition and update status to RequestButtons.
+async fn ask_for_buttons(
+  msg: &Message,
+  bot: &NotifyBot,
+  dialogue: &AddTaskDialogue,
+  (name, content, parse_mode, schedule, weekdays): (
+    Option<String>,
+    Notification,
+    Option<ParseMode>,
+    Schedule,
+    WeekdaySet,
+  ),
 ) -> Result<()> {
-  tracing::info!(
-    "User {} try adding new schedule task",
-    msg.from().unwrap().id
-  );
   bot
     .send_message(
       msg.chat.id,
-      "正在创建一个新的定时任务，请发送通知的内容：".to_string(),
+      format!("接下来请你输入附带在定时通知上的按钮信息:\n{BUTTON_FORMAT_HELP}"),
     )
     .await?;
   dialogue
-    .update(AddTaskDialogueCurrentState::RequestNotifyText)
+    .update(AddTaskDialogueCurrentState::RequestButtons {
+      name,
+      content,
+      parse_mode,
+      schedule,
+      weekdays,
+    })
     .await?;
 
   Ok(())
 }
 
-/// Handler for /listtask.
-async fn list_task_handler(msg: Message, bot: AutoSend<Bot>, rt: BotRuntime) -> Result<()> {
-  let task = rt.task_pool.list_task();
-
-  let text = format!("总共 {} 个任务\n", task.len());
-  let text = task.iter().fold(text, |acc, x| {
-    let id = x.0;
-    let inv = x.1;
-    let content = &x.2;
-    format!(
-      "{acc}任务 {id}，循环周期：{inv} 秒，任务内容：{content}\n{}\n\n",
-      "=".repeat(35)
-    )
-  });
-  bot.send_message(msg.chat.id, text).await?;
+/// Handler for AddTaskDialogueCurrentState::RequestButtons status
+/// It parse input to buttons, then preview the notification and ask for confirmation.
+async fn request_buttons(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+  (name, content, parse_mode, schedule, weekdays): (
+    Option<String>,
+    Notification,
+    Option<ParseMode>,
+    Schedule,
+    WeekdaySet,
+  ),
+) -> Result<()> {
+  if msg.text().is_none() {
+    bot
+      .send_message(msg.chat.id, "bot 需要文字消息！请重新输入！")
+      .await?;
+    anyhow::bail!("invalid message text for parsing buttons");
+  }
+
+  let buttons = match parse_keyboard(msg.text().unwrap(), &rt.button_schemes) {
+    Ok(buttons) => buttons,
+    Err(e) => {
+      bot.send_message(msg.chat.id, e.hint()).await?;
+      anyhow::bail!("{e}");
+    }
+  };
+
+  ask_for_groups(
+    &msg,
+    &bot,
+    &dialogue,
+    &rt,
+    (name, content, parse_mode, schedule, weekdays, buttons),
+  )
+  .await
+}
+
+/// Ask for the target groups and update status to RequestGroups.
+async fn ask_for_groups(
+  msg: &Message,
+  bot: &NotifyBot,
+  dialogue: &AddTaskDialogue,
+  rt: &BotRuntime,
+  (name, content, parse_mode, schedule, weekdays, buttons): (
+    Option<String>,
+    Notification,
+    Option<ParseMode>,
+    Schedule,
+    WeekdaySet,
+    InlineKeyboardMarkup,
+  ),
+) -> Result<()> {
+  // every group the user can manage is selected by default
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  let groups = rt.manageable_groups(user);
+  let markup = create_task_groups_buttons(bot, rt, user, &groups).await;
+  bot
+    .send_message(msg.chat.id, "请选择需要发送通知的群组：")
+    .reply_markup(markup)
+    .await?;
+  dialogue
+    .update(AddTaskDialogueCurrentState::RequestGroups {
+      name,
+      content,
+      parse_mode,
+      schedule,
+      weekdays,
+      buttons,
+      groups,
+    })
+    .await?;
 
   Ok(())
 }
 
-fn parse_first_arg_as_num<T: FromStr>(text: &str) -> Option<T> {
-  let args = text.split(' ').skip(1).collect::<Vec<&str>>();
-  if args.is_empty() {
-    return None;
+/// Replace the placeholders of the notification as the first target group will see it in the
+/// `count`-th send
+async fn render_preview(
+  bot: &NotifyBot,
+  rt: &BotRuntime,
+  notify: &Notification,
+  parse_mode: Option<ParseMode>,
+  groups: &[ChatId],
+  count: u64,
+) -> Notification {
+  let group_title = group_titles(bot, rt, &groups[..groups.len().min(1)])
+    .await
+    .pop()
+    .unwrap_or_default();
+  let vars = TemplateVars {
+    now: clock::now(),
+    count,
+    group_title,
+  };
+  notify.render(&vars, parse_mode)
+}
+
+/// Get the titles of the groups. The unknown title is fetched by `get_chat` and cached in the
+/// runtime, the chat id is used instead if it can't be fetched.
+async fn group_titles(bot: &NotifyBot, rt: &BotRuntime, groups: &[ChatId]) -> Vec<String> {
+  let mut titles = Vec::with_capacity(groups.len());
+  for &group in groups {
+    let cached = rt.group_titles.read().get(&group).cloned();
+    let title = match cached {
+      Some(title) => title,
+      None => match bot.get_chat(group).await {
+        Ok(chat) => {
+          let title = chat
+            .title()
+            .map_or_else(|| group.0.to_string(), str::to_string);
+          rt.group_titles.write().insert(group, title.clone());
+          title
+        }
+        Err(e) => {
+          tracing::warn!("Fail to get the title of group {}: {e}", group.0);
+          group.0.to_string()
+        }
+      },
+    };
+    titles.push(title);
   }
+  titles
+}
+
+/// Create a InlineKeyboardMarkup for choosing the target groups from the groups that the user
+/// can manage. Callback data is prefixed by `task_group_`. Suffix `all` means select all,
+/// `done` means finish the selection, otherwise it is the chat id to toggle.
+async fn create_task_groups_buttons(
+  bot: &NotifyBot,
+  rt: &BotRuntime,
+  user: UserId,
+  selected: &[ChatId],
+) -> InlineKeyboardMarkup {
+  let whitelist = rt.manageable_groups(user);
+  let titles = group_titles(bot, rt, &whitelist).await;
+  let mut buttons = whitelist
+    .iter()
+    .zip(titles)
+    .map(|(group, title)| {
+      let mark = if selected.contains(group) {
+        "✅"
+      } else {
+        "◻"
+      };
+      vec![InlineKeyboardButton::callback(
+        format!("{mark} {title}"),
+        format!("task_group_{}", group.0),
+      )]
+    })
+    .collect::<Vec<_>>();
+  buttons.push(vec![
+    InlineKeyboardButton::callback("全选", "task_group_all"),
+    InlineKeyboardButton::callback("完成", "task_group_done"),
+  ]);
+  InlineKeyboardMarkup::new(buttons)
+}
 
-  args[0].parse::<T>().ok()
+/// Callback handler for AddTaskDialogueCurrentState::RequestGroups status
+/// It toggle the selected groups, then preview the new task when the selection is done.
+async fn task_groups_callback_handler(
+  q: CallbackQuery,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+  (name, content, parse_mode, schedule, weekdays, buttons, mut groups): (
+    Option<String>,
+    Notification,
+    Option<ParseMode>,
+    Schedule,
+    WeekdaySet,
+    InlineKeyboardMarkup,
+    Vec<ChatId>,
+  ),
+) -> Result<()> {
+  let data = match q
+    .data
+    .as_deref()
+    .and_then(|d| d.strip_prefix("task_group_"))
+  {
+    Some(data) => data,
+    None => return answer_callback(&bot, &q, Some(CALLBACK_HANDLED)).await,
+  };
+  let message = q
+    .message
+    .as_ref()
+    .ok_or_else(|| anyhow::anyhow!("A button callback without message can't be handle"))?;
+  let chat_id = message.chat.id;
+  answer_callback(&bot, &q, None).await?;
+
+  match data {
+    "done" if groups.is_empty() => {
+      bot.send_message(chat_id, "至少需要选择一个群组！").await?;
+      return Ok(());
+    }
+    "done" => {
+      close_keyboard(&bot, message, None).await;
+      return preview_new_task(
+        chat_id,
+        &bot,
+        &dialogue,
+        &rt,
+        NotifyOptions::default(),
+        (
+          name, content, parse_mode, schedule, weekdays, buttons, groups,
+        ),
+      )
+      .await;
+    }
+    "all" => groups = rt.manageable_groups(q.from.id),
+    id => {
+      let group = match id.parse() {
+        Ok(id) => ChatId(id),
+        Err(_) => return Ok(()),
+      };
+      if !rt.can_manage_groups(q.from.id, &[group]) {
+        bot.send_message(chat_id, "无权限").await?;
+        return Ok(());
+      }
+      match groups.iter().position(|&g| g == group) {
+        Some(i) => {
+          groups.remove(i);
+        }
+        None => groups.push(group),
+      }
+    }
+  }
+
+  bot
+    .edit_message_reply_markup(chat_id, message.id)
+    .reply_markup(create_task_groups_buttons(&bot, &rt, q.from.id, &groups).await)
+    .await?;
+  dialogue
+    .update(AddTaskDialogueCurrentState::RequestGroups {
+      name,
+      content,
+      parse_mode,
+      schedule,
+      weekdays,
+      buttons,
+      groups,
+    })
+    .await?;
+
+  Ok(())
+}
+
+/// The step before `state` in the add task dialogue, keeping the data entered before that step.
+/// The first step and the text fixing step stay where they are, `None` means `state` is not a
+/// step of the add task dialogue.
+fn previous_step(state: AddTaskDialogueCurrentState) -> Option<AddTaskDialogueCurrentState> {
+  use AddTaskDialogueCurrentState::*;
+
+  let prev = match state {
+    RequestTemplate { .. } | RequestNotifyText { .. } | RequestFixedText { .. } => state,
+    RequestTemplateSchedule { name, .. } => RequestTemplate { name },
+    RequestParseMode { name, .. } | RequestPollType { name, .. } => RequestNotifyText { name },
+    RequestRepeatInterval { name, content, .. } => match content {
+      Notification::Copy { .. } => RequestNotifyText { name },
+      Notification::Poll { .. } => RequestPollType { name, content },
+      _ => RequestParseMode { name, content },
+    },
+    RequestWeekdays {
+      name,
+      content,
+      parse_mode,
+      ..
+    } => RequestRepeatInterval {
+      name,
+      content,
+      parse_mode,
+    },
+    // one-shot task skip the weekdays
+    RequestButtons {
+      name,
+      content,
+      parse_mode,
+      schedule: Schedule::Once(_),
+      ..
+    } => RequestRepeatInterval {
+      name,
+      content,
+      parse_mode,
+    },
+    RequestButtons {
+      name,
+      content,
+      parse_mode,
+      schedule,
+      ..
+    } => RequestWeekdays {
+      name,
+      content,
+      parse_mode,
+      schedule,
+    },
+    RequestGroups {
+      name,
+      content,
+      parse_mode,
+      schedule,
+      weekdays,
+      ..
+    } => RequestButtons {
+      name,
+      content,
+      parse_mode,
+      schedule,
+      weekdays,
+    },
+    RequestConfirmation {
+      name,
+      content,
+      parse_mode,
+      schedule,
+      weekdays,
+      buttons,
+      groups,
+      ..
+    } => RequestGroups {
+      name,
+      content,
+      parse_mode,
+      schedule,
+      weekdays,
+      buttons,
+      groups,
+    },
+    ReviseTask { confirmation, .. } => *confirmation,
+    _ => return Option::None,
+  };
+  Some(prev)
+}
+
+#[test]
+fn previous_step_test() {
+  use AddTaskDialogueCurrentState::*;
+
+  let name = Some("早报".to_string());
+  let content = Notification::Text("早上好".to_string());
+  let schedule = Schedule::Every(Duration::from_secs(3600));
+  let buttons = RequestButtons {
+    name: name.clone(),
+    content: content.clone(),
+    parse_mode: Some(ParseMode::Html),
+    schedule: schedule.clone(),
+    weekdays: WeekdaySet::ALL,
+  };
+
+  let weekdays = previous_step(buttons).unwrap();
+  assert!(matches!(
+    &weekdays,
+    RequestWeekdays { name: n, schedule: Schedule::Every(d), parse_mode: Some(ParseMode::Html), .. }
+      if n == &name && d.as_secs() == 3600
+  ));
+  let interval = previous_step(weekdays).unwrap();
+  assert!(matches!(&interval, RequestRepeatInterval { content: c, .. } if c == &content));
+  let parse_mode = previous_step(interval).unwrap();
+  assert!(matches!(&parse_mode, RequestParseMode { content: c, .. } if c == &content));
+  let text = previous_step(parse_mode).unwrap();
+  assert!(matches!(&text, RequestNotifyText { name: n } if n == &name));
+  // the first step stays
+  assert!(matches!(
+    previous_step(text),
+    Some(RequestNotifyText { .. })
+  ));
+
+  // the template schedule go back to choosing the template
+  let template = RequestTemplateSchedule {
+    name: name.clone(),
+    template: MessageTemplate {
+      content: content.clone(),
+      parse_mode: None,
+      buttons: None,
+    },
+  };
+  let choose = previous_step(template).unwrap();
+  assert!(matches!(&choose, RequestTemplate { name: n } if n == &name));
+  assert!(matches!(
+    previous_step(choose),
+    Some(RequestTemplate { .. })
+  ));
+
+  let once = RequestButtons {
+    name: None,
+    content: Notification::Copy {
+      from_chat: ChatId(-1001),
+      message_id: 42,
+    },
+    parse_mode: None,
+    schedule: Schedule::Once(clock::now()),
+    weekdays: WeekdaySet::ALL,
+  };
+  let interval = previous_step(once).unwrap();
+  assert!(matches!(interval, RequestRepeatInterval { .. }));
+  assert!(matches!(
+    previous_step(interval),
+    Some(RequestNotifyText { name: Option::None })
+  ));
+
+  assert!(previous_step(AddTaskDialogueCurrentState::None).is_none());
+  assert!(previous_step(EditTaskMenu {
+    id: 1,
+    changes: Vec::new()
+  })
+  .is_none());
+}
+
+/// Handler for /back command. It moves the add task dialogue one step backwards and asks for
+/// the input of that step again.
+async fn back_handler(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+  state: AddTaskDialogueCurrentState,
+) -> Result<()> {
+  let chat_id = msg.chat.id;
+  let prev = match previous_step(state) {
+    Some(prev) => prev,
+    None => {
+      bot
+        .send_message(chat_id, "/back 只能在添加任务的过程中使用")
+        .await?;
+      return Ok(());
+    }
+  };
+
+  match &prev {
+    AddTaskDialogueCurrentState::RequestTemplate { .. } => {
+      let names = rt.templates.list().await?.into_keys().collect::<Vec<_>>();
+      bot
+        .send_message(chat_id, "请选择一个模板，或者不使用模板：")
+        .reply_markup(create_template_buttons(&names))
+        .await?;
+    }
+    AddTaskDialogueCurrentState::RequestTemplateSchedule { .. } => {
+      bot.send_message(chat_id, SCHEDULE_FORMAT_HELP).await?;
+    }
+    AddTaskDialogueCurrentState::RequestNotifyText { .. }
+    | AddTaskDialogueCurrentState::RequestFixedText { .. } => {
+      bot.send_message(chat_id, NOTIFICATION_HELP).await?;
+    }
+    AddTaskDialogueCurrentState::RequestParseMode { .. } => {
+      bot
+        .send_message(chat_id, "请选择通知文本的格式：")
+        .reply_markup(create_parse_mode_buttons())
+        .await?;
+    }
+    AddTaskDialogueCurrentState::RequestPollType { .. } => {
+      bot
+        .send_message(chat_id, "请选择投票的类型：")
+        .reply_markup(create_poll_type_buttons())
+        .await?;
+    }
+    AddTaskDialogueCurrentState::RequestRepeatInterval { .. } => {
+      bot.send_message(chat_id, SCHEDULE_FORMAT_HELP).await?;
+    }
+    AddTaskDialogueCurrentState::RequestWeekdays { .. } => {
+      bot.send_message(chat_id, WEEKDAYS_FORMAT_HELP).await?;
+    }
+    AddTaskDialogueCurrentState::RequestButtons { .. } => {
+      bot
+        .send_message(
+          chat_id,
+          format!("接下来请你输入附带在定时通知上的按钮信息:\n{BUTTON_FORMAT_HELP}"),
+        )
+        .await?;
+    }
+    AddTaskDialogueCurrentState::RequestGroups { groups, .. } => {
+      let user = msg
+        .from()
+        .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+        .id;
+      let markup = create_task_groups_buttons(&bot, &rt, user, groups).await;
+      bot
+        .send_message(chat_id, "请选择需要发送通知的群组：")
+        .reply_markup(markup)
+        .await?;
+    }
+    AddTaskDialogueCurrentState::RequestConfirmation { .. } => {
+      bot
+        .send_message(chat_id, "请使用上方的按钮确认添加这个新的通知")
+        .await?;
+    }
+    _ => {}
+  }
+  dialogue.update(prev).await?;
+
+  Ok(())
+}
+
+/// Handler for AddTaskDialogueCurrentState::RequestFixedText status
+/// It receive the corrected notification, then preview the notification again.
+async fn request_fixed_text(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+  (name, parse_mode, schedule, weekdays, buttons, groups): (
+    Option<String>,
+    Option<ParseMode>,
+    Schedule,
+    WeekdaySet,
+    InlineKeyboardMarkup,
+    Vec<ChatId>,
+  ),
+) -> Result<()> {
+  let chat_id = msg.chat.id;
+  receive_notification(msg, bot.clone(), rt.clone(), move |content| async move {
+    preview_new_task(
+      chat_id,
+      &bot,
+      &dialogue,
+      &rt,
+      NotifyOptions::default(),
+      (
+        name, content, parse_mode, schedule, weekdays, buttons, groups,
+      ),
+    )
+    .await
+  })
+  .await
+}
+
+/// Test if Telegram refuse the message because the text is not valid in the parse mode
+fn is_parse_entities_error(e: &RequestError) -> bool {
+  match e {
+    RequestError::Api(ApiError::CantParseEntities) => true,
+    RequestError::Api(ApiError::Unknown(s)) => s.contains("can't parse entities"),
+    _ => false,
+  }
+}
+
+/// NotifyOptions describe how the notification of a new task is sent
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct NotifyOptions {
+  /// Don't attach the link preview to the notification
+  disable_preview: bool,
+  /// Send the notification without sound
+  silent: bool,
+  /// Forbid forwarding and saving the notification
+  protect_content: bool,
+  /// Pin the notification in the group after sending
+  pin: bool,
+  /// Delete the previous notification before sending the new one
+  delete_previous: bool,
+  /// Edit the same message instead of sending a new one
+  edit_in_place: bool,
+  /// Close the previous poll before sending the new one
+  stop_previous_poll: bool,
+  /// How the notification is picked from the pool
+  #[serde(default)]
+  rotation: RotationMode,
+}
+
+impl Default for NotifyOptions {
+  fn default() -> Self {
+    Self {
+      // link preview bury the notification text, so disable it by default
+      disable_preview: true,
+      silent: false,
+      protect_content: false,
+      pin: false,
+      delete_previous: false,
+      edit_in_place: false,
+      stop_previous_poll: false,
+      rotation: RotationMode::Sequential,
+    }
+  }
+}
+
+/// Send the notification exactly as the groups will see, then ask for confirmation and update
+/// status to RequestConfirmation. If the text can't be rendered with the parse mode, update
+/// status to RequestFixedText instead.
+async fn preview_new_task(
+  chat_id: ChatId,
+  bot: &NotifyBot,
+  dialogue: &AddTaskDialogue,
+  rt: &BotRuntime,
+  options: NotifyOptions,
+  (name, content, parse_mode, schedule, weekdays, buttons, groups): (
+    Option<String>,
+    Notification,
+    Option<ParseMode>,
+    Schedule,
+    WeekdaySet,
+    InlineKeyboardMarkup,
+    Vec<ChatId>,
+  ),
+) -> Result<()> {
+  // the admin's preview stay loud and forwardable
+  let send_options = SendOptions {
+    buttons: Some(buttons.clone()),
+    parse_mode,
+    disable_preview: options.disable_preview,
+    ..SendOptions::default()
+  };
+
+  // show the placeholders as the members will see them in the first send
+  let rendered = render_preview(bot, rt, &content, parse_mode, &groups, 1).await;
+  let preview = match send_notification(bot, chat_id, &rendered, &send_options).await {
+    Ok(preview) => preview,
+    Err(e) if is_parse_entities_error(&e) => {
+      bot
+        .send_message(
+          chat_id,
+          format!("通知文本的格式有误：{e}\n请修改后重新发送通知的文本："),
+        )
+        .await?;
+      dialogue
+        .update(AddTaskDialogueCurrentState::RequestFixedText {
+          name,
+          parse_mode,
+          schedule,
+          weekdays,
+          buttons,
+          groups,
+        })
+        .await?;
+      return Ok(());
+    }
+    Err(RequestError::Api(e)) if matches!(content, Notification::Copy { .. }) => {
+      bot
+        .send_message(
+          chat_id,
+          format!("无法复制转发的消息：{e}\n请确认机器人已经加入了原频道，然后重新发送通知："),
+        )
+        .await?;
+      dialogue
+        .update(AddTaskDialogueCurrentState::RequestFixedText {
+          name,
+          parse_mode,
+          schedule,
+          weekdays,
+          buttons,
+          groups,
+        })
+        .await?;
+      return Ok(());
+    }
+    Err(e) => return Err(e.into()),
+  };
+
+  // every confirmation message get a new nonce, the buttons of the old ones are stale
+  let nonce = rand::random();
+  bot
+    .send_message(
+      chat_id,
+      confirmation_text(bot, rt, &schedule, weekdays, &groups).await,
+    )
+    .reply_markup(create_add_task_confirm_buttons(options, nonce))
+    .await?;
+
+  dialogue
+    .update(AddTaskDialogueCurrentState::RequestConfirmation {
+      name,
+      content,
+      parse_mode,
+      schedule,
+      weekdays,
+      buttons,
+      groups,
+      options,
+      preview,
+      nonce,
+    })
+    .await?;
+
+  Ok(())
+}
+
+/// The text of the confirmation message
+async fn confirmation_text(
+  bot: &NotifyBot,
+  rt: &BotRuntime,
+  schedule: &Schedule,
+  weekdays: WeekdaySet,
+  groups: &[ChatId],
+) -> String {
+  let titles = group_titles(bot, rt, groups).await;
+  format!(
+    "上面的信息将会按照 {} 发送到 {}，首条通知将在 {} 发送。\n\
+    注意：开启禁止转发后，群成员将无法转发、保存或者复制通知的内容。\n请确认添加这个新的通知：",
+    describe_schedule(schedule, weekdays),
+    titles.join("、"),
+    format_time(schedule.next_fire(clock::now())),
+  )
+}
+
+/// Create a InlineKeyboardMarkup for confirmation. Callback data is prefixed
+/// by `add_task_confirm_`. Suffix `y` means confirm, `now` means confirm and send once
+/// immediately, `preview` means toggle the link preview, `silent` means toggle the silent
+/// mode, `protect` means toggle the content protection, `pin` means toggle the pinning,
+/// `latest` means toggle deleting the previous notification, `edit` means toggle editing the
+/// message in place, `n` means cancel. Buttons prefixed by `add_task_edit_` revise the `text`,
+/// `interval` or `buttons` of the task. The `y`, `now` and `n` buttons carry the `nonce` of the
+/// dialogue after a colon, like `add_task_confirm_y:42`.
+fn create_add_task_confirm_buttons(options: NotifyOptions, nonce: u32) -> InlineKeyboardMarkup {
+  let on_off = |on: bool| if on { "开启" } else { "关闭" };
+  let buttons = vec![
+    vec![
+      InlineKeyboardButton::callback("确认", format!("add_task_confirm_y:{nonce}")),
+      InlineKeyboardButton::callback("取消", format!("add_task_confirm_n:{nonce}")),
+    ],
+    vec![InlineKeyboardButton::callback(
+      "确认并立即发送一次",
+      format!("add_task_confirm_now:{nonce}"),
+    )],
+    vec![
+      InlineKeyboardButton::callback("修改文本", "add_task_edit_text"),
+      InlineKeyboardButton::callback("修改间隔", "add_task_edit_interval"),
+      InlineKeyboardButton::callback("修改按钮", "add_task_edit_buttons"),
+    ],
+    vec![
+      InlineKeyboardButton::callback(
+        format!("链接预览：{}", on_off(!options.disable_preview)),
+        "add_task_confirm_preview",
+      ),
+      InlineKeyboardButton::callback(
+        format!("静音发送：{}", on_off(options.silent)),
+        "add_task_confirm_silent",
+      ),
+    ],
+    vec![
+      InlineKeyboardButton::callback(
+        format!("禁止转发：{}", on_off(options.protect_content)),
+        "add_task_confirm_protect",
+      ),
+      InlineKeyboardButton::callback(
+        format!("发送后置顶：{}", on_off(options.pin)),
+        "add_task_confirm_pin",
+      ),
+    ],
+    vec![
+      InlineKeyboardButton::callback(
+        format!("只保留最新一条：{}", on_off(options.delete_previous)),
+        "add_task_confirm_latest",
+      ),
+      InlineKeyboardButton::callback(
+        format!("原地编辑：{}", on_off(options.edit_in_place)),
+        "add_task_confirm_edit",
+      ),
+    ],
+    vec![
+      InlineKeyboardButton::callback(
+        format!("关闭上一次投票：{}", on_off(options.stop_previous_poll)),
+        "add_task_confirm_stop_poll",
+      ),
+      InlineKeyboardButton::callback(
+        format!("轮播方式：{}", options.rotation),
+        "add_task_confirm_rotation",
+      ),
+    ],
+  ];
+  InlineKeyboardMarkup::new(buttons)
+}
+
+/// Handler for AddTaskDialogueCurrentState::ReviseTask status
+/// It replace the field of the new task, then refresh the preview and go back to
+/// RequestConfirmation. Invalid input is refused and the field is asked again.
+async fn revise_task_handler(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+  (field, confirmation, confirm_msg): (ReviseField, Box<AddTaskDialogueCurrentState>, i32),
+) -> Result<()> {
+  let chat_id = msg.chat.id;
+  let mut confirmation = *confirmation;
+
+  if field == ReviseField::Text {
+    return receive_notification(msg, bot.clone(), rt.clone(), move |new| async move {
+      if let AddTaskDialogueCurrentState::RequestConfirmation { content, .. } = &mut confirmation {
+        *content = match (&*content, new) {
+          // the poll keep its type, only the question and the options are replaced
+          (
+            Notification::Poll {
+              anonymous,
+              multiple_answers,
+              ..
+            },
+            Notification::Text(text),
+          ) => match parse_poll(&text) {
+            Ok(Notification::Poll {
+              question, options, ..
+            }) => Notification::Poll {
+              question,
+              options,
+              anonymous: *anonymous,
+              multiple_answers: *multiple_answers,
+            },
+            Ok(other) => other,
+            Err(e) => {
+              bot
+                .send_message(chat_id, format!("{e}\n{POLL_FORMAT_HELP}"))
+                .await?;
+              return Ok(());
+            }
+          },
+          (_, new) => new,
+        };
+      }
+      refresh_confirmation(
+        chat_id,
+        &bot,
+        &dialogue,
+        &rt,
+        field,
+        confirmation,
+        confirm_msg,
+      )
+      .await
+    })
+    .await;
+  }
+
+  let text = match msg.text() {
+    Some(text) => text,
+    None => {
+      bot
+        .send_message(chat_id, "bot 需要文字消息！请重新输入！")
+        .await?;
+      return Ok(());
+    }
+  };
+  if let AddTaskDialogueCurrentState::RequestConfirmation {
+    schedule,
+    weekdays,
+    buttons,
+    ..
+  } = &mut confirmation
+  {
+    match field {
+      ReviseField::Schedule => match parse_schedule(text, &rt.interval_bounds) {
+        Ok(new) => {
+          // one-shot task doesn't need weekdays
+          if let Schedule::Once(_) = new {
+            *weekdays = WeekdaySet::ALL;
+          }
+          *schedule = new;
+        }
+        Err(e) => {
+          bot.send_message(chat_id, e).await?;
+          return Ok(());
+        }
+      },
+      ReviseField::Buttons => match parse_keyboard(text, &rt.button_schemes) {
+        Ok(new) => *buttons = new,
+        Err(e) => {
+          bot.send_message(chat_id, e.hint()).await?;
+          return Ok(());
+        }
+      },
+      ReviseField::Text => unreachable!(),
+    }
+  }
+  refresh_confirmation(
+    chat_id,
+    &bot,
+    &dialogue,
+    &rt,
+    field,
+    confirmation,
+    confirm_msg,
+  )
+  .await
+}
+
+/// Edit the preview and the confirmation message into the revised task, and go back to
+/// RequestConfirmation. If the preview can't be edited, like the content type is changed, it is
+/// sent again instead.
+async fn refresh_confirmation(
+  chat_id: ChatId,
+  bot: &NotifyBot,
+  dialogue: &AddTaskDialogue,
+  rt: &BotRuntime,
+  field: ReviseField,
+  confirmation: AddTaskDialogueCurrentState,
+  confirm_msg: i32,
+) -> Result<()> {
+  let (name, content, parse_mode, schedule, weekdays, buttons, groups, options, preview, nonce) =
+    match confirmation {
+      AddTaskDialogueCurrentState::RequestConfirmation {
+        name,
+        content,
+        parse_mode,
+        schedule,
+        weekdays,
+        buttons,
+        groups,
+        options,
+        preview,
+        nonce,
+      } => (
+        name, content, parse_mode, schedule, weekdays, buttons, groups, options, preview, nonce,
+      ),
+      _ => {
+        dialogue.exit().await?;
+        anyhow::bail!("revising a task without confirmation");
+      }
+    };
+
+  match field {
+    ReviseField::Text | ReviseField::Buttons => {
+      let send_options = SendOptions {
+        buttons: Some(buttons.clone()),
+        parse_mode,
+        disable_preview: options.disable_preview,
+        ..SendOptions::default()
+      };
+      let edited = match preview.as_slice() {
+        [msg_id] => matches!(
+          bot
+            .edit_notification(chat_id, *msg_id, &content, &send_options)
+            .await,
+          Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified))
+        ),
+        _ => false,
+      };
+      if !edited {
+        for &msg_id in preview.iter().chain([&confirm_msg]) {
+          if let Err(e) = bot.delete_message(chat_id, msg_id).await {
+            tracing::warn!("Fail to delete the old preview: {e}");
+          }
+        }
+        return preview_new_task(
+          chat_id,
+          bot,
+          dialogue,
+          rt,
+          options,
+          (
+            name, content, parse_mode, schedule, weekdays, buttons, groups,
+          ),
+        )
+        .await;
+      }
+    }
+    ReviseField::Schedule => {
+      let text = confirmation_text(bot, rt, &schedule, weekdays, &groups).await;
+      match bot
+        .edit_message_text(chat_id, confirm_msg, text)
+        .reply_markup(create_add_task_confirm_buttons(options, nonce))
+        .await
+      {
+        Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => {}
+        Err(e) => return Err(e.into()),
+      }
+    }
+  }
+
+  bot
+    .send_message(chat_id, "已更新上方的预览，请确认添加这个新的通知")
+    .await?;
+  dialogue
+    .update(AddTaskDialogueCurrentState::RequestConfirmation {
+      name,
+      content,
+      parse_mode,
+      schedule,
+      weekdays,
+      buttons,
+      groups,
+      options,
+      preview,
+      nonce,
+    })
+    .await?;
+
+  Ok(())
+}
+
+/// Create a InlineKeyboardMarkup for confirmation. Callback data is prefixed
+/// by `set_buttons_confirm_`. Suffix `y` means confirm, `n` means cancel.
+fn create_set_buttons_confirm_buttons() -> InlineKeyboardMarkup {
+  let buttons = vec![vec![
+    InlineKeyboardButton::callback("确认", "set_buttons_confirm_y"),
+    InlineKeyboardButton::callback("取消", "set_buttons_confirm_n"),
+  ]];
+  InlineKeyboardMarkup::new(buttons)
+}
+
+/// Split the nonce off the callback data, like `add_task_confirm_y:42`. Data without a valid
+/// nonce is returned as is.
+fn split_nonce(data: &str) -> (&str, Option<u32>) {
+  match data.split_once(':') {
+    Some((data, nonce)) => (data, nonce.parse().ok()),
+    None => (data, None),
+  }
+}
+
+/// Test if the button carry the nonce of the dialogue, and mark the nonce consumed. Return false
+/// if the nonce doesn't match or it is consumed by an earlier press.
+fn claim_confirmation(nonces: &Mutex<ConsumedNonces>, pressed: Option<u32>, nonce: u32) -> bool {
+  pressed == Some(nonce) && nonces.lock().consume(nonce, Instant::now())
+}
+
+#[tokio::test]
+async fn confirm_once_test() {
+  use crate::schedule::test_pool;
+  use tokio::sync::watch;
+
+  let (_tx, rx) = watch::channel(0);
+  let (mut pool, _) = test_pool(rx.clone());
+  let nonces = Mutex::new(ConsumedNonces::default());
+
+  // the same confirmation pressed twice, then a stale button of an older confirmation
+  for data in [
+    "add_task_confirm_y:42",
+    "add_task_confirm_y:42",
+    "add_task_confirm_y:7",
+  ] {
+    let (data, pressed) = split_nonce(data);
+    assert_eq!(data, "add_task_confirm_y");
+    if claim_confirmation(&nonces, pressed, 42) {
+      let task = ScheduleTask::new(rx.clone())
+        .schedule(Schedule::Every(Duration::from_secs(3600)))
+        .pending_notification(vec![Notification::Text("A".to_string())])
+        .groups(vec![ChatId(-1001)]);
+      pool.add_task(task).await.unwrap();
+    }
+  }
+  assert_eq!(pool.list_id().len(), 1);
+  assert!(!claim_confirmation(&nonces, None, 42));
+  pool.shutdown_all().await.unwrap();
+}
+
+/// Callback handler for buttons CallbackQuery.
+async fn button_callback_handler(
+  q: CallbackQuery,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  mut rt: BotRuntime,
+  (name, content, parse_mode, schedule, weekdays, buttons, groups, options, preview, nonce): (
+    Option<String>,
+    Notification,
+    Option<ParseMode>,
+    Schedule,
+    WeekdaySet,
+    InlineKeyboardMarkup,
+    Vec<ChatId>,
+    NotifyOptions,
+    Vec<i32>,
+    u32,
+  ),
+) -> Result<()> {
+  // we might create some empty button for dressing
+  let (data, pressed) = match q.data.as_deref() {
+    Some(data) if data.starts_with("add_task_") => split_nonce(data),
+    _ => return answer_callback(&bot, &q, Some(CALLBACK_HANDLED)).await,
+  };
+  let data = data.to_string();
+  // the double-taps arrive before the dialogue exits, only the first one go through
+  if matches!(
+    data.as_str(),
+    "add_task_confirm_y" | "add_task_confirm_now" | "add_task_confirm_n"
+  ) && !claim_confirmation(&rt.consumed_nonces, pressed, nonce)
+  {
+    return answer_callback(&bot, &q, Some(CALLBACK_HANDLED)).await;
+  }
+  answer_callback(&bot, &q, None).await?;
+  let message = q
+    .message
+    .ok_or_else(|| anyhow::anyhow!("A button callback without message can't be handle"))?;
+  let chat_id = message.chat.id;
+
+  match data.as_str() {
+    "add_task_confirm_y" | "add_task_confirm_now" => {
+      let task = ScheduleTask::new(rt.subscribe_shutdown_sig())
+        .send_immediately(data == "add_task_confirm_now")
+        .name(name)
+        .creator(q.from.id)
+        .schedule(schedule)
+        .weekdays(weekdays)
+        .pending_notification(vec![content])
+        .parse_mode(parse_mode)
+        .disable_web_page_preview(options.disable_preview)
+        .silent(options.silent)
+        .protect_content(options.protect_content)
+        .pin(options.pin)
+        .delete_previous(options.delete_previous)
+        .edit_in_place(options.edit_in_place)
+        .stop_previous_poll(options.stop_previous_poll)
+        .rotation(options.rotation)
+        .groups(groups)
+        .msg_buttons(buttons);
+      let outcome = match rt.task_pool.add_task(task).await {
+        Ok(id) => {
+          rt.audit(q.from.id, format!("添加任务 {id}")).await;
+          format!("已创建任务 #{id}")
+        }
+        Err(e) => format!("添加任务失败：{}", e.user_message()),
+      };
+      close_keyboard(&bot, &message, Some(outcome)).await;
+      dialogue.exit().await?;
+    }
+    "add_task_confirm_preview" => {
+      close_keyboard(&bot, &message, None).await;
+      // preview again, so the admin can see how the notification looks like now
+      let options = NotifyOptions {
+        disable_preview: !options.disable_preview,
+        ..options
+      };
+      preview_new_task(
+        chat_id,
+        &bot,
+        &dialogue,
+        &rt,
+        options,
+        (
+          name, content, parse_mode, schedule, weekdays, buttons, groups,
+        ),
+      )
+      .await?;
+    }
+    "add_task_confirm_silent"
+    | "add_task_confirm_protect"
+    | "add_task_confirm_pin"
+    | "add_task_confirm_latest"
+    | "add_task_confirm_edit"
+    | "add_task_confirm_stop_poll"
+    | "add_task_confirm_rotation" => {
+      // these options don't change how the preview looks like, so only the confirmation
+      // buttons need to be updated
+      let options = NotifyOptions {
+        silent: options.silent ^ (data == "add_task_confirm_silent"),
+        protect_content: options.protect_content ^ (data == "add_task_confirm_protect"),
+        pin: options.pin ^ (data == "add_task_confirm_pin"),
+        delete_previous: options.delete_previous ^ (data == "add_task_confirm_latest"),
+        edit_in_place: options.edit_in_place ^ (data == "add_task_confirm_edit"),
+        stop_previous_poll: options.stop_previous_poll ^ (data == "add_task_confirm_stop_poll"),
+        rotation: if data == "add_task_confirm_rotation" {
+          options.rotation.next_mode()
+        } else {
+          options.rotation
+        },
+        ..options
+      };
+      bot
+        .edit_message_reply_markup(chat_id, message.id)
+        .reply_markup(create_add_task_confirm_buttons(options, nonce))
+        .await?;
+      dialogue
+        .update(AddTaskDialogueCurrentState::RequestConfirmation {
+          name,
+          content,
+          parse_mode,
+          schedule,
+          weekdays,
+          buttons,
+          groups,
+          options,
+          preview,
+          nonce,
+        })
+        .await?;
+    }
+    "add_task_edit_text" | "add_task_edit_interval" | "add_task_edit_buttons" => {
+      let (field, prompt) = match data.as_str() {
+        "add_task_edit_text" => (ReviseField::Text, NOTIFICATION_HELP.to_string()),
+        "add_task_edit_interval" => (ReviseField::Schedule, SCHEDULE_FORMAT_HELP.to_string()),
+        _ => (
+          ReviseField::Buttons,
+          format!("请输入新的按钮信息:\n{BUTTON_FORMAT_HELP}"),
+        ),
+      };
+      bot.send_message(chat_id, prompt).await?;
+      dialogue
+        .update(AddTaskDialogueCurrentState::ReviseTask {
+          field,
+          confirmation: Box::new(AddTaskDialogueCurrentState::RequestConfirmation {
+            name,
+            content,
+            parse_mode,
+            schedule,
+            weekdays,
+            buttons,
+            groups,
+            options,
+            preview,
+            nonce,
+          }),
+          confirm_msg: message.id,
+        })
+        .await?;
+    }
+    "add_task_confirm_n" => {
+      close_keyboard(&bot, &message, Some("已取消".to_string())).await;
+      dialogue.exit().await?;
+    }
+    _ => {}
+  }
+
+  Ok(())
+}
+
+/// Callback handler for the new buttons confirmation.
+async fn set_buttons_callback_handler(
+  q: CallbackQuery,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  mut rt: BotRuntime,
+  (id, buttons): (u32, Option<InlineKeyboardMarkup>),
+) -> Result<()> {
+  let confirm = match q.data.as_deref() {
+    Some("set_buttons_confirm_y") => true,
+    Some("set_buttons_confirm_n") => false,
+    _ => return answer_callback(&bot, &q, Some(CALLBACK_HANDLED)).await,
+  };
+  answer_callback(&bot, &q, None).await?;
+  let message = q
+    .message
+    .ok_or_else(|| anyhow::anyhow!("A button callback without message can't be handle"))?;
+
+  let outcome = if confirm {
+    match rt.task_pool.set_buttons(id, buttons).await {
+      Ok(_) => {
+        rt.audit(q.from.id, format!("修改任务 {id} 的按钮")).await;
+        "按钮已更新！".to_string()
+      }
+      Err(e) => format!("更新失败：{}", e.user_message()),
+    }
+  } else {
+    "你已取消了修改！".to_string()
+  };
+  close_keyboard(&bot, &message, Some(outcome)).await;
+  dialogue.exit().await?;
+
+  Ok(())
+}
+
+#[derive(BotCommands, Debug, Clone)]
+#[command(rename = "lowercase", description = "These commands are supported:")]
+enum Command {
+  #[command(description = "显示这条帮助消息")]
+  Help,
+  #[command(description = "显示这条帮助消息")]
+  Start,
+  #[command(description = "添加一个新的播报任务，可以附带任务名称。")]
+  AddTask,
+  #[command(description = "在添加任务的过程中返回上一步")]
+  Back,
+  #[command(description = "列出当前所有的播报任务")]
+  ListTask,
+  #[command(description = "删除指定的任务。")]
+  DelTask,
+  #[command(description = "查看任务的详细信息和预览。")]
+  TaskInfo,
+  #[command(description = "查看任务的发送统计。")]
+  TaskStats,
+  #[command(description = "编辑指定的任务。")]
+  EditTask,
+  #[command(description = "复制指定的任务，然后设置新任务的循环周期。")]
+  CloneTask,
+  #[command(description = "修改指定任务的按钮。")]
+  SetButtons,
+  #[command(description = "修改指定任务的循环周期。")]
+  SetInterval,
+  #[command(description = "设置指定任务的结束时间。")]
+  SetEnd,
+  #[command(description = "暂停指定的任务。")]
+  PauseTask,
+  #[command(description = "恢复已暂停的任务。")]
+  ResumeTask,
+  #[command(description = "立即发送一次指定任务的通知，不影响原有的发送时间。")]
+  SendNow,
+  #[command(description = "只向当前聊天发送一次指定任务的下一条通知，用于检查效果。")]
+  TestSend,
+  #[command(description = "为指定的任务添加一条轮播通知。")]
+  AddNotify,
+  #[command(description = "修改指定任务中的一条轮播通知。")]
+  SetText,
+  #[command(description = "列出指定任务的所有轮播通知")]
+  ListNotify,
+  #[command(description = "删除指定任务中的一条轮播通知。")]
+  DelNotify,
+  #[command(description = "修改指定任务中一条轮播通知的权重，例如 /setweight 1 0 4")]
+  SetWeight,
+  #[command(description = "把指定任务的通知、格式和按钮保存为模板，例如 /savetemplate 周报 1")]
+  SaveTemplate,
+  #[command(description = "列出所有保存的模板")]
+  ListTemplate,
+  #[command(description = "删除指定的模板。")]
+  DelTemplate,
+  #[command(
+    description = "添加一个新的 bot 管理员，可以在用户 id 后面加上可管理的群组 id（维护者专用）"
+  )]
+  AddAdmin,
+  #[command(description = "删除 bot 管理员（维护者专用）")]
+  DelAdmin,
+  #[command(description = "封禁用户，被封禁的用户无法使用 bot（维护者专用）")]
+  Ban,
+  #[command(description = "解除用户的封禁（维护者专用）")]
+  Unban,
+  #[command(description = "查看最近的管理操作记录，可以指定条数（维护者专用）")]
+  AuditLog,
+  #[command(description = "重新读取白名单文件或配置，不影响正在运行的任务（维护者专用）")]
+  Reload,
+  #[command(description = "停止并删除全部任务（维护者专用）")]
+  StopAll,
+  #[command(description = "把全部任务导出为 JSON 文件（维护者专用）")]
+  ExportTasks,
+  #[command(description = "从导出的 JSON 文件导入任务，可以回复这个文件使用（维护者专用）")]
+  ImportTasks,
+  #[command(
+    description = "为任务中的一个群组设置单独的通知内容，例如 /override 1 -1001（维护者专用）"
+  )]
+  Override,
+  #[command(description = "列出指定任务中设置了单独通知的群组（维护者专用）")]
+  ListOverride,
+  #[command(
+    description = "删除群组的单独通知，恢复发送任务的通知，例如 /deloverride 1 -1001（维护者专用）"
+  )]
+  DelOverride,
+  #[command(description = "添加一个新的通知群，可以在群组 id 后面加上话题 id")]
+  AddGroup,
+  #[command(description = "列出所有通知群")]
+  ListGroup,
+  #[command(description = "删除通知群")]
+  DelGroup,
+  #[command(description = "重新启用被停用的通知群")]
+  EnableGroup,
+  #[command(description = "查看自己的用户 id")]
+  WhoAmI,
+  #[command(description = "在群组中查看群组的 id")]
+  ChatId,
+}
+
+impl Command {
+  /// Commands only the maintainers can use
+  fn is_maintainer_only(&self) -> bool {
+    matches!(
+      self,
+      Self::AddAdmin
+        | Self::DelAdmin
+        | Self::Ban
+        | Self::Unban
+        | Self::AuditLog
+        | Self::Reload
+        | Self::StopAll
+        | Self::ExportTasks
+        | Self::ImportTasks
+        | Self::Override
+        | Self::ListOverride
+        | Self::DelOverride
+    )
+  }
+
+  /// Section title of the command in the help text
+  fn section(&self) -> &'static str {
+    match self {
+      Self::AddTask
+      | Self::Back
+      | Self::ListTask
+      | Self::DelTask
+      | Self::TaskInfo
+      | Self::TaskStats
+      | Self::EditTask
+      | Self::CloneTask
+      | Self::SetButtons
+      | Self::SetInterval
+      | Self::SetEnd
+      | Self::PauseTask
+      | Self::ResumeTask
+      | Self::SendNow
+      | Self::TestSend
+      | Self::AddNotify
+      | Self::SetText
+      | Self::ListNotify
+      | Self::DelNotify
+      | Self::SetWeight
+      | Self::SaveTemplate
+      | Self::ListTemplate
+      | Self::DelTemplate
+      | Self::StopAll
+      | Self::ExportTasks
+      | Self::ImportTasks
+      | Self::Override
+      | Self::ListOverride
+      | Self::DelOverride => "任务管理",
+      Self::AddGroup | Self::ListGroup | Self::DelGroup | Self::EnableGroup | Self::ChatId => {
+        "群组管理"
+      }
+      Self::AddAdmin | Self::DelAdmin | Self::Ban | Self::Unban | Self::AuditLog | Self::Reload => {
+        "管理员管理"
+      }
+      Self::Help | Self::Start | Self::WhoAmI => "其他",
+    }
+  }
+}
+
+/// Order of the sections in the help text
+const HELP_SECTIONS: [&str; 4] = ["任务管理", "群组管理", "管理员管理", "其他"];
+
+/// Build the help text of the commands the caller can use, grouped by sections
+fn help_text(maintainer: bool) -> String {
+  let commands = command_list(maintainer);
+  let mut text = String::from("支持以下命令：");
+  for section in HELP_SECTIONS {
+    let lines = commands
+      .iter()
+      .filter(|cmd| {
+        Command::parse(&format!("/{}", cmd.command), "").map_or(false, |c| c.section() == section)
+      })
+      .map(|cmd| format!("/{} — {}", cmd.command, cmd.description))
+      .collect::<Vec<String>>();
+    if !lines.is_empty() {
+      text.push_str(&format!("\n\n{section}\n{}", lines.join("\n")));
+    }
+  }
+  text
+}
+
+/// The command list shown in the Telegram "/" menu. The maintainer only commands are hidden
+/// unless `maintainer` is true.
+pub fn command_list(maintainer: bool) -> Vec<BotCommand> {
+  Command::bot_commands()
+    .into_iter()
+    .map(|cmd| BotCommand::new(cmd.command.trim_start_matches('/'), cmd.description))
+    .filter(|cmd| {
+      maintainer
+        || Command::parse(&format!("/{}", cmd.command), "")
+          .map_or(true, |c| !c.is_maintainer_only())
+    })
+    .collect()
+}
+
+/// Register the command list with Telegram, the full list is registered for each maintainer's
+/// private chat. Failures are logged only, the bot works without the menu.
+pub async fn register_commands(bot: &NotifyBot, maintainers: &[UserId]) {
+  if let Err(e) = bot.set_my_commands(command_list(false)).await {
+    tracing::error!("Fail to register the commands: {e}");
+  }
+  for user in maintainers {
+    let scope = BotCommandScope::Chat {
+      chat_id: Recipient::Id(ChatId(user.0 as i64)),
+    };
+    if let Err(e) = bot.set_my_commands(command_list(true)).scope(scope).await {
+      tracing::error!(
+        "Fail to register the commands for maintainer {}: {e}",
+        user.0
+      );
+    }
+  }
+}
+
+#[test]
+fn command_list_test() {
+  let all = command_list(true);
+  assert_eq!(all.len(), Command::bot_commands().len());
+  for cmd in &all {
+    assert!(
+      cmd
+        .command
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'),
+      "{} is not a valid telegram command",
+      cmd.command
+    );
+    assert!(Command::parse(&format!("/{}", cmd.command), "").is_ok());
+  }
+
+  let public = command_list(false);
+  assert!(public.iter().any(|cmd| cmd.command == "addtask"));
+  assert!(!public.iter().any(|cmd| cmd.command == "reload"));
+  assert_eq!(all.len() - public.len(), 12);
+}
+
+#[test]
+fn help_text_test() {
+  let admin = help_text(false);
+  assert!(admin.contains("任务管理\n/addtask — "));
+  assert!(admin.contains("群组管理\n/addgroup — "));
+  assert!(!admin.contains("管理员管理"));
+  assert!(!admin.contains("/addadmin"));
+
+  let maintainer = help_text(true);
+  assert!(maintainer.contains("管理员管理\n/addadmin — "));
+  // every command is in one section
+  for cmd in command_list(true) {
+    assert_eq!(
+      maintainer.matches(&format!("/{} — ", cmd.command)).count(),
+      1
+    );
+  }
+}
+
+/// Response command man page, only the commands the caller can use are listed
+async fn help(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let maintainer = msg
+    .from()
+    .map_or(false, |user| rt.whitelist.read().is_maintainers(user.id));
+  bot.send_message(msg.chat.id, help_text(maintainer)).await?;
+  Ok(())
+}
+
+/// Handler for adding task command. This start the add task dialogue, and change
+/// AddTaskDialogueCurrentState to RequestNotifyText.
+async fn add_task_handler(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+) -> Result<()> {
+  tracing::info!(
+    "User {} try adding new schedule task",
+    msg.from().unwrap().id
+  );
+
+  // the optional task name is the whole argument of the command
+  let name = msg
+    .text()
+    .and_then(|text| text.split_once(' '))
+    .map(|(_, name)| name.trim().trim_matches('"').to_string())
+    .filter(|name| !name.is_empty());
+  if let Some(name) = &name {
+    if name.parse::<u32>().is_ok() || name.contains('"') {
+      bot
+        .send_message(
+          msg.chat.id,
+          "任务名称不能是纯数字，也不能包含引号！参考用法：/addtask 每日提醒",
+        )
+        .await?;
+      return Ok(());
+    }
+    if rt.task_pool.find_task(name).is_some() {
+      bot
+        .send_message(msg.chat.id, format!("任务名称 {name} 已被使用！"))
+        .await?;
+      return Ok(());
+    }
+  }
+
+  // a broken template file should not block adding the task from scratch
+  let names = match rt.templates.list().await {
+    Ok(templates) => templates.into_keys().collect::<Vec<_>>(),
+    Err(e) => {
+      tracing::warn!("Fail to read the templates: {e}");
+      Vec::new()
+    }
+  };
+  if !names.is_empty() {
+    bot
+      .send_message(
+        msg.chat.id,
+        "正在创建一个新的定时任务，可以选择一个模板，或者不使用模板（发送 /back 可以返回上一步）：",
+      )
+      .reply_markup(create_template_buttons(&names))
+      .await?;
+    dialogue
+      .update(AddTaskDialogueCurrentState::RequestTemplate { name })
+      .await?;
+    return Ok(());
+  }
+
+  bot
+    .send_message(
+      msg.chat.id,
+      "正在创建一个新的定时任务，请发送通知的内容（发送 /back 可以返回上一步）：".to_string(),
+    )
+    .await?;
+  dialogue
+    .update(AddTaskDialogueCurrentState::RequestNotifyText { name })
+    .await?;
+
+  Ok(())
+}
+
+/// Create a InlineKeyboardMarkup for choosing the template, two templates in a row. Callback
+/// data is `use_template:` followed by the template name, or `use_template_none` for starting
+/// from scratch.
+fn create_template_buttons(names: &[String]) -> InlineKeyboardMarkup {
+  let mut buttons = names
+    .chunks(2)
+    .map(|row| {
+      row
+        .iter()
+        .map(|name| InlineKeyboardButton::callback(name.clone(), format!("use_template:{name}")))
+        .collect::<Vec<_>>()
+    })
+    .collect::<Vec<_>>();
+  buttons.push(vec![InlineKeyboardButton::callback(
+    "不使用模板",
+    "use_template_none",
+  )]);
+  InlineKeyboardMarkup::new(buttons)
+}
+
+/// Callback handler for AddTaskDialogueCurrentState::RequestTemplate status
+/// It load the chosen template and update status to RequestTemplateSchedule, or update status
+/// to RequestNotifyText if no template is used.
+async fn template_callback_handler(
+  q: CallbackQuery,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+  name: Option<String>,
+) -> Result<()> {
+  let message = q
+    .message
+    .as_ref()
+    .ok_or_else(|| anyhow::anyhow!("A button callback without message can't be handle"))?;
+  let chat_id = message.chat.id;
+  let chosen = match q.data.as_deref() {
+    Some("use_template_none") => None,
+    Some(data) => match data.strip_prefix("use_template:") {
+      Some(template) => Some(template.to_string()),
+      None => return answer_callback(&bot, &q, Some(CALLBACK_HANDLED)).await,
+    },
+    None => return answer_callback(&bot, &q, Some(CALLBACK_HANDLED)).await,
+  };
+
+  let chosen = match chosen {
+    Some(chosen) => chosen,
+    None => {
+      answer_callback(&bot, &q, None).await?;
+      close_keyboard(&bot, message, Some("不使用模板".to_string())).await;
+      bot.send_message(chat_id, NOTIFICATION_HELP).await?;
+      dialogue
+        .update(AddTaskDialogueCurrentState::RequestNotifyText { name })
+        .await?;
+      return Ok(());
+    }
+  };
+  let template = match rt.templates.get(&chosen).await {
+    Ok(template) => template,
+    Err(e) => return answer_callback(&bot, &q, Some(&e.user_message())).await,
+  };
+  answer_callback(&bot, &q, None).await?;
+  close_keyboard(&bot, message, Some(format!("使用模板 {chosen}"))).await;
+
+  bot
+    .send_message(
+      chat_id,
+      format!(
+        "已填入模板的通知内容和按钮：\n\n{}\n\n{SCHEDULE_FORMAT_HELP}",
+        template.content
+      ),
+    )
+    .await?;
+  dialogue
+    .update(AddTaskDialogueCurrentState::RequestTemplateSchedule { name, template })
+    .await?;
+
+  Ok(())
+}
+
+/// Handler for AddTaskDialogueCurrentState::RequestTemplateSchedule status
+/// It parse the input to schedule, then ask for the target groups. The task made from the
+/// template send notification every day.
+async fn request_template_schedule(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+  (name, template): (Option<String>, MessageTemplate),
+) -> Result<()> {
+  let schedule = match msg
+    .text()
+    .map(|text| parse_schedule(text, &rt.interval_bounds))
+  {
+    Some(Ok(schedule)) => schedule,
+    Some(Err(e)) => {
+      bot.send_message(msg.chat.id, e).await?;
+      return Ok(());
+    }
+    None => {
+      bot.send_message(msg.chat.id, SCHEDULE_FORMAT_HELP).await?;
+      return Ok(());
+    }
+  };
+
+  ask_for_groups(
+    &msg,
+    &bot,
+    &dialogue,
+    &rt,
+    (
+      name,
+      template.content,
+      template.parse_mode,
+      schedule,
+      WeekdaySet::ALL,
+      template.buttons.unwrap_or_default(),
+    ),
+  )
+  .await
+}
+
+/// How many tasks are shown in one page of /listtask
+const LIST_TASK_PAGE_SIZE: usize = 8;
+
+/// Telegram reject message longer than 4096 characters, keep some room for safety
+const MESSAGE_CHUNK_LIMIT: usize = 4000;
+
+/// Join the entries into chunks that are shorter than `limit` characters. Entries are never
+/// split, unless a single entry is longer than the limit itself.
+fn split_entries(entries: &[String], limit: usize) -> Vec<String> {
+  let mut chunks = Vec::new();
+  let mut current = String::new();
+  let mut current_len = 0;
+
+  for entry in entries {
+    let len = entry.chars().count();
+    if current_len + len > limit && !current.is_empty() {
+      chunks.push(std::mem::take(&mut current));
+      current_len = 0;
+    }
+
+    if len > limit {
+      // the entry can't fit in one message, we have no choice but to cut it
+      let chars = entry.chars().collect::<Vec<char>>();
+      for piece in chars.chunks(limit) {
+        chunks.push(piece.iter().collect());
+      }
+      continue;
+    }
+
+    current.push_str(entry);
+    current_len += len;
+  }
+
+  if !current.is_empty() {
+    chunks.push(current);
+  }
+  chunks
+}
+
+/// Send the long text as multiple messages. The buttons are attached to the last message.
+async fn send_chunks(
+  bot: &NotifyBot,
+  chat_id: ChatId,
+  chunks: Vec<String>,
+  buttons: Option<InlineKeyboardMarkup>,
+) -> Result<()> {
+  let last = chunks.len().saturating_sub(1);
+  for (i, chunk) in chunks.into_iter().enumerate() {
+    let mut req = bot.send_message(chat_id, chunk);
+    if i == last {
+      if let Some(buttons) = &buttons {
+        req = req.reply_markup(buttons.clone());
+      }
+    }
+    req.await?;
+  }
+  Ok(())
+}
+
+/// Format the task id along with the optional task name
+fn task_title(id: u32, name: Option<&str>) -> String {
+  match name {
+    Some(name) => format!("任务 {id}「{name}」"),
+    None => format!("任务 {id}"),
+  }
+}
+
+/// Describe the schedule along with the weekdays restriction
+fn describe_schedule(schedule: &Schedule, weekdays: WeekdaySet) -> String {
+  if weekdays.is_all() {
+    schedule.to_string()
+  } else {
+    format!("{schedule}（仅{weekdays}）")
+  }
+}
+
+/// Describe the schedule of the task, including the weekdays and the end time
+fn describe_task_schedule(task: &TaskInfo) -> String {
+  let schedule = describe_schedule(&task.schedule, task.weekdays);
+  match task.ends_at {
+    Some(ends_at) => format!("{schedule}，{} 结束", ends_at.format("%Y-%m-%d %H:%M")),
+    None => schedule,
+  }
+}
+
+/// Format the creator of the task for display
+fn format_creator(creator: Option<UserId>) -> String {
+  creator
+    .map(|user| user.to_string())
+    .unwrap_or_else(|| "未知".to_string())
+}
+
+/// Describe the status marks of the task, like `🔕（已暂停）`. Empty for a running loud task.
+fn describe_status(task: &TaskInfo) -> String {
+  let paused = if task.paused { "（已暂停）" } else { "" };
+  format!("{}{paused}", describe_marks(task))
+}
+
+/// Describe the sending options of the task as marks, 🔕 for silent, 🔒 for protected,
+/// 📌 for pinned and ✏️ for editing in place
+fn describe_marks(task: &TaskInfo) -> String {
+  let silent = if task.silent { "🔕" } else { "" };
+  let protect = if task.protect_content { "🔒" } else { "" };
+  let pin = if task.pin { "📌" } else { "" };
+  let edit = if task.edit_in_place { "✏️" } else { "" };
+  format!("{silent}{protect}{pin}{edit}")
+}
+
+/// Format a single entry of the /listtask output
+fn format_task_entry(
+  id: u32,
+  name: Option<&str>,
+  creator: Option<UserId>,
+  schedule: &str,
+  status: &str,
+  timing: &str,
+  content: &str,
+) -> String {
+  let title = task_title(id, name);
+  let creator = format_creator(creator);
+  format!(
+    "{title}{status}，创建者：{creator}，循环周期：{schedule}，{timing}，任务内容：{content}\n{}\n\n",
+    "=".repeat(35)
+  )
+}
+
+/// Render the nth (start from 0) page of the task list. The page is clamped into the valid
+/// range, so tasks removed between page flips won't cause error. Return the text chunks and
+/// the page navigation buttons, buttons is `None` when there is only one page.
+fn render_task_page(
+  tasks: &[(u32, TaskInfo)],
+  page: usize,
+) -> (Vec<String>, Option<InlineKeyboardMarkup>) {
+  let total_page = ((tasks.len() + LIST_TASK_PAGE_SIZE - 1) / LIST_TASK_PAGE_SIZE).max(1);
+  let page = page.min(total_page - 1);
+
+  let mut entries = vec![format!("总共 {} 个任务\n", tasks.len())];
+  entries.extend(
+    tasks
+      .iter()
+      .skip(page * LIST_TASK_PAGE_SIZE)
+      .take(LIST_TASK_PAGE_SIZE)
+      .map(|(id, task)| {
+        format_task_entry(
+          *id,
+          task.name.as_deref(),
+          task.creator,
+          &describe_task_schedule(task),
+          &describe_status(task),
+          &describe_timing(task),
+          &task.skim(),
+        )
+      }),
+  );
+
+  if total_page == 1 {
+    return (split_entries(&entries, MESSAGE_CHUNK_LIMIT), None);
+  }
+
+  entries.push(format!("第 {}/{} 页", page + 1, total_page));
+  let mut row = Vec::new();
+  if page > 0 {
+    row.push(InlineKeyboardButton::callback(
+      "上一页",
+      format!("listtask_page_{}", page - 1),
+    ));
+  }
+  if page + 1 < total_page {
+    row.push(InlineKeyboardButton::callback(
+      "下一页",
+      format!("listtask_page_{}", page + 1),
+    ));
+  }
+
+  (
+    split_entries(&entries, MESSAGE_CHUNK_LIMIT),
+    Some(InlineKeyboardMarkup::new(vec![row])),
+  )
+}
+
+/// Handler for /listtask.
+async fn list_task_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  let task = rt.visible_tasks(user);
+
+  let (chunks, buttons) = render_task_page(&task, 0);
+  send_chunks(&bot, msg.chat.id, chunks, buttons).await
+}
+
+/// Callback handler for the /listtask page navigation buttons. The original message
+/// is edited in place. If the page is too long for one message, the rest of the page
+/// is sent as new messages.
+async fn list_task_page_callback_handler(
+  q: CallbackQuery,
+  bot: NotifyBot,
+  rt: BotRuntime,
+) -> Result<()> {
+  answer_callback(&bot, &q, None).await?;
+
+  let page: usize = match q
+    .data
+    .as_deref()
+    .and_then(|d| d.strip_prefix("listtask_page_"))
+    .and_then(|p| p.parse().ok())
+  {
+    Some(page) => page,
+    None => return Ok(()),
+  };
+  let msg = q
+    .message
+    .ok_or_else(|| anyhow::anyhow!("A button callback without message can't be handle"))?;
+
+  let task = rt.visible_tasks(q.from.id);
+  let (mut chunks, buttons) = render_task_page(&task, page);
+  let first = chunks.remove(0);
+  let mut req = bot.edit_message_text(msg.chat.id, msg.id, first);
+  if chunks.is_empty() {
+    if let Some(buttons) = buttons {
+      req = req.reply_markup(buttons);
+    }
+    req.await?;
+    return Ok(());
+  }
+
+  req.await?;
+  send_chunks(&bot, msg.chat.id, chunks, buttons).await
+}
+
+#[test]
+fn split_task_list() {
+  let content = "通知内容".repeat(50);
+  let entries = (0..100)
+    .map(|id| {
+      format_task_entry(
+        id,
+        None,
+        None,
+        "每 1 小时",
+        if id % 2 == 0 { "（已暂停）" } else { "" },
+        "尚未发送",
+        &content,
+      )
+    })
+    .collect::<Vec<String>>();
+  let chunks = split_entries(&entries, MESSAGE_CHUNK_LIMIT);
+
+  assert!(chunks.len() > 1);
+  for chunk in chunks.iter() {
+    assert!(chunk.chars().count() <= MESSAGE_CHUNK_LIMIT);
+  }
+  // every entry should be found in one of the chunks
+  for entry in entries.iter() {
+    assert!(chunks.iter().any(|c| c.contains(entry.as_str())));
+  }
+  assert_eq!(chunks.concat(), entries.concat());
+}
+
+/// Describe the last sent time and next fire time of the task
+fn describe_timing(task: &TaskInfo) -> String {
+  let timing = task.timing.read().clone();
+  let last = match timing.last_sent {
+    Some(t) => format!("上次发送：{}", t.format("%H:%M")),
+    None => "尚未发送".to_string(),
+  };
+  let next = match timing.next_fire {
+    _ if task.paused => "下次发送：已暂停".to_string(),
+    Some(t) => {
+      let secs = (t - clock::now()).num_seconds();
+      if secs <= 0 {
+        "下次发送：即将发送".to_string()
+      } else {
+        format!("下次发送：约 {} 分钟后", (secs + 59) / 60)
+      }
+    }
+    None => "下次发送：未知".to_string(),
+  };
+  format!("{last}，{next}")
+}
+
+/// Handler for /taskinfo command. Send the notifications exactly as the groups will see,
+/// then the metadata of the task.
+async fn task_info_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let (id, task) = match resolve_task_arg(&rt, text).map(|(id, _)| (id, rt.task_pool.get_task(id)))
+  {
+    Some((id, Ok(task))) => (id, task),
+    _ => {
+      let ids = rt
+        .task_pool
+        .list_id()
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+      bot
+        .send_message(
+          msg.chat.id,
+          format!("错误的任务 id！参考用法：/taskinfo 1\n当前可用的任务 id：{ids}"),
+        )
+        .await?;
+      anyhow::bail!("Invalid task id arguments")
+    }
+  };
+  if !ensure_task_visible(&msg, &bot, &rt, id).await? {
+    return Ok(());
+  }
+
+  let options = SendOptions {
+    buttons: task.buttons.clone(),
+    parse_mode: task.parse_mode,
+    disable_preview: task.disable_web_page_preview,
+    protect_content: task.protect_content,
+    ..SendOptions::default()
+  };
+  for notify in task.notifications.iter() {
+    send_notification(&bot, msg.chat.id, notify, &options).await?;
+  }
+
+  let status = format!(
+    "{}{}",
+    if task.paused {
+      "已暂停"
+    } else {
+      "运行中"
+    },
+    describe_marks(&task)
+  );
+  bot
+    .send_message(
+      msg.chat.id,
+      format!(
+        "以上是{} 的 {} 条轮播通知\n状态：{status}\n轮播方式：{}\n循环周期：{}\n通知群组数量：{}\n单独通知的群组数量：{}\n创建者：{}\n创建时间：{}\n{}",
+        task_title(id, task.name.as_deref()),
+        task.notifications.len(),
+        task.rotation,
+        describe_task_schedule(&task),
+        task.groups.len(),
+        task.overrides.len(),
+        format_creator(task.creator),
+        task.created_at.format("%Y-%m-%d %H:%M:%S"),
+        describe_timing(&task),
+      ),
+    )
+    .await?;
+
+  Ok(())
+}
+
+/// Format a optional time for display
+fn format_time(time: Option<DateTime<Tz>>) -> String {
+  time
+    .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+    .unwrap_or_else(|| "无".to_string())
+}
+
+/// Handler for /taskstats command. Show a summary of every task without argument,
+/// or the detail of the specific task.
+async fn task_stats_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let id: u32 = match resolve_task_arg(&rt, text) {
+    Some((id, _)) => id,
+    None => {
+      let user = msg
+        .from()
+        .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+        .id;
+      let stats = rt
+        .task_pool
+        .list_stats()
+        .into_iter()
+        .filter(|(id, _)| rt.can_view_task(user, *id))
+        .collect::<Vec<_>>();
+      let text = format!("总共 {} 个任务\n", stats.len());
+      let text = stats.iter().fold(text, |acc, (id, s)| {
+        format!(
+          "{acc}任务 {id}：发送 {} 次，成功 {} 次，失败 {} 次\n",
+          s.total.attempts, s.total.successes, s.total.failures
+        )
+      });
+      bot.send_message(msg.chat.id, text).await?;
+      return Ok(());
+    }
+  };
+  if !ensure_task_visible(&msg, &bot, &rt, id).await? {
+    return Ok(());
+  }
+
+  let stats = match rt.task_pool.get_task(id) {
+    Ok(task) => task.stats.read().clone(),
+    Err(e) => {
+      bot
+        .send_message(
+          msg.chat.id,
+          format!(
+            "查询失败：{}，请用 /listtask 确认任务存在。",
+            e.user_message()
+          ),
+        )
+        .await?;
+      return Ok(());
+    }
+  };
+
+  let text = format!(
+    "任务 {id} 发送统计\n发送 {} 次，成功 {} 次，失败 {} 次\n上次成功：{}\n上次失败：{}\n{}\n",
+    stats.total.attempts,
+    stats.total.successes,
+    stats.total.failures,
+    format_time(stats.total.last_success),
+    format_time(stats.total.last_failure),
+    "=".repeat(35)
+  );
+  let text = stats.groups.iter().fold(text, |acc, (gid, s)| {
+    format!(
+      "{acc}群组 {}：发送 {} 次，成功 {} 次，失败 {} 次，上次成功：{}，上次失败：{}\n",
+      gid.0,
+      s.attempts,
+      s.successes,
+      s.failures,
+      format_time(s.last_success),
+      format_time(s.last_failure)
+    )
+  });
+  bot.send_message(msg.chat.id, text).await?;
+
+  Ok(())
+}
+
+/// Reply to the user and return false if the user is not allowed to modify the task
+async fn ensure_task_owner(
+  msg: &Message,
+  bot: &NotifyBot,
+  rt: &BotRuntime,
+  id: u32,
+) -> Result<bool> {
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  if rt.can_modify_task(user, id) {
+    return Ok(true);
+  }
+
+  bot
+    .send_message(msg.chat.id, "无权限：这个任务由别的管理员创建")
+    .await?;
+  Ok(false)
+}
+
+/// Reply to the user and return false if the user is not allowed to see the task
+async fn ensure_task_visible(
+  msg: &Message,
+  bot: &NotifyBot,
+  rt: &BotRuntime,
+  id: u32,
+) -> Result<bool> {
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  if !rt.task_pool.has_task(id) || rt.can_view_task(user, id) {
+    return Ok(true);
+  }
+
+  bot.send_message(msg.chat.id, "无权限").await?;
+  Ok(false)
+}
+
+/// Resolve the task reference at the beginning of the command arguments. The reference can be
+/// a numeric id or a task name. Name with spaces should be quoted, unless it is the sole
+/// argument. Return the task id and the rest of the arguments.
+fn resolve_task_arg<'a>(rt: &BotRuntime, text: &'a str) -> Option<(u32, &'a str)> {
+  let args = text.split_once(' ').map(|x| x.1).unwrap_or_default().trim();
+
+  // the whole arguments is the task reference
+  if let Some(id) = rt.task_pool.find_task(args.trim_matches('"')) {
+    return Some((id, ""));
+  }
+
+  let (key, rest) = match args.strip_prefix('"') {
+    Some(quoted) => quoted.split_once('"')?,
+    None => args.split_once(char::is_whitespace).unwrap_or((args, "")),
+  };
+  let id = rt.task_pool.find_task(key)?;
+  Some((id, rest.trim()))
+}
+
+/// Parse the first word of the arguments as number
+fn parse_first_num<T: FromStr>(args: &str) -> Option<T> {
+  args.split_whitespace().next()?.parse::<T>().ok()
+}
+
+fn parse_first_arg_as_num<T: FromStr>(text: &str) -> Option<T> {
+  parse_nth_arg_as_num(text, 0)
+}
+
+/// Parse the nth (start from 0) argument of a command. Return `None` if the argument
+/// doesn't exist or is not a valid number.
+fn parse_nth_arg_as_num<T: FromStr>(text: &str, n: usize) -> Option<T> {
+  let args = text.split(' ').skip(1).collect::<Vec<&str>>();
+  if args.len() <= n {
+    return None;
+  }
+
+  args[n].parse::<T>().ok()
+}
+
+/// Max length of the callback data allowed by Telegram
+const CALLBACK_DATA_LIMIT: usize = 64;
+
+/// Max number of ids that a range can expand to, avoid allocating huge list for bogus input
+const MAX_ID_RANGE: u32 = 100;
+
+/// Parse a list of task id. The ids can be separated by space or comma, and a range
+/// like `2-6` is also accepted. Return `None` if any part is invalid.
+fn parse_task_ids(text: &str) -> Option<Vec<u32>> {
+  let mut ids = Vec::new();
+  for part in text
+    .split(|c: char| c == ',' || c.is_whitespace())
+    .filter(|x| !x.is_empty())
+  {
+    match part.split_once('-') {
+      Some((start, end)) => {
+        let start = start.parse::<u32>().ok()?;
+        let end = end.parse::<u32>().ok()?;
+        if start > end || end - start >= MAX_ID_RANGE {
+          return None;
+        }
+        ids.extend(start..=end);
+      }
+      None => ids.push(part.parse::<u32>().ok()?),
+    }
+  }
+
+  ids.sort_unstable();
+  ids.dedup();
+  if ids.is_empty() {
+    None
+  } else {
+    Some(ids)
+  }
+}
+
+#[test]
+fn parse_task_ids_test() {
+  assert_eq!(parse_task_ids("2"), Some(vec![2]));
+  assert_eq!(parse_task_ids("2 5 7"), Some(vec![2, 5, 7]));
+  assert_eq!(parse_task_ids("7,2, 5"), Some(vec![2, 5, 7]));
+  assert_eq!(parse_task_ids("2-6"), Some(vec![2, 3, 4, 5, 6]));
+  assert_eq!(parse_task_ids("1 3-4 4"), Some(vec![1, 3, 4]));
+  assert_eq!(parse_task_ids("2-"), None);
+  assert_eq!(parse_task_ids("-2"), None);
+  assert_eq!(parse_task_ids("6-2"), None);
+  assert_eq!(parse_task_ids("abc"), None);
+  assert_eq!(parse_task_ids(""), None);
+  assert_eq!(parse_task_ids("0-1000"), None);
+}
+
+/// Handler for /deltask command. It accept multiple ids like `/deltask 2 5 7` or
+/// `/deltask 2-6`. It doesn't delete the tasks immediately, but ask for confirmation with
+/// the task content.
+async fn del_task_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+  let args = text.split_once(' ').map(|x| x.1).unwrap_or_default();
+
+  // fallback to find the task by name
+  let ids = match parse_task_ids(args)
+    .or_else(|| resolve_task_arg(&rt, text).map(|(id, _)| vec![id]))
+  {
+    Some(ids) => ids,
+    None => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的任务 id！参考用法：/deltask 1 或 /deltask 2 5 7 或 /deltask 2-6，你可以用 /listtask 命令来查看任务 id",
+        )
+        .await?;
+      anyhow::bail!("Invalid task id arguments")
+    }
+  };
+
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  let (found, missing): (Vec<u32>, Vec<u32>) =
+    ids.into_iter().partition(|id| rt.task_pool.has_task(*id));
+  let (found, denied): (Vec<u32>, Vec<u32>) = found
+    .into_iter()
+    .partition(|id| rt.can_modify_task(user, *id));
+  let missing = missing
+    .iter()
+    .map(|id| format!("任务 {id}：不存在\n"))
+    .chain(denied.iter().map(|id| format!("任务 {id}：无权限\n")))
+    .collect::<String>();
+
+  if found.is_empty() {
+    bot
+      .send_message(
+        msg.chat.id,
+        format!("{missing}删除失败，请用 /listtask 确认任务存在。"),
+      )
+      .await?;
+    return Ok(());
+  }
+
+  let id_list = found
+    .iter()
+    .map(|id| id.to_string())
+    .collect::<Vec<String>>()
+    .join(",");
+  if format!("del_task_confirm_{id_list}_y").len() > CALLBACK_DATA_LIMIT {
+    bot
+      .send_message(msg.chat.id, "一次删除的任务太多了，请分批删除。")
+      .await?;
+    return Ok(());
+  }
+
+  let detail = found
+    .iter()
+    .filter_map(|id| rt.task_pool.get_task(*id).ok().map(|task| (id, task)))
+    .map(|(id, task)| {
+      format!(
+        "任务 {id}，循环周期：{}，任务内容：{}\n",
+        describe_task_schedule(&task),
+        task.skim()
+      )
+    })
+    .collect::<String>();
+
+  bot
+    .send_message(
+      msg.chat.id,
+      format!("{missing}确认要删除以下任务吗？\n{detail}"),
+    )
+    .reply_markup(create_del_task_confirm_buttons(&id_list))
+    .await?;
+
+  Ok(())
+}
+
+/// Create a InlineKeyboardMarkup for delete confirmation. Callback data is
+/// `del_task_confirm_{ids}_y` for confirm, and `del_task_confirm_{ids}_n` for cancel.
+/// The ids are separated by comma.
+fn create_del_task_confirm_buttons(ids: &str) -> InlineKeyboardMarkup {
+  let buttons = vec![vec![
+    InlineKeyboardButton::callback("确认删除", format!("del_task_confirm_{ids}_y")),
+    InlineKeyboardButton::callback("取消", format!("del_task_confirm_{ids}_n")),
+  ]];
+  InlineKeyboardMarkup::new(buttons)
+}
+
+/// Callback handler for the delete confirmation. The prompt message is edited to the result
+/// of each task. Failure of one task doesn't stop the others.
+async fn del_task_callback_handler(
+  q: CallbackQuery,
+  bot: NotifyBot,
+  mut rt: BotRuntime,
+) -> Result<()> {
+  answer_callback(&bot, &q, None).await?;
+
+  let (ids, confirm) = match q
+    .data
+    .as_deref()
+    .and_then(|d| d.strip_prefix("del_task_confirm_"))
+    .and_then(|d| d.rsplit_once('_'))
+  {
+    Some((ids, confirm)) => match parse_task_ids(ids) {
+      Some(ids) => (ids, confirm == "y"),
+      None => return Ok(()),
+    },
+    None => return Ok(()),
+  };
+  let msg = q
+    .message
+    .ok_or_else(|| anyhow::anyhow!("A button callback without message can't be handle"))?;
+
+  if !confirm {
+    bot
+      .edit_message_text(msg.chat.id, msg.id, "已取消删除")
+      .await?;
+    return Ok(());
+  }
+
+  let mut result = String::new();
+  for id in ids {
+    if rt.task_pool.has_task(id) && !rt.can_modify_task(q.from.id, id) {
+      result.push_str(&format!("任务 {id}：无权限\n"));
+      continue;
+    }
+    // someone else may have deleted it before the button is pressed
+    if !rt.task_pool.has_task(id) {
+      result.push_str(&format!("任务 {id}：不存在\n"));
+      continue;
+    }
+    match rt.task_pool.remove(id).await {
+      Ok(_) => {
+        rt.audit(q.from.id, format!("删除任务 {id}")).await;
+        result.push_str(&format!("任务 {id}：已删除\n"))
+      }
+      Err(e) => {
+        tracing::error!("{e}");
+        rt.audit(q.from.id, format!("删除任务 {id}")).await;
+        result.push_str(&format!("{}\n", e.user_message()))
+      }
+    }
+  }
+  bot.edit_message_text(msg.chat.id, msg.id, result).await?;
+
+  Ok(())
+}
+
+/// Format the keyboard back to the button definition syntax
+fn format_keyboard(keyboard: &InlineKeyboardMarkup) -> String {
+  keyboard
+    .inline_keyboard
+    .iter()
+    .map(|row| {
+      row
+        .iter()
+        .map(|button| match &button.kind {
+          InlineKeyboardButtonKind::Url(url) => format!("[{}|{}]", button.text, url),
+          InlineKeyboardButtonKind::CallbackData(data) => format!("[{}|{}]", button.text, data),
+          InlineKeyboardButtonKind::SwitchInlineQuery(query) => {
+            format!("[{}|{SHARE_BUTTON_PREFIX}{}]", button.text, query)
+          }
+          _ => format!("[{}]", button.text),
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+    })
+    .collect::<Vec<String>>()
+    .join("\n")
+}
+
+/// Describe the task detail for the edit dialogue
+fn describe_task(id: u32, task: &TaskInfo) -> String {
+  let notifications = task
+    .notifications
+    .iter()
+    .enumerate()
+    .map(|(i, n)| format!("{i}. {n}"))
+    .collect::<Vec<String>>()
+    .join("\n");
+  let buttons = task
+    .buttons
+    .as_ref()
+    .map(format_keyboard)
+    .unwrap_or_else(|| "无".to_string());
+  let groups = task
+    .groups
+    .iter()
+    .map(|g| g.0.to_string())
+    .collect::<Vec<String>>()
+    .join(",");
+  let title = task_title(id, task.name.as_deref());
+  let status = describe_status(task);
+
+  format!(
+    "{title}{status}\n\n通知内容（{}轮播）：\n{notifications}\n\n循环周期：{}\n\n按钮：\n{buttons}\n\n通知群组：{groups}",
+    task.rotation,
+    describe_task_schedule(task)
+  )
+}
+
+/// Create a InlineKeyboardMarkup for the edit task menu. Callback data is prefixed
+/// by `edit_task_`.
+fn create_edit_task_menu_buttons() -> InlineKeyboardMarkup {
+  let buttons = vec![
+    vec![
+      InlineKeyboardButton::callback("修改文本", "edit_task_text"),
+      InlineKeyboardButton::callback("修改间隔", "edit_task_interval"),
+    ],
+    vec![
+      InlineKeyboardButton::callback("修改按钮", "edit_task_buttons"),
+      InlineKeyboardButton::callback("修改群组", "edit_task_groups"),
+    ],
+    vec![
+      InlineKeyboardButton::callback("切换静音", "edit_task_silent"),
+      InlineKeyboardButton::callback("切换禁止转发", "edit_task_protect"),
+    ],
+    vec![
+      InlineKeyboardButton::callback("切换置顶", "edit_task_pin"),
+      InlineKeyboardButton::callback("切换只保留最新", "edit_task_latest"),
+    ],
+    vec![
+      InlineKeyboardButton::callback("切换轮播方式", "edit_task_rotation"),
+      InlineKeyboardButton::callback("完成", "edit_task_done"),
+    ],
+  ];
+  InlineKeyboardMarkup::new(buttons)
+}
+
+/// Send the task detail with the edit menu, and change the dialogue back to EditTaskMenu.
+async fn show_edit_task_menu(
+  chat_id: ChatId,
+  bot: &NotifyBot,
+  dialogue: &AddTaskDialogue,
+  rt: &BotRuntime,
+  (id, changes): (u32, Vec<String>),
+) -> Result<()> {
+  let task = match rt.task_pool.get_task(id) {
+    Ok(task) => task,
+    Err(e) => return edit_task_failed(chat_id, bot, dialogue, e).await,
+  };
+
+  bot
+    .send_message(
+      chat_id,
+      format!("{}\n\n请选择要修改的内容：", describe_task(id, &task)),
+    )
+    .reply_markup(create_edit_task_menu_buttons())
+    .await?;
+  dialogue
+    .update(AddTaskDialogueCurrentState::EditTaskMenu { id, changes })
+    .await?;
+
+  Ok(())
+}
+
+/// Record the latest change of the edit task dialogue in the audit log
+async fn audit_task_change(rt: &BotRuntime, user: UserId, id: u32, changes: &[String]) {
+  if let Some(change) = changes.last() {
+    let change = change.trim_start_matches("- ");
+    rt.audit(user, format!("编辑任务 {id}：{change}")).await;
+  }
+}
+
+/// Stop the edit dialogue when the task can't be edited anymore. Mostly because
+/// the task is deleted during the dialogue.
+async fn edit_task_failed(
+  chat_id: ChatId,
+  bot: &NotifyBot,
+  dialogue: &AddTaskDialogue,
+  e: Error,
+) -> Result<()> {
+  bot
+    .send_message(
+      chat_id,
+      format!(
+        "编辑失败：{}，任务可能已被删除，编辑已结束。",
+        e.user_message()
+      ),
+    )
+    .await?;
+  dialogue.exit().await?;
+  Ok(())
+}
+
+/// Handler for /edittask command. This start the edit task dialogue, and change
+/// AddTaskDialogueCurrentState to EditTaskMenu.
+async fn edit_task_handler(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let id: u32 = match resolve_task_arg(&rt, text) {
+    Some((id, _)) => id,
+    _ => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的任务 id！参考用法：/edittask 1，你可以用 /listtask 命令来查看任务 id",
+        )
+        .await?;
+      anyhow::bail!("Invalid task id arguments")
+    }
+  };
+
+  if !ensure_task_owner(&msg, &bot, &rt, id).await? {
+    return Ok(());
+  }
+
+  show_edit_task_menu(msg.chat.id, &bot, &dialogue, &rt, (id, Vec::new())).await
+}
+
+/// Callback handler for the edit task menu.
+async fn edit_task_menu_callback_handler(
+  q: CallbackQuery,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  mut rt: BotRuntime,
+  (id, mut changes): (u32, Vec<String>),
+) -> Result<()> {
+  let data = match q.data.as_deref() {
+    Some(data) if data.starts_with("edit_task_") => data.to_string(),
+    _ => return answer_callback(&bot, &q, Some(CALLBACK_HANDLED)).await,
+  };
+  answer_callback(&bot, &q, None).await?;
+  let message = q
+    .message
+    .as_ref()
+    .ok_or_else(|| anyhow::anyhow!("A button callback without message can't be handle"))?;
+  let chat_id = message.chat.id;
+  // a new menu is shown after each change, only the latest one is clickable
+  close_keyboard(&bot, message, None).await;
+
+  let task = match rt.task_pool.get_task(id) {
+    Ok(task) => task,
+    Err(e) => return edit_task_failed(chat_id, &bot, &dialogue, e).await,
+  };
+
+  let (prompt, next) = match data.as_str() {
+    "edit_task_text" => (
+      "请发送新的通知内容（将替换第一条通知，其他通知请使用 /settext 修改）：".to_string(),
+      AddTaskDialogueCurrentState::EditTaskText { id, changes },
+    ),
+    "edit_task_interval" => (
+      SCHEDULE_FORMAT_HELP.to_string(),
+      AddTaskDialogueCurrentState::EditTaskInterval { id, changes },
+    ),
+    "edit_task_buttons" => (
+      format!("请输入新的按钮信息，发送“清除”可以移除所有按钮:\n{BUTTON_FORMAT_HELP}"),
+      AddTaskDialogueCurrentState::EditTaskButtons { id, changes },
+    ),
+    "edit_task_groups" => {
+      let groups = rt
+        .manageable_groups(q.from.id)
+        .iter()
+        .map(|g| g.0.to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+      (
+        format!("请发送新的通知群组 id，多个群组用逗号分隔。\n可用的群组：{groups}"),
+        AddTaskDialogueCurrentState::EditTaskGroups { id, changes },
+      )
+    }
+    "edit_task_silent" => {
+      if let Err(e) = rt.task_pool.set_silent(id, !task.silent).await {
+        return edit_task_failed(chat_id, &bot, &dialogue, e).await;
+      }
+      changes.push(if task.silent {
+        "- 关闭了静音发送".to_string()
+      } else {
+        "- 开启了静音发送".to_string()
+      });
+      audit_task_change(&rt, q.from.id, id, &changes).await;
+      return show_edit_task_menu(chat_id, &bot, &dialogue, &rt, (id, changes)).await;
+    }
+    "edit_task_protect" => {
+      let protect = !task.protect_content;
+      if let Err(e) = rt.task_pool.set_protect_content(id, protect).await {
+        return edit_task_failed(chat_id, &bot, &dialogue, e).await;
+      }
+      changes.push(if protect {
+        "- 开启了禁止转发，群成员将无法转发、保存或者复制通知".to_string()
+      } else {
+        "- 关闭了禁止转发".to_string()
+      });
+      audit_task_change(&rt, q.from.id, id, &changes).await;
+      return show_edit_task_menu(chat_id, &bot, &dialogue, &rt, (id, changes)).await;
+    }
+    "edit_task_pin" => {
+      if let Err(e) = rt.task_pool.set_pin(id, !task.pin).await {
+        return edit_task_failed(chat_id, &bot, &dialogue, e).await;
+      }
+      changes.push(if task.pin {
+        "- 关闭了发送后置顶".to_string()
+      } else {
+        "- 开启了发送后置顶，bot 需要置顶消息的权限".to_string()
+      });
+      audit_task_change(&rt, q.from.id, id, &changes).await;
+      return show_edit_task_menu(chat_id, &bot, &dialogue, &rt, (id, changes)).await;
+    }
+    "edit_task_latest" => {
+      if let Err(e) = rt
+        .task_pool
+        .set_delete_previous(id, !task.delete_previous)
+        .await
+      {
+        return edit_task_failed(chat_id, &bot, &dialogue, e).await;
+      }
+      changes.push(if task.delete_previous {
+        "- 关闭了只保留最新一条".to_string()
+      } else {
+        "- 开启了只保留最新一条，bot 需要删除消息的权限".to_string()
+      });
+      audit_task_change(&rt, q.from.id, id, &changes).await;
+      return show_edit_task_menu(chat_id, &bot, &dialogue, &rt, (id, changes)).await;
+    }
+    "edit_task_rotation" => {
+      let rotation = task.rotation.next_mode();
+      if let Err(e) = rt.task_pool.set_rotation(id, rotation).await {
+        return edit_task_failed(chat_id, &bot, &dialogue, e).await;
+      }
+      changes.push(format!("- 轮播方式修改为{rotation}"));
+      audit_task_change(&rt, q.from.id, id, &changes).await;
+      return show_edit_task_menu(chat_id, &bot, &dialogue, &rt, (id, changes)).await;
+    }
+    "edit_task_done" => {
+      let summary = if changes.is_empty() {
+        format!("任务 {id} 没有任何修改。")
+      } else {
+        format!("任务 {id} 编辑完成，修改内容：\n{}", changes.join("\n"))
+      };
+      bot.send_message(chat_id, summary).await?;
+      dialogue.exit().await?;
+      return Ok(());
+    }
+    _ => return Ok(()),
+  };
+
+  bot.send_message(chat_id, prompt).await?;
+  dialogue.update(next).await?;
+
+  Ok(())
+}
+
+/// Handler for AddTaskDialogueCurrentState::EditTaskText status
+async fn request_edit_text(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  mut rt: BotRuntime,
+  (id, mut changes): (u32, Vec<String>),
+) -> Result<()> {
+  let chat_id = msg.chat.id;
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  receive_notification(msg, bot.clone(), rt.clone(), move |notify| async move {
+    if let Err(e) = rt.task_pool.replace_notification(id, 0, notify).await {
+      return edit_task_failed(chat_id, &bot, &dialogue, e).await;
+    }
+    changes.push("- 修改了通知文本".to_string());
+    audit_task_change(&rt, user, id, &changes).await;
+
+    show_edit_task_menu(chat_id, &bot, &dialogue, &rt, (id, changes)).await
+  })
+  .await
+}
+
+/// Handler for AddTaskDialogueCurrentState::EditTaskInterval status
+async fn request_edit_interval(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  mut rt: BotRuntime,
+  (id, mut changes): (u32, Vec<String>),
+) -> Result<()> {
+  let schedule = match msg
+    .text()
+    .map(|text| parse_schedule(text, &rt.interval_bounds))
+  {
+    Some(Ok(schedule)) => schedule,
+    Some(Err(e)) => {
+      bot.send_message(msg.chat.id, e).await?;
+      return Ok(());
+    }
+    None => {
+      bot.send_message(msg.chat.id, SCHEDULE_FORMAT_HELP).await?;
+      return Ok(());
+    }
+  };
+
+  changes.push(format!("- 循环周期修改为 {schedule}"));
+  if let Err(e) = rt.task_pool.set_schedule(id, schedule).await {
+    return edit_task_failed(msg.chat.id, &bot, &dialogue, e).await;
+  }
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  audit_task_change(&rt, user, id, &changes).await;
+
+  show_edit_task_menu(msg.chat.id, &bot, &dialogue, &rt, (id, changes)).await
+}
+
+/// Handler for AddTaskDialogueCurrentState::EditTaskButtons status
+async fn request_edit_buttons(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  mut rt: BotRuntime,
+  (id, mut changes): (u32, Vec<String>),
+) -> Result<()> {
+  let msg_text = match msg.text() {
+    Some(text) => text.trim(),
+    None => {
+      bot
+        .send_message(msg.chat.id, "bot 需要文字消息！请重新输入！")
+        .await?;
+      return Ok(());
+    }
+  };
+
+  let buttons = if msg_text == "清除" {
+    None
+  } else {
+    match parse_keyboard(msg_text, &rt.button_schemes) {
+      Ok(buttons) => Some(buttons),
+      Err(e) => {
+        bot.send_message(msg.chat.id, e.hint()).await?;
+        anyhow::bail!("{e}");
+      }
+    }
+  };
+
+  let change = if buttons.is_some() {
+    "- 修改了按钮"
+  } else {
+    "- 移除了所有按钮"
+  };
+  if let Err(e) = rt.task_pool.set_buttons(id, buttons).await {
+    return edit_task_failed(msg.chat.id, &bot, &dialogue, e).await;
+  }
+  changes.push(change.to_string());
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  audit_task_change(&rt, user, id, &changes).await;
+
+  show_edit_task_menu(msg.chat.id, &bot, &dialogue, &rt, (id, changes)).await
+}
+
+/// Handler for AddTaskDialogueCurrentState::EditTaskGroups status. The groups should
+/// be in the whitelist.
+async fn request_edit_groups(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  mut rt: BotRuntime,
+  (id, mut changes): (u32, Vec<String>),
+) -> Result<()> {
+  let msg_text = match msg.text() {
+    Some(text) => text,
+    None => {
+      bot
+        .send_message(msg.chat.id, "bot 需要文字消息！请重新输入！")
+        .await?;
+      return Ok(());
+    }
+  };
+
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  let whitelist = rt.manageable_groups(user);
+  let groups = msg_text
+    .split(|c: char| c == ',' || c.is_whitespace())
+    .filter(|x| !x.is_empty())
+    .map(|x| x.parse::<i64>().ok().map(ChatId))
+    .collect::<Option<Vec<ChatId>>>();
+  let groups = match groups {
+    Some(groups) if !groups.is_empty() && groups.iter().all(|g| whitelist.contains(g)) => groups,
+    _ => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的群组 id！群组需要先用 /addgroup 添加，并且在你管理的范围内，请重新输入！",
+        )
+        .await?;
+      return Ok(());
+    }
+  };
+
+  let change = format!(
+    "- 通知群组修改为 {}",
+    groups
+      .iter()
+      .map(|g| g.0.to_string())
+      .collect::<Vec<String>>()
+      .join(",")
+  );
+  if let Err(e) = rt.task_pool.set_groups(id, groups).await {
+    return edit_task_failed(msg.chat.id, &bot, &dialogue, e).await;
+  }
+  changes.push(change);
+  audit_task_change(&rt, user, id, &changes).await;
+
+  show_edit_task_menu(msg.chat.id, &bot, &dialogue, &rt, (id, changes)).await
+}
+
+/// Handler for /setbuttons command. This start a dialogue that require the new button
+/// definition, and change AddTaskDialogueCurrentState to RequestNewButtons.
+async fn set_buttons_handler(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let id: u32 = match resolve_task_arg(&rt, text) {
+    Some((id, _)) => id,
+    _ => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的任务 id！参考用法：/setbuttons 1，你可以用 /listtask 命令来查看任务 id",
+        )
+        .await?;
+      anyhow::bail!("Invalid task id arguments")
+    }
+  };
+
+  if !ensure_task_owner(&msg, &bot, &rt, id).await? {
+    return Ok(());
+  }
+
+  bot
+    .send_message(
+      msg.chat.id,
+      format!("请输入任务 {id} 的新按钮信息，发送“清除”可以移除所有按钮:\n{BUTTON_FORMAT_HELP}"),
+    )
+    .await?;
+  dialogue
+    .update(AddTaskDialogueCurrentState::RequestNewButtons { id })
+    .await?;
+
+  Ok(())
+}
+
+/// Handler for AddTaskDialogueCurrentState::RequestNewButtons status
+/// It parse input to buttons, send a preview, then update status to
+/// RequestNewButtonsConfirmation.
+async fn request_new_buttons(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+  id: u32,
+) -> Result<()> {
+  let msg_text = match msg.text() {
+    Some(text) => text.trim(),
+    None => {
+      bot
+        .send_message(msg.chat.id, "bot 需要文字消息！请重新输入！")
+        .await?;
+      anyhow::bail!("invalid message text for parsing buttons");
+    }
+  };
+
+  let buttons = if msg_text == "清除" {
+    None
+  } else {
+    match parse_keyboard(msg_text, &rt.button_schemes) {
+      Ok(buttons) => Some(buttons),
+      Err(e) => {
+        bot.send_message(msg.chat.id, e.hint()).await?;
+        anyhow::bail!("{e}");
+      }
+    }
+  };
+
+  // preview the first notification with the new buttons
+  let preview = rt
+    .task_pool
+    .list_notification(id)?
+    .into_iter()
+    .next()
+    .unwrap_or_default();
+  let mut req = bot.send_message(msg.chat.id, preview);
+  if let Some(buttons) = &buttons {
+    req = req.reply_markup(buttons.clone());
+  }
+  req.await?;
+
+  bot
+    .send_message(
+      msg.chat.id,
+      if buttons.is_some() {
+        "上面是使用新按钮的预览。\n请确认修改按钮："
+      } else {
+        "上面是移除按钮后的预览。\n请确认移除按钮："
+      },
+    )
+    .reply_markup(create_set_buttons_confirm_buttons())
+    .await?;
+
+  dialogue
+    .update(AddTaskDialogueCurrentState::RequestNewButtonsConfirmation { id, buttons })
+    .await?;
+
+  Ok(())
+}
+
+/// Hint for the schedule of the cloned task
+const CLONE_SCHEDULE_HELP: &str = "发送“跳过”沿用原任务的循环周期。";
+
+/// Handler for /clonetask. It copy the task into a paused task, then ask for the schedule of
+/// the new task.
+async fn clone_task_handler(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  mut rt: BotRuntime,
+) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let source = match resolve_task_arg(&rt, text) {
+    Some((id, _)) => id,
+    None => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的任务 id！参考用法：/clonetask 1，你可以用 /listtask 命令来查看任务 id",
+        )
+        .await?;
+      anyhow::bail!("Invalid task id arguments")
+    }
+  };
+
+  if !ensure_task_owner(&msg, &bot, &rt, source).await? {
+    return Ok(());
+  }
+
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  let id = match rt.task_pool.clone_task(source, user).await {
+    Ok(id) => id,
+    Err(e) => {
+      bot
+        .send_message(msg.chat.id, format!("复制失败：{}", e.user_message()))
+        .await?;
+      return Ok(());
+    }
+  };
+  rt.audit(user, format!("复制任务 {source} 为任务 {id}"))
+    .await;
+
+  bot
+    .send_message(
+      msg.chat.id,
+      format!(
+        "已复制为任务 {id}，设置循环周期后开始发送。\n\n{SCHEDULE_FORMAT_HELP}\n{CLONE_SCHEDULE_HELP}"
+      ),
+    )
+    .await?;
+  dialogue
+    .update(AddTaskDialogueCurrentState::RequestCloneInterval { id, source })
+    .await?;
+
+  Ok(())
+}
+
+/// Handler for AddTaskDialogueCurrentState::RequestCloneInterval status
+/// It set the schedule of the cloned task and resume it, then exit the dialogue.
+async fn request_clone_interval(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  mut rt: BotRuntime,
+  (id, source): (u32, u32),
+) -> Result<()> {
+  let text = match msg.text() {
+    Some(text) => text.trim(),
+    None => {
+      bot
+        .send_message(
+          msg.chat.id,
+          format!("{SCHEDULE_FORMAT_HELP}\n{CLONE_SCHEDULE_HELP}"),
+        )
+        .await?;
+      return Ok(());
+    }
+  };
+  let schedule = if matches!(text, "跳过" | "skip") {
+    None
+  } else {
+    match parse_schedule(text, &rt.interval_bounds) {
+      Ok(schedule) => Some(schedule),
+      Err(e) => {
+        bot.send_message(msg.chat.id, e).await?;
+        return Ok(());
+      }
+    }
+  };
+
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  if let Some(schedule) = schedule {
+    let action = format!("修改任务 {id} 的循环周期为 {schedule}");
+    if let Err(e) = rt.task_pool.set_schedule(id, schedule).await {
+      bot
+        .send_message(msg.chat.id, format!("修改失败：{}", e.user_message()))
+        .await?;
+      dialogue.exit().await?;
+      return Ok(());
+    }
+    rt.audit(user, action).await;
+  }
+  let outcome = match rt.task_pool.resume(id).await {
+    Ok(_) => format!("已创建任务 #{id}（复制自任务 {source}）"),
+    Err(e) => format!("启动任务 {id} 失败：{}", e.user_message()),
+  };
+  bot.send_message(msg.chat.id, outcome).await?;
+  dialogue.exit().await?;
+
+  Ok(())
+}
+
+/// Handler for /setinterval command. It accept a interval in minute unit or a cron expression.
+async fn set_interval_handler(msg: Message, bot: NotifyBot, mut rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let (id, schedule) = match resolve_task_arg(&rt, text)
+    .map(|(id, rest)| (id, parse_schedule(rest, &rt.interval_bounds)))
+  {
+    Some((id, Ok(schedule))) => (id, schedule),
+    Some((_, Err(e))) => {
+      bot.send_message(msg.chat.id, e).await?;
+      anyhow::bail!("Invalid setinterval arguments")
+    }
+    None => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的参数！参考用法：/setinterval 1 30（单位：分钟）或 /setinterval 1 30 9 * * 1-5",
+        )
+        .await?;
+      anyhow::bail!("Invalid setinterval arguments")
+    }
+  };
+
+  if !ensure_task_owner(&msg, &bot, &rt, id).await? {
+    return Ok(());
+  }
+
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  let action = format!("修改任务 {id} 的循环周期为 {schedule}");
+  match rt.task_pool.set_schedule(id, schedule).await {
+    Ok(_) => {
+      rt.audit(user, action).await;
+      bot.send_message(msg.chat.id, "修改成功").await?;
+    }
+    Err(e) => {
+      bot
+        .send_message(
+          msg.chat.id,
+          format!(
+            "修改失败：{}，请用 /listtask 确认任务存在。",
+            e.user_message()
+          ),
+        )
+        .await?;
+    }
+  }
+
+  Ok(())
+}
+
+/// Parse the end time. A date without time means the end of that day. `清除` means no end time.
+fn parse_end_time(text: &str) -> Result<Option<DateTime<Tz>>, String> {
+  let text = text.trim();
+  if text == "清除" {
+    return Ok(None);
+  }
+
+  let end = match NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M") {
+    Ok(end) => end,
+    Err(_) => NaiveDate::parse_from_str(text, "%Y-%m-%d")
+      .map_err(|_| format!("非法的时间 {text}，请使用 YYYY-MM-DD 或 YYYY-MM-DD HH:MM 格式"))?
+      .succ()
+      .and_hms(0, 0, 0),
+  };
+  match clock::TIMEZONE.from_local_datetime(&end).single() {
+    Some(end) if end > clock::now() => Ok(Some(end)),
+    Some(_) => Err(format!("{text} 已经过去了，请输入一个未来的时间")),
+    None => Err(format!("{text} 不是一个有效的本地时间")),
+  }
+}
+
+/// Handler for /setend command. The task stop and remove itself after the end time.
+async fn set_end_handler(msg: Message, bot: NotifyBot, mut rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let (id, ends_at) = match resolve_task_arg(&rt, text).map(|(id, rest)| (id, parse_end_time(rest)))
+  {
+    Some((id, Ok(ends_at))) => (id, ends_at),
+    Some((_, Err(e))) => {
+      bot.send_message(msg.chat.id, e).await?;
+      anyhow::bail!("Invalid setend arguments")
+    }
+    None => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的参数！参考用法：/setend 1 2024-06-01 或 /setend 1 2024-06-01 20:00，使用 /setend 1 清除 移除结束时间",
+        )
+        .await?;
+      anyhow::bail!("Invalid setend arguments")
+    }
+  };
+
+  if !ensure_task_owner(&msg, &bot, &rt, id).await? {
+    return Ok(());
+  }
+
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  let action = format!("修改任务 {id} 的结束时间为 {}", format_time(ends_at));
+  match rt.task_pool.set_ends_at(id, ends_at).await {
+    Ok(_) => {
+      rt.audit(user, action).await;
+      bot.send_message(msg.chat.id, "修改成功").await?;
+    }
+    Err(e) => {
+      bot
+        .send_message(
+          msg.chat.id,
+          format!(
+            "修改失败：{}，请用 /listtask 确认任务存在。",
+            e.user_message()
+          ),
+        )
+        .await?;
+    }
+  }
+
+  Ok(())
+}
+
+/// Handler for /pausetask and /resumetask command.
+async fn pause_task_handler(
+  msg: Message,
+  bot: NotifyBot,
+  mut rt: BotRuntime,
+  cmd: Command,
+) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+  let pause = matches!(cmd, Command::PauseTask);
+
+  let id: u32 = match resolve_task_arg(&rt, text) {
+    Some((id, _)) => id,
+    None => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的任务 id！你可以用 /listtask 命令来查看任务 id",
+        )
+        .await?;
+      anyhow::bail!("Invalid task id arguments")
+    }
+  };
+
+  if !ensure_task_owner(&msg, &bot, &rt, id).await? {
+    return Ok(());
+  }
+
+  let result = if pause {
+    rt.task_pool.pause(id).await
+  } else {
+    rt.task_pool.resume(id).await
+  };
+  if let Ok(true) = result {
+    let user = msg
+      .from()
+      .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+      .id;
+    let action = if pause { "暂停" } else { "恢复" };
+    rt.audit(user, format!("{action}任务 {id}")).await;
+  }
+
+  let reply = match (result, pause) {
+    (Ok(true), true) => format!("任务 {id} 已暂停，使用 /resumetask {id} 恢复。"),
+    (Ok(true), false) => format!("任务 {id} 已恢复。"),
+    (Ok(false), true) => format!("任务 {id} 已经是暂停状态了。"),
+    (Ok(false), false) => format!("任务 {id} 没有被暂停，无需恢复。"),
+    (Err(Error::NoGroup), false) => {
+      format!("任务 {id} 没有通知群，请先用 /edittask {id} 设置群组再恢复。")
+    }
+    (Err(e), _) => format!(
+      "操作失败：{}，请用 /listtask 确认任务存在。",
+      e.user_message()
+    ),
+  };
+  bot.send_message(msg.chat.id, reply).await?;
+
+  Ok(())
+}
+
+/// Handler for /sendnow command. The task send its next notification once immediately, then
+/// the groups reached and the failures are reported.
+async fn send_now_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let id: u32 = match resolve_task_arg(&rt, text) {
+    Some((id, _)) => id,
+    None => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的任务 id！你可以用 /listtask 命令来查看任务 id",
+        )
+        .await?;
+      anyhow::bail!("Invalid task id arguments")
+    }
+  };
+
+  if !ensure_task_owner(&msg, &bot, &rt, id).await? {
+    return Ok(());
+  }
+
+  let report = match rt.task_pool.send_now(id).await {
+    Ok(report) => report,
+    Err(e) => {
+      bot
+        .send_message(
+          msg.chat.id,
+          format!(
+            "发送失败：{}，请用 /listtask 确认任务存在。",
+            e.user_message()
+          ),
+        )
+        .await?;
+      return Ok(());
+    }
+  };
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  rt.audit(user, format!("立即发送任务 {id}")).await;
+
+  let mut reply = format!("任务 {id} 已发送到 {} 个群组。", report.sent);
+  if !report.failed.is_empty() {
+    let groups = report
+      .failed
+      .iter()
+      .map(|(gid, _)| *gid)
+      .collect::<Vec<_>>();
+    let titles = group_titles(&bot, &rt, &groups).await;
+    reply.push_str(&format!("\n{} 个群组发送失败：", report.failed.len()));
+    for (title, (_, e)) in titles.iter().zip(&report.failed) {
+      reply.push_str(&format!("\n{title}：{e}"));
+    }
+  }
+  bot.send_message(msg.chat.id, reply).await?;
+
+  Ok(())
+}
+
+/// Handler for /testsend command. The next notification of the task is sent into the current
+/// chat only, with the parse mode and the buttons the groups will see.
+async fn test_send_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let (id, task) = match resolve_task_arg(&rt, text).map(|(id, _)| (id, rt.task_pool.get_task(id)))
+  {
+    Some((id, Ok(task))) => (id, task),
+    _ => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的任务 id！你可以用 /listtask 命令来查看任务 id",
+        )
+        .await?;
+      anyhow::bail!("Invalid task id arguments")
+    }
+  };
+  if !ensure_task_visible(&msg, &bot, &rt, id).await? {
+    return Ok(());
+  }
+  let notify = match task.next_notification() {
+    Some(notify) => notify,
+    None => {
+      bot
+        .send_message(msg.chat.id, format!("任务 {id} 没有可以发送的通知。"))
+        .await?;
+      return Ok(());
+    }
+  };
+
+  bot
+    .send_message(
+      msg.chat.id,
+      format!(
+        "【预览】以下是{} 的下一条通知，只发送到这里：",
+        task_title(id, task.name.as_deref())
+      ),
+    )
+    .await?;
+  let options = SendOptions {
+    buttons: task.buttons.clone(),
+    parse_mode: task.parse_mode,
+    disable_preview: task.disable_web_page_preview,
+    protect_content: task.protect_content,
+    ..SendOptions::default()
+  };
+  let count = task.timing.read().fired + 1;
+  let notify = render_preview(&bot, &rt, notify, task.parse_mode, &task.groups, count).await;
+  if let Err(e) = send_notification(&bot, msg.chat.id, &notify, &options).await {
+    bot
+      .send_message(msg.chat.id, format!("【预览】发送失败：{e}"))
+      .await?;
+  }
+
+  Ok(())
+}
+
+/// Handler for /addnotify command. This start a dialogue that require a new notification text,
+/// and change AddTaskDialogueCurrentState to RequestExtraNotifyText.
+async fn add_notify_handler(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let id: u32 = match resolve_task_arg(&rt, text) {
+    Some((id, _)) => id,
+    _ => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的任务 id！参考用法：/addnotify 1，你可以用 /listtask 命令来查看任务 id",
+        )
+        .await?;
+      anyhow::bail!("Invalid task id arguments")
+    }
+  };
+
+  if !ensure_task_owner(&msg, &bot, &rt, id).await? {
+    return Ok(());
+  }
+
+  bot
+    .send_message(msg.chat.id, format!("请发送要添加到任务 {id} 的通知内容："))
+    .await?;
+  dialogue
+    .update(AddTaskDialogueCurrentState::RequestExtraNotifyText { id })
+    .await?;
+
+  Ok(())
+}
+
+/// Handler for AddTaskDialogueCurrentState::RequestExtraNotifyText status
+/// It keeps the notification and ask for its weight.
+async fn request_extra_notify_text(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+  id: u32,
+) -> Result<()> {
+  let chat_id = msg.chat.id;
+  receive_notification(msg, bot.clone(), rt, move |content| async move {
+    bot.send_message(chat_id, NOTIFY_WEIGHT_HELP).await?;
+    dialogue
+      .update(AddTaskDialogueCurrentState::RequestExtraNotifyWeight { id, content })
+      .await?;
+    Ok(())
+  })
+  .await
+}
+
+/// Hint for the weight of the new notification
+const NOTIFY_WEIGHT_HELP: &str = "请发送这条通知的权重（1 到 100 的整数），权重越大发送得越频繁，\
+  例如权重 4 的通知发送次数是权重 1 的四倍。发送“跳过”使用默认权重 1。";
+
+/// Parse the weight of the notification, `跳过` means the default weight 1
+fn parse_weight(text: &str) -> Result<u32, Error> {
+  let text = text.trim();
+  if matches!(text, "跳过" | "skip") {
+    return Ok(1);
+  }
+  let weight = text.parse().map_err(|_| Error::InvalidWeight(0))?;
+  validate_weight(weight)?;
+  Ok(weight)
+}
+
+#[test]
+fn parse_weight_test() {
+  assert_eq!(parse_weight("跳过").unwrap(), 1);
+  assert_eq!(parse_weight(" 4 ").unwrap(), 4);
+  assert!(parse_weight("0").is_err());
+  assert!(parse_weight("101").is_err());
+  assert!(parse_weight("-1").is_err());
+  assert!(parse_weight("四").is_err());
+}
+
+/// Handler for AddTaskDialogueCurrentState::RequestExtraNotifyWeight status
+/// It append the notification with the weight into the task's notification pool, then exit the
+/// dialogue.
+async fn request_extra_notify_weight(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  mut rt: BotRuntime,
+  (id, content): (u32, Notification),
+) -> Result<()> {
+  let chat_id = msg.chat.id;
+  let weight = match msg.text().map(parse_weight) {
+    Some(Ok(weight)) => weight,
+    Some(Err(e)) => {
+      bot.send_message(chat_id, e.user_message()).await?;
+      return Ok(());
+    }
+    None => {
+      bot.send_message(chat_id, NOTIFY_WEIGHT_HELP).await?;
+      return Ok(());
+    }
+  };
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+
+  match rt.task_pool.add_notification(id, content, weight).await {
+    Ok(size) => {
+      rt.audit(user, format!("为任务 {id} 添加通知，权重 {weight}"))
+        .await;
+      bot
+        .send_message(
+          chat_id,
+          format!("添加成功，任务 {id} 当前共有 {size} 条轮播通知。"),
+        )
+        .await?;
+    }
+    Err(e) => {
+      bot
+        .send_message(
+          chat_id,
+          format!(
+            "添加失败：{}，请用 /listtask 确认任务存在。",
+            e.user_message()
+          ),
+        )
+        .await?;
+    }
+  }
+  dialogue.exit().await?;
+
+  Ok(())
+}
+
+/// Handler for /settext command. Usage: `/settext <task_id> [index]`, the index default to 0.
+/// This start a dialogue that require the replacement text, and change
+/// AddTaskDialogueCurrentState to RequestReplaceText.
+async fn set_text_handler(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let (id, rest) = match resolve_task_arg(&rt, text) {
+    Some(x) => x,
+    None => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的任务 id！参考用法：/settext 1 0，你可以用 /listtask 命令来查看任务 id",
+        )
+        .await?;
+      anyhow::bail!("Invalid task id arguments")
+    }
+  };
+
+  if !ensure_task_owner(&msg, &bot, &rt, id).await? {
+    return Ok(());
+  }
+  let index: usize = parse_first_num(rest).unwrap_or(0);
+
+  let notifications = match rt.task_pool.list_notification(id) {
+    Ok(n) => n,
+    Err(e) => {
+      bot
+        .send_message(
+          msg.chat.id,
+          format!(
+            "查询失败：{}，请用 /listtask 确认任务存在。",
+            e.user_message()
+          ),
+        )
+        .await?;
+      return Ok(());
+    }
+  };
+
+  let current = match notifications.get(index) {
+    Some(n) => n,
+    None => {
+      bot
+        .send_message(
+          msg.chat.id,
+          format!(
+            "错误的通知序号 {index}！任务 {id} 可用的序号为 0 ~ {}",
+            notifications.len().saturating_sub(1)
+          ),
+        )
+        .await?;
+      return Ok(());
+    }
+  };
+
+  bot
+    .send_message(
+      msg.chat.id,
+      format!("当前的通知内容：\n\n{current}\n\n请发送新的通知内容："),
+    )
+    .await?;
+  dialogue
+    .update(AddTaskDialogueCurrentState::RequestReplaceText { id, index })
+    .await?;
+
+  Ok(())
+}
+
+/// Handler for AddTaskDialogueCurrentState::RequestReplaceText status
+/// It replace the notification with the new text, then exit the dialogue.
+async fn request_replace_text(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  mut rt: BotRuntime,
+  (id, index): (u32, usize),
+) -> Result<()> {
+  let chat_id = msg.chat.id;
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  receive_notification(msg, bot.clone(), rt.clone(), move |notify| async move {
+    match rt.task_pool.replace_notification(id, index, notify).await {
+      Ok(_) => {
+        rt.audit(user, format!("修改任务 {id} 的第 {index} 条通知"))
+          .await;
+        bot.send_message(chat_id, "修改成功").await?;
+      }
+      Err(e) => {
+        bot
+          .send_message(chat_id, format!("修改失败：{}", e.user_message()))
+          .await?;
+      }
+    }
+    dialogue.exit().await?;
+
+    Ok(())
+  })
+  .await
+}
+
+/// Handler for /listnotify command.
+async fn list_notify_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let id: u32 = match resolve_task_arg(&rt, text) {
+    Some((id, _)) => id,
+    None => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的任务 id！参考用法：/listnotify 1，你可以用 /listtask 命令来查看任务 id",
+        )
+        .await?;
+      anyhow::bail!("Invalid task id arguments")
+    }
+  };
+  if !ensure_task_visible(&msg, &bot, &rt, id).await? {
+    return Ok(());
+  }
+
+  let task = match rt.task_pool.get_task(id) {
+    Ok(task) => task,
+    Err(e) => {
+      bot
+        .send_message(
+          msg.chat.id,
+          format!(
+            "查询失败：{}，请用 /listtask 确认任务存在。",
+            e.user_message()
+          ),
+        )
+        .await?;
+      return Ok(());
+    }
+  };
+
+  let mut entries = vec![format!(
+    "任务 {id} 总共 {} 条轮播通知\n",
+    task.notifications.len()
+  )];
+  entries.extend(
+    task
+      .notifications
+      .iter()
+      .zip(&task.weights)
+      .enumerate()
+      .map(|(i, (content, weight))| {
+        format!(
+          "通知 {i}（权重 {weight}）：{content}\n{}\n\n",
+          "=".repeat(35)
+        )
+      }),
+  );
+  send_chunks(
+    &bot,
+    msg.chat.id,
+    split_entries(&entries, MESSAGE_CHUNK_LIMIT),
+    None,
+  )
+  .await
+}
+
+/// Handler for /delnotify command.
+async fn del_notify_handler(msg: Message, bot: NotifyBot, mut rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let (id, index): (u32, usize) =
+    match resolve_task_arg(&rt, text).map(|(id, rest)| (id, parse_first_num(rest))) {
+      Some((id, Some(index))) => (id, index),
+      _ => {
+        bot
+          .send_message(
+            msg.chat.id,
+            "错误的参数！参考用法：/delnotify 1 0，你可以用 /listnotify 命令来查看通知序号",
+          )
+          .await?;
+        anyhow::bail!("Invalid delnotify arguments")
+      }
+    };
+
+  if !ensure_task_owner(&msg, &bot, &rt, id).await? {
+    return Ok(());
+  }
+
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  match rt.task_pool.remove_notification(id, index).await {
+    Ok(size) => {
+      rt.audit(user, format!("删除任务 {id} 的第 {index} 条通知"))
+        .await;
+      bot
+        .send_message(
+          msg.chat.id,
+          format!("删除成功，任务 {id} 还剩 {size} 条轮播通知。"),
+        )
+        .await?;
+    }
+    Err(e) => {
+      bot
+        .send_message(
+          msg.chat.id,
+          format!(
+            "删除失败：{}。如需删除整个任务请使用 /deltask。",
+            e.user_message()
+          ),
+        )
+        .await?;
+    }
+  }
+
+  Ok(())
+}
+
+/// Handler for /setweight command. Usage: `/setweight <task_id> <index> <weight>`
+async fn set_weight_handler(msg: Message, bot: NotifyBot, mut rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let parsed = resolve_task_arg(&rt, text).and_then(|(id, rest)| {
+    let mut args = rest.split_whitespace();
+    let index = args.next()?.parse::<usize>().ok()?;
+    let weight = args.next()?;
+    Some((id, index, weight))
+  });
+  let (id, index, weight) = match parsed {
+    Some((id, index, weight)) => (id, index, weight),
+    None => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的参数！参考用法：/setweight 1 0 4，你可以用 /listnotify 命令来查看通知序号",
+        )
+        .await?;
+      anyhow::bail!("Invalid setweight arguments")
+    }
+  };
+  let weight = match parse_weight(weight) {
+    Ok(weight) => weight,
+    Err(e) => {
+      bot.send_message(msg.chat.id, e.user_message()).await?;
+      return Ok(());
+    }
+  };
+
+  if !ensure_task_owner(&msg, &bot, &rt, id).await? {
+    return Ok(());
+  }
+
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  match rt.task_pool.set_weight(id, index, weight).await {
+    Ok(_) => {
+      rt.audit(
+        user,
+        format!("修改任务 {id} 第 {index} 条通知的权重为 {weight}"),
+      )
+      .await;
+      bot.send_message(msg.chat.id, "修改成功").await?;
+    }
+    Err(e) => {
+      bot
+        .send_message(msg.chat.id, format!("修改失败：{}", e.user_message()))
+        .await?;
+    }
+  }
+
+  Ok(())
+}
+
+/// Parse the `<task> <group_id>` arguments of the override commands
+fn parse_override_args(rt: &BotRuntime, text: &str) -> Option<(u32, ChatId)> {
+  let (id, rest) = resolve_task_arg(rt, text)?;
+  Some((id, ChatId(parse_first_num(rest)?)))
+}
+
+/// Hint for the buttons of the override content
+const OVERRIDE_BUTTONS_HELP: &str = "请输入这个群组使用的按钮，发送“跳过”沿用任务的按钮:";
+
+/// Handler for /override command. It start a dialogue that require the content sent to the
+/// group instead of the notification of the task.
+async fn override_handler(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let (id, group) = match parse_override_args(&rt, text) {
+    Some(args) => args,
+    None => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的参数！参考用法：/override 1 -1001，你可以用 /taskinfo 命令来查看任务的群组",
+        )
+        .await?;
+      anyhow::bail!("Invalid override arguments")
+    }
+  };
+
+  let task = match rt.task_pool.get_task(id) {
+    Ok(task) if task.groups.contains(&group) => task,
+    Ok(_) => {
+      let e = Error::GroupNotInTask { id, group };
+      bot.send_message(msg.chat.id, e.user_message()).await?;
+      return Ok(());
+    }
+    Err(e) => {
+      bot
+        .send_message(
+          msg.chat.id,
+          format!(
+            "查询失败：{}，请用 /listtask 确认任务存在。",
+            e.user_message()
+          ),
+        )
+        .await?;
+      return Ok(());
+    }
+  };
+
+  let current = match task.overrides.get(&group) {
+    Some(value) => format!("当前的单独通知：\n\n{}\n\n", value.content),
+    None => String::new(),
+  };
+  bot
+    .send_message(
+      msg.chat.id,
+      format!(
+        "{current}请发送群组 {} 收到的通知内容，它会代替任务 {id} 的轮播通知发送到这个群组：",
+        group.0
+      ),
+    )
+    .await?;
+  dialogue
+    .update(AddTaskDialogueCurrentState::RequestOverrideText { id, group })
+    .await?;
+
+  Ok(())
+}
+
+/// Handler for AddTaskDialogueCurrentState::RequestOverrideText status
+/// It keeps the override content and ask for the buttons.
+async fn request_override_text(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+  (id, group): (u32, ChatId),
+) -> Result<()> {
+  let chat_id = msg.chat.id;
+  receive_notification(msg, bot.clone(), rt, move |content| async move {
+    bot
+      .send_message(
+        chat_id,
+        format!("{OVERRIDE_BUTTONS_HELP}\n{BUTTON_FORMAT_HELP}"),
+      )
+      .await?;
+    dialogue
+      .update(AddTaskDialogueCurrentState::RequestOverrideButtons { id, group, content })
+      .await?;
+    Ok(())
+  })
+  .await
+}
+
+/// Handler for AddTaskDialogueCurrentState::RequestOverrideButtons status
+/// It save the override of the group into the task, then exit the dialogue.
+async fn request_override_buttons(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  mut rt: BotRuntime,
+  (id, group, content): (u32, ChatId, Notification),
+) -> Result<()> {
+  let text = match msg.text() {
+    Some(text) => text.trim(),
+    None => {
+      bot
+        .send_message(
+          msg.chat.id,
+          format!("{OVERRIDE_BUTTONS_HELP}\n{BUTTON_FORMAT_HELP}"),
+        )
+        .await?;
+      return Ok(());
+    }
+  };
+  let buttons = if matches!(text, "跳过" | "skip") {
+    None
+  } else {
+    match parse_keyboard(text, &rt.button_schemes) {
+      Ok(buttons) => Some(buttons),
+      Err(e) => {
+        bot.send_message(msg.chat.id, e.hint()).await?;
+        return Ok(());
+      }
+    }
+  };
+
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  let value = GroupOverride { content, buttons };
+  match rt.task_pool.set_override(id, group, value).await {
+    Ok(_) => {
+      rt.audit(user, format!("为任务 {id} 设置群组 {} 的单独通知", group.0))
+        .await;
+      bot
+        .send_message(
+          msg.chat.id,
+          format!("设置成功，群组 {} 将收到单独的通知。", group.0),
+        )
+        .await?;
+    }
+    Err(e) => {
+      bot
+        .send_message(msg.chat.id, format!("设置失败：{}", e.user_message()))
+        .await?;
+    }
+  }
+  dialogue.exit().await?;
+
+  Ok(())
+}
+
+/// Handler for /listoverride command.
+async fn list_override_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let task = resolve_task_arg(&rt, text).map(|(id, _)| (id, rt.task_pool.get_task(id)));
+  let (id, task) = match task {
+    Some((id, Ok(task))) => (id, task),
+    _ => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的任务 id！参考用法：/listoverride 1，你可以用 /listtask 命令来查看任务 id",
+        )
+        .await?;
+      anyhow::bail!("Invalid task id arguments")
+    }
+  };
+
+  if task.overrides.is_empty() {
+    bot
+      .send_message(msg.chat.id, format!("任务 {id} 没有设置单独的通知"))
+      .await?;
+    return Ok(());
+  }
+
+  let mut entries = vec![format!(
+    "任务 {id} 总共 {} 个群组设置了单独的通知\n",
+    task.overrides.len()
+  )];
+  entries.extend(task.overrides.iter().map(|(group, value)| {
+    let buttons = if value.buttons.is_some() {
+      "，使用单独的按钮"
+    } else {
+      ""
+    };
+    format!(
+      "群组 {}{buttons}：{}\n{}\n\n",
+      group.0,
+      value.content,
+      "=".repeat(35)
+    )
+  }));
+  send_chunks(
+    &bot,
+    msg.chat.id,
+    split_entries(&entries, MESSAGE_CHUNK_LIMIT),
+    None,
+  )
+  .await
+}
+
+/// Handler for /deloverride command.
+async fn del_override_handler(msg: Message, bot: NotifyBot, mut rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let (id, group) = match parse_override_args(&rt, text) {
+    Some(args) => args,
+    None => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的参数！参考用法：/deloverride 1 -1001，你可以用 /listoverride 命令来查看设置了单独通知的群组",
+        )
+        .await?;
+      anyhow::bail!("Invalid deloverride arguments")
+    }
+  };
+
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  match rt.task_pool.remove_override(id, group).await {
+    Ok(_) => {
+      rt.audit(user, format!("删除任务 {id} 中群组 {} 的单独通知", group.0))
+        .await;
+      bot
+        .send_message(
+          msg.chat.id,
+          format!("删除成功，群组 {} 将恢复接收任务的通知。", group.0),
+        )
+        .await?;
+    }
+    Err(e) => {
+      bot
+        .send_message(msg.chat.id, format!("删除失败：{}", e.user_message()))
+        .await?;
+    }
+  }
+
+  Ok(())
+}
+
+/// Handler for /savetemplate command. The first notification of the task, with its parse mode
+/// and buttons, is saved under the given name.
+async fn save_template_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+  let args = text.split_once(' ').map(|x| x.1).unwrap_or_default().trim();
+
+  let parsed = args
+    .split_once(char::is_whitespace)
+    .and_then(|(name, key)| Some((name, rt.task_pool.find_task(key.trim().trim_matches('"'))?)));
+  let (name, id) = match parsed {
+    Some(parsed) => parsed,
+    None => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的参数！参考用法：/savetemplate 周报 1，你可以用 /listtask 命令来查看任务 id",
+        )
+        .await?;
+      anyhow::bail!("Invalid savetemplate arguments")
+    }
+  };
+  if !ensure_task_visible(&msg, &bot, &rt, id).await? {
+    return Ok(());
+  }
+
+  let template = match rt
+    .task_pool
+    .get_task(id)
+    .ok()
+    .as_ref()
+    .and_then(MessageTemplate::from_task)
+  {
+    Some(template) => template,
+    None => {
+      bot
+        .send_message(msg.chat.id, format!("任务 {id} 没有可以保存的通知。"))
+        .await?;
+      return Ok(());
+    }
+  };
+  if let Err(e) = rt.templates.insert(name, template).await {
+    bot
+      .send_message(msg.chat.id, format!("保存失败：{}", e.user_message()))
+      .await?;
+    return Ok(());
+  }
+
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  rt.audit(user, format!("把任务 {id} 保存为模板 {name}"))
+    .await;
+  bot
+    .send_message(
+      msg.chat.id,
+      format!("已把任务 {id} 保存为模板 {name}，使用 /addtask 时可以选择这个模板。"),
+    )
+    .await?;
+
+  Ok(())
+}
+
+/// Handler for /listtemplate command
+async fn list_template_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let templates = rt.templates.list().await?;
+  if templates.is_empty() {
+    bot
+      .send_message(
+        msg.chat.id,
+        "还没有保存任何模板，可以用 /savetemplate 模板名 任务id 保存。",
+      )
+      .await?;
+    return Ok(());
+  }
+
+  let list = templates
+    .iter()
+    .map(|(name, template)| {
+      let preview = template.content.to_string();
+      let preview = preview.chars().take(30).collect::<String>();
+      format!("{name}：{preview}")
+    })
+    .collect::<Vec<String>>()
+    .join("\n");
+  bot
+    .send_message(msg.chat.id, format!("保存的模板：\n{list}"))
+    .await?;
+
+  Ok(())
+}
+
+/// Handler for /deltemplate command
+async fn del_template_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+  let name = text.split_once(' ').map(|x| x.1).unwrap_or_default().trim();
+  if name.is_empty() {
+    bot
+      .send_message(
+        msg.chat.id,
+        "错误的参数！参考用法：/deltemplate 周报，你可以用 /listtemplate 命令来查看模板",
+      )
+      .await?;
+    anyhow::bail!("Invalid deltemplate arguments")
+  }
+
+  if let Err(e) = rt.templates.remove(name).await {
+    bot
+      .send_message(msg.chat.id, format!("删除失败：{}", e.user_message()))
+      .await?;
+    return Ok(());
+  }
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  rt.audit(user, format!("删除模板 {name}")).await;
+  bot
+    .send_message(msg.chat.id, format!("模板 {name} 已删除。"))
+    .await?;
+
+  Ok(())
+}
+
+async fn add_admin(msg: Message, bot: NotifyBot, mut rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+
+  // user ids are positive, the negative group ids after them limit the admins to manage only
+  // these groups
+  let (groups, users): (Vec<&str>, Vec<&str>) = split_batch_args(text)
+    .into_iter()
+    .partition(|arg| arg.starts_with('-'));
+  if users.is_empty() {
+    bot
+      .send_message(
+        msg.chat.id,
+        "错误的用户 id！参考用法： /addadmin 123456789、/addadmin 123 456 或 /addadmin 123456789 -1001234567",
+      )
+      .await?;
+    anyhow::bail!("Invalid admin id");
+  }
+
+  let whitelist = rt.get_group();
+  let scope = groups
+    .iter()
+    .map(|x| x.parse::<i64>().ok().map(ChatId))
+    .collect::<Option<Vec<ChatId>>>();
+  let scope = match scope {
+    Some(scope) if scope.iter().all(|g| whitelist.contains(g)) => scope,
+    _ => {
+      bot
+        .send_message(
+          msg.chat.id,
+          "错误的群组 id！群组需要先用 /addgroup 添加，你可以用 /listgroup 查看群组 id",
+        )
+        .await?;
+      anyhow::bail!("Invalid admin scope");
+    }
+  };
+  let scope_desc = if scope.is_empty() {
+    String::new()
+  } else {
+    let groups = scope
+      .iter()
+      .map(|g| g.0.to_string())
+      .collect::<Vec<String>>()
+      .join(",");
+    format!("，可管理的群组：{groups}")
+  };
+  let scope = if scope.is_empty() { None } else { Some(scope) };
+
+  let mut summary = String::new();
+  let mut changed = false;
+  for arg in users {
+    let id: u64 = match arg.parse() {
+      Ok(id) => id,
+      Err(_) => {
+        summary.push_str(&format!("{arg}：无效的 id\n"));
+        continue;
+      }
+    };
+    match rt.add_admin(id, scope.clone()) {
+      Ok(true) => {
+        changed = true;
+        rt.audit(user, format!("添加管理员 {id}{scope_desc}")).await;
+        summary.push_str(&format!("{id}：已添加\n"));
+      }
+      Ok(false) => summary.push_str(&format!("{id}：该用户已是管理员\n")),
+      Err(e) => summary.push_str(&format!("{}\n", e.user_message())),
+    }
+  }
+
+  reply_batch_result(&bot, &rt, msg.chat.id, summary, changed).await
+}
+
+/// Split the arguments of the batch commands, they are separated by spaces or commas
+fn split_batch_args(text: &str) -> Vec<&str> {
+  text
+    .split(|c: char| c == ',' || c.is_whitespace())
+    .filter(|x| !x.is_empty())
+    .skip(1)
+    .collect()
+}
+
+/// Reply with the result of every entry in the batch, and save the whitelist once if anything
+/// is changed.
+async fn reply_batch_result(
+  bot: &NotifyBot,
+  rt: &BotRuntime,
+  chat_id: ChatId,
+  summary: String,
+  changed: bool,
+) -> Result<()> {
+  if !changed {
+    bot.send_message(chat_id, summary).await?;
+    return Ok(());
+  }
+
+  let msg = bot
+    .send_message(chat_id, format!("{summary}\n正在保存..."))
+    .await?;
+  rt.save_whitelist().await?;
+  bot
+    .edit_message_text(chat_id, msg.id, format!("{summary}\n保存完成。"))
+    .await?;
+
+  Ok(())
+}
+
+/// Handler for /ban command. Banned user lose the access even if they are in the admins.
+async fn ban_handler(msg: Message, bot: NotifyBot, mut rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let id: u64 = match parse_first_arg_as_num(text) {
+    Some(id) => id,
+    None => {
+      bot
+        .send_message(msg.chat.id, "错误的用户 id！参考用法： /ban 123456789")
+        .await?;
+      anyhow::bail!("Invalid user id");
+    }
+  };
+
+  if rt.whitelist.read().is_maintainers(UserId(id)) {
+    bot.send_message(msg.chat.id, "不能封禁维护者！").await?;
+    return Ok(());
+  }
+  if !rt.ban(id) {
+    bot
+      .send_message(msg.chat.id, format!("用户 {id} 已经被封禁了。"))
+      .await?;
+    return Ok(());
+  }
+
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  rt.audit(user, format!("封禁用户 {id}")).await;
+  let msg = bot
+    .send_message(msg.chat.id, "封禁完成，正在保存...")
+    .await?;
+  rt.save_whitelist().await?;
+  bot
+    .edit_message_text(msg.chat.id, msg.id, "保存完成。")
+    .await?;
+
+  Ok(())
+}
+
+/// Handler for /unban command.
+async fn unban_handler(msg: Message, bot: NotifyBot, mut rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+
+  let id: u64 = match parse_first_arg_as_num(text) {
+    Some(id) => id,
+    None => {
+      bot
+        .send_message(msg.chat.id, "错误的用户 id！参考用法： /unban 123456789")
+        .await?;
+      anyhow::bail!("Invalid user id");
+    }
+  };
+
+  if let Err(e) = rt.unban(id) {
+    bot
+      .send_message(msg.chat.id, format!("{}，请重新确认 id", e.user_message()))
+      .await?;
+    anyhow::bail!("fail to unban user: {e}")
+  };
+
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  rt.audit(user, format!("解除封禁用户 {id}")).await;
+  let msg = bot
+    .send_message(msg.chat.id, "解除封禁完成，正在保存...")
+    .await?;
+  rt.save_whitelist().await?;
+  bot
+    .edit_message_text(msg.chat.id, msg.id, "保存完成。")
+    .await?;
+
+  Ok(())
+}
+
+/// Number of entries shown by /auditlog without argument
+const AUDIT_LOG_DEFAULT: usize = 20;
+const AUDIT_LOG_MAX: usize = 200;
+
+/// Handler for /auditlog command. Show the last n entries of the audit log.
+async fn audit_log_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+  let n = parse_first_arg_as_num::<usize>(text)
+    .unwrap_or(AUDIT_LOG_DEFAULT)
+    .clamp(1, AUDIT_LOG_MAX);
+
+  let entries = match rt.read_audit_log(n).await {
+    Ok(entries) => entries,
+    Err(e) => {
+      bot
+        .send_message(
+          msg.chat.id,
+          format!("读取审计日志失败：{}", e.user_message()),
+        )
+        .await?;
+      anyhow::bail!("fail to read audit log: {e}")
+    }
+  };
+  if entries.is_empty() {
+    bot
+      .send_message(msg.chat.id, "还没有任何操作记录。")
+      .await?;
+    return Ok(());
+  }
+
+  let entries = entries
+    .iter()
+    .map(|entry| {
+      let time = DateTime::parse_from_rfc3339(&entry.time)
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|_| entry.time.clone());
+      format!("{time} 用户 {}：{}\n", entry.user, entry.action)
+    })
+    .collect::<Vec<String>>();
+  let chunks = split_entries(&entries, MESSAGE_CHUNK_LIMIT);
+  send_chunks(&bot, msg.chat.id, chunks, None).await
+}
+
+/// Handler for /exporttasks command. The file is always sent in the private chat, so the
+/// tasks are not leaked into the group.
+async fn export_tasks_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  let snapshots = rt.task_pool.snapshots();
+  if snapshots.is_empty() {
+    bot.send_message(msg.chat.id, "当前没有任务。").await?;
+    return Ok(());
+  }
+
+  let document = InputFile::memory(export_tasks(&snapshots)?.into_bytes())
+    .file_name(format!("tasks-{}.json", clock::now().format("%Y%m%d%H%M")));
+  bot
+    .send_document(ChatId(user.0 as i64), document)
+    .caption(format!(
+      "共 {} 个任务，可以用 /importtasks 导入",
+      snapshots.len()
+    ))
+    .await?;
+  if !msg.chat.is_private() {
+    bot
+      .send_message(msg.chat.id, "已私聊发送导出的文件。")
+      .await?;
+  }
+  rt.audit(user, format!("导出全部 {} 个任务", snapshots.len()))
+    .await;
+
+  Ok(())
+}
+
+/// The exported file larger than this is rejected, a task takes about 1 KB
+const IMPORT_FILE_MAX_SIZE: u64 = 1024 * 1024;
+
+/// Handler for /importtasks command. Import the file replied to, or ask for the file in the
+/// private chat.
+async fn import_tasks_handler(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+) -> Result<()> {
+  if let Some(document) = msg.reply_to_message().and_then(|reply| reply.document()) {
+    return import_document(&msg, &bot, rt, document).await;
+  }
+  if !msg.chat.is_private() {
+    bot
+      .send_message(msg.chat.id, "请回复导出的 JSON 文件使用 /importtasks")
+      .await?;
+    return Ok(());
+  }
+
+  bot
+    .send_message(msg.chat.id, "请发送 /exporttasks 导出的 JSON 文件：")
+    .await?;
+  dialogue
+    .update(AddTaskDialogueCurrentState::RequestImportFile)
+    .await?;
+  Ok(())
+}
+
+/// Handler for AddTaskDialogueCurrentState::RequestImportFile status
+async fn request_import_file(
+  msg: Message,
+  bot: NotifyBot,
+  dialogue: AddTaskDialogue,
+  rt: BotRuntime,
+) -> Result<()> {
+  let document = match msg.document() {
+    Some(document) => document,
+    None => {
+      bot
+        .send_message(msg.chat.id, "bot 需要 JSON 文件！请重新发送！")
+        .await?;
+      return Ok(());
+    }
+  };
+  dialogue.exit().await?;
+  import_document(&msg, &bot, rt, document).await
+}
+
+/// Download the exported file, import the tasks in it, then report the result of each task
+async fn import_document(
+  msg: &Message,
+  bot: &NotifyBot,
+  mut rt: BotRuntime,
+  document: &Document,
+) -> Result<()> {
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  if document
+    .file_size
+    .map_or(false, |size| size as u64 > IMPORT_FILE_MAX_SIZE)
+  {
+    bot
+      .send_message(
+        msg.chat.id,
+        "文件太大了，请确认这是 /exporttasks 导出的文件。",
+      )
+      .await?;
+    return Ok(());
+  }
+
+  let file = bot.get_file(&document.file_id).await?;
+  let mut content = Vec::new();
+  bot.download_file(&file.file_path, &mut content).await?;
+  let entries = match parse_exported_tasks(&content) {
+    Ok(entries) => entries,
+    Err(e) => {
+      bot
+        .send_message(msg.chat.id, format!("无法读取文件：{}", e.user_message()))
+        .await?;
+      return Ok(());
+    }
+  };
+  if entries.is_empty() {
+    bot.send_message(msg.chat.id, "文件中没有任务。").await?;
+    return Ok(());
+  }
+
+  let outcomes = rt.task_pool.import_tasks(entries).await;
+  let imported = outcomes.iter().filter(|o| o.result.is_ok()).count();
+  rt.audit(user, format!("导入 {imported}/{} 个任务", outcomes.len()))
+    .await;
+
+  let mut entries = vec![format!(
+    "导入完成：成功 {imported} 个，共 {} 个\n",
+    outcomes.len()
+  )];
+  entries.extend(format_import_report(&outcomes));
+  let chunks = split_entries(&entries, MESSAGE_CHUNK_LIMIT);
+  send_chunks(bot, msg.chat.id, chunks, None).await
+}
+
+/// One line for each imported task, numbered from 1 in the order of the file
+fn format_import_report(outcomes: &[ImportOutcome]) -> Vec<String> {
+  outcomes
+    .iter()
+    .enumerate()
+    .map(|(i, outcome)| {
+      let mut line = match &outcome.result {
+        Ok(id) => format!("{}. 已创建任务 {id}", i + 1),
+        Err(e) => format!("{}. 失败：{}", i + 1, e.user_message()),
+      };
+      if !outcome.skipped_groups.is_empty() {
+        let groups = outcome
+          .skipped_groups
+          .iter()
+          .map(|group| group.0.to_string())
+          .collect::<Vec<_>>()
+          .join(", ");
+        line.push_str(&format!("（跳过不在白名单中的群组 {groups}）"));
+      }
+      line.push('\n');
+      line
+    })
+    .collect()
+}
+
+#[test]
+fn format_import_report_test() {
+  let outcomes = [
+    ImportOutcome {
+      result: Ok(7),
+      skipped_groups: Vec::new(),
+    },
+    ImportOutcome {
+      result: Ok(8),
+      skipped_groups: vec![ChatId(-1002), ChatId(-1003)],
+    },
+    ImportOutcome {
+      result: Err(Error::NoGroup),
+      skipped_groups: vec![ChatId(-1002)],
+    },
+  ];
+  assert_eq!(
+    format_import_report(&outcomes),
+    vec![
+      "1. 已创建任务 7\n",
+      "2. 已创建任务 8（跳过不在白名单中的群组 -1002, -1003）\n",
+      "3. 失败：任务至少需要一个群组（跳过不在白名单中的群组 -1002）\n",
+    ]
+  );
+}
+
+/// Handler for /stopall command. Ask for confirmation before stopping every task.
+async fn stop_all_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let count = rt.task_pool.list_id().len();
+  if count == 0 {
+    bot.send_message(msg.chat.id, "当前没有任务。").await?;
+    return Ok(());
+  }
+
+  let buttons = InlineKeyboardMarkup::new(vec![vec![
+    InlineKeyboardButton::callback("确认停止", "stop_all_confirm_y"),
+    InlineKeyboardButton::callback("取消", "stop_all_confirm_n"),
+  ]]);
+  bot
+    .send_message(
+      msg.chat.id,
+      format!("确认要停止并删除全部 {count} 个任务吗？这个操作无法撤销。"),
+    )
+    .reply_markup(buttons)
+    .await?;
+  Ok(())
+}
+
+/// Callback handler for the /stopall confirmation
+async fn stop_all_callback_handler(
+  q: CallbackQuery,
+  bot: NotifyBot,
+  mut rt: BotRuntime,
+) -> Result<()> {
+  answer_callback(&bot, &q, None).await?;
+  let msg = q
+    .message
+    .ok_or_else(|| anyhow::anyhow!("A button callback without message can't be handle"))?;
+
+  if q.data.as_deref() != Some("stop_all_confirm_y") {
+    bot
+      .edit_message_text(msg.chat.id, msg.id, "已取消。")
+      .await?;
+    return Ok(());
+  }
+
+  bot
+    .edit_message_text(msg.chat.id, msg.id, "正在停止全部任务...")
+    .await?;
+  let count = rt.task_pool.shutdown_all().await?;
+  rt.audit(q.from.id, format!("停止全部任务，共 {count} 个"))
+    .await;
+  bot
+    .edit_message_text(msg.chat.id, msg.id, format!("已停止 {count} 个任务。"))
+    .await?;
+  Ok(())
 }
 
-/// Handler for /deltask command.
-async fn del_task_handler(msg: Message, bot: AutoSend<Bot>, mut rt: BotRuntime) -> Result<()> {
-  bot.send_message(msg.chat.id, "正在删除任务").await?;
-  let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+/// Handler for /reload command. Load the whitelist again and report the changes.
+async fn reload_handler(msg: Message, bot: NotifyBot, mut rt: BotRuntime) -> Result<()> {
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
 
-  let id: u32 = match parse_first_arg_as_num(text) {
-    Some(id) => id,
-    None => {
+  let (diff, paused) = match rt.reload().await {
+    Ok(result) => result,
+    Err(e) => {
       bot
         .send_message(
           msg.chat.id,
-          "错误的任务 id！你可以用 /listtask 命令来查看任务 id",
+          format!("重新加载失败，继续使用原有配置：{}", e.user_message()),
         )
         .await?;
-      anyhow::bail!("Invalid task id arguments")
+      anyhow::bail!("fail to reload: {e:#}")
     }
   };
+  rt.audit(user, "重新加载配置").await;
 
-  match rt.task_pool.remove(id).await {
-    Ok(_) => {
-      bot.send_message(msg.chat.id, "删除成功").await?;
-    }
-    Err(e) => {
-      bot
-        .send_message(
-          msg.chat.id,
-          format!("删除失败：{}，请用 /listtask 确认任务存在。", e),
-        )
-        .await?;
+  if diff.is_empty() {
+    bot
+      .send_message(msg.chat.id, "重新加载完成，管理员和通知群没有变化。")
+      .await?;
+    return Ok(());
+  }
+
+  let join = |ids: Vec<String>| ids.join(", ");
+  let users = |list: &[UserId]| join(list.iter().map(|u| u.0.to_string()).collect());
+  let groups = |list: &[ChatId]| join(list.iter().map(|g| g.0.to_string()).collect());
+  let mut text = String::from("重新加载完成。");
+  for (label, list) in [
+    ("新增管理员", users(&diff.added_admins)),
+    ("移除管理员", users(&diff.removed_admins)),
+    ("新增通知群", groups(&diff.added_groups)),
+    ("移除通知群", groups(&diff.removed_groups)),
+  ] {
+    if !list.is_empty() {
+      text.push_str(&format!("\n{label}：{list}"));
     }
   }
+  if !paused.is_empty() {
+    let ids = join(paused.iter().map(|id| id.to_string()).collect());
+    text.push_str(&format!(
+      "\n以下任务没有其他通知群，已暂停，需要用 /edittask 重新设置群组后才能恢复：{ids}"
+    ));
+  }
+  bot.send_message(msg.chat.id, text).await?;
+
   Ok(())
 }
 
-async fn add_admin(msg: Message, bot: AutoSend<Bot>, mut rt: BotRuntime) -> Result<()> {
+async fn del_admin(msg: Message, bot: NotifyBot, mut rt: BotRuntime) -> Result<()> {
   let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
 
-  let id: u64 = match parse_first_arg_as_num(text) {
-    Some(id) => id,
-    None => {
-      bot
-        .send_message(msg.chat.id, "错误的用户 id！参考用法： /addadmin 123456789")
-        .await?;
-      anyhow::bail!("Invalid admin id");
+  let args = split_batch_args(text);
+  if args.is_empty() {
+    bot
+      .send_message(
+        msg.chat.id,
+        "错误的用户 id！参考用法： /deladmin 123456789 或 /deladmin 123 456",
+      )
+      .await?;
+    anyhow::bail!("Invalid admin id");
+  }
+
+  let mut summary = String::new();
+  let mut changed = false;
+  for arg in args {
+    let id: u64 = match arg.parse() {
+      Ok(id) => id,
+      Err(_) => {
+        summary.push_str(&format!("{arg}：无效的 id\n"));
+        continue;
+      }
+    };
+    match rt.del_admin(id) {
+      Ok(_) => {
+        changed = true;
+        rt.audit(user, format!("删除管理员 {id}")).await;
+        summary.push_str(&format!("{id}：已删除\n"));
+      }
+      Err(e) => summary.push_str(&format!("{}\n", e.user_message())),
     }
-  };
+  }
 
-  rt.add_admin(id);
-  let msg = bot
-    .send_message(msg.chat.id, "添加完成，正在保存...")
-    .await?;
-  rt.save_whitelist().await?;
-  bot
-    .edit_message_text(msg.chat.id, msg.id, "保存完成。")
-    .await?;
+  reply_batch_result(&bot, &rt, msg.chat.id, summary, changed).await
+}
 
-  Ok(())
+/// Resolve the public username of a group or channel into the chat, return the reason in
+/// Chinese if it can't be resolved.
+async fn resolve_username(bot: &NotifyBot, username: &str) -> Result<Chat, String> {
+  match bot
+    .get_chat(Recipient::ChannelUsername(format!("@{username}")))
+    .await
+  {
+    Ok(chat) => Ok(chat),
+    Err(RequestError::Api(ApiError::ChatNotFound)) => Err(format!(
+      "找不到 @{username}，请确认用户名是否正确，并且这是一个公开的群组或者频道。"
+    )),
+    Err(e) => Err(format!("无法获取 @{username} 的信息：{e}")),
+  }
 }
 
-async fn del_admin(msg: Message, bot: AutoSend<Bot>, mut rt: BotRuntime) -> Result<()> {
+async fn add_group_handler(msg: Message, bot: NotifyBot, mut rt: BotRuntime) -> Result<()> {
   let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
 
-  let id: u64 = match parse_first_arg_as_num(text) {
-    Some(id) => id,
-    None => {
-      bot
-        .send_message(msg.chat.id, "错误的用户 id！参考用法： /deladmin 123456789")
-        .await?;
-      anyhow::bail!("Invalid admin id");
+  // a positive number after the group is the topic id of that group
+  let mut entries: Vec<(&str, Option<i32>)> = Vec::new();
+  for arg in split_batch_args(text) {
+    match (entries.last_mut(), arg.parse::<i32>()) {
+      (Some((_, thread @ None)), Ok(topic)) if topic > 0 => *thread = Some(topic),
+      _ => entries.push((arg, None)),
     }
-  };
-
-  if let Err(e) = rt.del_admin(id) {
+  }
+  if entries.is_empty() {
     bot
-      .send_message(msg.chat.id, "用户不存在！请重新确认 id")
+      .send_message(
+        msg.chat.id,
+        "错误的输入！你应该输入群组的 id 或者 @用户名，以及可选的话题 id，多个群组用空格或逗号分隔。\
+        参考例子：/addgroup -1234567、/addgroup @my_channel、/addgroup -1234567 42 或者 /addgroup -1234567,-7654321",
+      )
       .await?;
-    anyhow::bail!("fail to delete user: {e}")
+    anyhow::bail!("Invalid group id input")
+  }
+
+  let mut summary = String::new();
+  let mut changed = false;
+  for (arg, thread) in entries {
+    let (id, title): (i64, Option<String>) = match arg.strip_prefix('@') {
+      Some(username) => match resolve_username(&bot, username).await {
+        Ok(chat) => (chat.id.0, chat.title().map(str::to_string)),
+        Err(reason) => {
+          summary.push_str(&format!("{arg}：{reason}\n"));
+          continue;
+        }
+      },
+      // group ids are always negative
+      None => match arg.parse::<i64>() {
+        Ok(id) if id < 0 => (id, None),
+        _ => {
+          summary.push_str(&format!("{arg}：无效的 id\n"));
+          continue;
+        }
+      },
+    };
+    if !rt.can_manage_groups(user, &[ChatId(id)]) {
+      summary.push_str(&format!("{arg}：无权限\n"));
+      continue;
+    }
+    let added = {
+      let wt = rt.whitelist.read();
+      wt.groups.contains(&ChatId(id)) && wt.topics.get(&ChatId(id)).copied() == thread
+    };
+    if added {
+      summary.push_str(&format!("{arg}：该群已在列表中\n"));
+      continue;
+    }
+
+    // validate group id, and the topic if given
+    let options = SendOptions {
+      thread_id: thread,
+      ..SendOptions::default()
+    };
+    let test = Notification::Text("群组测试".to_string());
+    if let Err(e) = send_notification(&bot, ChatId(id), &test, &options).await {
+      tracing::warn!("Fail to add group id {id}: {e}");
+      summary.push_str(&format!(
+        "{arg}：无法发送消息：{e}，是不是没把 bot 拉进群？\n"
+      ));
+      continue;
+    }
+
+    rt.add_group(id, thread);
+    changed = true;
+    let group = rt.whitelist.read().format_group(ChatId(id));
+    rt.audit(user, format!("添加群组 {group}")).await;
+    match title {
+      Some(title) => {
+        summary.push_str(&format!("{arg}：已添加 {title}（id：{id}）\n"));
+        rt.group_titles.write().insert(ChatId(id), title);
+      }
+      None => summary.push_str(&format!("{arg}：已添加\n")),
+    }
+  }
+
+  reply_batch_result(&bot, &rt, msg.chat.id, summary, changed).await
+}
+
+/// Reply to the user and return false if the user is not allowed to manage the group
+async fn ensure_group_manager(
+  msg: &Message,
+  bot: &NotifyBot,
+  rt: &BotRuntime,
+  group: ChatId,
+) -> Result<bool> {
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  if rt.can_manage_groups(user, &[group]) {
+    return Ok(true);
+  }
+
+  bot.send_message(msg.chat.id, "无权限").await?;
+  Ok(false)
+}
+
+/// Handler for /listgroup command. Admin with a scope only see the groups they manage.
+async fn list_group_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  let text = {
+    let wt = rt.whitelist.read();
+    wt.manageable_groups(user)
+      .iter()
+      .map(|&group| {
+        let mut line = format!("- {}", group.0);
+        if let Some(thread) = wt.topics.get(&group) {
+          line.push_str(&format!("（话题 {thread}）"));
+        }
+        if wt.is_disabled(group) {
+          line.push_str("（已停用）");
+        }
+        line
+      })
+      .collect::<Vec<String>>()
+      .join("\n")
   };
 
-  let msg = bot
-    .send_message(msg.chat.id, "删除完成，正在保存...")
-    .await?;
-  rt.save_whitelist().await?;
-  bot
-    .edit_message_text(msg.chat.id, msg.id, "保存完成。")
-    .await?;
+  let text = if text.is_empty() {
+    "还没有添加任何通知群。".to_string()
+  } else {
+    format!("通知群列表：\n{text}")
+  };
+  bot.send_message(msg.chat.id, text).await?;
 
   Ok(())
 }
 
-async fn add_group_handler(msg: Message, bot: AutoSend<Bot>, mut rt: BotRuntime) -> Result<()> {
+async fn del_group_handler(msg: Message, bot: NotifyBot, mut rt: BotRuntime) -> Result<()> {
   let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
 
-  let id: i64 = match parse_first_arg_as_num(text) {
-    Some(i) => i,
-    None => {
-      bot
-        .send_message(
-          msg.chat.id,
-          "错误的输入！你应该输入群组的 id。参考例子：/addgroup -1234567",
-        )
-        .await?;
-      anyhow::bail!("Invalid group id input")
-    }
-  };
-
-  // validate group id
-  let result = bot.send_message(ChatId(id), "群组测试").await;
-  if let Err(e) = result {
+  let args = split_batch_args(text);
+  if args.is_empty() {
     bot
       .send_message(
         msg.chat.id,
-        "无法加入 id 为 {id} 的群组：{e}\n\n提示：是不是没把 bot 拉进群？",
+        "错误的输入！你应该输入群组的 id，多个群组用空格或逗号分隔。参考例子：/delgroup -1234567",
       )
       .await?;
-    anyhow::bail!("Fail to add group id {id}: {e}");
+    anyhow::bail!("Invalid group id input")
   }
 
-  rt.add_group(id);
-
-  let msg = bot
-    .send_message(msg.chat.id, "添加完成，正在保存...")
-    .await?;
-  rt.save_whitelist().await?;
-  bot
-    .edit_message_text(msg.chat.id, msg.id, "保存完成。")
-    .await?;
+  let mut summary = String::new();
+  let mut changed = false;
+  for arg in args {
+    let id: i64 = match arg.parse() {
+      Ok(id) => id,
+      Err(_) => {
+        summary.push_str(&format!("{arg}：无效的 id\n"));
+        continue;
+      }
+    };
+    if !rt.can_manage_groups(user, &[ChatId(id)]) {
+      summary.push_str(&format!("{id}：无权限\n"));
+      continue;
+    }
+    match rt.del_group(id) {
+      Ok(_) => {
+        changed = true;
+        rt.audit(user, format!("删除群组 {id}")).await;
+        summary.push_str(&format!("{id}：已删除\n"));
+      }
+      Err(e) => summary.push_str(&format!("{}\n", e.user_message())),
+    }
+  }
 
-  Ok(())
+  reply_batch_result(&bot, &rt, msg.chat.id, summary, changed).await
 }
 
-async fn del_group_handler(msg: Message, bot: AutoSend<Bot>, mut rt: BotRuntime) -> Result<()> {
+async fn enable_group_handler(msg: Message, bot: NotifyBot, mut rt: BotRuntime) -> Result<()> {
   let text = msg.text().ok_or_else(|| anyhow::anyhow!("非法字符！"))?;
   let id: i64 = match parse_first_arg_as_num(text) {
     Some(i) => i,
@@ -563,26 +6688,241 @@ async fn del_group_handler(msg: Message, bot: AutoSend<Bot>, mut rt: BotRuntime)
       bot
         .send_message(
           msg.chat.id,
-          "错误的输入！你应该输入群组的 id。参考例子：/delgroup -1234567",
+          "错误的输入！你应该输入群组的 id。参考例子：/enablegroup -1234567",
         )
         .await?;
       anyhow::bail!("Invalid group id input")
     }
   };
+  if !ensure_group_manager(&msg, &bot, &rt, ChatId(id)).await? {
+    return Ok(());
+  }
 
-  if let Err(e) = rt.del_group(id) {
+  if let Err(e) = rt.enable_group(id) {
     bot
-      .send_message(msg.chat.id, "群组不存在！请重新确认 id")
+      .send_message(msg.chat.id, format!("{}，请重新确认 id", e.user_message()))
       .await?;
-    anyhow::bail!("fail to delete user: {e}")
+    anyhow::bail!("fail to enable group: {e}")
   }
+  let user = msg
+    .from()
+    .ok_or_else(|| anyhow::anyhow!("A message without sender can't be handle"))?
+    .id;
+  rt.audit(user, format!("重新启用群组 {id}")).await;
 
-  let msg = bot
-    .send_message(msg.chat.id, "删除完成，正在保存...")
+  bot
+    .send_message(msg.chat.id, format!("群组 {id} 已重新启用。"))
     .await?;
+
+  Ok(())
+}
+
+/// Handler for the membership change of the bot itself. When a maintainer or admin add the bot
+/// into a group they can manage, the group is added to the whitelist. When someone else add the
+/// bot, or a scoped admin add it into a group outside the scope, the bot leave the group and
+/// alert the maintainers. When the bot is kicked or the group is deleted,
+/// the group is removed from the whitelist and the tasks.
+async fn bot_member_handler(
+  upd: ChatMemberUpdated,
+  bot: NotifyBot,
+  mut rt: BotRuntime,
+) -> Result<()> {
+  if upd.chat.is_private() {
+    return Ok(());
+  }
+
+  let gid = upd.chat.id;
+  let title = upd
+    .chat
+    .title()
+    .map_or_else(|| gid.0.to_string(), str::to_string);
+  let (was_present, is_present) = (
+    upd.old_chat_member.is_present(),
+    upd.new_chat_member.is_present(),
+  );
+  if was_present && !is_present {
+    return bot_removed(&bot, &mut rt, upd.from.id, gid, &title).await;
+  }
+  let joined = !was_present && is_present;
+  if !joined || upd.chat.is_channel() {
+    return Ok(());
+  }
+
+  if !rt.whitelist.read().can_register_group(upd.from.id, gid) {
+    tracing::warn!(
+      "User {} add the bot into group {}, leaving",
+      upd.from.id,
+      gid.0
+    );
+    let (maintainers, scoped) = {
+      let wt = rt.whitelist.read();
+      (wt.maintainers.clone(), wt.has_access(upd.from.id))
+    };
+    let reason = if scoped {
+      "，该群组不在其管理范围内"
+    } else {
+      ""
+    };
+    let text = format!(
+      "用户 {}（{}）把 bot 拉进了群组 {title}（{}）{reason}，bot 已自动退出。",
+      upd.from.full_name(),
+      upd.from.id,
+      gid.0
+    );
+    alert_maintainers(&bot, &maintainers, &text).await;
+    bot.leave_chat(gid).await?;
+    return Ok(());
+  }
+
+  // the group might be whitelisted already, keep its topic
+  if !rt.get_group().contains(&gid) {
+    rt.add_group(gid.0, None);
+    rt.audit(upd.from.id, format!("把 bot 拉进群组 {title}（{}）", gid.0))
+      .await;
+    rt.save_whitelist().await?;
+  }
+  rt.group_titles.write().insert(gid, title);
+  bot.send_message(gid, "本群已加入通知列表").await?;
+
+  Ok(())
+}
+
+/// Drop the group that the bot is removed from, so the tasks don't keep failing on it. The
+/// group need to be added by the admin again if the bot come back.
+async fn bot_removed(
+  bot: &NotifyBot,
+  rt: &mut BotRuntime,
+  user: UserId,
+  gid: ChatId,
+  title: &str,
+) -> Result<()> {
+  if rt.del_group(gid.0).is_err() {
+    return Ok(());
+  }
+  rt.audit(user, format!("把 bot 移出群组 {title}（{}）", gid.0))
+    .await;
+  tracing::warn!("Bot is removed from group {}, dropping it", gid.0);
   rt.save_whitelist().await?;
+
+  let paused = rt.task_pool.remove_group(gid).await?;
+  let mut text = format!(
+    "bot 已被移出群组 {title}（{}），该群组已从通知列表中删除。",
+    gid.0
+  );
+  if !paused.is_empty() {
+    let ids = paused
+      .iter()
+      .map(|id| id.to_string())
+      .collect::<Vec<String>>()
+      .join(", ");
+    text.push_str(&format!(
+      "\n以下任务没有其他通知群，已暂停，需要用 /edittask 重新设置群组后才能恢复：{ids}"
+    ));
+  }
+  let maintainers = rt.whitelist.read().maintainers.clone();
+  alert_maintainers(bot, &maintainers, &text).await;
+
+  Ok(())
+}
+
+/// Handler for the service message sent when the group is upgraded to supergroup. The old id
+/// is rewritten to the new one everywhere.
+async fn group_migrated_handler(msg: Message, to: ChatId, mut rt: BotRuntime) -> Result<()> {
+  rt.task_pool.migrate_group(msg.chat.id, to).await?;
+  Ok(())
+}
+
+/// Reply the commands that start a dialogue but sent in the group
+async fn private_only_handler(msg: Message, bot: NotifyBot) -> Result<()> {
   bot
-    .edit_message_text(msg.chat.id, msg.id, "保存完成。")
+    .send_message(msg.chat.id, "这个命令需要多步对话，请在私聊中使用。")
+    .await?;
+  Ok(())
+}
+
+/// Handler for the private messages from the users without access. The maintainers are alerted
+/// when someone keep sending messages to the bot.
+async fn unauthorized_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let user = match msg.from() {
+    Some(user) => user,
+    None => return Ok(()),
+  };
+  if !rt.access_tracker.lock().record(user.id, Instant::now()) {
+    return Ok(());
+  }
+
+  let username = user
+    .username
+    .as_deref()
+    .map_or_else(|| "无".to_string(), |name| format!("@{name}"));
+  let sample = msg.text().or_else(|| msg.caption()).map_or_else(
+    || "（非文字消息）".to_string(),
+    |text| text.chars().take(100).collect(),
+  );
+  let text = format!(
+    "用户 {}（用户名：{username}）在短时间内多次尝试使用 bot，均已被拒绝。\n最近发送的内容：{sample}",
+    user.id
+  );
+  let maintainers = rt.whitelist.read().maintainers.clone();
+  alert_maintainers(&bot, &maintainers, &text).await;
+
+  Ok(())
+}
+
+/// Handler for /whoami command, anyone can use it.
+async fn whoami_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let user = match msg.from() {
+    Some(user) => user,
+    None => return Ok(()),
+  };
+
+  let role = {
+    let whitelist = rt.whitelist.read();
+    if whitelist.is_banned(user.id) {
+      "已封禁"
+    } else if whitelist.is_maintainers(user.id) {
+      "维护者"
+    } else if whitelist.has_access(user.id) {
+      "管理员"
+    } else {
+      "普通用户"
+    }
+  };
+  bot
+    .send_message(
+      msg.chat.id,
+      format!("你的用户 id 是 {}\n身份：{role}", user.id),
+    )
+    .await?;
+
+  Ok(())
+}
+
+/// Handler for /chatid command, anyone can use it in the group.
+async fn chat_id_handler(msg: Message, bot: NotifyBot, rt: BotRuntime) -> Result<()> {
+  let chat = &msg.chat;
+  let kind = if chat.is_private() {
+    "私聊"
+  } else if chat.is_group() {
+    "群组"
+  } else if chat.is_supergroup() {
+    "超级群组"
+  } else {
+    "频道"
+  };
+  let whitelisted = if rt.get_group().contains(&chat.id) {
+    "已加入通知列表"
+  } else {
+    "未加入通知列表"
+  };
+  bot
+    .send_message(
+      chat.id,
+      format!(
+        "当前聊天的 id 是 {}\n类型：{kind}\n{whitelisted}",
+        chat.id.0
+      ),
+    )
     .await?;
 
   Ok(())
@@ -605,16 +6945,71 @@ pub fn handler_schema() -> UpdateHandler<anyhow::Error> {
       // admins accessible commands
       .branch(dptree::case![Command::Help].endpoint(help))
       .branch(dptree::case![Command::Start].endpoint(help))
-      .branch(dptree::case![Command::AddTask].endpoint(add_task_handler))
       .branch(dptree::case![Command::ListTask].endpoint(list_task_handler))
       .branch(dptree::case![Command::DelTask].endpoint(del_task_handler))
+      .branch(dptree::case![Command::TaskInfo].endpoint(task_info_handler))
+      .branch(dptree::case![Command::TaskStats].endpoint(task_stats_handler))
+      .branch(dptree::case![Command::SetInterval].endpoint(set_interval_handler))
+      .branch(dptree::case![Command::SetEnd].endpoint(set_end_handler))
+      .branch(dptree::case![Command::PauseTask].endpoint(pause_task_handler))
+      .branch(dptree::case![Command::ResumeTask].endpoint(pause_task_handler))
+      .branch(dptree::case![Command::SendNow].endpoint(send_now_handler))
+      .branch(dptree::case![Command::TestSend].endpoint(test_send_handler))
+      .branch(dptree::case![Command::ListNotify].endpoint(list_notify_handler))
+      .branch(dptree::case![Command::DelNotify].endpoint(del_notify_handler))
+      .branch(dptree::case![Command::SetWeight].endpoint(set_weight_handler))
+      .branch(dptree::case![Command::SaveTemplate].endpoint(save_template_handler))
+      .branch(dptree::case![Command::ListTemplate].endpoint(list_template_handler))
+      .branch(dptree::case![Command::DelTemplate].endpoint(del_template_handler))
       .branch(dptree::case![Command::AddGroup].endpoint(add_group_handler))
+      .branch(dptree::case![Command::ListGroup].endpoint(list_group_handler))
       .branch(dptree::case![Command::DelGroup].endpoint(del_group_handler))
+      .branch(dptree::case![Command::EnableGroup].endpoint(enable_group_handler))
+      .branch(
+        // the dialogues stay in the private chat, so they don't clutter the groups
+        dptree::filter(|msg: Message| msg.chat.is_private())
+          .branch(dptree::case![Command::AddTask].endpoint(add_task_handler))
+          .branch(dptree::case![Command::EditTask].endpoint(edit_task_handler))
+          .branch(dptree::case![Command::CloneTask].endpoint(clone_task_handler))
+          .branch(dptree::case![Command::SetButtons].endpoint(set_buttons_handler))
+          .branch(dptree::case![Command::AddNotify].endpoint(add_notify_handler))
+          .branch(dptree::case![Command::SetText].endpoint(set_text_handler)),
+      )
+      .branch(
+        dptree::filter(|cmd: Command| {
+          matches!(
+            cmd,
+            Command::AddTask
+              | Command::EditTask
+              | Command::CloneTask
+              | Command::SetButtons
+              | Command::AddNotify
+              | Command::SetText
+          )
+        })
+        .endpoint(private_only_handler),
+      )
       .branch(
         // Maintainer only commands
         dptree::filter(move |msg: Message, rt: BotRuntime| can_process_admin(&msg, &rt))
           .branch(dptree::case![Command::AddAdmin].endpoint(add_admin))
-          .branch(dptree::case![Command::DelAdmin].endpoint(del_admin)),
+          .branch(dptree::case![Command::DelAdmin].endpoint(del_admin))
+          .branch(dptree::case![Command::Ban].endpoint(ban_handler))
+          .branch(dptree::case![Command::Unban].endpoint(unban_handler))
+          .branch(dptree::case![Command::AuditLog].endpoint(audit_log_handler))
+          .branch(dptree::case![Command::Reload].endpoint(reload_handler))
+          .branch(dptree::case![Command::StopAll].endpoint(stop_all_handler))
+          .branch(dptree::case![Command::ExportTasks].endpoint(export_tasks_handler))
+          .branch(dptree::case![Command::ImportTasks].endpoint(import_tasks_handler))
+          .branch(dptree::case![Command::ListOverride].endpoint(list_override_handler))
+          .branch(dptree::case![Command::DelOverride].endpoint(del_override_handler))
+          .branch(
+            dptree::case![Command::Override]
+              .branch(
+                dptree::filter(|msg: Message| msg.chat.is_private()).endpoint(override_handler),
+              )
+              .endpoint(private_only_handler),
+          ),
       ),
   );
 
@@ -625,39 +7020,240 @@ pub fn handler_schema() -> UpdateHandler<anyhow::Error> {
       None => return false,
     };
     let whitelist = rt.whitelist.read();
-    // if it is in chat, and it is maintainer/admin calling
-    msg.chat.is_private() && whitelist.has_access(id)
+    // maintainer/admin calling in private chat, or in the whitelisted groups
+    whitelist.has_access(id) && (msg.chat.is_private() || whitelist.groups.contains(&msg.chat.id))
   };
 
   // build the text message handler
-  let message_handler = Update::filter_message().branch(
-    // basic auth
-    dptree::filter(move |msg: Message, rt: BotRuntime| has_access(&msg, &rt))
-      // enter command filter
-      .branch(command_handler)
-      // handle non command message
-      .branch(
-        dptree::case![AddTaskDialogueCurrentState::RequestNotifyText].endpoint(request_notify_text),
-      )
-      .branch(
-        dptree::case![AddTaskDialogueCurrentState::RequestRepeatInterval { text }]
+  let message_handler = Update::filter_message()
+    .branch(
+      // the group is upgraded to supergroup
+      dptree::filter_map(|msg: Message| msg.migrate_to_chat_id()).endpoint(group_migrated_handler),
+    )
+    .branch(
+      // commands for everyone, even in the groups
+      teloxide::filter_command::<Command, _>()
+        .branch(dptree::case![Command::WhoAmI].endpoint(whoami_handler))
+        .branch(dptree::case![Command::ChatId].endpoint(chat_id_handler)),
+    )
+    .branch(
+      // basic auth
+      dptree::filter(move |msg: Message, rt: BotRuntime| has_access(&msg, &rt))
+        // /back works in the middle of the add task dialogue
+        .branch(
+          teloxide::filter_command::<Command, _>()
+            .branch(dptree::case![Command::Back].endpoint(back_handler)),
+        )
+        // enter command filter
+        .branch(command_handler)
+        // handle non command message
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::RequestTemplateSchedule { name, template }]
+            .endpoint(request_template_schedule),
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::RequestNotifyText { name }]
+            .endpoint(request_notify_text),
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::RequestRepeatInterval {
+            name,
+            content,
+            parse_mode
+          }]
           .endpoint(request_repeat_interval),
-      )
-      .branch(
-        dptree::case![AddTaskDialogueCurrentState::RequestButtons { text, interval }]
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::RequestWeekdays {
+            name,
+            content,
+            parse_mode,
+            schedule
+          }]
+          .endpoint(request_weekdays),
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::RequestButtons {
+            name,
+            content,
+            parse_mode,
+            schedule,
+            weekdays
+          }]
           .endpoint(request_buttons),
-      ),
-  );
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::RequestFixedText {
+            name,
+            parse_mode,
+            schedule,
+            weekdays,
+            buttons,
+            groups
+          }]
+          .endpoint(request_fixed_text),
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::ReviseTask {
+            field,
+            confirmation,
+            confirm_msg
+          }]
+          .endpoint(revise_task_handler),
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::RequestExtraNotifyText { id }]
+            .endpoint(request_extra_notify_text),
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::RequestExtraNotifyWeight { id, content }]
+            .endpoint(request_extra_notify_weight),
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::RequestReplaceText { id, index }]
+            .endpoint(request_replace_text),
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::RequestNewButtons { id }]
+            .endpoint(request_new_buttons),
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::RequestCloneInterval { id, source }]
+            .endpoint(request_clone_interval),
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::RequestImportFile]
+            .endpoint(request_import_file),
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::RequestOverrideText { id, group }]
+            .endpoint(request_override_text),
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::RequestOverrideButtons { id, group, content }]
+            .endpoint(request_override_buttons),
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::EditTaskText { id, changes }]
+            .endpoint(request_edit_text),
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::EditTaskInterval { id, changes }]
+            .endpoint(request_edit_interval),
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::EditTaskButtons { id, changes }]
+            .endpoint(request_edit_buttons),
+        )
+        .branch(
+          dptree::case![AddTaskDialogueCurrentState::EditTaskGroups { id, changes }]
+            .endpoint(request_edit_groups),
+        ),
+    )
+    .branch(
+      // strangers talking to the bot in private
+      dptree::filter(move |msg: Message, rt: BotRuntime| {
+        msg.chat.is_private() && !has_access(&msg, &rt)
+      })
+      .endpoint(unauthorized_handler),
+    );
+
+  // test if the user who press the button has access to the bot
+  let callback_has_access = |q: &CallbackQuery, rt: &BotRuntime| -> bool {
+    let whitelist = rt.whitelist.read();
+    whitelist.has_access(q.from.id)
+  };
 
   // build the callback handler
-  let callback_handler = Update::filter_callback_query().branch(
-    dptree::case![AddTaskDialogueCurrentState::RequestConfirmation {
-      text,
-      interval,
-      buttons
-    }]
-    .endpoint(button_callback_handler),
-  );
+  let callback_handler = Update::filter_callback_query()
+    .branch(
+      // the counter buttons on the notifications are pressed by the group members
+      dptree::filter(|q: CallbackQuery| {
+        q.data
+          .as_deref()
+          .map_or(false, |d| d.starts_with(COUNTER_BUTTON_PREFIX))
+      })
+      .endpoint(counter_button_callback_handler),
+    )
+    .branch(
+      // page navigation is not bound to any dialogue
+      dptree::filter(move |q: CallbackQuery, rt: BotRuntime| {
+        callback_has_access(&q, &rt)
+          && q
+            .data
+            .as_deref()
+            .map_or(false, |d| d.starts_with("listtask_page_"))
+      })
+      .endpoint(list_task_page_callback_handler),
+    )
+    .branch(
+      dptree::filter(move |q: CallbackQuery, rt: BotRuntime| {
+        callback_has_access(&q, &rt)
+          && q
+            .data
+            .as_deref()
+            .map_or(false, |d| d.starts_with("del_task_confirm_"))
+      })
+      .endpoint(del_task_callback_handler),
+    )
+    .branch(
+      dptree::filter(|q: CallbackQuery, rt: BotRuntime| {
+        rt.whitelist.read().is_maintainers(q.from.id)
+          && q
+            .data
+            .as_deref()
+            .map_or(false, |d| d.starts_with("stop_all_confirm_"))
+      })
+      .endpoint(stop_all_callback_handler),
+    )
+    .branch(
+      dptree::case![AddTaskDialogueCurrentState::RequestTemplate { name }]
+        .endpoint(template_callback_handler),
+    )
+    .branch(
+      dptree::case![AddTaskDialogueCurrentState::RequestParseMode { name, content }]
+        .endpoint(parse_mode_callback_handler),
+    )
+    .branch(
+      dptree::case![AddTaskDialogueCurrentState::RequestPollType { name, content }]
+        .endpoint(poll_type_callback_handler),
+    )
+    .branch(
+      dptree::case![AddTaskDialogueCurrentState::RequestGroups {
+        name,
+        content,
+        parse_mode,
+        schedule,
+        weekdays,
+        buttons,
+        groups
+      }]
+      .endpoint(task_groups_callback_handler),
+    )
+    .branch(
+      dptree::case![AddTaskDialogueCurrentState::RequestConfirmation {
+        name,
+        content,
+        parse_mode,
+        schedule,
+        weekdays,
+        buttons,
+        groups,
+        options,
+        preview,
+        nonce
+      }]
+      .endpoint(button_callback_handler),
+    )
+    .branch(
+      dptree::case![AddTaskDialogueCurrentState::RequestNewButtonsConfirmation { id, buttons }]
+        .endpoint(set_buttons_callback_handler),
+    )
+    .branch(
+      dptree::case![AddTaskDialogueCurrentState::EditTaskMenu { id, changes }]
+        .endpoint(edit_task_menu_callback_handler),
+    )
+    .endpoint(stale_callback_handler);
 
   /*
    * Update --> <IsMessage> --> message_handler --> <IsCommand> --> command_handler
@@ -665,16 +7261,15 @@ pub fn handler_schema() -> UpdateHandler<anyhow::Error> {
    *      \                                   * --> normal_message_handler
    *       \
    *        *--> <IsCallbackQuery> --> query_handler
+   *         \
+   *          *--> <IsMyChatMember> --> bot_member_handler
    */
   let root = dptree::entry()
     .branch(message_handler)
-    .branch(callback_handler);
-
-  dialogue::enter::<
-        Update,
-        InMemStorage<AddTaskDialogueCurrentState>,
-        AddTaskDialogueCurrentState,
-        _,
-    >()
+    .branch(callback_handler)
+    .branch(Update::filter_my_chat_member().endpoint(bot_member_handler));
+
+  dialogue::enter::<Update, DialogueStorage, AddTaskDialogueCurrentState, _>()
+    .chain(dptree::filter_map_async(expire_dialogue))
     .branch(root)
 }
@@ -1,5 +1,18 @@
+mod audit;
+mod clock;
+mod config;
+mod error;
 pub mod handler;
 mod runtime;
 mod schedule;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod store;
+mod template;
 
-pub use runtime::{BotRuntime, Whitelist};
+pub use config::{Config, ConfigError};
+pub use error::Error;
+pub use runtime::{throttle_limits_from_env, BotRuntime, NotifyBot, Whitelist};
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+pub use store::{JsonFileStore, NoopStore, TaskSnapshot, TaskStore, WhitelistStore};
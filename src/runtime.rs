@@ -1,20 +1,35 @@
-use crate::schedule::TaskPool;
-use anyhow::Result;
-use parking_lot::RwLock;
+use crate::{
+  audit::{self, AuditEntry},
+  clock,
+  config::Config,
+  error::{Error, Result},
+  schedule::{
+    alert_maintainers, migrate_group, IntervalBounds, Messenger, QuietHours, TaskInfo, TaskPool,
+  },
+  store::{TaskStore, WhitelistStore},
+  template::{self, TemplateFile},
+};
+use chrono_tz::Tz;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::{
+  collections::{HashMap, VecDeque},
   env::var,
-  fmt::{Debug, Display},
-  str::FromStr,
+  fmt::Display,
+  path::{Path, PathBuf},
+  time::{Duration, Instant},
 };
 use teloxide::{
+  adaptors::{throttle::Limits, Throttle},
   prelude::*,
   types::{ChatId, UserId},
 };
-use tokio::{fs, sync::watch};
+use tokio::{fs, io::AsyncWriteExt, sync::watch};
 
 /// Whitelist store context for authorization
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Whitelist {
   /// Maintainers can grant admin, manage bot
   pub maintainers: Vec<UserId>,
@@ -22,13 +37,23 @@ pub struct Whitelist {
   pub admins: Vec<UserId>,
   /// List of groups that bot make response
   pub groups: Vec<ChatId>,
+  /// Groups that are disabled because of repeated delivery failures. They are still in the
+  /// `groups` list, but notification will not be sent to them.
+  pub disabled_groups: Vec<ChatId>,
+  /// The forum topic (message thread id) to send notification into for each group. Group not
+  /// in this map receive notification in the General topic.
+  pub topics: HashMap<ChatId, i32>,
+  /// Banned users never have access to the bot, even if they are in the admins
+  pub banned: Vec<UserId>,
+  /// The groups each admin can manage. Admin not in this map can manage every group.
+  pub admin_scopes: HashMap<UserId, Vec<ChatId>>,
 }
 
 impl Display for Whitelist {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     write!(
       f,
-      "Maintainers: {} || Admins: {} || Groups: {}",
+      "Maintainers: {} || Admins: {} || Groups: {} || Banned: {}",
       self
         .maintainers
         .iter()
@@ -44,6 +69,12 @@ impl Display for Whitelist {
       self
         .groups
         .iter()
+        .map(|&x| self.format_group(x))
+        .collect::<Vec<String>>()
+        .join(","),
+      self
+        .banned
+        .iter()
         .map(|x| x.0.to_string())
         .collect::<Vec<String>>()
         .join(","),
@@ -52,15 +83,34 @@ impl Display for Whitelist {
 }
 
 impl Whitelist {
-  /// Create a default whitelist.
+  /// Create an empty whitelist without any maintainer or group.
   pub fn new() -> Self {
     Self::default()
   }
 
-  /// Test if the user is one of the maintainers or admins.
+  /// Describe the whitelist by the number of entries, so it can be logged without leaking ids
+  pub fn summary(&self) -> String {
+    format!(
+      "{} maintainers, {} admins ({} scoped), {} groups ({} disabled), {} banned",
+      self.maintainers.len(),
+      self.admins.len(),
+      self.admin_scopes.len(),
+      self.groups.len(),
+      self.disabled_groups.len(),
+      self.banned.len()
+    )
+  }
+
+  /// Test if the user is one of the maintainers or admins, and is not banned.
   #[inline]
   pub fn has_access(&self, user: UserId) -> bool {
-    self.is_maintainers(user) || self.admins.iter().any(|&id| id == user)
+    !self.is_banned(user) && (self.is_maintainers(user) || self.admins.iter().any(|&id| id == user))
+  }
+
+  /// Test if the user is banned
+  #[inline]
+  pub fn is_banned(&self, user: UserId) -> bool {
+    self.banned.contains(&user)
   }
 
   /// Test if the user is one of the maintainers
@@ -69,112 +119,712 @@ impl Whitelist {
     self.maintainers.iter().any(|&id| id == user)
   }
 
-  #[inline]
-  fn env_to_num_collect<T: FromStr>(k: &str) -> Option<Vec<T>>
-  where
-    <T as FromStr>::Err: Debug,
-  {
-    if let Ok(val) = var(k) {
-      let val = val
-        .split(',')
-        .map(|x| {
-          x.trim()
-            .parse::<T>()
-            .unwrap_or_else(|_| panic!("{x} is not a valid number"))
-        })
-        .collect::<Vec<T>>();
-      Some(val)
-    } else {
-      None
+  /// Return the groups the user can manage, `None` means the user is not scoped and can
+  /// manage every group. Maintainers are never scoped.
+  pub fn scope(&self, user: UserId) -> Option<&[ChatId]> {
+    if self.is_maintainers(user) {
+      return None;
     }
+    self.admin_scopes.get(&user).map(Vec::as_slice)
   }
 
-  // Expect: `export NOTIFY_BOT_MAINTAINERS="123,456,789"`
-  pub fn parse_maintainers(mut self) -> Self {
-    if let Some(m) = Self::env_to_num_collect("NOTIFY_BOT_MAINTAINERS") {
-      self.maintainers = m.iter().map(|x| UserId(*x)).collect();
-      self.maintainers.sort_unstable();
+  /// Test if the user can manage all of the given groups
+  pub fn can_manage_groups(&self, user: UserId, groups: &[ChatId]) -> bool {
+    match self.scope(user) {
+      Some(scope) => groups.iter().all(|g| scope.contains(g)),
+      None => true,
     }
+  }
 
+  /// Test if the user can add the group by inviting the bot into it. Scoped admins can only
+  /// bring in the groups in their scope, like `/addgroup`.
+  pub fn can_register_group(&self, user: UserId, group: ChatId) -> bool {
+    self.has_access(user) && self.can_manage_groups(user, &[group])
+  }
+
+  /// Return the groups in the whitelist that the user can manage
+  pub fn manageable_groups(&self, user: UserId) -> Vec<ChatId> {
     self
+      .groups
+      .iter()
+      .filter(|&&g| self.can_manage_groups(user, &[g]))
+      .copied()
+      .collect()
+  }
+
+  /// Test if the group is disabled
+  #[inline]
+  pub fn is_disabled(&self, group: ChatId) -> bool {
+    self.disabled_groups.contains(&group)
   }
 
-  pub fn parse_admins(mut self) -> Self {
-    if let Some(a) = Self::env_to_num_collect("NOTIFY_BOT_ADMINS") {
-      self.admins = a.iter().map(|x| UserId(*x)).collect();
-      self.admins.sort_unstable();
+  /// Rewrite the id of the group upgraded to supergroup. Return `false` if the old id is not
+  /// in the whitelist.
+  pub fn migrate_group(&mut self, from: ChatId, to: ChatId) -> bool {
+    if !migrate_group(&mut self.groups, from, to) {
+      return false;
+    }
+    self.groups.sort_unstable();
+    migrate_group(&mut self.disabled_groups, from, to);
+    if let Some(thread) = self.topics.remove(&from) {
+      self.topics.insert(to, thread);
+    }
+    for scope in self.admin_scopes.values_mut() {
+      migrate_group(scope, from, to);
+    }
+    true
+  }
+
+  /// Format the group as `chat_id:thread_id` if it has a topic, otherwise only the chat id
+  pub fn format_group(&self, group: ChatId) -> String {
+    match self.topics.get(&group) {
+      Some(thread) => format!("{}:{thread}", group.0),
+      None => group.0.to_string(),
+    }
+  }
+
+  /// Sort the lists and remove the duplicated entries. Old versions of the bot might save
+  /// the same admin or group twice.
+  pub fn dedup(mut self) -> Self {
+    for list in [&mut self.maintainers, &mut self.admins, &mut self.banned] {
+      list.sort_unstable();
+      list.dedup();
+    }
+    for list in [&mut self.groups, &mut self.disabled_groups] {
+      list.sort_unstable();
+      list.dedup();
+    }
+    for scope in self.admin_scopes.values_mut() {
+      scope.sort_unstable();
+      scope.dedup();
     }
     self
   }
 
-  pub fn parse_groups(mut self) -> Self {
-    if let Some(g) = Self::env_to_num_collect("NOTIFY_BOT_GROUPS") {
-      self.groups = g.iter().map(|x| ChatId(*x)).collect();
-      self.groups.sort_unstable();
+  /// Load the whitelist from the JSON file. If the file is broken, the backup of the previous
+  /// save is loaded instead.
+  pub async fn load_from(path: &Path) -> Result<Self> {
+    let err = match Self::read_file(path).await {
+      Ok(wt) => return Ok(wt.dedup()),
+      Err(e) => e,
+    };
+
+    let backup = with_suffix(path, "bak");
+    match Self::read_file(&backup).await {
+      Ok(wt) => {
+        tracing::warn!(
+          "Fail to load {}: {err}, using backup {}",
+          path.display(),
+          backup.display()
+        );
+        Ok(wt.dedup())
+      }
+      Err(_) => Err(err),
+    }
+  }
+
+  async fn read_file(path: &Path) -> Result<Self> {
+    let content = fs::read_to_string(path).await?;
+    if content.trim().is_empty() {
+      return Err(Error::EmptyFile(path.to_path_buf()));
+    }
+    Ok(serde_json::from_str(&content)?)
+  }
+
+  /// Save the whitelist into the JSON file. The content is written into a temporary file then
+  /// renamed, so a crash never leave a truncated file. The previous file is kept as the
+  /// `.bak` backup.
+  pub async fn save_to(&self, path: &Path) -> Result<()> {
+    let content = serde_json::to_string_pretty(self)?;
+
+    let tmp = with_suffix(path, "tmp");
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(content.as_bytes()).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    if fs::metadata(path).await.is_ok() {
+      fs::copy(path, with_suffix(path, "bak")).await?;
+    }
+    fs::rename(&tmp, path).await?;
+    Ok(())
+  }
+}
+
+impl From<&Config> for Whitelist {
+  /// Build the initial whitelist from the config, used when the whitelist file doesn't exist yet
+  fn from(config: &Config) -> Self {
+    let wt = &config.whitelist;
+    let users = |list: &Option<Vec<u64>>| {
+      list
+        .iter()
+        .flatten()
+        .map(|id| UserId(*id))
+        .collect::<Vec<UserId>>()
+    };
+
+    let mut whitelist = Whitelist {
+      maintainers: users(&wt.maintainers),
+      admins: users(&wt.admins),
+      banned: users(&wt.banned),
+      ..Whitelist::default()
+    };
+    for group in wt.groups.iter().flatten() {
+      whitelist.groups.push(ChatId(group.id));
+      if let Some(thread) = group.thread {
+        whitelist.topics.insert(ChatId(group.id), thread);
+      }
     }
+    for scope in wt.admin_scopes.iter().flatten() {
+      whitelist.admin_scopes.insert(
+        UserId(scope.admin),
+        scope.groups.iter().map(|g| ChatId(*g)).collect(),
+      );
+    }
+    whitelist.dedup()
+  }
+}
+
+/// Append the suffix to the file name, like `whitelist.json.bak`
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+  let mut name = path.as_os_str().to_owned();
+  name.push(".");
+  name.push(suffix);
+  PathBuf::from(name)
+}
+
+#[cfg(test)]
+impl Whitelist {
+  /// Whitelist with every field populated for testing
+  pub(crate) fn fixture() -> Self {
+    let mut wt = Whitelist {
+      maintainers: vec![UserId(1)],
+      admins: vec![UserId(2), UserId(3)],
+      groups: vec![ChatId(-1003), ChatId(-1002), ChatId(-1001)],
+      disabled_groups: vec![ChatId(-1002)],
+      banned: vec![UserId(4)],
+      ..Whitelist::default()
+    };
+    wt.topics.insert(ChatId(-1001), 42);
+    wt.admin_scopes.insert(UserId(3), vec![ChatId(-1001)]);
+    wt
+  }
+}
+
+#[test]
+fn whitelist_summary_test() {
+  assert!(Whitelist::new().maintainers.is_empty());
+  assert!(Whitelist::new().groups.is_empty());
+  assert_eq!(
+    Whitelist::fixture().summary(),
+    "1 maintainers, 2 admins (1 scoped), 3 groups (1 disabled), 1 banned"
+  );
+}
+
+#[test]
+fn whitelist_from_config_test() {
+  assert_eq!(Whitelist::from(&Config::default()), Whitelist::default());
+
+  let mut config = Config::default();
+  config.whitelist.maintainers = Some(vec![2, 1, 2]);
+  config.whitelist.groups = Some(vec!["-1001:42".parse().unwrap(), "-1002".parse().unwrap()]);
+  let wt = Whitelist::from(&config);
+  assert_eq!(wt.maintainers, vec![UserId(1), UserId(2)]);
+  assert_eq!(wt.groups, vec![ChatId(-1002), ChatId(-1001)]);
+  assert_eq!(wt.topics.get(&ChatId(-1001)), Some(&42));
+  assert!(wt.admins.is_empty());
+  assert!(wt.admin_scopes.is_empty());
+
+  config.whitelist.admins = Some(vec![3]);
+  config.whitelist.admin_scopes = Some(vec!["3:-1001".parse().unwrap()]);
+  let wt = Whitelist::from(&config);
+  assert_eq!(wt.scope(UserId(3)), Some(&[ChatId(-1001)][..]));
+}
+
+#[test]
+fn whitelist_diff_test() {
+  let old = Whitelist::fixture();
+  let mut new = old.clone();
+  assert!(WhitelistDiff::between(&old, &new).is_empty());
+
+  new.admins = vec![UserId(3), UserId(5)];
+  new.groups = vec![ChatId(-1003), ChatId(-1004)];
+  let diff = WhitelistDiff::between(&old, &new);
+  assert_eq!(diff.added_admins, vec![UserId(5)]);
+  assert_eq!(diff.removed_admins, vec![UserId(2)]);
+  assert_eq!(diff.added_groups, vec![ChatId(-1004)]);
+  assert_eq!(diff.removed_groups, vec![ChatId(-1002), ChatId(-1001)]);
+}
+
+#[tokio::test]
+async fn whitelist_round_trip_test() {
+  let wt = Whitelist::fixture();
+
+  let path = std::env::temp_dir().join(format!("notify-bot-whitelist-{}.json", std::process::id()));
+  wt.save_to(&path).await.unwrap();
+  let loaded = Whitelist::load_from(&path).await.unwrap();
+  fs::remove_file(&path).await.unwrap();
+  assert_eq!(loaded, wt);
+
+  // missing fields fall back to default
+  let loaded: Whitelist = serde_json::from_str(r#"{"maintainers":[1],"groups":[-1001]}"#).unwrap();
+  assert_eq!(loaded.maintainers, vec![UserId(1)]);
+  assert_eq!(loaded.groups, vec![ChatId(-1001)]);
+  assert!(loaded.admins.is_empty());
+}
+
+#[tokio::test]
+async fn load_backup_test() {
+  let dir = std::env::temp_dir().join(format!("notify-bot-backup-{}", std::process::id()));
+  fs::create_dir_all(&dir).await.unwrap();
+  let path = dir.join("whitelist.json");
+
+  let first = Whitelist {
+    admins: vec![UserId(1)],
+    ..Whitelist::default()
+  };
+  let second = Whitelist {
+    admins: vec![UserId(1), UserId(2)],
+    ..Whitelist::default()
+  };
+  first.save_to(&path).await.unwrap();
+  second.save_to(&path).await.unwrap();
+  assert_eq!(Whitelist::load_from(&path).await.unwrap(), second);
+
+  // the file is broken by a crash, the previous save is recovered
+  fs::write(&path, "{\"admins\": [1, 2").await.unwrap();
+  assert_eq!(Whitelist::load_from(&path).await.unwrap(), first);
+  fs::write(&path, "").await.unwrap();
+  assert_eq!(Whitelist::load_from(&path).await.unwrap(), first);
+
+  fs::remove_dir_all(&dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn save_keep_env_file_test() {
+  let dir = std::env::temp_dir().join(format!("notify-bot-env-{}", std::process::id()));
+  fs::create_dir_all(&dir).await.unwrap();
+  let env = dir.join(".env");
+  let content = "# proxy\nRUST_LOG=debug\nHTTPS_PROXY=http://127.0.0.1:7890\n\nTELOXIDE_TOKEN=abc:123\nNOTIFY_BOT_ADMINS=1\n";
+  fs::write(&env, content).await.unwrap();
+
+  let wt = Whitelist {
+    admins: vec![UserId(1), UserId(2)],
+    ..Whitelist::default()
+  };
+  wt.save_to(&dir.join("whitelist.json")).await.unwrap();
+  let after = fs::read_to_string(&env).await.unwrap();
+  fs::remove_dir_all(&dir).await.unwrap();
+  assert_eq!(after, content);
+}
+
+#[test]
+fn whitelist_dedup_test() {
+  let wt = Whitelist {
+    admins: vec![UserId(2), UserId(1), UserId(2)],
+    groups: vec![ChatId(-1), ChatId(-2), ChatId(-1)],
+    ..Whitelist::default()
+  }
+  .dedup();
+  assert_eq!(wt.admins, vec![UserId(1), UserId(2)]);
+  assert_eq!(wt.groups, vec![ChatId(-2), ChatId(-1)]);
+}
+
+#[test]
+fn admin_scope_test() {
+  let mut wt = Whitelist {
+    maintainers: vec![UserId(1)],
+    admins: vec![UserId(2), UserId(3)],
+    groups: vec![ChatId(-3), ChatId(-2), ChatId(-1)],
+    ..Whitelist::default()
+  };
+  wt.admin_scopes
+    .insert(UserId(3), vec![ChatId(-1), ChatId(-2)]);
+
+  assert_eq!(wt.manageable_groups(UserId(1)), wt.groups);
+  assert_eq!(wt.manageable_groups(UserId(2)), wt.groups);
+  assert_eq!(
+    wt.manageable_groups(UserId(3)),
+    vec![ChatId(-2), ChatId(-1)]
+  );
+  assert!(wt.can_manage_groups(UserId(3), &[ChatId(-1)]));
+  assert!(!wt.can_manage_groups(UserId(3), &[ChatId(-1), ChatId(-3)]));
+
+  // inviting the bot into a group outside the scope doesn't register it
+  assert!(wt.can_register_group(UserId(1), ChatId(-4)));
+  assert!(wt.can_register_group(UserId(2), ChatId(-4)));
+  assert!(wt.can_register_group(UserId(3), ChatId(-1)));
+  assert!(!wt.can_register_group(UserId(3), ChatId(-4)));
+  assert!(!wt.can_register_group(UserId(4), ChatId(-4)));
+}
+
+/// Alert the maintainers when a user is rejected this many times in the window
+const UNAUTHORIZED_THRESHOLD: usize = 5;
+const UNAUTHORIZED_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// Don't alert for the same user again in this duration
+const UNAUTHORIZED_MUTE: Duration = Duration::from_secs(60 * 60);
+/// Max users tracked at the same time, inactive users are evicted when it is full
+const UNAUTHORIZED_MAX_USERS: usize = 1024;
+
+#[derive(Debug)]
+struct Attempts {
+  /// Time of the recent rejected messages in the window, at most `UNAUTHORIZED_THRESHOLD`
+  times: VecDeque<Instant>,
+  muted_until: Option<Instant>,
+}
+
+impl Attempts {
+  fn last_seen(&self) -> Option<Instant> {
+    self.times.back().copied()
+  }
+
+  fn is_active(&self, now: Instant) -> bool {
+    self.muted_until.map_or(false, |t| t > now)
+      || self
+        .last_seen()
+        .map_or(false, |t| now.duration_since(t) < UNAUTHORIZED_WINDOW)
+  }
+}
+
+/// AccessTracker count the rejected messages of the users without access, so the maintainers
+/// can know someone is probing the bot.
+#[derive(Debug, Default)]
+pub struct AccessTracker {
+  users: HashMap<UserId, Attempts>,
+}
+
+impl AccessTracker {
+  /// Record a rejected message of the user. Return true if the user exceed the threshold and
+  /// the maintainers should be alerted. The user is muted for a while after the alert.
+  pub fn record(&mut self, user: UserId, now: Instant) -> bool {
+    if !self.users.contains_key(&user) && self.users.len() >= UNAUTHORIZED_MAX_USERS {
+      self.evict(now);
+    }
+
+    let attempts = self.users.entry(user).or_insert_with(|| Attempts {
+      times: VecDeque::with_capacity(UNAUTHORIZED_THRESHOLD),
+      muted_until: None,
+    });
+    while let Some(&t) = attempts.times.front() {
+      if now.duration_since(t) < UNAUTHORIZED_WINDOW
+        && attempts.times.len() < UNAUTHORIZED_THRESHOLD
+      {
+        break;
+      }
+      attempts.times.pop_front();
+    }
+    attempts.times.push_back(now);
+
+    if attempts.muted_until.map_or(false, |t| t > now) {
+      return false;
+    }
+    if attempts.times.len() < UNAUTHORIZED_THRESHOLD {
+      return false;
+    }
+    attempts.muted_until = Some(now + UNAUTHORIZED_MUTE);
+    true
+  }
+
+  /// Remove the inactive users. If all of them are active, remove the least recently seen one.
+  fn evict(&mut self, now: Instant) {
+    self.users.retain(|_, a| a.is_active(now));
+    if self.users.len() < UNAUTHORIZED_MAX_USERS {
+      return;
+    }
+    let oldest = self
+      .users
+      .iter()
+      .min_by_key(|(_, a)| a.last_seen())
+      .map(|(&user, _)| user);
+    if let Some(user) = oldest {
+      self.users.remove(&user);
+    }
+  }
+}
+
+#[test]
+fn access_tracker_test() {
+  let mut tracker = AccessTracker::default();
+  let user = UserId(42);
+  let start = Instant::now();
+  let at = |secs: u64| start + Duration::from_secs(secs);
+
+  for i in 0..UNAUTHORIZED_THRESHOLD as u64 - 1 {
+    assert!(!tracker.record(user, at(i)));
+  }
+  assert!(tracker.record(user, at(10)));
+  // muted after the alert
+  assert!(!tracker.record(user, at(20)));
+  assert!(!tracker.record(user, at(30)));
+
+  // the old attempts are out of the window
+  let later = UNAUTHORIZED_MUTE.as_secs() + 100;
+  assert!(!tracker.record(user, at(later)));
+
+  // the memory is bounded
+  for i in 0..UNAUTHORIZED_MAX_USERS as u64 * 2 {
+    tracker.record(UserId(i + 100), at(later));
+  }
+  assert!(tracker.users.len() <= UNAUTHORIZED_MAX_USERS);
+}
+
+/// Keep the consumed nonces this long, the replayed callbacks come within seconds
+const NONCE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// ConsumedNonces remember the nonces of the confirmation buttons already pressed, so pressing
+/// the button again before the dialogue exits is rejected.
+#[derive(Debug, Default)]
+pub struct ConsumedNonces {
+  nonces: HashMap<u32, Instant>,
+}
+
+impl ConsumedNonces {
+  /// Mark the nonce consumed. Return false if it is consumed already.
+  pub fn consume(&mut self, nonce: u32, now: Instant) -> bool {
     self
+      .nonces
+      .retain(|_, &mut t| now.duration_since(t) < NONCE_TTL);
+    if self.nonces.contains_key(&nonce) {
+      return false;
+    }
+    self.nonces.insert(nonce, now);
+    true
   }
+}
 
-  pub async fn save(&self) -> Result<()> {
-    let file = ".env";
-    // we can guarantee that when we save the config, teloxide token is already init
-    let content = vec![
-      format!(
-        "TELOXIDE_TOKEN={}",
-        std::env::var("TELOXIDE_TOKEN").unwrap()
-      ),
-      format!(
-        "NOTIFY_BOT_ADMINS={}",
-        self
-          .admins
-          .iter()
-          .map(|x| x.to_string())
-          .collect::<Vec<String>>()
-          .join(",")
-      ),
-      format!(
-        "NOTIFY_BOT_GROUPS={}",
-        self
-          .groups
-          .iter()
-          .map(|x| x.to_string())
-          .collect::<Vec<String>>()
-          .join(",")
-      ),
-      format!(
-        "NOTIFY_BOT_MAINTAINERS={}",
-        self
-          .maintainers
-          .iter()
-          .map(|x| x.to_string())
-          .collect::<Vec<String>>()
-          .join(",")
-      ),
-    ]
-    .join("\n");
+#[test]
+fn consumed_nonces_test() {
+  let mut nonces = ConsumedNonces::default();
+  let start = Instant::now();
+  assert!(nonces.consume(42, start));
+  assert!(!nonces.consume(42, start + Duration::from_secs(1)));
+  assert!(nonces.consume(43, start + Duration::from_secs(1)));
+
+  // the expired nonces are forgotten
+  let later = start + NONCE_TTL + Duration::from_secs(2);
+  assert!(nonces.consume(42, later));
+  assert_eq!(nonces.nonces.len(), 1);
+}
 
-    Ok(fs::write(file, content).await?)
+/// The bot type used everywhere. Requests are throttled to respect the Telegram limits.
+pub type NotifyBot = AutoSend<Throttle<Bot>>;
+
+/// The file the whitelist is saved to, shared by the runtime and the task pool. When a
+/// [`WhitelistStore`] is set, the whitelist is saved there instead.
+pub struct WhitelistFile {
+  path: Mutex<PathBuf>,
+  store: Mutex<Option<Arc<dyn WhitelistStore>>>,
+  /// Serialize the saves, so the concurrent saves don't share the temporary file
+  save_lock: tokio::sync::Mutex<()>,
+}
+
+impl Default for WhitelistFile {
+  fn default() -> Self {
+    Self {
+      path: Mutex::new(PathBuf::from("whitelist.json")),
+      store: Mutex::new(None),
+      save_lock: tokio::sync::Mutex::default(),
+    }
   }
 }
 
-/// BotRuntime is a memory storage for running the bot.
-pub struct BotRuntime {
+impl WhitelistFile {
+  pub fn path(&self) -> PathBuf {
+    self.path.lock().clone()
+  }
+
+  pub fn set_path(&self, path: PathBuf) {
+    *self.path.lock() = path;
+  }
+
+  pub fn store(&self) -> Option<Arc<dyn WhitelistStore>> {
+    self.store.lock().clone()
+  }
+
+  pub fn set_store(&self, store: Arc<dyn WhitelistStore>) {
+    *self.store.lock() = Some(store);
+  }
+
+  /// Take a copy of the whitelist then save it
+  pub async fn save(&self, whitelist: &RwLock<Whitelist>) -> Result<()> {
+    let _guard = self.save_lock.lock().await;
+    let wt = whitelist.read().clone();
+    match self.store() {
+      Some(store) => store.save_whitelist(&wt).await,
+      None => wt.save_to(&self.path()).await,
+    }
+  }
+}
+
+/// Admins and groups changed by a reload
+#[derive(Debug, Default, PartialEq)]
+pub struct WhitelistDiff {
+  pub added_admins: Vec<UserId>,
+  pub removed_admins: Vec<UserId>,
+  pub added_groups: Vec<ChatId>,
+  pub removed_groups: Vec<ChatId>,
+}
+
+impl WhitelistDiff {
+  pub fn between(old: &Whitelist, new: &Whitelist) -> Self {
+    fn changes<T: Copy + PartialEq>(old: &[T], new: &[T]) -> (Vec<T>, Vec<T>) {
+      let added = new.iter().filter(|x| !old.contains(x)).copied().collect();
+      let removed = old.iter().filter(|x| !new.contains(x)).copied().collect();
+      (added, removed)
+    }
+
+    let (added_admins, removed_admins) = changes(&old.admins, &new.admins);
+    let (added_groups, removed_groups) = changes(&old.groups, &new.groups);
+    Self {
+      added_admins,
+      removed_admins,
+      added_groups,
+      removed_groups,
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self == &Self::default()
+  }
+}
+
+/// Read the throttle limits from `NOTIFY_BOT_MESSAGES_PER_SEC` (messages per second overall)
+/// and `NOTIFY_BOT_MESSAGES_PER_MIN_CHAT` (messages per minute in one chat), use the teloxide
+/// default value when the variable is not set or invalid.
+pub fn throttle_limits_from_env() -> Limits {
+  let parse = |k: &str| var(k).ok().and_then(|v| v.trim().parse::<u32>().ok());
+
+  let mut limits = Limits::default();
+  if let Some(n) = parse("NOTIFY_BOT_MESSAGES_PER_SEC") {
+    limits.messages_per_sec_overall = n.max(1);
+  }
+  if let Some(n) = parse("NOTIFY_BOT_MESSAGES_PER_MIN_CHAT") {
+    limits.messages_per_min_chat = n.max(1);
+  }
+  limits
+}
+
+/// Telegram accept at most 4096 characters in a message, counted in UTF-16 code units
+pub(crate) const TEXT_MAX_LEN: usize = 4096;
+
+/// Read the max length of the notification text from `NOTIFY_BOT_MAX_TEXT_LENGTH`. The limit
+/// can only be stricter than Telegram's, use the Telegram limit when the variable is not set or
+/// invalid.
+fn max_text_length_from_env() -> usize {
+  match var("NOTIFY_BOT_MAX_TEXT_LENGTH") {
+    Ok(val) => match val.trim().parse::<usize>() {
+      Ok(n) if (1..=TEXT_MAX_LEN).contains(&n) => n,
+      _ => {
+        tracing::error!("Invalid NOTIFY_BOT_MAX_TEXT_LENGTH {val}, fallback to {TEXT_MAX_LEN}");
+        TEXT_MAX_LEN
+      }
+    },
+    Err(_) => TEXT_MAX_LEN,
+  }
+}
+
+/// The link schemes accepted in the buttons by default
+pub(crate) const DEFAULT_BUTTON_SCHEMES: [&str; 3] = ["http", "https", "tg"];
+
+/// Read the link schemes accepted in the buttons from `NOTIFY_BOT_BUTTON_SCHEMES`, a comma
+/// separated list like `https,tg`. Use the default schemes when the variable is not set or empty.
+fn button_schemes_from_env() -> Vec<String> {
+  let schemes = var("NOTIFY_BOT_BUTTON_SCHEMES")
+    .unwrap_or_default()
+    .split(',')
+    .map(|s| s.trim().to_lowercase())
+    .filter(|s| !s.is_empty())
+    .collect::<Vec<_>>();
+  if schemes.is_empty() {
+    return DEFAULT_BUTTON_SCHEMES
+      .iter()
+      .map(|s| s.to_string())
+      .collect();
+  }
+  schemes
+}
+
+/// Wait for Ctrl-C, or SIGTERM sent by systemd and docker on unix
+async fn shutdown_signal() {
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut term = signal(SignalKind::terminate()).expect("Fail to listen SIGTERM");
+    tokio::select! {
+      r = tokio::signal::ctrl_c() => r.expect("Fail to listen ctrl c signal"),
+      _ = term.recv() => tracing::info!("Receive SIGTERM"),
+    }
+  }
+  #[cfg(not(unix))]
+  tokio::signal::ctrl_c()
+    .await
+    .expect("Fail to listen ctrl c signal");
+}
+
+/// BotRuntime is a memory storage for running the bot. The bot type is generic for testing,
+/// see [`Messenger`].
+pub struct BotRuntime<M = NotifyBot> {
   pub whitelist: Arc<RwLock<Whitelist>>,
+  shutdown_tx: Arc<watch::Sender<u8>>,
   shutdown_sig: watch::Receiver<u8>,
-  pub task_pool: TaskPool,
+  pub task_pool: TaskPool<M>,
+  /// Global quiet hours read from `NOTIFY_BOT_QUIET_HOURS`
+  pub quiet_hours: Option<QuietHours>,
+  /// Accepted range of the repeat interval read from `NOTIFY_BOT_MIN_INTERVAL` and
+  /// `NOTIFY_BOT_MAX_INTERVAL`
+  pub interval_bounds: IntervalBounds,
+  /// Max length of the notification text read from `NOTIFY_BOT_MAX_TEXT_LENGTH`
+  pub max_text_length: usize,
+  /// Link schemes accepted in the buttons read from `NOTIFY_BOT_BUTTON_SCHEMES`
+  pub button_schemes: Arc<Vec<String>>,
+  /// Timezone of the wall clock time read from `NOTIFY_BOT_TIMEZONE`
+  pub timezone: Tz,
+  /// Messages of the albums being received, keyed by the media group id
+  pub albums: Arc<Mutex<HashMap<String, Vec<Message>>>>,
+  /// Cached titles of the groups
+  pub group_titles: Arc<RwLock<HashMap<ChatId, String>>>,
+  /// Rejected messages of the users without access
+  pub access_tracker: Arc<Mutex<AccessTracker>>,
+  /// Nonces of the confirmation buttons already pressed
+  pub consumed_nonces: Arc<Mutex<ConsumedNonces>>,
+  /// Path of the audit log read from `NOTIFY_BOT_AUDIT_LOG`
+  pub audit_log: PathBuf,
+  /// Saved message templates, the path is read from `NOTIFY_BOT_TEMPLATE_FILE`
+  pub templates: Arc<TemplateFile>,
+  /// The whitelist file, shared with the task pool
+  pub whitelist_file: Arc<WhitelistFile>,
+  /// The config file given by `--config`, read again on reload
+  pub config_path: Option<PathBuf>,
+  /// Serialize the reloads
+  reload_lock: Arc<tokio::sync::Mutex<()>>,
 }
 
-impl Clone for BotRuntime {
+impl<M: Messenger> Clone for BotRuntime<M> {
   fn clone(&self) -> Self {
     Self {
       whitelist: Arc::clone(&self.whitelist),
+      shutdown_tx: Arc::clone(&self.shutdown_tx),
       shutdown_sig: self.shutdown_sig.clone(),
       task_pool: self.task_pool.clone(),
+      quiet_hours: self.quiet_hours,
+      interval_bounds: self.interval_bounds,
+      max_text_length: self.max_text_length,
+      button_schemes: Arc::clone(&self.button_schemes),
+      timezone: self.timezone,
+      albums: Arc::clone(&self.albums),
+      group_titles: Arc::clone(&self.group_titles),
+      access_tracker: Arc::clone(&self.access_tracker),
+      consumed_nonces: Arc::clone(&self.consumed_nonces),
+      audit_log: self.audit_log.clone(),
+      templates: Arc::clone(&self.templates),
+      whitelist_file: Arc::clone(&self.whitelist_file),
+      config_path: self.config_path.clone(),
+      reload_lock: Arc::clone(&self.reload_lock),
     }
   }
 }
 
-impl BotRuntime {
+impl<M: Messenger> BotRuntime<M> {
   /// get_group lock the RwLock in read mode, return a Atomic reference to the groups array
   pub fn get_group(&self) -> Vec<ChatId> {
     let wt = self.whitelist.read();
@@ -182,21 +832,49 @@ impl BotRuntime {
   }
 
   /// Create a new runtime with activated bot and bot username.
-  pub fn new(bot: AutoSend<Bot>) -> Self {
+  pub fn new(bot: M) -> Self {
     let (tx, rx) = watch::channel(0);
+    let tx = Arc::new(tx);
 
+    let signal_tx = Arc::clone(&tx);
     tokio::spawn(async move {
-      tokio::signal::ctrl_c()
-        .await
-        .expect("Fail to listen ctrl c signal, do you running this program in Linux?");
-
-      tx.send(1).expect("Fail to send shutdown signal");
+      shutdown_signal().await;
+      // the receivers are kept by the runtime, sending never fail
+      let _ = signal_tx.send(1);
     });
 
+    // read the timezone at startup, so invalid timezone fail fast
+    let timezone = *clock::TIMEZONE;
+    tracing::info!("Using timezone {}", timezone);
+
+    let whitelist = Arc::new(RwLock::new(Whitelist::new()));
+    let whitelist_file = Arc::new(WhitelistFile::default());
+    let quiet_hours = QuietHours::from_env();
     Self {
-      whitelist: Arc::new(RwLock::new(Whitelist::new())),
+      task_pool: TaskPool::new(
+        bot,
+        Arc::clone(&whitelist),
+        Arc::clone(&whitelist_file),
+        quiet_hours,
+        rx.clone(),
+      ),
+      whitelist,
+      shutdown_tx: tx,
       shutdown_sig: rx,
-      task_pool: TaskPool::new(bot),
+      quiet_hours,
+      interval_bounds: IntervalBounds::from_env(),
+      max_text_length: max_text_length_from_env(),
+      button_schemes: Arc::new(button_schemes_from_env()),
+      timezone,
+      albums: Arc::default(),
+      group_titles: Arc::default(),
+      access_tracker: Arc::default(),
+      consumed_nonces: Arc::default(),
+      audit_log: audit::path_from_env(),
+      templates: Arc::new(TemplateFile::new(template::path_from_env())),
+      whitelist_file,
+      config_path: None,
+      reload_lock: Arc::default(),
     }
   }
 
@@ -205,15 +883,120 @@ impl BotRuntime {
     self.shutdown_sig.clone()
   }
 
-  pub fn whitelist(mut self, wt: Whitelist) -> Self {
-    self.whitelist = Arc::new(RwLock::new(wt));
+  /// Wait until the shutdown signal is sent
+  pub async fn wait_shutdown_sig(&self) {
+    let mut sig = self.subscribe_shutdown_sig();
+    while *sig.borrow() == 0 {
+      if sig.changed().await.is_err() {
+        return;
+      }
+    }
+  }
+
+  /// Send the shutdown signal to all the tasks, and wait up to `timeout` for them to stop.
+  /// Return false if some tasks are still running after the timeout.
+  pub async fn stop_tasks(&self, timeout: Duration) -> bool {
+    let _ = self.shutdown_tx.send(1);
+    tokio::time::timeout(timeout, self.task_pool.wait_stopped())
+      .await
+      .is_ok()
+  }
+
+  /// Stop all the tasks and wait for the sends in flight up to `timeout`, then save the
+  /// whitelist. Tell the maintainers the bot is going down if `notify` is true.
+  pub async fn shutdown(&self, bot: &M, timeout: Duration, notify: bool) {
+    tracing::info!("Waiting for the tasks to stop...");
+    if !self.stop_tasks(timeout).await {
+      tracing::warn!("Tasks are not stopped in {timeout:?}, aborting them");
+    }
+    // clear the pool, and abort the tasks still sending
+    if let Err(e) = self.task_pool.clone().shutdown_all().await {
+      tracing::error!("Fail to stop the tasks: {e}");
+    }
+    if let Err(e) = self.save_whitelist().await {
+      tracing::error!("Fail to save the whitelist: {e}");
+    }
+
+    if notify {
+      let maintainers = self.whitelist.read().maintainers.clone();
+      alert_maintainers(bot, &maintainers, "bot 正在关闭").await;
+    }
+  }
+
+  pub fn whitelist(self, wt: Whitelist) -> Self {
+    // update in place, the task pool share the same whitelist
+    *self.whitelist.write() = wt;
+    self
+  }
+
+  /// Set the file the whitelist is saved to
+  pub fn whitelist_path(self, path: PathBuf) -> Self {
+    self.whitelist_file.set_path(path);
+    self
+  }
+
+  /// Set the config file read again on reload
+  pub fn config_path(mut self, path: Option<PathBuf>) -> Self {
+    self.config_path = path;
+    self
+  }
+
+  /// Set where the tasks are saved, see [`TaskPool::restore`] for loading them back
+  pub fn task_store(mut self, store: Arc<dyn TaskStore>) -> Self {
+    self.task_pool = self.task_pool.with_store(store);
+    self
+  }
+
+  /// Save the whitelist into `store` instead of the whitelist file
+  pub fn whitelist_store(self, store: Arc<dyn WhitelistStore>) -> Self {
+    self.whitelist_file.set_store(store);
     self
   }
 
-  pub fn add_admin(&mut self, id: u64) {
+  /// Add the admin, or update the scope if the admin is added already. Admin with `scope`
+  /// can only manage the given groups. Return false if the admin is added already with the
+  /// same scope, nothing is changed. Return error if the user is banned.
+  pub fn add_admin(&mut self, id: u64, scope: Option<Vec<ChatId>>) -> Result<bool> {
     let mut wt = self.whitelist.write();
-    wt.admins.push(UserId(id));
-    wt.admins.sort_unstable();
+    if wt.is_banned(UserId(id)) {
+      return Err(Error::UserBanned(UserId(id)));
+    }
+    let scope = scope.map(|mut scope| {
+      scope.sort_unstable();
+      scope.dedup();
+      scope
+    });
+    match wt.admins.binary_search(&UserId(id)) {
+      Ok(_) if wt.admin_scopes.get(&UserId(id)) == scope.as_ref() => return Ok(false),
+      Ok(_) => {}
+      Err(i) => wt.admins.insert(i, UserId(id)),
+    }
+    match scope {
+      Some(scope) => wt.admin_scopes.insert(UserId(id), scope),
+      None => wt.admin_scopes.remove(&UserId(id)),
+    };
+    Ok(true)
+  }
+
+  /// Ban the user. Return false if the user is banned already.
+  pub fn ban(&mut self, id: u64) -> bool {
+    let mut wt = self.whitelist.write();
+    if wt.is_banned(UserId(id)) {
+      return false;
+    }
+    wt.banned.push(UserId(id));
+    wt.banned.sort_unstable();
+    true
+  }
+
+  pub fn unban(&mut self, id: u64) -> Result<()> {
+    let mut wt = self.whitelist.write();
+    let i = wt
+      .banned
+      .binary_search(&UserId(id))
+      .map_err(|_| Error::UserNotBanned(UserId(id)))?;
+    wt.banned.remove(i);
+    Ok(())
   }
 
   pub fn del_admin(&mut self, id: u64) -> Result<()> {
@@ -221,15 +1004,24 @@ impl BotRuntime {
     let i = wt
       .admins
       .binary_search(&UserId(id))
-      .map_err(|_| anyhow::anyhow!("User not exist!"))?;
+      .map_err(|_| Error::AdminNotFound(UserId(id)))?;
     wt.admins.remove(i);
+    wt.admin_scopes.remove(&UserId(id));
     Ok(())
   }
 
-  pub fn add_group(&mut self, gid: i64) {
+  /// Add the group, or update the forum topic of the group if it is added already. Notification
+  /// is sent into the topic `thread` if given.
+  pub fn add_group(&mut self, gid: i64, thread: Option<i32>) {
     let mut wt = self.whitelist.write();
-    wt.groups.push(ChatId(gid));
-    wt.groups.sort_unstable();
+    if !wt.groups.contains(&ChatId(gid)) {
+      wt.groups.push(ChatId(gid));
+      wt.groups.sort_unstable();
+    }
+    match thread {
+      Some(thread) => wt.topics.insert(ChatId(gid), thread),
+      None => wt.topics.remove(&ChatId(gid)),
+    };
   }
 
   pub fn del_group(&mut self, gid: i64) -> Result<()> {
@@ -237,19 +1029,140 @@ impl BotRuntime {
     let i = wt
       .groups
       .binary_search(&ChatId(gid))
-      .map_err(|_| anyhow::anyhow!("Group not exist!"))?;
+      .map_err(|_| Error::GroupNotFound(ChatId(gid)))?;
     wt.groups.remove(i);
+    wt.disabled_groups.retain(|&g| g != ChatId(gid));
+    wt.topics.remove(&ChatId(gid));
+    for scope in wt.admin_scopes.values_mut() {
+      scope.retain(|&g| g != ChatId(gid));
+    }
     Ok(())
   }
 
-  fn copy_whitelist(&self) -> Whitelist {
+  /// Enable the disabled group. Return error if the group is not disabled.
+  pub fn enable_group(&mut self, gid: i64) -> Result<()> {
+    let mut wt = self.whitelist.write();
+    let i = wt
+      .disabled_groups
+      .iter()
+      .position(|&g| g == ChatId(gid))
+      .ok_or(Error::GroupNotDisabled(ChatId(gid)))?;
+    wt.disabled_groups.remove(i);
+    Ok(())
+  }
+
+  /// Return the groups in the whitelist that the user can manage
+  pub fn manageable_groups(&self, user: UserId) -> Vec<ChatId> {
+    self.whitelist.read().manageable_groups(user)
+  }
+
+  /// Test if the user can manage all of the given groups
+  pub fn can_manage_groups(&self, user: UserId, groups: &[ChatId]) -> bool {
+    self.whitelist.read().can_manage_groups(user, groups)
+  }
+
+  /// Test if the user can see the task. Admin with a scope can only see the tasks that target
+  /// the groups in the scope.
+  pub fn can_view_task(&self, user: UserId, id: u32) -> bool {
+    self
+      .task_pool
+      .get_task(id)
+      .map_or(false, |task| self.can_manage_groups(user, &task.groups))
+  }
+
+  /// Return the tasks that the user can see
+  pub fn visible_tasks(&self, user: UserId) -> Vec<(u32, TaskInfo)> {
+    let tasks = self.task_pool.list_task();
     let wt = self.whitelist.read();
-    wt.clone()
+    tasks
+      .into_iter()
+      .filter(|(_, task)| wt.can_manage_groups(user, &task.groups))
+      .collect()
+  }
+
+  /// Test if the user can modify the task. Maintainers can modify every task, while admins
+  /// can only modify the tasks created by themselves and targeting the groups they manage.
+  pub fn can_modify_task(&self, user: UserId, id: u32) -> bool {
+    if self.whitelist.read().is_maintainers(user) {
+      return true;
+    }
+    self.task_pool.get_task(id).map_or(false, |task| {
+      task.creator == Some(user) && self.can_manage_groups(user, &task.groups)
+    })
+  }
+
+  /// Append the action done by the user to the audit log. Failure is logged only, it should
+  /// not block the action itself.
+  pub async fn audit(&self, user: UserId, action: impl Into<String>) {
+    let entry = AuditEntry {
+      time: clock::now().to_rfc3339(),
+      user: user.0,
+      action: action.into(),
+    };
+    if let Err(e) = audit::append(&self.audit_log, &entry).await {
+      tracing::error!("Fail to write audit log {}: {e}", self.audit_log.display());
+    }
+  }
+
+  /// Read the last `n` entries of the audit log
+  pub async fn read_audit_log(&self, n: usize) -> Result<Vec<AuditEntry>> {
+    audit::read_last(&self.audit_log, n).await
+  }
+
+  /// Tell the maintainers the bot is started, so the restarts don't go unnoticed
+  pub async fn notify_startup(&self, bot: &M, username: &str) {
+    let (maintainers, groups) = {
+      let wt = self.whitelist.read();
+      (wt.maintainers.clone(), wt.groups.len())
+    };
+    let text = format!(
+      "bot @{username} 已启动\n版本：{}\n通知群：{groups} 个\n已恢复任务：{} 个\n时间：{}",
+      env!("CARGO_PKG_VERSION"),
+      self.task_pool.list_id().len(),
+      clock::now().format("%Y-%m-%d %H:%M:%S %Z"),
+    );
+    alert_maintainers(bot, &maintainers, &text).await;
   }
 
   pub async fn save_whitelist(&self) -> Result<()> {
-    // take a copy then save it
-    let wt = self.copy_whitelist();
-    wt.save().await
+    self.whitelist_file.save(&self.whitelist).await
+  }
+
+  /// Load the whitelist again the same way as startup: from the whitelist store or the
+  /// whitelist file if it exists, otherwise from the config file and the environment variables.
+  /// The current whitelist is kept if the loading fails. The removed groups are dropped from
+  /// the tasks, return the diff and the ids of the tasks paused for losing all their groups.
+  pub async fn reload(&mut self) -> Result<(WhitelistDiff, Vec<u32>)> {
+    let _guard = self.reload_lock.lock().await;
+
+    let stored = match self.whitelist_file.store() {
+      Some(store) => store.load_whitelist().await?,
+      None => None,
+    };
+    let path = self.whitelist_file.path();
+    let new = if let Some(stored) = stored {
+      stored
+    } else if path.exists() {
+      // don't fall back to the backup, the broken file should be reported
+      Whitelist::read_file(&path).await?.dedup()
+    } else {
+      Whitelist::from(&Config::load(self.config_path.as_deref()).map_err(Error::Config)?)
+    };
+    if new.maintainers.is_empty() {
+      return Err(Error::NoMaintainer);
+    }
+
+    let diff = {
+      let mut wt = self.whitelist.write();
+      let diff = WhitelistDiff::between(&wt, &new);
+      *wt = new;
+      diff
+    };
+
+    let mut paused = Vec::new();
+    for &group in &diff.removed_groups {
+      paused.extend(self.task_pool.remove_group(group).await?);
+    }
+    Ok((diff, paused))
   }
 }
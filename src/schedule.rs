@@ -1,7 +1,21 @@
-use anyhow::Result;
-use parking_lot::RwLock;
+use crate::{
+  clock,
+  error::{Error, Result},
+  runtime::WhitelistFile,
+  store::{NoopStore, TaskSnapshot, TaskStore},
+  NotifyBot, Whitelist,
+};
+use chrono::{DateTime, Datelike, NaiveTime, Weekday};
+use chrono_tz::Tz;
+use futures::{future::BoxFuture, stream, StreamExt};
+use parking_lot::{Mutex, RwLock};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
 use std::{
-  collections::HashMap,
+  collections::{BTreeMap, HashMap, HashSet},
+  fmt::Display,
+  future::Future,
+  str::FromStr,
   sync::{
     atomic::{AtomicU32, Ordering},
     Arc,
@@ -9,224 +23,4812 @@ use std::{
   time::Duration,
 };
 use teloxide::{
-  payloads::SendMessageSetters,
+  payloads::{
+    EditMessageMediaSetters, EditMessageTextSetters, PinChatMessageSetters, SendMessageSetters,
+    SendPhotoSetters, UnpinChatMessageSetters,
+  },
   prelude::*,
-  types::{ChatId, InlineKeyboardMarkup},
+  types::{
+    ChatId, InlineKeyboardButtonKind, InlineKeyboardMarkup, InputFile, InputMedia, InputMediaPhoto,
+    ParseMode, UserId,
+  },
+  ApiError, RequestError,
 };
-use tokio::sync::{mpsc, watch};
-use tokio::time as tok_time;
-use tracing::error;
-
-/// A global counter to assign unique id for task
-static TASK_INC_ID: AtomicU32 = AtomicU32::new(0);
+use tokio::time::{self as tok_time, MissedTickBehavior};
+use tokio::{
+  sync::{mpsc, oneshot, watch},
+  task::JoinHandle,
+};
+use tracing::{error, warn};
 
 /// TaskPool store tasks and a copy of bot.
-pub struct TaskPool {
+pub struct TaskPool<M = NotifyBot> {
   pool: Arc<RwLock<HashMap<u32, TaskInfo>>>,
-  bot: AutoSend<Bot>,
+  /// The id assigned to the next task
+  next_id: Arc<AtomicU32>,
+  bot: M,
+  retry_policy: RetryPolicy,
+  /// Default delay between sending to two groups
+  send_delay: Duration,
+  quiet_hours: Option<QuietHours>,
+  whitelist: Arc<RwLock<Whitelist>>,
+  whitelist_file: Arc<WhitelistFile>,
+  /// Shutdown signal shared by all the tasks
+  shutdown_sig: watch::Receiver<u8>,
+  /// Read locked by every running task, the write lock is granted once all the tasks stopped
+  running: Arc<tokio::sync::RwLock<()>>,
+  /// Tasks report the state changes made by themselves, so the pool can keep the snapshot in
+  /// sync
+  events: mpsc::UnboundedSender<TaskEvent>,
+  /// Where the tasks are saved. It is shared, so every clone of the pool see the replaced store.
+  store: Arc<RwLock<Arc<dyn TaskStore>>>,
+  /// Serialize the saves, so an older snapshot never overwrite a newer one
+  persist_lock: Arc<tokio::sync::Mutex<()>>,
 }
 
-impl Clone for TaskPool {
+impl<M: Messenger> Clone for TaskPool<M> {
   fn clone(&self) -> Self {
     Self {
       pool: Arc::clone(&self.pool),
+      next_id: Arc::clone(&self.next_id),
       bot: self.bot.clone(),
+      retry_policy: self.retry_policy.clone(),
+      send_delay: self.send_delay,
+      quiet_hours: self.quiet_hours,
+      whitelist: Arc::clone(&self.whitelist),
+      whitelist_file: Arc::clone(&self.whitelist_file),
+      shutdown_sig: self.shutdown_sig.clone(),
+      running: Arc::clone(&self.running),
+      events: self.events.clone(),
+      store: Arc::clone(&self.store),
+      persist_lock: Arc::clone(&self.persist_lock),
     }
   }
 }
 
-#[derive(Debug)]
-pub struct TaskInfo {
-  interval: u64,
-  content: String,
-  editor: Editor,
+/// RetryPolicy describe how to retry a failed send.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+  /// Max retry times for transient network error
+  pub max_retries: u32,
+  /// Delay before the first retry, it get doubled on every retry
+  pub base_delay: Duration,
+  /// Disable the group after this many consecutive failed ticks
+  pub max_failures: u32,
 }
 
-impl TaskPool {
-  /// Create a new task pool with zero size vector
-  pub fn new(bot: AutoSend<Bot>) -> Self {
+impl Default for RetryPolicy {
+  fn default() -> Self {
     Self {
-      pool: Arc::new(RwLock::new(HashMap::new())),
-      bot,
+      max_retries: 3,
+      base_delay: Duration::from_secs(1),
+      max_failures: 5,
     }
   }
+}
 
-  /// Spawn a new task. It needs repeat interval, a list of groups to send message, and a init
-  /// text to notify.
-  pub fn add_task(&mut self, task: ScheduleTask) {
-    // lock the pool and write to it
-    let mut pool = self.pool.write();
-    let id = TASK_INC_ID.fetch_add(1, Ordering::SeqCst);
-    let task = task.run(id, self.bot.clone());
-    pool.insert(id, task);
+impl RetryPolicy {
+  /// Read the max retry times from `NOTIFY_BOT_SEND_RETRIES` and the max consecutive failures
+  /// from `NOTIFY_BOT_MAX_FAILURES`, use the default value when the variable is not set or
+  /// invalid.
+  pub fn from_env() -> Self {
+    let parse = |k: &str| {
+      std::env::var(k)
+        .ok()
+        .and_then(|v| v.trim().parse::<u32>().ok())
+    };
+
+    let mut policy = Self::default();
+    if let Some(n) = parse("NOTIFY_BOT_SEND_RETRIES") {
+      policy.max_retries = n;
+    }
+    if let Some(n) = parse("NOTIFY_BOT_MAX_FAILURES") {
+      policy.max_failures = n.max(1);
+    }
+    policy
   }
 
-  /// List current running task, return a list of (id, interval, skim content)
-  pub fn list_task(&self) -> Vec<(u32, u64, String)> {
-    let pool = self.pool.read();
+  /// The total backoff of the transient errors when all the retries are used
+  pub fn max_total_wait(&self) -> Duration {
+    self
+      .base_delay
+      .saturating_mul(2_u32.saturating_pow(self.max_retries).saturating_sub(1))
+  }
+}
 
-    pool
-      .iter()
-      .map(|x| (*(x.0), x.1.interval, x.1.content.to_string()))
-      .collect()
+/// Default delay between sending to two groups, avoid hitting the flood limit
+const DEFAULT_SEND_DELAY: Duration = Duration::from_millis(300);
+
+/// Default max number of the sends in flight for a task
+const DEFAULT_SEND_CONCURRENCY: usize = 5;
+
+/// Read the delay between sending to two groups from `NOTIFY_BOT_SEND_DELAY_MS`, use the default
+/// value when the variable is not set or invalid.
+fn send_delay_from_env() -> Duration {
+  std::env::var("NOTIFY_BOT_SEND_DELAY_MS")
+    .ok()
+    .and_then(|v| v.trim().parse::<u64>().ok())
+    .map(Duration::from_millis)
+    .unwrap_or(DEFAULT_SEND_DELAY)
+}
+
+/// QuietHours is a daily time window that the tasks don't send notification
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuietHours {
+  start: NaiveTime,
+  end: NaiveTime,
+}
+
+impl QuietHours {
+  /// Read the quiet hours from `NOTIFY_BOT_QUIET_HOURS`, like `23:00-08:00`. Return `None` when
+  /// the variable is not set or invalid.
+  pub fn from_env() -> Option<Self> {
+    let val = std::env::var("NOTIFY_BOT_QUIET_HOURS").ok()?;
+    match val.parse() {
+      Ok(quiet) => Some(quiet),
+      Err(e) => {
+        error!("Invalid NOTIFY_BOT_QUIET_HOURS {}: {}", val, e);
+        None
+      }
+    }
   }
 
-  fn remove_task(&mut self, index: u32) -> Result<TaskInfo> {
-    let mut pool = self.pool.write();
-    pool
-      .remove(&index)
-      .ok_or_else(|| anyhow::anyhow!("Invalid index, no task found"))
+  /// Test if the time is in the window. The window might cross the midnight.
+  pub fn contains(&self, time: NaiveTime) -> bool {
+    if self.start <= self.end {
+      self.start <= time && time < self.end
+    } else {
+      time >= self.start || time < self.end
+    }
   }
+}
 
-  /// Stop a task, and remove it from pool
-  pub async fn remove(&mut self, index: u32) -> Result<()> {
-    let task = self.remove_task(index)?;
-    task.editor.shutdown().await;
-    Ok(())
+impl FromStr for QuietHours {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> anyhow::Result<Self> {
+    let (start, end) = s
+      .split_once('-')
+      .ok_or_else(|| anyhow::anyhow!("expect format like 23:00-08:00"))?;
+    Ok(Self {
+      start: NaiveTime::parse_from_str(start.trim(), "%H:%M")?,
+      end: NaiveTime::parse_from_str(end.trim(), "%H:%M")?,
+    })
   }
 }
 
-#[derive(Clone, Debug)]
-pub struct Editor(mpsc::Sender<TaskEditType>);
+impl Display for QuietHours {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "{}-{}",
+      self.start.format("%H:%M"),
+      self.end.format("%H:%M")
+    )
+  }
+}
 
-impl Editor {
-  pub async fn shutdown(&self) {
-    if let Err(e) = self.0.send(TaskEditType::ShutdownTask).await {
-      error!("Task has a unexpected closed edit channel: {e}")
+/// The default shortest repeat interval
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The default longest repeat interval
+const DEFAULT_MAX_INTERVAL: Duration = Duration::from_secs(30 * 86400);
+
+/// IntervalBounds is the accepted range of the repeat interval
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IntervalBounds {
+  pub min: Duration,
+  pub max: Duration,
+}
+
+impl Default for IntervalBounds {
+  fn default() -> Self {
+    Self {
+      min: DEFAULT_MIN_INTERVAL,
+      max: DEFAULT_MAX_INTERVAL,
     }
   }
 }
 
-/// A unit of a repeating notify task
-pub struct ScheduleTask {
-  /// Repeat interval, in minute unit
-  interval: u64,
-  /// A pool of notifications
-  pending_notification: Vec<String>,
-  /// A button set to attached on message
-  msg_buttons: Option<InlineKeyboardMarkup>,
-  /// A channel to edit this task
-  editor: mpsc::Sender<TaskEditType>,
-  /// A list of chat id
-  groups: Vec<ChatId>,
+impl IntervalBounds {
+  /// Read the bounds from `NOTIFY_BOT_MIN_INTERVAL` and `NOTIFY_BOT_MAX_INTERVAL`, like `1m` or
+  /// `30d`. Use the default value when the variable is not set or invalid.
+  pub fn from_env() -> Self {
+    let read = |key: &str, default: Duration| match std::env::var(key) {
+      Ok(val) => parse_duration(&val).unwrap_or_else(|| {
+        error!("Invalid {} {}, fallback to default value", key, val);
+        default
+      }),
+      Err(_) => default,
+    };
 
-  // Temporary storage for channel receive, don't touch it!
-  editor_rx: mpsc::Receiver<TaskEditType>,
-  ctrl_c_sig: watch::Receiver<u8>,
+    let bounds = Self {
+      min: read("NOTIFY_BOT_MIN_INTERVAL", DEFAULT_MIN_INTERVAL),
+      max: read("NOTIFY_BOT_MAX_INTERVAL", DEFAULT_MAX_INTERVAL),
+    };
+    if bounds.min.is_zero() || bounds.min > bounds.max {
+      error!(
+        "Invalid interval bounds {:?}-{:?}, fallback to default value",
+        bounds.min, bounds.max
+      );
+      return Self::default();
+    }
+    bounds
+  }
 }
 
-#[derive(Debug)]
-/// TaskEditType describe the behavior about updating the task.
-enum TaskEditType {
-  /// AddNotification describe a add notification behavior. It will add a new notification
-  /// text into the task storage.
-  // AddNotification(String),
-  /// ShutdownTask describe that this task should be closed
-  ShutdownTask,
+/// Test if the error means that we can never send message to this chat again, so
+/// there is no need to wait for more failures.
+fn is_permanent_error(e: &RequestError) -> bool {
+  matches!(
+    e,
+    RequestError::Api(
+      ApiError::BotKicked
+        | ApiError::BotKickedFromSupergroup
+        | ApiError::BotBlocked
+        | ApiError::ChatNotFound
+        | ApiError::GroupDeactivated
+        | ApiError::UserDeactivated
+    )
+  )
 }
 
-impl ScheduleTask {
-  pub fn new(ctrl_c_sig: watch::Receiver<u8>) -> Self {
-    let (editor, editor_rx) = mpsc::channel(5);
-    Self {
-      interval: 0,
-      pending_notification: Vec::new(),
-      msg_buttons: None,
-      groups: Vec::new(),
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+  /// Optional unique name for referring the task
+  pub name: Option<String>,
+  /// The admin who created this task
+  pub creator: Option<UserId>,
+  /// When to send the notification
+  pub schedule: Schedule,
+  /// The weekdays that the task is allowed to send notification
+  pub weekdays: WeekdaySet,
+  /// The task stop automatically after this time
+  pub ends_at: Option<DateTime<Tz>>,
+  /// A snapshot of the task's notification pool
+  pub notifications: Vec<Notification>,
+  /// The weight of each notification in the pool
+  pub weights: Vec<u32>,
+  /// A snapshot of the task's buttons
+  pub buttons: Option<InlineKeyboardMarkup>,
+  /// How the notification text is formatted, `None` means plain text
+  pub parse_mode: Option<ParseMode>,
+  /// How the notification is picked from the pool
+  pub rotation: RotationMode,
+  /// Don't attach the link preview to the notification
+  pub disable_web_page_preview: bool,
+  /// Send the notification without sound
+  pub silent: bool,
+  /// Forbid forwarding and saving the notification
+  pub protect_content: bool,
+  /// Pin the notification in the group after sending
+  pub pin: bool,
+  /// Only keep the latest notification in the group
+  pub delete_previous: bool,
+  /// Edit the same message instead of sending a new one
+  pub edit_in_place: bool,
+  /// Close the previous poll in the group before sending
+  pub stop_previous_poll: bool,
+  /// Fire at the same phase as the other tasks with the same interval
+  pub synchronized: bool,
+  /// A snapshot of the task's target groups
+  pub groups: Vec<ChatId>,
+  /// The content sent to the specific groups instead of the notification pool
+  pub overrides: BTreeMap<ChatId, GroupOverride>,
+  /// Is the task paused
+  pub paused: bool,
+  /// Delivery statistics, updated by the running task
+  pub stats: Arc<RwLock<TaskStats>>,
+  /// Last sent time and next fire time, updated by the running task
+  pub timing: Arc<RwLock<TaskTiming>>,
+  /// Press counts of the counter buttons, updated by the group members
+  pub counters: Arc<RwLock<ButtonCounters>>,
+  /// When the task is created
+  pub created_at: DateTime<Tz>,
+  editor: Editor,
+  /// Handle of the running task, taken by the one who stops the task
+  handle: Arc<Mutex<Option<JoinHandle<Result<()>>>>>,
+}
 
-      editor,
-      editor_rx,
+impl TaskInfo {
+  /// Return the notification that the next tick will send
+  pub fn next_notification(&self) -> Option<&Notification> {
+    let len = self.notifications.len();
+    if len == 0 {
+      return None;
+    }
+    self.notifications.get(self.timing.read().cursor % len)
+  }
 
-      ctrl_c_sig,
+  /// Wait for the running task to return after it is told to shutdown. Abort it and return
+  /// false if it doesn't stop in time.
+  async fn wait_stopped(&self) -> bool {
+    let handle = self.handle.lock().take();
+    let mut handle = match handle {
+      Some(handle) => handle,
+      // someone else is stopping it
+      None => return true,
+    };
+    if tok_time::timeout(TASK_STOP_TIMEOUT, &mut handle)
+      .await
+      .is_err()
+    {
+      handle.abort();
+      return false;
     }
+    true
   }
 
-  pub fn interval(mut self, interval: u64) -> Self {
-    self.interval = interval;
-    self
+  /// Return a skim of the notification pool for describing this task
+  pub fn skim(&self) -> String {
+    skim_content(&self.notifications)
   }
+}
 
-  pub fn pending_notification(mut self, pn: Vec<String>) -> Self {
-    self.pending_notification = pn;
-    self
+/// Notification is a single entry of the notification pool
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Notification {
+  /// A text only message
+  Text(String),
+  /// A photo identified by the Telegram file id, with an optional caption
+  Photo {
+    file_id: String,
+    caption: Option<String>,
+  },
+  /// An existing message to be copied, keeping all the formatting and media
+  Copy { from_chat: ChatId, message_id: i32 },
+  /// Several photos sent as an album, the caption is attached to the first photo
+  Album {
+    file_ids: Vec<String>,
+    caption: Option<String>,
+  },
+  /// A poll, a new one is created in every tick
+  Poll {
+    question: String,
+    options: Vec<String>,
+    anonymous: bool,
+    multiple_answers: bool,
+  },
+}
+
+impl Display for Notification {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Text(text) => write!(f, "{text}"),
+      Self::Photo {
+        caption: Some(caption),
+        ..
+      } => write!(f, "[图片] {caption}"),
+      Self::Photo { caption: None, .. } => write!(f, "[图片]"),
+      Self::Copy {
+        from_chat,
+        message_id,
+      } => write!(f, "[复制消息] {} #{message_id}", from_chat.0),
+      Self::Album { file_ids, caption } => {
+        write!(f, "[相册 {} 张]", file_ids.len())?;
+        match caption {
+          Some(caption) => write!(f, " {caption}"),
+          None => Ok(()),
+        }
+      }
+      Self::Poll {
+        question, options, ..
+      } => write!(f, "[投票] {question}（{}）", options.join(" / ")),
+    }
   }
+}
 
-  pub fn msg_buttons(mut self, btn: InlineKeyboardMarkup) -> Self {
-    self.msg_buttons = Some(btn);
-    self
+impl Notification {
+  /// Test if the text, caption or poll of the notification contains the placeholder
+  fn contains_placeholder(&self, placeholder: &str) -> bool {
+    match self {
+      Self::Text(text)
+      | Self::Photo {
+        caption: Some(text),
+        ..
+      }
+      | Self::Album {
+        caption: Some(text),
+        ..
+      } => text.contains(placeholder),
+      Self::Poll {
+        question, options, ..
+      } => question.contains(placeholder) || options.iter().any(|o| o.contains(placeholder)),
+      _ => false,
+    }
   }
 
-  pub fn groups(mut self, groups: Vec<ChatId>) -> Self {
-    self.groups = groups;
-    self
+  /// Return a copy with the placeholders in the text and caption replaced. The poll is always
+  /// plain text, so its values are not escaped.
+  pub fn render(&self, vars: &TemplateVars, parse_mode: Option<ParseMode>) -> Self {
+    let mut rendered = self.clone();
+    match &mut rendered {
+      Self::Text(text)
+      | Self::Photo {
+        caption: Some(text),
+        ..
+      }
+      | Self::Album {
+        caption: Some(text),
+        ..
+      } => *text = render_template(text, vars, parse_mode),
+      Self::Poll {
+        question, options, ..
+      } => {
+        *question = render_template(question, vars, None);
+        for option in options {
+          *option = render_template(option, vars, None);
+        }
+      }
+      _ => (),
+    }
+    rendered
   }
+}
 
-  /// Spawn a new tokio task to run a forever loop. It will notify when the ticker send a tick.
-  /// Task will consume itself and return necessary information about the task
-  pub fn run(self, id: u32, bot: AutoSend<Bot>) -> TaskInfo {
-    // copy a skim of the content for describing this task
-    let content = self.pending_notification[0].to_string();
-    let editor = self.editor.clone();
-    let interval = self.interval;
+/// Values of the placeholders in the notification text
+#[derive(Clone, Debug)]
+pub struct TemplateVars {
+  /// The send time, for `{date}`, `{time}` and `{weekday}`
+  pub now: DateTime<Tz>,
+  /// Times the task has sent the notification, counting the current one, for `{count}`
+  pub count: u64,
+  /// Title of the target group, for `{group_title}`
+  pub group_title: String,
+}
 
-    // move self into the new tokio task
-    tokio::spawn(self.into_background(id, bot));
+/// Fetching the group title costs a request, so it is fetched only when this placeholder is
+/// used
+const GROUP_TITLE_PLACEHOLDER: &str = "{group_title}";
 
-    TaskInfo {
-      interval,
-      content,
-      editor: Editor(editor),
+impl TemplateVars {
+  /// Return the value of the placeholder, `None` if the name is unknown
+  fn get(&self, name: &str) -> Option<String> {
+    const WEEKDAYS: [&str; 7] = ["一", "二", "三", "四", "五", "六", "日"];
+    let value = match name {
+      "date" => self.now.format("%Y-%m-%d").to_string(),
+      "time" => self.now.format("%H:%M").to_string(),
+      "weekday" => format!(
+        "周{}",
+        WEEKDAYS[self.now.weekday().num_days_from_monday() as usize]
+      ),
+      "count" => self.count.to_string(),
+      "group_title" => self.group_title.clone(),
+      _ => return None,
+    };
+    Some(value)
+  }
+}
+
+/// Replace the placeholders like `{date}` in the text. The values are escaped for the parse
+/// mode, and the unknown placeholders are kept as is.
+pub fn render_template(text: &str, vars: &TemplateVars, parse_mode: Option<ParseMode>) -> String {
+  let mut result = String::with_capacity(text.len());
+  let mut rest = text;
+  while let Some(start) = rest.find('{') {
+    result.push_str(&rest[..start]);
+    let tail = &rest[start..];
+    let value = tail
+      .find('}')
+      .and_then(|end| Some((end, vars.get(&tail[1..end])?)));
+    match value {
+      Some((end, value)) => {
+        let value = match parse_mode {
+          Some(ParseMode::MarkdownV2) => teloxide::utils::markdown::escape(&value),
+          Some(ParseMode::Html) => teloxide::utils::html::escape(&value),
+          _ => value,
+        };
+        result.push_str(&value);
+        rest = &tail[end + 1..];
+      }
+      None => {
+        result.push('{');
+        rest = &tail[1..];
+      }
     }
   }
+  result.push_str(rest);
+  result
+}
 
-  async fn into_background(mut self, id: u32, bot: AutoSend<Bot>) -> Result<()> {
-    let mut ticker = tok_time::interval(Duration::from_secs(self.interval));
-    loop {
-      tokio::select! {
-        // receive edit message
-        edit = self.editor_rx.recv() => {
-          tracing::info!("Editing task {}", id);
-          match edit {
-            // Some(TaskEditType::AddNotification(s)) => {
-            //     self.pending_notification.push(s);
-            // },
-            Some(TaskEditType::ShutdownTask) => {
-                tracing::info!("Task {} is shutdown", id);
-                return Ok(());
-            }
-            None => {
-                // Editor channel might be shutdown when the value is dropped
-                tracing::info!("Task {} is closed by other", id);
-                return Ok(());
-            }
-          }
-        }
+#[test]
+fn render_template_test() {
+  use chrono::TimeZone;
 
-        _ = self.ctrl_c_sig.changed() => {
-          tracing::info!("Task {} receive ctrl c signal, exiting...", id);
-          return Ok(())
-        }
+  let vars = TemplateVars {
+    // a Saturday
+    now: Tz::UTC.ymd(2024, 6, 1).and_hms(20, 5, 0),
+    count: 12,
+    group_title: "Rust <中文> 社区_1".to_string(),
+  };
+  assert_eq!(
+    render_template("{date} {time} {weekday} 第 {count} 期", &vars, None),
+    "2024-06-01 20:05 周六 第 12 期"
+  );
+  // unknown and unclosed placeholders are kept
+  assert_eq!(
+    render_template("{unknown} {count {date", &vars, None),
+    "{unknown} {count {date"
+  );
+  assert_eq!(
+    render_template("欢迎 {group_title}", &vars, None),
+    "欢迎 Rust <中文> 社区_1"
+  );
+  assert_eq!(
+    render_template("<b>{group_title}</b>", &vars, Some(ParseMode::Html)),
+    "<b>Rust &lt;中文&gt; 社区_1</b>"
+  );
+  assert_eq!(
+    render_template("*{group_title}*", &vars, Some(ParseMode::MarkdownV2)),
+    "*Rust <中文\\> 社区\\_1*"
+  );
 
-        // new ticker received
-        _ = ticker.tick() => {
-          tracing::trace!("schedule task {} start sending notification", id);
+  let poll = Notification::Poll {
+    question: "{date} 的 {group_title}".to_string(),
+    options: vec!["{count}".to_string(), "否".to_string()],
+    anonymous: true,
+    multiple_answers: false,
+  };
+  assert!(poll.contains_placeholder(GROUP_TITLE_PLACEHOLDER));
+  assert_eq!(
+    poll.render(&vars, Some(ParseMode::MarkdownV2)).to_string(),
+    "[投票] 2024-06-01 的 Rust <中文> 社区_1（12 / 否）"
+  );
+}
+
+/// SendOptions describe how the notification is sent
+#[derive(Clone, Debug, Default)]
+pub struct SendOptions {
+  pub buttons: Option<InlineKeyboardMarkup>,
+  pub parse_mode: Option<ParseMode>,
+  pub disable_preview: bool,
+  pub silent: bool,
+  pub protect_content: bool,
+  /// The forum topic to send into, differ from group to group. Teloxide 0.9 doesn't know the
+  /// `message_thread_id` field, but the thread id is the id of the first message of the topic,
+  /// and a reply to it lands in the topic. So the topic send is an ordinary reply, throttled
+  /// like any other send.
+  pub thread_id: Option<i32>,
+}
 
-          // clone once for move between thread
-          let text = Arc::new(self.pending_notification[0].to_owned());
-          let buttons = self.msg_buttons.as_ref().unwrap();
+/// Text of the message carrying the buttons of an album
+const ALBUM_BUTTONS_TEXT: &str = "👆";
 
-          for gid in self.groups.iter() {
-              let bot = bot.clone();
-              let text = text.clone();
-              let gid = gid.0;
-              let group_id = ChatId(gid);
-              tracing::trace!("Going to send {:?} to {:?}", text, gid);
-              bot.send_message(group_id, text.as_str())
-                  .reply_markup(buttons.clone())
-                  .await?;
+/// Send the notification to the chat with the options, return the ids of the sent messages
+pub async fn send_notification(
+  bot: &NotifyBot,
+  chat_id: ChatId,
+  notification: &Notification,
+  options: &SendOptions,
+) -> Result<Vec<i32>, RequestError> {
+  match notification {
+    Notification::Text(text) => {
+      let mut req = bot
+        .send_message(chat_id, text.as_str())
+        .disable_web_page_preview(options.disable_preview)
+        .disable_notification(options.silent)
+        .protect_content(options.protect_content);
+      if let Some(buttons) = &options.buttons {
+        req = req.reply_markup(buttons.clone());
+      }
+      if let Some(mode) = options.parse_mode {
+        req = req.parse_mode(mode);
+      }
+      if let Some(thread_id) = options.thread_id {
+        req = req.reply_to_message_id(thread_id);
+      }
+      req.await.map(|msg| vec![msg.id])
+    }
+    Notification::Photo { file_id, caption } => {
+      let mut req = bot
+        .send_photo(chat_id, InputFile::file_id(file_id.as_str()))
+        .disable_notification(options.silent)
+        .protect_content(options.protect_content);
+      if let Some(caption) = caption {
+        req = req.caption(caption.as_str());
+      }
+      if let Some(buttons) = &options.buttons {
+        req = req.reply_markup(buttons.clone());
+      }
+      if let Some(mode) = options.parse_mode {
+        req = req.parse_mode(mode);
+      }
+      if let Some(thread_id) = options.thread_id {
+        req = req.reply_to_message_id(thread_id);
+      }
+      req.await.map(|msg| vec![msg.id])
+    }
+    Notification::Copy {
+      from_chat,
+      message_id,
+    } => {
+      let mut req = bot
+        .copy_message(chat_id, *from_chat, *message_id)
+        .disable_notification(options.silent)
+        .protect_content(options.protect_content);
+      if let Some(buttons) = &options.buttons {
+        req = req.reply_markup(buttons.clone());
+      }
+      if let Some(thread_id) = options.thread_id {
+        req = req.reply_to_message_id(thread_id);
+      }
+      req.await.map(|id| vec![id.message_id])
+    }
+    Notification::Album { file_ids, caption } => {
+      let media = file_ids.iter().enumerate().map(|(i, file_id)| {
+        let mut photo = InputMediaPhoto::new(InputFile::file_id(file_id.as_str()));
+        if let (0, Some(caption)) = (i, caption) {
+          photo = photo.caption(caption.as_str());
+          if let Some(mode) = options.parse_mode {
+            photo = photo.parse_mode(mode);
           }
         }
+        InputMedia::Photo(photo)
+      });
+      let mut req = bot
+        .send_media_group(chat_id, media)
+        .disable_notification(options.silent)
+        .protect_content(options.protect_content);
+      if let Some(thread_id) = options.thread_id {
+        req = req.reply_to_message_id(thread_id);
+      }
+      let album = req.await?;
+      let mut ids: Vec<i32> = album.iter().map(|msg| msg.id).collect();
+      // media group can't carry the buttons, send them in a following message
+      if let Some(buttons) = &options.buttons {
+        let mut req = bot
+          .send_message(chat_id, ALBUM_BUTTONS_TEXT)
+          .reply_markup(buttons.clone())
+          .disable_notification(true)
+          .protect_content(options.protect_content);
+        if let Some(thread_id) = options.thread_id {
+          req = req.reply_to_message_id(thread_id);
+        }
+        let msg = req.await?;
+        ids.push(msg.id);
+      }
+      Ok(ids)
+    }
+    Notification::Poll {
+      question,
+      options: poll_options,
+      anonymous,
+      multiple_answers,
+    } => {
+      let mut req = bot
+        .send_poll(chat_id, question.as_str(), poll_options.iter().cloned())
+        .is_anonymous(*anonymous)
+        .allows_multiple_answers(*multiple_answers)
+        .disable_notification(options.silent)
+        .protect_content(options.protect_content);
+      if let Some(buttons) = &options.buttons {
+        req = req.reply_markup(buttons.clone());
+      }
+      if let Some(thread_id) = options.thread_id {
+        req = req.reply_to_message_id(thread_id);
+      }
+      req.await.map(|msg| vec![msg.id])
+    }
+  }
+}
+
+/// Test if the error means that the source message of the copy is gone
+fn is_copy_source_gone(e: &RequestError) -> bool {
+  match e {
+    RequestError::Api(ApiError::Unknown(s)) => s.contains("message to copy not found"),
+    _ => false,
+  }
+}
+
+/// Edit the sent message into the notification with the options
+async fn edit_notification(
+  bot: &NotifyBot,
+  chat_id: ChatId,
+  msg_id: i32,
+  notification: &Notification,
+  options: &SendOptions,
+) -> Result<Message, RequestError> {
+  match notification {
+    Notification::Text(text) => {
+      let mut req = bot
+        .edit_message_text(chat_id, msg_id, text.as_str())
+        .disable_web_page_preview(options.disable_preview);
+      if let Some(buttons) = &options.buttons {
+        req = req.reply_markup(buttons.clone());
+      }
+      if let Some(mode) = options.parse_mode {
+        req = req.parse_mode(mode);
+      }
+      req.await
+    }
+    Notification::Photo { file_id, caption } => {
+      let mut media = InputMediaPhoto::new(InputFile::file_id(file_id.as_str()));
+      if let Some(caption) = caption {
+        media = media.caption(caption.as_str());
+      }
+      if let Some(mode) = options.parse_mode {
+        media = media.parse_mode(mode);
+      }
+      let mut req = bot.edit_message_media(chat_id, msg_id, InputMedia::Photo(media));
+      if let Some(buttons) = &options.buttons {
+        req = req.reply_markup(buttons.clone());
+      }
+      req.await
+    }
+    // the copied message might be any kind of message, the album is several messages, and the
+    // poll can't be edited, send a new one instead
+    Notification::Copy { .. } | Notification::Album { .. } | Notification::Poll { .. } => {
+      Err(RequestError::Api(ApiError::MessageCantBeEdited))
+    }
+  }
+}
+
+/// The Bot API calls made by the running tasks. The tasks and the pool are generic over it,
+/// so the tests can record the calls instead of talking to Telegram.
+pub trait Messenger: Clone + Send + Sync + 'static {
+  fn send_notification<'a>(
+    &'a self,
+    chat_id: ChatId,
+    notification: &'a Notification,
+    options: &'a SendOptions,
+  ) -> BoxFuture<'a, Result<Vec<i32>, RequestError>>;
+
+  fn edit_notification<'a>(
+    &'a self,
+    chat_id: ChatId,
+    msg_id: i32,
+    notification: &'a Notification,
+    options: &'a SendOptions,
+  ) -> BoxFuture<'a, Result<(), RequestError>>;
+
+  fn send_text<'a>(
+    &'a self,
+    chat_id: ChatId,
+    text: &'a str,
+  ) -> BoxFuture<'a, Result<(), RequestError>>;
+
+  fn delete(&self, chat_id: ChatId, msg_id: i32) -> BoxFuture<'_, Result<(), RequestError>>;
+
+  fn close_poll(&self, chat_id: ChatId, msg_id: i32) -> BoxFuture<'_, Result<(), RequestError>>;
+
+  /// Pin the message without notifying the members
+  fn pin(&self, chat_id: ChatId, msg_id: i32) -> BoxFuture<'_, Result<(), RequestError>>;
+
+  fn unpin(&self, chat_id: ChatId, msg_id: i32) -> BoxFuture<'_, Result<(), RequestError>>;
+
+  /// Return the title of the chat
+  fn chat_title(&self, chat_id: ChatId) -> BoxFuture<'_, Result<String, RequestError>>;
+}
+
+impl Messenger for NotifyBot {
+  fn send_notification<'a>(
+    &'a self,
+    chat_id: ChatId,
+    notification: &'a Notification,
+    options: &'a SendOptions,
+  ) -> BoxFuture<'a, Result<Vec<i32>, RequestError>> {
+    Box::pin(send_notification(self, chat_id, notification, options))
+  }
+
+  fn edit_notification<'a>(
+    &'a self,
+    chat_id: ChatId,
+    msg_id: i32,
+    notification: &'a Notification,
+    options: &'a SendOptions,
+  ) -> BoxFuture<'a, Result<(), RequestError>> {
+    Box::pin(async move {
+      edit_notification(self, chat_id, msg_id, notification, options).await?;
+      Ok(())
+    })
+  }
+
+  fn send_text<'a>(
+    &'a self,
+    chat_id: ChatId,
+    text: &'a str,
+  ) -> BoxFuture<'a, Result<(), RequestError>> {
+    Box::pin(async move {
+      Requester::send_message(self, chat_id, text).await?;
+      Ok(())
+    })
+  }
+
+  fn delete(&self, chat_id: ChatId, msg_id: i32) -> BoxFuture<'_, Result<(), RequestError>> {
+    Box::pin(async move {
+      Requester::delete_message(self, chat_id, msg_id).await?;
+      Ok(())
+    })
+  }
+
+  fn close_poll(&self, chat_id: ChatId, msg_id: i32) -> BoxFuture<'_, Result<(), RequestError>> {
+    Box::pin(async move {
+      Requester::stop_poll(self, chat_id, msg_id).await?;
+      Ok(())
+    })
+  }
+
+  fn pin(&self, chat_id: ChatId, msg_id: i32) -> BoxFuture<'_, Result<(), RequestError>> {
+    Box::pin(async move {
+      Requester::pin_chat_message(self, chat_id, msg_id)
+        .disable_notification(true)
+        .await?;
+      Ok(())
+    })
+  }
+
+  fn unpin(&self, chat_id: ChatId, msg_id: i32) -> BoxFuture<'_, Result<(), RequestError>> {
+    Box::pin(async move {
+      Requester::unpin_chat_message(self, chat_id)
+        .message_id(msg_id)
+        .await?;
+      Ok(())
+    })
+  }
+
+  fn chat_title(&self, chat_id: ChatId) -> BoxFuture<'_, Result<String, RequestError>> {
+    Box::pin(async move {
+      let chat = Requester::get_chat(self, chat_id).await?;
+      Ok(
+        chat
+          .title()
+          .map_or_else(|| chat_id.0.to_string(), str::to_string),
+      )
+    })
+  }
+}
+
+/// Schedule describe when the task should send the notification
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(into = "ScheduleRepr", try_from = "ScheduleRepr")]
+pub enum Schedule {
+  /// Repeat with the fixed interval
+  Every(Duration),
+  /// Fire at the given wall clock time every day
+  Daily(NaiveTime),
+  /// Fire only once at the given time
+  Once(DateTime<Tz>),
+  /// Fire at the time matching the cron expression. `expr` is the original 5-field
+  /// expression input by user.
+  Cron {
+    expr: String,
+    schedule: cron::Schedule,
+  },
+}
+
+/// Serialized form of the schedule, the cron schedule is rebuilt from the expression
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ScheduleRepr {
+  /// Interval in seconds
+  Every(u64),
+  /// Time like `09:30:00`
+  Daily(String),
+  /// RFC 3339 time
+  Once(String),
+  Cron(String),
+}
+
+impl From<Schedule> for ScheduleRepr {
+  fn from(schedule: Schedule) -> Self {
+    match schedule {
+      Schedule::Every(period) => Self::Every(period.as_secs()),
+      Schedule::Daily(time) => Self::Daily(time.format("%H:%M:%S").to_string()),
+      Schedule::Once(at) => Self::Once(at.to_rfc3339()),
+      Schedule::Cron { expr, .. } => Self::Cron(expr),
+    }
+  }
+}
+
+impl TryFrom<ScheduleRepr> for Schedule {
+  type Error = anyhow::Error;
+
+  fn try_from(repr: ScheduleRepr) -> anyhow::Result<Self> {
+    Ok(match repr {
+      ScheduleRepr::Every(secs) => Self::Every(Duration::from_secs(secs)),
+      ScheduleRepr::Daily(time) => Self::Daily(NaiveTime::parse_from_str(&time, "%H:%M:%S")?),
+      ScheduleRepr::Once(at) => {
+        Self::Once(DateTime::parse_from_rfc3339(&at)?.with_timezone(&*clock::TIMEZONE))
+      }
+      ScheduleRepr::Cron(expr) => Self::from_cron(&expr)?,
+    })
+  }
+}
+
+/// Name of the 5 fields of a cron expression
+const CRON_FIELDS: [&str; 5] = ["minute", "hour", "day-of-month", "month", "day-of-week"];
+
+impl Schedule {
+  /// Parse a 5-field cron expression like `30 9 * * 1-5`. Day of week count from 0 (Sunday)
+  /// like the standard cron. Return error naming the invalid field.
+  pub fn from_cron(expr: &str) -> anyhow::Result<Self> {
+    let fields = expr.split_whitespace().collect::<Vec<&str>>();
+    if fields.len() != CRON_FIELDS.len() {
+      anyhow::bail!(
+        "cron expression needs 5 fields (minute hour day-of-month month day-of-week), got {}",
+        fields.len()
+      );
+    }
+
+    // check the fields one by one, so we can tell which one is invalid
+    for (i, name) in CRON_FIELDS.iter().enumerate() {
+      let mut probe = ["*"; 5];
+      probe[i] = fields[i];
+      if to_cron_schedule(&probe).is_err() {
+        anyhow::bail!("invalid {} field `{}`", name, fields[i]);
       }
     }
+
+    Ok(Self::Cron {
+      expr: fields.join(" "),
+      schedule: to_cron_schedule(&fields)?,
+    })
+  }
+
+  /// Return the next fire time after `now`. Return `None` if the schedule never fire again.
+  pub fn next_fire(&self, now: DateTime<Tz>) -> Option<DateTime<Tz>> {
+    match self {
+      Self::Every(period) => Some(now + chrono::Duration::from_std(*period).ok()?),
+      // the time might not exist in some day because of the DST, so try the next few days
+      Self::Daily(time) => (0..3)
+        .filter_map(|day| (now.date() + chrono::Duration::days(day)).and_time(*time))
+        .find(|next| *next > now),
+      Self::Once(at) => Some(*at).filter(|at| *at > now),
+      Self::Cron { schedule, .. } => schedule.after(&now).next(),
+    }
+  }
+
+  /// Test if the schedule is valid for running a task
+  pub(crate) fn is_valid(&self) -> bool {
+    match self {
+      Self::Every(period) => !period.is_zero(),
+      Self::Once(at) => *at > clock::now(),
+      Self::Daily(_) | Self::Cron { .. } => true,
+    }
+  }
+}
+
+impl Display for Schedule {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Every(period) => write!(f, "每 {}", format_duration(*period)),
+      Self::Daily(time) => write!(f, "每天 {}", time.format("%H:%M")),
+      Self::Once(at) => write!(f, "单次 {}", at.format("%Y-%m-%d %H:%M")),
+      Self::Cron { expr, .. } => write!(f, "cron `{expr}`"),
+    }
+  }
+}
+
+/// Format the duration like `1 天 12 小时 30 分钟`, zero parts are omitted
+pub fn format_duration(duration: Duration) -> String {
+  let secs = duration.as_secs();
+  let parts = [
+    (secs / 86400, "天"),
+    (secs % 86400 / 3600, "小时"),
+    (secs % 3600 / 60, "分钟"),
+    (secs % 60, "秒"),
+  ];
+  let text = parts
+    .iter()
+    .filter(|(n, _)| *n > 0)
+    .map(|(n, unit)| format!("{n} {unit}"))
+    .collect::<Vec<String>>()
+    .join(" ");
+  if text.is_empty() {
+    "0 秒".to_string()
+  } else {
+    text
+  }
+}
+
+/// Parse human friendly duration like `90m`, `2h`, `1d12h` or `1h30m`. Supported units are
+/// `d`, `h`, `m` and `s`. Every number should be followed by a unit.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+  let mut total: u64 = 0;
+  let mut num = String::new();
+  let mut has_unit = false;
+  for c in text.chars().filter(|c| !c.is_whitespace()) {
+    if c.is_ascii_digit() {
+      num.push(c);
+      continue;
+    }
+
+    let unit = match c.to_ascii_lowercase() {
+      'd' => 86400,
+      'h' => 3600,
+      'm' => 60,
+      's' => 1,
+      _ => return None,
+    };
+    let n = num.parse::<u64>().ok()?;
+    total = total.checked_add(n.checked_mul(unit)?)?;
+    num.clear();
+    has_unit = true;
+  }
+
+  if !has_unit || !num.is_empty() {
+    return None;
   }
+  Some(Duration::from_secs(total))
+}
+
+#[test]
+fn parse_duration_test() {
+  let secs = |text| parse_duration(text).map(|d| d.as_secs());
+  assert_eq!(secs("90m"), Some(5400));
+  assert_eq!(secs("2h"), Some(7200));
+  assert_eq!(secs("1d12h"), Some(129600));
+  assert_eq!(secs("1h30m"), Some(5400));
+  assert_eq!(secs("1H 30M"), Some(5400));
+  assert_eq!(secs("0m"), Some(0));
+
+  assert_eq!(secs("30"), None);
+  assert_eq!(secs("h"), None);
+  assert_eq!(secs("1h30"), None);
+  assert_eq!(secs("1w"), None);
+  assert_eq!(secs("一小时"), None);
+  assert_eq!(secs("99999999999999999999d"), None);
+
+  let schedule = Schedule::Every(parse_duration("1d12h").unwrap());
+  assert_eq!(schedule.to_string(), "每 1 天 12 小时");
+}
+
+/// Convert the 5-field cron expression for the cron crate, which require the second field and
+/// count the day of week from 1 (Sunday).
+fn to_cron_schedule(fields: &[&str]) -> anyhow::Result<cron::Schedule> {
+  let expr = format!(
+    "0 {} {} {} {} {}",
+    fields[0],
+    fields[1],
+    fields[2],
+    fields[3],
+    shift_day_of_week(fields[4])
+  );
+  Ok(cron::Schedule::from_str(&expr)?)
+}
+
+/// Shift the numeric day of week from the standard cron (0-7, both 0 and 7 are Sunday) to the
+/// cron crate (1-7, 1 is Sunday). Day names are kept as is.
+fn shift_day_of_week(field: &str) -> String {
+  let shift = |day: &str| match day.parse::<u8>() {
+    Ok(7) => "1".to_string(),
+    Ok(n) => (n + 1).to_string(),
+    Err(_) => day.to_string(),
+  };
+
+  field
+    .split(',')
+    .map(|part| {
+      let (days, step) = match part.split_once('/') {
+        Some((days, step)) => (days, Some(step)),
+        None => (part, None),
+      };
+      let days = match days.split_once('-') {
+        // Sunday at the end of the range wrap to the beginning
+        Some((start, "7")) => format!("{}-7,1", shift(start)),
+        Some((start, end)) => format!("{}-{}", shift(start), shift(end)),
+        None => shift(days),
+      };
+      match step {
+        Some(step) => format!("{days}/{step}"),
+        None => days,
+      }
+    })
+    .collect::<Vec<String>>()
+    .join(",")
+}
+
+/// A set of weekdays that the task is allowed to send notification
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WeekdaySet(u8);
+
+impl Default for WeekdaySet {
+  fn default() -> Self {
+    Self::ALL
+  }
+}
+
+impl WeekdaySet {
+  /// All the seven days
+  pub const ALL: Self = Self(0b111_1111);
+
+  /// Test if the day is in the set
+  pub fn contains(&self, day: Weekday) -> bool {
+    self.0 & (1 << day.num_days_from_monday()) != 0
+  }
+
+  /// Test if the set contains all the days
+  pub fn is_all(&self) -> bool {
+    *self == Self::ALL
+  }
+
+  fn insert(&mut self, day: Weekday) {
+    self.0 |= 1 << day.num_days_from_monday();
+  }
+
+  /// The days as bits, Monday is the lowest bit
+  #[cfg(feature = "sqlite")]
+  pub(crate) fn bits(&self) -> u8 {
+    self.0
+  }
+
+  #[cfg(feature = "sqlite")]
+  pub(crate) fn from_bits(bits: u8) -> Self {
+    Self(bits & Self::ALL.0)
+  }
+}
+
+impl FromStr for WeekdaySet {
+  type Err = anyhow::Error;
+
+  /// Parse days like `一三五`, `周一,周三,周五`, `mon,wed,fri` or `1,3,5` (7 is Sunday)
+  fn from_str(s: &str) -> anyhow::Result<Self> {
+    let mut set = Self(0);
+    for token in s
+      .split(|c: char| c.is_whitespace() || matches!(c, ',' | '，' | '、'))
+      .filter(|t| !t.is_empty())
+    {
+      if let Ok(day) = token.parse::<Weekday>() {
+        set.insert(day);
+        continue;
+      }
+
+      for c in token.chars().filter(|c| !matches!(c, '周' | '星' | '期')) {
+        let day = match c {
+          '一' | '1' => Weekday::Mon,
+          '二' | '2' => Weekday::Tue,
+          '三' | '3' => Weekday::Wed,
+          '四' | '4' => Weekday::Thu,
+          '五' | '5' => Weekday::Fri,
+          '六' | '6' => Weekday::Sat,
+          '日' | '天' | '7' => Weekday::Sun,
+          _ => anyhow::bail!("unknown weekday `{}`", token),
+        };
+        set.insert(day);
+      }
+    }
+
+    if set.0 == 0 {
+      anyhow::bail!("no weekday is given");
+    }
+    Ok(set)
+  }
+}
+
+impl Display for WeekdaySet {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    const NAMES: [&str; 7] = ["一", "二", "三", "四", "五", "六", "日"];
+    let days = NAMES
+      .iter()
+      .enumerate()
+      .filter(|(i, _)| self.0 & (1 << i) != 0)
+      .map(|(_, name)| *name)
+      .collect::<Vec<&str>>()
+      .join("、");
+    write!(f, "周{days}")
+  }
+}
+
+/// How the task picks the notification from the pool in every tick
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RotationMode {
+  /// One by one in the order of the pool
+  Sequential,
+  /// A random one in every tick
+  Random,
+  /// A random one other than the last one, if the pool has more than one notification
+  RandomNoRepeat,
+}
+
+impl Default for RotationMode {
+  fn default() -> Self {
+    Self::Sequential
+  }
+}
+
+impl RotationMode {
+  /// The mode after this one, for switching the mode with one button
+  pub fn next_mode(self) -> Self {
+    match self {
+      Self::Sequential => Self::Random,
+      Self::Random => Self::RandomNoRepeat,
+      Self::RandomNoRepeat => Self::Sequential,
+    }
+  }
+
+  /// Pick the index of the notification to send after the one at `current`. `weights` has the
+  /// weight of every notification in the pool. The sequential mode walks through
+  /// [`weighted_cycle`], and `slot` is the position of `current` in it.
+  pub fn next_index<R: Rng>(
+    self,
+    rng: &mut R,
+    current: usize,
+    weights: &[u32],
+    slot: &mut usize,
+  ) -> usize {
+    match self {
+      _ if weights.len() <= 1 => 0,
+      Self::Sequential => {
+        let cycle = weighted_cycle(weights);
+        *slot = (*slot + 1) % cycle.len();
+        cycle[*slot]
+      }
+      Self::Random => weighted_pick(rng, weights, None),
+      Self::RandomNoRepeat => weighted_pick(rng, weights, Some(current)),
+    }
+  }
+}
+
+/// The weight of a notification is 1 to this value
+pub const MAX_NOTIFICATION_WEIGHT: u32 = 100;
+
+/// Test if the weight can be given to a notification
+pub fn validate_weight(weight: u32) -> Result<()> {
+  if weight == 0 || weight > MAX_NOTIFICATION_WEIGHT {
+    return Err(Error::InvalidWeight(weight));
+  }
+  Ok(())
+}
+
+/// One round of the sequential rotation: every notification appears as many times as its
+/// weight, spread evenly by the smooth weighted round-robin. Equal weights give the pool order.
+pub fn weighted_cycle(weights: &[u32]) -> Vec<usize> {
+  let total: i64 = weights.iter().map(|&w| w as i64).sum();
+  let mut credits = vec![0i64; weights.len()];
+  let mut cycle = Vec::with_capacity(total as usize);
+  for _ in 0..total {
+    let mut chosen = 0;
+    for (i, &w) in weights.iter().enumerate() {
+      credits[i] += w as i64;
+      // the first one wins the tie, so the order of the pool is kept
+      if credits[i] > credits[chosen] {
+        chosen = i;
+      }
+    }
+    credits[chosen] -= total;
+    cycle.push(chosen);
+  }
+  cycle
+}
+
+/// Pick a random index with the chance proportional to the weight. `skip` is never picked,
+/// unless it is the only one.
+fn weighted_pick<R: Rng>(rng: &mut R, weights: &[u32], skip: Option<usize>) -> usize {
+  let weight = |i: usize| {
+    if Some(i) == skip {
+      0
+    } else {
+      weights[i] as u64
+    }
+  };
+  let total: u64 = (0..weights.len()).map(weight).sum();
+  if total == 0 {
+    return skip.unwrap_or(0);
+  }
+  let mut r = rng.gen_range(0..total);
+  for i in 0..weights.len() {
+    if r < weight(i) {
+      return i;
+    }
+    r -= weight(i);
+  }
+  unreachable!("the random number is less than the total weight")
+}
+
+impl Display for RotationMode {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let text = match self {
+      Self::Sequential => "顺序",
+      Self::Random => "随机",
+      Self::RandomNoRepeat => "随机（不连续重复）",
+    };
+    write!(f, "{text}")
+  }
+}
+
+/// TaskTiming describe when the task sent the notification and when will it send next time
+#[derive(Clone, Debug, Default)]
+pub struct TaskTiming {
+  /// `None` if the task never send any notification
+  pub last_sent: Option<DateTime<Tz>>,
+  pub next_fire: Option<DateTime<Tz>>,
+  /// Index of the notification to send next in the pool
+  pub cursor: usize,
+  /// Times the task has sent the notification since the bot started
+  pub fired: u64,
+}
+
+/// Counters about the notification delivery
+#[derive(Clone, Debug, Default)]
+pub struct DeliveryStats {
+  /// Total sends attempted
+  pub attempts: u64,
+  pub successes: u64,
+  pub failures: u64,
+  pub last_success: Option<DateTime<Tz>>,
+  pub last_failure: Option<DateTime<Tz>>,
+}
+
+impl DeliveryStats {
+  fn record(&mut self, ok: bool, now: DateTime<Tz>) {
+    self.attempts += 1;
+    if ok {
+      self.successes += 1;
+      self.last_success = Some(now);
+    } else {
+      self.failures += 1;
+      self.last_failure = Some(now);
+    }
+  }
+}
+
+/// Delivery statistics of a task. It only lives for the process lifetime.
+#[derive(Clone, Debug, Default)]
+pub struct TaskStats {
+  /// Statistics of all the groups
+  pub total: DeliveryStats,
+  /// Statistics of each group
+  pub groups: BTreeMap<ChatId, DeliveryStats>,
+}
+
+impl TaskStats {
+  /// Record the result of a broadcast
+  fn record(&mut self, groups: &[ChatId], failed: &[(ChatId, RequestError)]) {
+    let now = clock::now();
+    for gid in groups {
+      let ok = !failed.iter().any(|(g, _)| g == gid);
+      self.total.record(ok, now);
+      self.groups.entry(*gid).or_default().record(ok, now);
+    }
+  }
+}
+
+/// Press counts of the counter buttons of a task, keyed by the action. It only lives for the
+/// process lifetime.
+pub type ButtonCounters = HashMap<String, u32>;
+
+/// Prefix of the callback data of the counter buttons. The task stores `cb:<action>`, and the
+/// sent notification carries `cb:<task id>:<action>`.
+pub const COUNTER_BUTTON_PREFIX: &str = "cb:";
+
+/// Split the callback data of a sent counter button into the task id and the action
+pub fn parse_counter_button(data: &str) -> Option<(u32, &str)> {
+  let (id, action) = data.strip_prefix(COUNTER_BUTTON_PREFIX)?.split_once(':')?;
+  Some((id.parse().ok()?, action))
+}
+
+/// Strip the count appended to the label by [`bind_counter_buttons`]
+fn counter_base_label(label: &str) -> &str {
+  match label.rsplit_once(" (") {
+    Some((base, count))
+      if count.strip_suffix(')').map_or(false, |n| {
+        !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit())
+      }) =>
+    {
+      base
+    }
+    _ => label,
+  }
+}
+
+/// Bind the counter buttons of the keyboard to the task `id`, and show the current counts in
+/// their labels. The keyboard of a sent notification can be bound again to refresh the counts.
+pub fn bind_counter_buttons(
+  keyboard: &InlineKeyboardMarkup,
+  id: u32,
+  counters: &ButtonCounters,
+) -> InlineKeyboardMarkup {
+  let mut keyboard = keyboard.clone();
+  for button in keyboard.inline_keyboard.iter_mut().flatten() {
+    let data = match &mut button.kind {
+      InlineKeyboardButtonKind::CallbackData(data) => data,
+      _ => continue,
+    };
+    let action = match parse_counter_button(data) {
+      Some((_, action)) => action.to_string(),
+      None => match data.strip_prefix(COUNTER_BUTTON_PREFIX) {
+        Some(action) => action.to_string(),
+        None => continue,
+      },
+    };
+    let base = counter_base_label(&button.text).to_string();
+    button.text = match counters.get(&action) {
+      Some(count) if *count > 0 => format!("{base} ({count})"),
+      _ => base,
+    };
+    *data = format!("{COUNTER_BUTTON_PREFIX}{id}:{action}");
+  }
+  keyboard
+}
+
+#[test]
+fn bind_counter_buttons_test() {
+  use teloxide::types::InlineKeyboardButton;
+
+  let keyboard = InlineKeyboardMarkup::new(vec![vec![
+    InlineKeyboardButton::callback("报名", "cb:signup"),
+    InlineKeyboardButton::callback("请假", "cb:leave"),
+    InlineKeyboardButton::url("官网", "https://example.com".parse().unwrap()),
+  ]]);
+  let mut counters = ButtonCounters::new();
+  let bound = bind_counter_buttons(&keyboard, 7, &counters);
+  let row = &bound.inline_keyboard[0];
+  assert_eq!(row[0].text, "报名");
+  assert!(matches!(&row[0].kind, InlineKeyboardButtonKind::CallbackData(d) if d == "cb:7:signup"));
+  assert!(matches!(&row[2].kind, InlineKeyboardButtonKind::Url(_)));
+  assert_eq!(parse_counter_button("cb:7:signup"), Some((7, "signup")));
+  assert_eq!(parse_counter_button("cb:signup"), None);
+
+  // the sent keyboard is refreshed with the new counts
+  counters.insert("signup".to_string(), 3);
+  let bound = bind_counter_buttons(&bound, 7, &counters);
+  let row = &bound.inline_keyboard[0];
+  assert_eq!(row[0].text, "报名 (3)");
+  assert_eq!(row[1].text, "请假");
+  counters.insert("signup".to_string(), 4);
+  let bound = bind_counter_buttons(&bound, 7, &counters);
+  assert_eq!(bound.inline_keyboard[0][0].text, "报名 (4)");
+  assert!(matches!(
+    &bound.inline_keyboard[0][0].kind,
+    InlineKeyboardButtonKind::CallbackData(d) if d == "cb:7:signup"
+  ));
+}
+
+/// The content sent to one group instead of the notification from the pool, so the groups
+/// sharing a schedule can receive different text
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GroupOverride {
+  pub content: Notification,
+  /// Buttons attached to the content, `None` means using the buttons of the task
+  #[serde(default)]
+  pub buttons: Option<InlineKeyboardMarkup>,
+}
+
+/// Result of sending the notification once
+#[derive(Clone, Debug, Default)]
+pub struct SendReport {
+  /// Number of the groups reached
+  pub sent: usize,
+  /// The groups failed to reach, with the error
+  pub failed: Vec<(ChatId, String)>,
+}
+
+/// Result of importing one task
+#[derive(Debug)]
+pub struct ImportOutcome {
+  /// Id of the new task, or why the task is not imported
+  pub result: Result<u32>,
+  /// The groups of the task not in the whitelist, dropped from the new task
+  pub skipped_groups: Vec<ChatId>,
+}
+
+/// How long to wait for a removed task to finish its current send
+const TASK_STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl<M: Messenger> TaskPool<M> {
+  /// Create a new task pool with zero size vector. The whitelist is shared with the tasks
+  /// for skipping disabled groups and alerting maintainers, and saved to `whitelist_file`
+  /// after group migration. Tasks don't send notification during the quiet hours, and stop
+  /// when `shutdown_sig` is sent. The tasks are not saved until a store is set by
+  /// [`TaskPool::with_store`].
+  pub fn new(
+    bot: M,
+    whitelist: Arc<RwLock<Whitelist>>,
+    whitelist_file: Arc<WhitelistFile>,
+    quiet_hours: Option<QuietHours>,
+    shutdown_sig: watch::Receiver<u8>,
+  ) -> Self {
+    let pool: Arc<RwLock<HashMap<u32, TaskInfo>>> = Arc::new(RwLock::new(HashMap::new()));
+    let (events, mut events_rx) = mpsc::unbounded_channel();
+    let task_pool = Self {
+      pool,
+      next_id: Arc::default(),
+      bot,
+      retry_policy: RetryPolicy::from_env(),
+      send_delay: send_delay_from_env(),
+      quiet_hours,
+      whitelist,
+      whitelist_file,
+      shutdown_sig,
+      running: Arc::default(),
+      events,
+      store: Arc::new(RwLock::new(Arc::new(NoopStore))),
+      persist_lock: Arc::default(),
+    };
+
+    // apply the state changes made by the tasks themselves
+    let mut events_pool = task_pool.clone();
+    tokio::spawn(async move {
+      while let Some(event) = events_rx.recv().await {
+        match event {
+          TaskEvent::Finished(id) => {
+            tracing::info!("Task {} is finished, removing it from the pool", id);
+            events_pool.pool.write().remove(&id);
+            events_pool.unpersist(id).await;
+          }
+          TaskEvent::Paused(id) => {
+            tracing::info!("Task {} paused itself", id);
+            if let Some(task) = events_pool.pool.write().get_mut(&id) {
+              task.paused = true;
+            }
+            events_pool.persist().await;
+          }
+          TaskEvent::Migrated { from, to } => {
+            if let Err(e) = events_pool.migrate_group(from, to).await {
+              error!("Fail to migrate group {} to {}: {e}", from.0, to.0);
+            }
+          }
+        }
+      }
+    });
+
+    task_pool
+  }
+
+  /// Start numbering the new tasks from `id`, so the restored pool doesn't reuse the old ids
+  pub fn with_start_id(self, id: u32) -> Self {
+    self.next_id.store(id, Ordering::SeqCst);
+    self
+  }
+
+  /// Save the tasks into `store` after every change, and restore them from it by
+  /// [`TaskPool::restore`]
+  pub fn with_store(self, store: Arc<dyn TaskStore>) -> Self {
+    *self.store.write() = store;
+    self
+  }
+
+  /// Start the tasks saved in the store with their old ids, the new tasks are numbered after
+  /// them. The tasks that never send again, like the one-shot task in the past, are deleted
+  /// from the store instead. Return how many tasks are restored.
+  pub async fn restore(&mut self) -> Result<usize> {
+    let store = self.store();
+    let mut restored = 0;
+    for snapshot in store.load_all().await? {
+      let id = snapshot.id;
+      if snapshot.is_expired() {
+        tracing::info!("Task {} is expired, removing it from the store", id);
+        store.delete(id).await?;
+        continue;
+      }
+
+      self.next_id.fetch_max(id + 1, Ordering::SeqCst);
+      let task = self.spawn(id, snapshot.into_task(self.shutdown_sig.clone()));
+      self.pool.write().insert(id, task);
+      restored += 1;
+    }
+    Ok(restored)
+  }
+
+  /// Spawn a new task. It needs repeat interval, a list of groups to send message, and a init
+  /// text to notify. Return the id of the new task, or error if the task name is already used.
+  pub async fn add_task(&mut self, task: ScheduleTask) -> Result<u32> {
+    if !task.schedule.is_valid() {
+      return Err(Error::InvalidSchedule);
+    }
+    self.check_quiet_hours(&task.schedule)?;
+
+    // lock the pool and write to it
+    let id = {
+      let mut pool = self.pool.write();
+      if let Some(name) = &task.name {
+        if pool.values().any(|t| t.name.as_ref() == Some(name)) {
+          return Err(Error::TaskNameUsed(name.clone()));
+        }
+      }
+      let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+      pool.insert(id, self.spawn(id, task));
+      id
+    };
+
+    self.persist().await;
+    Ok(id)
+  }
+
+  /// Copy the notifications, buttons, options and groups of the specific task into a new
+  /// paused task owned by `creator`. The name is not copied as it must be unique. Return the id
+  /// of the new task.
+  pub async fn clone_task(&mut self, id: u32, creator: UserId) -> Result<u32> {
+    let mut snapshot = TaskSnapshot::new(id, &self.get_task(id)?);
+    snapshot.name = None;
+    snapshot.creator = Some(creator);
+    snapshot.paused = true;
+    snapshot.created_at = clock::now();
+    self
+      .add_task(snapshot.into_task(self.shutdown_sig.clone()))
+      .await
+  }
+
+  /// Add the exported tasks with new ids, in order. The groups not in the whitelist are dropped,
+  /// and the task is not imported if no group is left. One task failing to import doesn't stop
+  /// the others.
+  pub async fn import_tasks(&mut self, entries: Vec<Result<TaskSnapshot>>) -> Vec<ImportOutcome> {
+    let mut outcomes = Vec::with_capacity(entries.len());
+    for entry in entries {
+      let mut snapshot = match entry {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+          outcomes.push(ImportOutcome {
+            result: Err(e),
+            skipped_groups: Vec::new(),
+          });
+          continue;
+        }
+      };
+
+      let (groups, skipped_groups) = {
+        let whitelist = self.whitelist.read();
+        std::mem::take(&mut snapshot.groups)
+          .into_iter()
+          .partition::<Vec<ChatId>, _>(|group| whitelist.groups.contains(group))
+      };
+      snapshot.overrides.retain(|group, _| groups.contains(group));
+      snapshot.groups = groups;
+      let result = if snapshot.notifications.is_empty() {
+        Err(Error::NoNotification)
+      } else if snapshot.groups.is_empty() {
+        Err(Error::NoGroup)
+      } else if snapshot.is_expired() {
+        Err(Error::InvalidSchedule)
+      } else {
+        self
+          .add_task(snapshot.into_task(self.shutdown_sig.clone()))
+          .await
+      };
+      outcomes.push(ImportOutcome {
+        result,
+        skipped_groups,
+      });
+    }
+    outcomes
+  }
+
+  /// Fill in the states shared by the pool, then start the task
+  fn spawn(&self, id: u32, task: ScheduleTask) -> TaskInfo {
+    let send_delay = task.send_delay.unwrap_or(self.send_delay);
+    task
+      .send_delay(send_delay)
+      .retry_policy(self.retry_policy.clone())
+      .quiet_hours(self.quiet_hours)
+      .whitelist(Arc::clone(&self.whitelist))
+      .shutdown_sig(self.shutdown_sig.clone())
+      .running(Arc::clone(&self.running))
+      .events(self.events.clone())
+      .run(id, self.bot.clone())
+  }
+
+  fn store(&self) -> Arc<dyn TaskStore> {
+    Arc::clone(&self.store.read())
+  }
+
+  /// Return the snapshots of all the tasks, sorted by id
+  pub fn snapshots(&self) -> Vec<TaskSnapshot> {
+    let mut snapshots = self
+      .pool
+      .read()
+      .iter()
+      .map(|(&id, task)| TaskSnapshot::new(id, task))
+      .collect::<Vec<_>>();
+    snapshots.sort_unstable_by_key(|t| t.id);
+    snapshots
+  }
+
+  /// Save all the tasks into the store. The failure is logged only, as the change is applied
+  /// to the running task already.
+  async fn persist(&self) {
+    let _guard = self.persist_lock.lock().await;
+    // take the snapshot after the lock, so the last save always has the latest state
+    let snapshots = self.snapshots();
+    if let Err(e) = self.store().save_all(&snapshots).await {
+      error!("Fail to save the tasks: {e}");
+    }
+  }
+
+  /// Delete the removed task from the store
+  async fn unpersist(&self, id: u32) {
+    let _guard = self.persist_lock.lock().await;
+    if let Err(e) = self.store().delete(id).await {
+      error!("Fail to delete task {id} from the store: {e}");
+    }
+  }
+
+  /// Find the task by a numeric id or the task name. Numeric id takes precedence.
+  pub fn find_task(&self, key: &str) -> Option<u32> {
+    let pool = self.pool.read();
+    if let Ok(id) = key.parse::<u32>() {
+      if pool.contains_key(&id) {
+        return Some(id);
+      }
+    }
+
+    pool
+      .iter()
+      .find(|(_, task)| task.name.as_deref() == Some(key))
+      .map(|(id, _)| *id)
+  }
+
+  /// List current running task, return a list of (id, task information) sorted by id
+  pub fn list_task(&self) -> Vec<(u32, TaskInfo)> {
+    let pool = self.pool.read();
+
+    let mut tasks: Vec<(u32, TaskInfo)> = pool.iter().map(|x| (*(x.0), x.1.clone())).collect();
+    tasks.sort_unstable_by_key(|x| x.0);
+    tasks
+  }
+
+  /// Return a copy of the information about the specific task
+  pub fn get_task(&self, id: u32) -> Result<TaskInfo> {
+    self
+      .pool
+      .read()
+      .get(&id)
+      .cloned()
+      .ok_or(Error::TaskNotFound(id))
+  }
+
+  /// Count a press on the counter button `action` of the specific task, and return the counts
+  /// after the press. Return `None` if the task is gone or has no such button.
+  pub fn press_counter(&self, id: u32, action: &str) -> Option<ButtonCounters> {
+    let pool = self.pool.read();
+    let task = pool.get(&id)?;
+    let data = format!("{COUNTER_BUTTON_PREFIX}{action}");
+    let exists = task.buttons.as_ref().map_or(false, |keyboard| {
+      keyboard.inline_keyboard.iter().flatten().any(
+        |button| matches!(&button.kind, InlineKeyboardButtonKind::CallbackData(d) if *d == data),
+      )
+    });
+    if !exists {
+      return None;
+    }
+
+    let mut counters = task.counters.write();
+    *counters.entry(action.to_string()).or_default() += 1;
+    Some(counters.clone())
+  }
+
+  /// Return a copy of the delivery statistics of every task, sorted by task id
+  pub fn list_stats(&self) -> Vec<(u32, TaskStats)> {
+    let pool = self.pool.read();
+    let mut stats = pool
+      .iter()
+      .map(|(id, task)| (*id, task.stats.read().clone()))
+      .collect::<Vec<_>>();
+    stats.sort_unstable_by_key(|x| x.0);
+    stats
+  }
+
+  /// Return all the task id in ascending order
+  pub fn list_id(&self) -> Vec<u32> {
+    let mut ids = self.pool.read().keys().copied().collect::<Vec<u32>>();
+    ids.sort_unstable();
+    ids
+  }
+
+  /// Test if the task with the given id exist
+  pub fn has_task(&self, id: u32) -> bool {
+    self.pool.read().contains_key(&id)
+  }
+
+  /// Append a new notification with its weight into the pool of the specific task, return the
+  /// new pool size.
+  pub async fn add_notification(
+    &mut self,
+    id: u32,
+    notification: Notification,
+    weight: u32,
+  ) -> Result<usize> {
+    validate_weight(weight)?;
+    let editor = self.get_editor(id)?;
+    editor
+      .add_notification(notification.clone(), weight)
+      .await?;
+
+    let len = {
+      let mut pool = self.pool.write();
+      let task = Self::get_task_mut(&mut pool, id)?;
+      task.notifications.push(notification);
+      task.weights.push(weight);
+      task.notifications.len()
+    };
+    self.persist().await;
+    Ok(len)
+  }
+
+  /// Change the weight of the notification at `index` in the pool of the specific task
+  pub async fn set_weight(&mut self, id: u32, index: usize, weight: u32) -> Result<()> {
+    validate_weight(weight)?;
+    let editor = {
+      let pool = self.pool.read();
+      let task = pool.get(&id).ok_or(Error::TaskNotFound(id))?;
+      check_notification_index(&task.notifications, index)?;
+      task.editor.clone()
+    };
+
+    editor.set_weight(index, weight).await?;
+
+    Self::get_task_mut(&mut self.pool.write(), id)?.weights[index] = weight;
+    self.persist().await;
+    Ok(())
+  }
+
+  /// Return a copy of the notification pool of the specific task
+  pub fn list_notification(&self, id: u32) -> Result<Vec<Notification>> {
+    self
+      .pool
+      .read()
+      .get(&id)
+      .map(|task| task.notifications.clone())
+      .ok_or(Error::TaskNotFound(id))
+  }
+
+  /// Remove the notification at `index` from the pool of the specific task, return the new pool
+  /// size. The last notification in the pool can't be removed.
+  pub async fn remove_notification(&mut self, id: u32, index: usize) -> Result<usize> {
+    let editor = {
+      let pool = self.pool.read();
+      let task = pool.get(&id).ok_or(Error::TaskNotFound(id))?;
+      check_notification_index(&task.notifications, index)?;
+      if task.notifications.len() == 1 {
+        return Err(Error::LastNotification);
+      }
+      task.editor.clone()
+    };
+
+    editor.remove_notification(index).await?;
+
+    let len = {
+      let mut pool = self.pool.write();
+      let task = Self::get_task_mut(&mut pool, id)?;
+      task.notifications.remove(index);
+      task.weights.remove(index);
+      task.notifications.len()
+    };
+    self.persist().await;
+    Ok(len)
+  }
+
+  /// Replace the notification at `index` in the pool of the specific task with the new one
+  pub async fn replace_notification(
+    &mut self,
+    id: u32,
+    index: usize,
+    notification: Notification,
+  ) -> Result<()> {
+    let editor = {
+      let pool = self.pool.read();
+      let task = pool.get(&id).ok_or(Error::TaskNotFound(id))?;
+      check_notification_index(&task.notifications, index)?;
+      task.editor.clone()
+    };
+
+    editor
+      .replace_notification(index, notification.clone())
+      .await?;
+
+    Self::get_task_mut(&mut self.pool.write(), id)?.notifications[index] = notification;
+    self.persist().await;
+    Ok(())
+  }
+
+  /// Pause the specific task. Return `false` if the task is already paused.
+  pub async fn pause(&mut self, id: u32) -> Result<bool> {
+    self.set_paused(id, true).await
+  }
+
+  /// Resume the specific task. Return `false` if the task is not paused. The task without any
+  /// group is rejected.
+  pub async fn resume(&mut self, id: u32) -> Result<bool> {
+    if self.get_task(id)?.groups.is_empty() {
+      return Err(Error::NoGroup);
+    }
+    self.set_paused(id, false).await
+  }
+
+  async fn set_paused(&mut self, id: u32, paused: bool) -> Result<bool> {
+    let editor = {
+      let pool = self.pool.read();
+      let task = pool.get(&id).ok_or(Error::TaskNotFound(id))?;
+      if task.paused == paused {
+        return Ok(false);
+      }
+      task.editor.clone()
+    };
+
+    if paused {
+      editor.pause().await?;
+    } else {
+      editor.resume().await?;
+    }
+
+    Self::get_task_mut(&mut self.pool.write(), id)?.paused = paused;
+    self.persist().await;
+    Ok(true)
+  }
+
+  /// Send the notification of the specific task once now, the schedule is not affected
+  pub async fn send_now(&self, id: u32) -> Result<SendReport> {
+    let editor = self.get_editor(id)?;
+    editor.trigger_now().await
+  }
+
+  /// Change the schedule of the specific task
+  pub async fn set_schedule(&mut self, id: u32, schedule: Schedule) -> Result<()> {
+    if !schedule.is_valid() {
+      return Err(Error::InvalidSchedule);
+    }
+    self.check_quiet_hours(&schedule)?;
+
+    let editor = self.get_editor(id)?;
+    editor.change_schedule(schedule.clone()).await?;
+
+    Self::get_task_mut(&mut self.pool.write(), id)?.schedule = schedule;
+    self.persist().await;
+    Ok(())
+  }
+
+  /// Set the end time of the specific task. `None` means the task never end.
+  pub async fn set_ends_at(&mut self, id: u32, ends_at: Option<DateTime<Tz>>) -> Result<()> {
+    if ends_at.map_or(false, |t| t <= clock::now()) {
+      return Err(Error::InvalidEndTime);
+    }
+
+    let editor = self.get_editor(id)?;
+    editor.change_ends_at(ends_at).await?;
+
+    Self::get_task_mut(&mut self.pool.write(), id)?.ends_at = ends_at;
+    self.persist().await;
+    Ok(())
+  }
+
+  /// Replace the buttons of the specific task. `None` means remove all the buttons.
+  pub async fn set_buttons(
+    &mut self,
+    id: u32,
+    buttons: Option<InlineKeyboardMarkup>,
+  ) -> Result<()> {
+    let editor = self.get_editor(id)?;
+    editor.update_buttons(buttons.clone()).await?;
+
+    Self::get_task_mut(&mut self.pool.write(), id)?.buttons = buttons;
+    self.persist().await;
+    Ok(())
+  }
+
+  /// Send the notification of the specific task with or without sound
+  pub async fn set_silent(&mut self, id: u32, silent: bool) -> Result<()> {
+    let editor = self.get_editor(id)?;
+    editor.change_silent(silent).await?;
+
+    Self::get_task_mut(&mut self.pool.write(), id)?.silent = silent;
+    self.persist().await;
+    Ok(())
+  }
+
+  /// Change how the specific task picks the notification from the pool
+  pub async fn set_rotation(&mut self, id: u32, rotation: RotationMode) -> Result<()> {
+    let editor = self.get_editor(id)?;
+    editor.change_rotation(rotation).await?;
+
+    Self::get_task_mut(&mut self.pool.write(), id)?.rotation = rotation;
+    self.persist().await;
+    Ok(())
+  }
+
+  /// Forbid or allow forwarding the notification of the specific task
+  pub async fn set_protect_content(&mut self, id: u32, protect: bool) -> Result<()> {
+    let editor = self.get_editor(id)?;
+    editor.change_protect_content(protect).await?;
+
+    Self::get_task_mut(&mut self.pool.write(), id)?.protect_content = protect;
+    self.persist().await;
+    Ok(())
+  }
+
+  /// Pin the notification of the specific task after sending or not
+  pub async fn set_pin(&mut self, id: u32, pin: bool) -> Result<()> {
+    let editor = self.get_editor(id)?;
+    editor.change_pin(pin).await?;
+
+    Self::get_task_mut(&mut self.pool.write(), id)?.pin = pin;
+    self.persist().await;
+    Ok(())
+  }
+
+  /// Delete the previous notification of the specific task before sending or not
+  pub async fn set_delete_previous(&mut self, id: u32, delete: bool) -> Result<()> {
+    let editor = self.get_editor(id)?;
+    editor.change_delete_previous(delete).await?;
+
+    Self::get_task_mut(&mut self.pool.write(), id)?.delete_previous = delete;
+    self.persist().await;
+    Ok(())
+  }
+
+  /// Replace the target groups of the specific task, the overrides of the removed groups are
+  /// dropped
+  pub async fn set_groups(&mut self, id: u32, groups: Vec<ChatId>) -> Result<()> {
+    if groups.is_empty() {
+      return Err(Error::NoGroup);
+    }
+    self.replace_groups(id, groups).await
+  }
+
+  /// Replace the groups of the task and drop the overrides of the removed groups. The groups
+  /// can be empty here, for the task paused by [`TaskPool::remove_group`].
+  async fn replace_groups(&mut self, id: u32, groups: Vec<ChatId>) -> Result<()> {
+    let editor = self.get_editor(id)?;
+    editor.change_groups(groups.clone()).await?;
+
+    {
+      let mut pool = self.pool.write();
+      let task = Self::get_task_mut(&mut pool, id)?;
+      task.overrides.retain(|group, _| groups.contains(group));
+      task.groups = groups;
+    }
+    self.persist().await;
+    Ok(())
+  }
+
+  /// Send the content to the group instead of the notification from the pool. The group must
+  /// be one of the target groups of the task, the existing override is replaced.
+  pub async fn set_override(&mut self, id: u32, group: ChatId, value: GroupOverride) -> Result<()> {
+    let editor = {
+      let pool = self.pool.read();
+      let task = pool.get(&id).ok_or(Error::TaskNotFound(id))?;
+      if !task.groups.contains(&group) {
+        return Err(Error::GroupNotInTask { id, group });
+      }
+      task.editor.clone()
+    };
+
+    editor.change_override(group, Some(value.clone())).await?;
+
+    Self::get_task_mut(&mut self.pool.write(), id)?
+      .overrides
+      .insert(group, value);
+    self.persist().await;
+    Ok(())
+  }
+
+  /// Remove the override of the group, so it receives the notification from the pool again
+  pub async fn remove_override(&mut self, id: u32, group: ChatId) -> Result<()> {
+    let editor = {
+      let pool = self.pool.read();
+      let task = pool.get(&id).ok_or(Error::TaskNotFound(id))?;
+      if !task.overrides.contains_key(&group) {
+        return Err(Error::OverrideNotFound { id, group });
+      }
+      task.editor.clone()
+    };
+
+    editor.change_override(group, None).await?;
+
+    Self::get_task_mut(&mut self.pool.write(), id)?
+      .overrides
+      .remove(&group);
+    self.persist().await;
+    Ok(())
+  }
+
+  /// Rewrite the id of the group upgraded to supergroup, in the whitelist and every task, then
+  /// save the whitelist and alert the maintainers. Return `false` if the group is migrated
+  /// already.
+  pub async fn migrate_group(&mut self, from: ChatId, to: ChatId) -> Result<bool> {
+    let whitelisted = self.whitelist.write().migrate_group(from, to);
+    let affected = self
+      .pool
+      .read()
+      .iter()
+      .filter(|(_, task)| task.groups.contains(&from))
+      .map(|(&id, task)| (id, task.groups.clone(), task.overrides.get(&from).cloned()))
+      .collect::<Vec<_>>();
+    if !whitelisted && affected.is_empty() {
+      return Ok(false);
+    }
+
+    tracing::info!("Group {} is migrated to {}", from.0, to.0);
+    for (id, mut groups, value) in affected {
+      migrate_group(&mut groups, from, to);
+      self.set_groups(id, groups).await?;
+      // the override of the old id is dropped with the group, move it to the new id
+      if let Some(value) = value {
+        self.set_override(id, to, value).await?;
+      }
+    }
+    self.whitelist_file.save(&self.whitelist).await?;
+    let maintainers = self.whitelist.read().maintainers.clone();
+
+    let text = format!(
+      "群组 {} 已升级为超级群组，新的 id 为 {}，通知列表和任务已自动更新。",
+      from.0, to.0
+    );
+    alert_maintainers(&self.bot, &maintainers, &text).await;
+    Ok(true)
+  }
+
+  /// Remove the group and its override from every task. The task that only target this group
+  /// is paused, and can't be resumed until an admin set new groups. Return the ids of the paused
+  /// tasks.
+  pub async fn remove_group(&mut self, group: ChatId) -> Result<Vec<u32>> {
+    let affected = self
+      .pool
+      .read()
+      .iter()
+      .filter(|(_, task)| task.groups.contains(&group))
+      .map(|(&id, task)| {
+        let rest: Vec<ChatId> = task
+          .groups
+          .iter()
+          .copied()
+          .filter(|&g| g != group)
+          .collect();
+        (id, rest)
+      })
+      .collect::<Vec<_>>();
+
+    let mut paused = Vec::new();
+    for (id, rest) in affected {
+      if rest.is_empty() {
+        self.pause(id).await?;
+        paused.push(id);
+      }
+      self.replace_groups(id, rest).await?;
+    }
+    Ok(paused)
+  }
+
+  /// The one-shot task is never sent if its time is in the quiet hours, reject it up front
+  fn check_quiet_hours(&self, schedule: &Schedule) -> Result<()> {
+    match (schedule, self.quiet_hours) {
+      (Schedule::Once(at), Some(quiet)) if quiet.contains(at.time()) => {
+        Err(Error::InQuietHours(quiet))
+      }
+      _ => Ok(()),
+    }
+  }
+
+  /// Take a copy of the editor of the specific task. The editor should be copied out,
+  /// so we don't need to hold the lock across the await point.
+  fn get_editor(&self, id: u32) -> Result<Editor> {
+    self
+      .pool
+      .read()
+      .get(&id)
+      .map(|task| task.editor.clone())
+      .ok_or(Error::TaskNotFound(id))
+  }
+
+  /// Get the task info back after the edit message is sent
+  fn get_task_mut(pool: &mut HashMap<u32, TaskInfo>, id: u32) -> Result<&mut TaskInfo> {
+    pool.get_mut(&id).ok_or(Error::TaskNotFound(id))
+  }
+
+  fn remove_task(&mut self, index: u32) -> Result<TaskInfo> {
+    let mut pool = self.pool.write();
+    pool.remove(&index).ok_or(Error::TaskNotFound(index))
+  }
+
+  /// Wait until every task is stopped. Tasks finish the sends in flight before handling the
+  /// shutdown signal, so after the signal is sent, this returns once the last sends are done.
+  pub async fn wait_stopped(&self) {
+    let _guard = self.running.write().await;
+  }
+
+  /// Stop a task and remove it from pool. Return after the task is stopped, so it never send
+  /// again. If the task doesn't stop in time, it is aborted and a error is returned.
+  pub async fn remove(&mut self, index: u32) -> Result<()> {
+    let task = self.remove_task(index)?;
+    task.editor.shutdown().await;
+    self.unpersist(index).await;
+
+    if !task.wait_stopped().await {
+      return Err(Error::TaskStopTimeout(index));
+    }
+    Ok(())
+  }
+
+  /// Stop every task and clear the pool, return how many tasks are stopped. The tasks are
+  /// stopped at the same time, those not stopped in time are aborted.
+  pub async fn shutdown_all(&mut self) -> Result<usize> {
+    let tasks = self.pool.write().drain().collect::<Vec<(u32, TaskInfo)>>();
+    let count = tasks.len();
+
+    futures::future::join_all(tasks.into_iter().map(|(id, task)| async move {
+      // the task might be dead already, the error is logged only
+      task.editor.shutdown().await;
+      if !task.wait_stopped().await {
+        tracing::warn!("Task {id} is not stopped in {TASK_STOP_TIMEOUT:?}, aborted");
+      }
+    }))
+    .await;
+
+    Ok(count)
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct Editor {
+  /// Id of the task receiving the edits
+  id: u32,
+  tx: mpsc::Sender<TaskEditType>,
+}
+
+impl Editor {
+  async fn send(&self, edit: TaskEditType) -> Result<()> {
+    self
+      .tx
+      .send(edit)
+      .await
+      .map_err(|_| Error::TaskChannelClosed(self.id))
+  }
+
+  /// Append a new notification with its weight into the task's notification pool
+  pub async fn add_notification(&self, notification: Notification, weight: u32) -> Result<()> {
+    self
+      .send(TaskEditType::AddNotification {
+        notification,
+        weight,
+      })
+      .await
+  }
+
+  /// Change the weight of the notification at the given index
+  pub async fn set_weight(&self, index: usize, weight: u32) -> Result<()> {
+    self.send(TaskEditType::SetWeight { index, weight }).await
+  }
+
+  /// Remove the notification at the given index from the task's notification pool
+  pub async fn remove_notification(&self, index: usize) -> Result<()> {
+    self.send(TaskEditType::RemoveNotification(index)).await
+  }
+
+  /// Replace the notification at the given index in the task's notification pool
+  pub async fn replace_notification(&self, index: usize, notification: Notification) -> Result<()> {
+    self
+      .send(TaskEditType::ReplaceNotification {
+        index,
+        notification,
+      })
+      .await
+  }
+
+  /// Change the schedule of the task, the new schedule take effect immediately
+  pub async fn change_schedule(&self, schedule: Schedule) -> Result<()> {
+    self.send(TaskEditType::ChangeSchedule(schedule)).await
+  }
+
+  /// Change the end time of the task
+  pub async fn change_ends_at(&self, ends_at: Option<DateTime<Tz>>) -> Result<()> {
+    self.send(TaskEditType::ChangeEndsAt(ends_at)).await
+  }
+
+  /// Replace the buttons attached on the notification
+  pub async fn update_buttons(&self, buttons: Option<InlineKeyboardMarkup>) -> Result<()> {
+    self.send(TaskEditType::UpdateButtons(buttons)).await
+  }
+
+  /// Replace the groups that the notification will be sent to
+  pub async fn change_groups(&self, groups: Vec<ChatId>) -> Result<()> {
+    self.send(TaskEditType::ChangeGroups(groups)).await
+  }
+
+  /// Set or clear the content override of one group
+  pub async fn change_override(&self, group: ChatId, value: Option<GroupOverride>) -> Result<()> {
+    self
+      .send(TaskEditType::ChangeOverride { group, value })
+      .await
+  }
+
+  /// Send the notification with or without sound
+  pub async fn change_silent(&self, silent: bool) -> Result<()> {
+    self.send(TaskEditType::ChangeSilent(silent)).await
+  }
+
+  /// Pick the notification from the pool in the new way
+  pub async fn change_rotation(&self, rotation: RotationMode) -> Result<()> {
+    self.send(TaskEditType::ChangeRotation(rotation)).await
+  }
+
+  /// Forbid or allow forwarding and saving the notification
+  pub async fn change_protect_content(&self, protect: bool) -> Result<()> {
+    self.send(TaskEditType::ChangeProtectContent(protect)).await
+  }
+
+  /// Pin the notification after sending or not
+  pub async fn change_pin(&self, pin: bool) -> Result<()> {
+    self.send(TaskEditType::ChangePin(pin)).await
+  }
+
+  /// Delete the previous notification before sending or not
+  pub async fn change_delete_previous(&self, delete: bool) -> Result<()> {
+    self.send(TaskEditType::ChangeDeletePrevious(delete)).await
+  }
+
+  /// Stop sending notification until the task is resumed
+  pub async fn pause(&self) -> Result<()> {
+    self.send(TaskEditType::Pause).await
+  }
+
+  /// Continue sending notification
+  pub async fn resume(&self) -> Result<()> {
+    self.send(TaskEditType::Resume).await
+  }
+
+  /// Send the notification once immediately, and wait for the result
+  pub async fn trigger_now(&self) -> Result<SendReport> {
+    let (tx, rx) = oneshot::channel();
+    self.send(TaskEditType::TriggerNow(tx)).await?;
+    rx.await.map_err(|_| Error::TaskChannelClosed(self.id))
+  }
+
+  pub async fn shutdown(&self) {
+    if let Err(e) = self.send(TaskEditType::ShutdownTask).await {
+      error!("{e}")
+    }
+  }
+}
+
+/// A unit of a repeating notify task
+pub struct ScheduleTask {
+  /// Optional unique name of the task
+  name: Option<String>,
+  /// The admin who created this task
+  creator: Option<UserId>,
+  /// When to send the notification
+  schedule: Schedule,
+  /// The weekdays that the task is allowed to send notification
+  weekdays: WeekdaySet,
+  /// The task stop automatically after this time
+  ends_at: Option<DateTime<Tz>>,
+  /// A pool of notifications
+  pending_notification: Vec<Notification>,
+  /// The weight of each notification in the pool, missing ones are 1
+  weights: Vec<u32>,
+  /// Index of the next notification to send in the pool
+  cursor: usize,
+  /// Position of the cursor in the weighted cycle of the sequential rotation
+  slot: usize,
+  /// How the next notification is picked from the pool
+  rotation: RotationMode,
+  /// The task's own random source for the random rotation
+  rng: StdRng,
+  /// Paused task ignore the ticks
+  paused: bool,
+  /// When the task is created, kept across restarts
+  created_at: DateTime<Tz>,
+  /// Send once immediately after the task start, instead of waiting for the first fire time
+  send_immediately: bool,
+  /// Start the fixed interval ticker without the phase offset, so the tasks with the same
+  /// interval fire together
+  synchronized: bool,
+  /// What the fixed interval ticker does after a tick is missed because the sends are slow.
+  /// Default to `Delay`, so the missed ticks are not sent in a burst.
+  missed_tick_behavior: MissedTickBehavior,
+  /// A button set to attached on message
+  msg_buttons: Option<InlineKeyboardMarkup>,
+  /// How the notification text is formatted, `None` means plain text
+  parse_mode: Option<ParseMode>,
+  /// Don't attach the link preview to the notification
+  disable_web_page_preview: bool,
+  /// Send the notification without sound
+  silent: bool,
+  /// Forbid forwarding and saving the notification
+  protect_content: bool,
+  /// Pin the notification in the group after sending, and unpin the previous one
+  pin: bool,
+  /// Delete the previous notification in the group before sending the new one
+  delete_previous: bool,
+  /// Send the notification once, then edit the same message in every tick
+  edit_in_place: bool,
+  /// Close the previous poll in the group before sending the new notification
+  stop_previous_poll: bool,
+  /// A channel to edit this task
+  editor: mpsc::Sender<TaskEditType>,
+  /// A list of chat id
+  groups: Vec<ChatId>,
+  /// The content sent to the specific groups instead of the notification pool
+  overrides: BTreeMap<ChatId, GroupOverride>,
+  /// Consecutive delivery failures of each group
+  failures: HashMap<ChatId, u32>,
+  /// Ids of the last notification messages sent to each group, album has several messages
+  last_messages: HashMap<ChatId, Vec<i32>>,
+  /// Id of the last poll sent to each group
+  last_polls: HashMap<ChatId, i32>,
+  /// Groups that the pinning failure has been reported to maintainers
+  pin_failure_reported: HashSet<ChatId>,
+  /// Delivery statistics, shared with the TaskInfo
+  stats: Arc<RwLock<TaskStats>>,
+  /// Last sent time and next fire time, shared with the TaskInfo
+  timing: Arc<RwLock<TaskTiming>>,
+  /// Press counts of the counter buttons, shared with the TaskInfo
+  counters: Arc<RwLock<ButtonCounters>>,
+  /// How to retry the failed send
+  retry_policy: RetryPolicy,
+  /// Don't send notification in this time window
+  quiet_hours: Option<QuietHours>,
+  /// Delay between sending to two groups. `None` means using the default value of the pool.
+  send_delay: Option<Duration>,
+  /// Max number of the sends in flight
+  max_concurrency: usize,
+  /// Shared whitelist for looking up disabled groups and maintainers
+  whitelist: Arc<RwLock<Whitelist>>,
+  /// Read locked while the task is running, shared with the pool
+  running: Arc<tokio::sync::RwLock<()>>,
+  /// Notify the pool when the task is finished or paused by itself
+  events: Option<mpsc::UnboundedSender<TaskEvent>>,
+
+  // Temporary storage for channel receive, don't touch it!
+  editor_rx: mpsc::Receiver<TaskEditType>,
+  ctrl_c_sig: watch::Receiver<u8>,
+}
+
+/// Phase of the fixed interval task in `[0, period)`, derived from the task id. The fraction of
+/// the id times the golden ratio spreads the consecutive ids evenly over the period, and the
+/// task keeps its phase after restart.
+fn start_phase(id: u32, period: Duration) -> Duration {
+  let fraction = (id as f64 * 0.618_033_988_749_895).fract();
+  period.mul_f64(fraction)
+}
+
+/// The longest time the ticker sleep before checking the wall clock again
+const WALL_CLOCK_CHECK_PERIOD: Duration = Duration::from_secs(60);
+
+/// Ticker wait for the fire time of the schedule
+enum Ticker {
+  Every(tok_time::Interval),
+  /// Schedule based on the wall clock time. Fire once immediately if `immediate` is true.
+  WallClock {
+    schedule: Schedule,
+    immediate: bool,
+  },
+}
+
+impl Ticker {
+  /// Create a ticker for the schedule. Ticker fire once immediately if `immediate` is true,
+  /// otherwise it wait for the first fire time. One-shot schedule never fire immediately.
+  /// `missed` and `phase` only apply to the fixed interval, the wall clock schedules always skip
+  /// the missed fire time. The first tick comes `phase` earlier than a whole period.
+  fn new(
+    schedule: &Schedule,
+    immediate: bool,
+    missed: MissedTickBehavior,
+    phase: Duration,
+  ) -> Self {
+    match schedule {
+      Schedule::Every(period) => {
+        let start = if immediate {
+          tok_time::Instant::now()
+        } else {
+          tok_time::Instant::now() + period.saturating_sub(phase)
+        };
+        let mut interval = tok_time::interval_at(start, *period);
+        interval.set_missed_tick_behavior(missed);
+        Self::Every(interval)
+      }
+      Schedule::Once(_) => Self::WallClock {
+        schedule: schedule.clone(),
+        immediate: false,
+      },
+      Schedule::Daily(_) | Schedule::Cron { .. } => Self::WallClock {
+        schedule: schedule.clone(),
+        immediate,
+      },
+    }
+  }
+
+  /// Wait until the next fire time
+  async fn tick(&mut self) {
+    match self {
+      Self::Every(interval) => {
+        interval.tick().await;
+      }
+      Self::WallClock {
+        schedule,
+        immediate,
+      } => {
+        if *immediate {
+          *immediate = false;
+          return;
+        }
+
+        let target = match schedule.next_fire(clock::now()) {
+          Some(target) => target,
+          // the schedule never fire again
+          None => return std::future::pending().await,
+        };
+        // sleep in short steps and check the wall clock again, so the long sleep is not
+        // affected by the clock drift
+        while let Ok(wait) = (target - clock::now()).to_std() {
+          if wait.is_zero() {
+            break;
+          }
+          tok_time::sleep(wait.min(WALL_CLOCK_CHECK_PERIOD)).await;
+        }
+      }
+    }
+  }
+}
+
+/// TaskEvent describe the state changes made by the task itself
+#[derive(Debug)]
+pub enum TaskEvent {
+  /// The task is finished, and should be removed from the pool
+  Finished(u32),
+  /// The task paused itself
+  Paused(u32),
+  /// The task found that the group is upgraded to supergroup with the new id
+  Migrated { from: ChatId, to: ChatId },
+}
+
+/// Replace the migrated group id in the list. If the new id is in the list already, the old
+/// one is removed only. Return `false` if the old id is not in the list.
+pub fn migrate_group(groups: &mut Vec<ChatId>, from: ChatId, to: ChatId) -> bool {
+  let i = match groups.iter().position(|&g| g == from) {
+    Some(i) => i,
+    None => return false,
+  };
+  if groups.contains(&to) {
+    groups.remove(i);
+  } else {
+    groups[i] = to;
+  }
+  true
+}
+
+#[test]
+fn migrate_group_test() {
+  let mut groups = vec![ChatId(-1), ChatId(-2)];
+  assert!(migrate_group(&mut groups, ChatId(-1), ChatId(-1001)));
+  assert_eq!(groups, vec![ChatId(-1001), ChatId(-2)]);
+
+  // migrate again does nothing
+  assert!(!migrate_group(&mut groups, ChatId(-1), ChatId(-1001)));
+  assert_eq!(groups, vec![ChatId(-1001), ChatId(-2)]);
+
+  // the new id is added already, don't duplicate it
+  let mut groups = vec![ChatId(-2), ChatId(-1002)];
+  assert!(migrate_group(&mut groups, ChatId(-2), ChatId(-1002)));
+  assert_eq!(groups, vec![ChatId(-1002)]);
+}
+
+/// TaskEditType describe the behavior about updating the task.
+enum TaskEditType {
+  /// AddNotification describe a add notification behavior. It will add a new notification
+  /// with its weight into the task storage.
+  AddNotification {
+    notification: Notification,
+    weight: u32,
+  },
+  /// SetWeight describe that the notification at the given index should have a new weight
+  SetWeight { index: usize, weight: u32 },
+  /// RemoveNotification describe a remove notification behavior. It will remove the
+  /// notification at the given index from the task storage.
+  RemoveNotification(usize),
+  /// ReplaceNotification describe that the notification at the given index should be replaced
+  ReplaceNotification {
+    index: usize,
+    notification: Notification,
+  },
+  /// Pause describe that this task should stop sending notification, but keep alive
+  Pause,
+  /// Resume describe that this paused task should continue sending notification
+  Resume,
+  /// ChangeSchedule describe that this task should use a new schedule
+  ChangeSchedule(Schedule),
+  /// ChangeEndsAt describe that this task should stop at the new end time. `None` means never.
+  ChangeEndsAt(Option<DateTime<Tz>>),
+  /// UpdateButtons describe that this task should attach new buttons on the notification.
+  /// `None` means no buttons.
+  UpdateButtons(Option<InlineKeyboardMarkup>),
+  /// ChangeGroups describe that this task should send notification to the new groups
+  ChangeGroups(Vec<ChatId>),
+  /// ChangeOverride describe that this task should send the content to the group instead of
+  /// the notification from the pool. `None` means removing the override.
+  ChangeOverride {
+    group: ChatId,
+    value: Option<GroupOverride>,
+  },
+  /// ChangeSilent describe that this task should send notification with or without sound
+  ChangeSilent(bool),
+  /// ChangeProtectContent describe that this task should forbid forwarding the notification
+  ChangeProtectContent(bool),
+  /// ChangeRotation describe that this task should pick the notification in the new way
+  ChangeRotation(RotationMode),
+  /// ChangePin describe that this task should pin the notification or not
+  ChangePin(bool),
+  /// ChangeDeletePrevious describe that this task should delete the previous notification or not
+  ChangeDeletePrevious(bool),
+  /// TriggerNow describe that this task should send the next notification once immediately,
+  /// outside the schedule. The result is sent back through the channel.
+  TriggerNow(oneshot::Sender<SendReport>),
+  /// ShutdownTask describe that this task should be closed
+  ShutdownTask,
+}
+
+impl ScheduleTask {
+  pub fn new(ctrl_c_sig: watch::Receiver<u8>) -> Self {
+    let (editor, editor_rx) = mpsc::channel(5);
+    Self {
+      name: None,
+      creator: None,
+      schedule: Schedule::Every(Duration::ZERO),
+      weekdays: WeekdaySet::ALL,
+      ends_at: None,
+      pending_notification: Vec::new(),
+      weights: Vec::new(),
+      cursor: 0,
+      slot: 0,
+      rotation: RotationMode::Sequential,
+      rng: StdRng::from_entropy(),
+      paused: false,
+      created_at: clock::now(),
+      send_immediately: false,
+      synchronized: false,
+      missed_tick_behavior: MissedTickBehavior::Delay,
+      msg_buttons: None,
+      parse_mode: None,
+      disable_web_page_preview: true,
+      silent: false,
+      protect_content: false,
+      pin: false,
+      delete_previous: false,
+      edit_in_place: false,
+      stop_previous_poll: false,
+      groups: Vec::new(),
+      overrides: BTreeMap::new(),
+      failures: HashMap::new(),
+      last_messages: HashMap::new(),
+      last_polls: HashMap::new(),
+      pin_failure_reported: HashSet::new(),
+      stats: Arc::new(RwLock::new(TaskStats::default())),
+      timing: Arc::new(RwLock::new(TaskTiming::default())),
+      counters: Arc::default(),
+      retry_policy: RetryPolicy::default(),
+      quiet_hours: None,
+      send_delay: None,
+      max_concurrency: DEFAULT_SEND_CONCURRENCY,
+      whitelist: Arc::new(RwLock::new(Whitelist::new())),
+      running: Arc::default(),
+      events: None,
+
+      editor,
+      editor_rx,
+
+      ctrl_c_sig,
+    }
+  }
+
+  pub fn name(mut self, name: Option<String>) -> Self {
+    self.name = name;
+    self
+  }
+
+  pub fn creator(mut self, user: UserId) -> Self {
+    self.creator = Some(user);
+    self
+  }
+
+  pub fn schedule(mut self, schedule: Schedule) -> Self {
+    self.schedule = schedule;
+    self
+  }
+
+  /// Start the task paused
+  pub fn paused(mut self, paused: bool) -> Self {
+    self.paused = paused;
+    self
+  }
+
+  pub fn created_at(mut self, time: DateTime<Tz>) -> Self {
+    self.created_at = time;
+    self
+  }
+
+  pub fn send_immediately(mut self, immediately: bool) -> Self {
+    self.send_immediately = immediately;
+    self
+  }
+
+  /// Fire the fixed interval task without the phase offset. By default the first tick is shifted
+  /// by a phase derived from the task id, so the tasks started together don't send at the same
+  /// time forever.
+  pub fn synchronized(mut self, synchronized: bool) -> Self {
+    self.synchronized = synchronized;
+    self
+  }
+
+  /// Use `MissedTickBehavior::Burst` for the tasks that should catch up the missed ticks
+  pub fn missed_tick_behavior(mut self, behavior: MissedTickBehavior) -> Self {
+    self.missed_tick_behavior = behavior;
+    self
+  }
+
+  pub fn weekdays(mut self, weekdays: WeekdaySet) -> Self {
+    self.weekdays = weekdays;
+    self
+  }
+
+  pub fn ends_at(mut self, ends_at: DateTime<Tz>) -> Self {
+    self.ends_at = Some(ends_at);
+    self
+  }
+
+  pub fn pending_notification(mut self, pn: Vec<Notification>) -> Self {
+    self.pending_notification = pn;
+    self
+  }
+
+  /// The weight of each notification in the pool, missing ones are 1
+  pub fn weights(mut self, weights: Vec<u32>) -> Self {
+    self.weights = weights;
+    self
+  }
+
+  pub fn msg_buttons(mut self, btn: InlineKeyboardMarkup) -> Self {
+    self.msg_buttons = Some(btn);
+    self
+  }
+
+  pub fn rotation(mut self, rotation: RotationMode) -> Self {
+    self.rotation = rotation;
+    self
+  }
+
+  pub fn parse_mode(mut self, mode: Option<ParseMode>) -> Self {
+    self.parse_mode = mode;
+    self
+  }
+
+  pub fn disable_web_page_preview(mut self, disable: bool) -> Self {
+    self.disable_web_page_preview = disable;
+    self
+  }
+
+  pub fn silent(mut self, silent: bool) -> Self {
+    self.silent = silent;
+    self
+  }
+
+  pub fn protect_content(mut self, protect: bool) -> Self {
+    self.protect_content = protect;
+    self
+  }
+
+  pub fn pin(mut self, pin: bool) -> Self {
+    self.pin = pin;
+    self
+  }
+
+  pub fn delete_previous(mut self, delete: bool) -> Self {
+    self.delete_previous = delete;
+    self
+  }
+
+  pub fn edit_in_place(mut self, edit: bool) -> Self {
+    self.edit_in_place = edit;
+    self
+  }
+
+  pub fn stop_previous_poll(mut self, stop: bool) -> Self {
+    self.stop_previous_poll = stop;
+    self
+  }
+
+  pub fn groups(mut self, groups: Vec<ChatId>) -> Self {
+    self.groups = groups;
+    self
+  }
+
+  pub fn overrides(mut self, overrides: BTreeMap<ChatId, GroupOverride>) -> Self {
+    self.overrides = overrides;
+    self
+  }
+
+  pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+    self.retry_policy = policy;
+    self
+  }
+
+  pub fn send_delay(mut self, delay: Duration) -> Self {
+    self.send_delay = Some(delay);
+    self
+  }
+
+  pub fn max_concurrency(mut self, n: usize) -> Self {
+    self.max_concurrency = n.max(1);
+    self
+  }
+
+  pub fn quiet_hours(mut self, quiet_hours: Option<QuietHours>) -> Self {
+    self.quiet_hours = quiet_hours;
+    self
+  }
+
+  pub fn whitelist(mut self, whitelist: Arc<RwLock<Whitelist>>) -> Self {
+    self.whitelist = whitelist;
+    self
+  }
+
+  pub fn shutdown_sig(mut self, sig: watch::Receiver<u8>) -> Self {
+    self.ctrl_c_sig = sig;
+    self
+  }
+
+  pub fn running(mut self, running: Arc<tokio::sync::RwLock<()>>) -> Self {
+    self.running = running;
+    self
+  }
+
+  pub fn events(mut self, events: mpsc::UnboundedSender<TaskEvent>) -> Self {
+    self.events = Some(events);
+    self
+  }
+
+  /// Spawn a new tokio task to run a forever loop. It will notify when the ticker send a tick.
+  /// Task will consume itself and return necessary information about the task
+  pub fn run<M: Messenger>(mut self, id: u32, bot: M) -> TaskInfo {
+    // zero interval makes the ticker spin forever, use the shortest interval instead
+    if let Schedule::Every(interval) = &mut self.schedule {
+      if interval.is_zero() {
+        warn!(
+          "Task {} has zero interval, fallback to {:?}",
+          id, DEFAULT_MIN_INTERVAL
+        );
+        *interval = DEFAULT_MIN_INTERVAL;
+      }
+    }
+
+    self.sync_slot();
+    // the random rotation doesn't always start from the first notification
+    if self.rotation != RotationMode::Sequential && !self.pending_notification.is_empty() {
+      self.cursor = weighted_pick(&mut self.rng, &self.weights, None);
+      self.timing.write().cursor = self.cursor;
+    }
+
+    // copy the notifications for describing this task
+    let notifications = self.pending_notification.clone();
+    let weights = self.weights.clone();
+    let name = self.name.clone();
+    let creator = self.creator;
+    let editor = self.editor.clone();
+    let schedule = self.schedule.clone();
+    let weekdays = self.weekdays;
+    let ends_at = self.ends_at;
+    let paused = self.paused;
+    let buttons = self.msg_buttons.clone();
+    let parse_mode = self.parse_mode;
+    let rotation = self.rotation;
+    let disable_web_page_preview = self.disable_web_page_preview;
+    let silent = self.silent;
+    let protect_content = self.protect_content;
+    let pin = self.pin;
+    let delete_previous = self.delete_previous;
+    let edit_in_place = self.edit_in_place;
+    let stop_previous_poll = self.stop_previous_poll;
+    let synchronized = self.synchronized;
+    let groups = self.groups.clone();
+    let overrides = self.overrides.clone();
+    let stats = Arc::clone(&self.stats);
+    let created_at = self.created_at;
+    let timing = Arc::clone(&self.timing);
+    let counters = Arc::clone(&self.counters);
+    let phase = self.phase(id);
+    timing.write().next_fire = match &schedule {
+      // one-shot task ignore the immediate send
+      Schedule::Once(_) => schedule.next_fire(clock::now()),
+      _ if self.send_immediately => Some(clock::now()),
+      _ => schedule
+        .next_fire(clock::now())
+        .zip(chrono::Duration::from_std(phase).ok())
+        .map(|(next, phase)| next - phase),
+    };
+
+    // take the guard before spawning, so the pool never miss a task that hasn't started yet.
+    // It fails only when the pool is shutting down, and the task exits immediately then.
+    let running = Arc::clone(&self.running).try_read_owned().ok();
+    // move self into the new tokio task
+    let handle = tokio::spawn(self.into_background(id, bot, running));
+
+    TaskInfo {
+      name,
+      creator,
+      schedule,
+      weekdays,
+      ends_at,
+      notifications,
+      weights,
+      buttons,
+      parse_mode,
+      rotation,
+      disable_web_page_preview,
+      silent,
+      protect_content,
+      pin,
+      delete_previous,
+      edit_in_place,
+      stop_previous_poll,
+      synchronized,
+      groups,
+      overrides,
+      paused,
+      stats,
+      timing,
+      counters,
+      created_at,
+      editor: Editor { id, tx: editor },
+      handle: Arc::new(Mutex::new(Some(handle))),
+    }
+  }
+
+  /// Return the notification under the cursor, and move the cursor to the next one picked by
+  /// the rotation mode. In sequential mode the cursor wrap around when it reach the end of the
+  /// pool.
+  fn next_notification(&mut self) -> &Notification {
+    self.normalize_weights();
+    let i = self.cursor % self.pending_notification.len();
+    self.cursor = self
+      .rotation
+      .next_index(&mut self.rng, i, &self.weights, &mut self.slot);
+    self.timing.write().cursor = self.cursor;
+    &self.pending_notification[i]
+  }
+
+  /// Offset of the first tick of the fixed interval schedule, zero for the synchronized task
+  fn phase(&self, id: u32) -> Duration {
+    match self.schedule {
+      Schedule::Every(period) if !self.synchronized => start_phase(id, period),
+      _ => Duration::ZERO,
+    }
+  }
+
+  /// Give every notification a valid weight, 1 for the missing ones
+  fn normalize_weights(&mut self) {
+    self.weights.resize(self.pending_notification.len(), 1);
+    for weight in &mut self.weights {
+      *weight = (*weight).clamp(1, MAX_NOTIFICATION_WEIGHT);
+    }
+  }
+
+  /// Move the slot to where the cursor is in the weighted cycle, after the pool or the weights
+  /// are changed
+  fn sync_slot(&mut self) {
+    self.normalize_weights();
+    self.slot = weighted_cycle(&self.weights)
+      .iter()
+      .position(|&i| i == self.cursor)
+      .unwrap_or(0);
+  }
+
+  /// Remove the notification at the given index. The cursor is adjusted to keep pointing
+  /// at the same next notification. Invalid index or removing the last notification is ignored.
+  fn remove_notification(&mut self, index: usize) {
+    let len = self.pending_notification.len();
+    if index >= len || len == 1 {
+      return;
+    }
+    self.pending_notification.remove(index);
+    if index < self.weights.len() {
+      self.weights.remove(index);
+    }
+    if index < self.cursor {
+      self.cursor -= 1;
+    }
+    if self.cursor >= self.pending_notification.len() {
+      self.cursor = 0;
+    }
+    self.timing.write().cursor = self.cursor;
+    self.sync_slot();
+  }
+
+  /// The `running` guard is released when the task returns, so the pool knows it is stopped
+  async fn into_background<M: Messenger>(
+    mut self,
+    id: u32,
+    bot: M,
+    _running: Option<tokio::sync::OwnedRwLockReadGuard<()>>,
+  ) -> Result<()> {
+    if *self.ctrl_c_sig.borrow() != 0 {
+      tracing::info!("Task {} is started after shutdown, exiting...", id);
+      return Ok(());
+    }
+
+    let mut ticker = Ticker::new(
+      &self.schedule,
+      self.send_immediately,
+      self.missed_tick_behavior,
+      self.phase(id),
+    );
+    loop {
+      tokio::select! {
+        // receive edit message
+        edit = self.editor_rx.recv() => {
+          tracing::info!("Editing task {}", id);
+          match edit {
+            Some(TaskEditType::AddNotification { notification, weight }) => {
+                // new notification is appended to the tail, so the cursor is still valid
+                self.normalize_weights();
+                self.pending_notification.push(notification);
+                self.weights.push(weight);
+                self.sync_slot();
+            },
+            Some(TaskEditType::SetWeight { index, weight }) => {
+                self.normalize_weights();
+                if let Some(w) = self.weights.get_mut(index) {
+                    *w = weight;
+                }
+                self.sync_slot();
+            },
+            Some(TaskEditType::RemoveNotification(i)) => {
+                self.remove_notification(i);
+            },
+            Some(TaskEditType::ReplaceNotification { index, notification }) => {
+                if let Some(notify) = self.pending_notification.get_mut(index) {
+                    *notify = notification;
+                }
+            },
+            Some(TaskEditType::Pause) => {
+                tracing::info!("Task {} is paused", id);
+                self.paused = true;
+            },
+            Some(TaskEditType::Resume) => {
+                tracing::info!("Task {} is resumed", id);
+                self.paused = false;
+            },
+            Some(TaskEditType::UpdateButtons(buttons)) => {
+                tracing::info!("Task {} update buttons", id);
+                self.msg_buttons = buttons;
+            },
+            Some(TaskEditType::ChangeGroups(groups)) => {
+                tracing::info!("Task {} change groups to {:?}", id, groups);
+                self.last_messages.retain(|gid, _| groups.contains(gid));
+                self.overrides.retain(|gid, _| groups.contains(gid));
+                self.groups = groups;
+            },
+            Some(TaskEditType::ChangeOverride { group, value }) => {
+                tracing::info!("Task {} change override of {}", id, group.0);
+                match value {
+                    Some(value) => self.overrides.insert(group, value),
+                    None => self.overrides.remove(&group),
+                };
+            },
+            Some(TaskEditType::ChangeSilent(silent)) => {
+                tracing::info!("Task {} change silent mode to {}", id, silent);
+                self.silent = silent;
+            },
+            Some(TaskEditType::ChangeProtectContent(protect)) => {
+                tracing::info!("Task {} change content protection to {}", id, protect);
+                self.protect_content = protect;
+            },
+            Some(TaskEditType::ChangeRotation(rotation)) => {
+                tracing::info!("Task {} change rotation mode to {:?}", id, rotation);
+                self.rotation = rotation;
+                self.sync_slot();
+            },
+            Some(TaskEditType::ChangePin(pin)) => {
+                tracing::info!("Task {} change pinning to {}", id, pin);
+                self.pin = pin;
+            },
+            Some(TaskEditType::ChangeDeletePrevious(delete)) => {
+                tracing::info!("Task {} change deleting previous notification to {}", id, delete);
+                self.delete_previous = delete;
+            },
+            Some(TaskEditType::ChangeSchedule(schedule)) => {
+                tracing::info!("Task {} change schedule to {}", id, schedule);
+                self.schedule = schedule;
+                // rebuild the ticker, so we don't need to wait for the old period
+                let phase = self.phase(id);
+                ticker = Ticker::new(&self.schedule, false, self.missed_tick_behavior, phase);
+                self.set_next_fire();
+                // the first tick comes `phase` earlier, like the one after start
+                if let Ok(phase) = chrono::Duration::from_std(phase) {
+                  let mut timing = self.timing.write();
+                  timing.next_fire = timing.next_fire.map(|next| next - phase);
+                }
+            },
+            Some(TaskEditType::ChangeEndsAt(ends_at)) => {
+                tracing::info!("Task {} change end time to {:?}", id, ends_at);
+                self.ends_at = ends_at;
+            },
+            Some(TaskEditType::TriggerNow(reply)) => {
+                // the ticker is untouched, the regular sends keep their time
+                tracing::info!("Task {} is triggered to send now", id);
+                let report = self.send_once(id, &bot).await;
+                let _ = reply.send(report);
+            },
+            Some(TaskEditType::ShutdownTask) => {
+                tracing::info!("Task {} is shutdown", id);
+                return Ok(());
+            }
+            None => {
+                // Editor channel might be shutdown when the value is dropped
+                tracing::info!("Task {} is closed by other", id);
+                return Ok(());
+            }
+          }
+        }
+
+        _ = self.ctrl_c_sig.changed() => {
+          tracing::info!("Task {} receive ctrl c signal, exiting...", id);
+          return Ok(())
+        }
+
+        // new ticker received
+        _ = ticker.tick() => {
+          // both branches might be ready, never start sending after the shutdown
+          if *self.ctrl_c_sig.borrow() != 0 {
+            tracing::info!("Task {} receive shutdown signal, exiting...", id);
+            return Ok(());
+          }
+          self.set_next_fire();
+
+          if self.ends_at.map_or(false, |t| t <= clock::now()) {
+            tracing::info!("Task {} reach the end time", id);
+            self.notify_creator(&bot, format!("任务 {id} 已到达结束时间，已自动停止。")).await;
+            self.finish(id);
+            return Ok(());
+          }
+
+          if self.paused {
+            tracing::trace!("schedule task {} is paused, skip this tick", id);
+            if self.finish_skipped_once(id, &bot, "任务处于暂停状态").await {
+              return Ok(());
+            }
+            continue;
+          }
+
+          let now = clock::now();
+          let today = now.weekday();
+          if !self.weekdays.contains(today) {
+            tracing::debug!("schedule task {} is not enabled on {}, skip this tick", id, today);
+            if self.finish_skipped_once(id, &bot, "当天不在任务的发送日").await {
+              return Ok(());
+            }
+            continue;
+          }
+
+          if self.quiet_hours.map_or(false, |q| q.contains(now.time())) {
+            tracing::debug!("schedule task {} is in the quiet hours, skip this tick", id);
+            if self.finish_skipped_once(id, &bot, "处于免打扰时段").await {
+              return Ok(());
+            }
+            continue;
+          }
+
+          tracing::trace!("schedule task {} start sending notification", id);
+
+          self.send_once(id, &bot).await;
+
+          // one-shot task deregister itself after sending
+          if let Schedule::Once(_) = self.schedule {
+            tracing::info!("One-shot task {} is done", id);
+            self.finish(id);
+            return Ok(());
+          }
+        }
+      }
+    }
+  }
+
+  /// Send the next notification to the active groups once, then record the result. Both the
+  /// ticks and the [`TaskEditType::TriggerNow`] go through here.
+  async fn send_once<M: Messenger>(&mut self, id: u32, bot: &M) -> SendReport {
+    // clone once for move between thread
+    let notification = self.next_notification().clone();
+    let vars = TemplateVars {
+      now: clock::now(),
+      count: {
+        let mut timing = self.timing.write();
+        timing.fired += 1;
+        timing.fired
+      },
+      group_title: String::new(),
+    };
+    let options = SendOptions {
+      buttons: self
+        .msg_buttons
+        .as_ref()
+        .map(|keyboard| bind_counter_buttons(keyboard, id, &self.counters.read())),
+      parse_mode: self.parse_mode,
+      disable_preview: self.disable_web_page_preview,
+      silent: self.silent,
+      protect_content: self.protect_content,
+      thread_id: None,
+    };
+    let overrides = self
+      .overrides
+      .iter()
+      .map(|(&gid, value)| {
+        let buttons = value
+          .buttons
+          .as_ref()
+          .map(|keyboard| bind_counter_buttons(keyboard, id, &self.counters.read()));
+        (
+          gid,
+          GroupOverride {
+            content: value.content.clone(),
+            buttons,
+          },
+        )
+      })
+      .collect::<BTreeMap<_, _>>();
+    let topics = self.whitelist.read().topics.clone();
+    let (notification, options, overrides, topics, vars) =
+      (&notification, &options, &overrides, &topics, &vars);
+    // previous notifications waiting for deletion, each of them is taken by the first
+    // attempt, so the retries don't delete again
+    let stale = parking_lot::Mutex::new(if self.delete_previous && !self.edit_in_place {
+      self.last_messages.clone()
+    } else {
+      HashMap::new()
+    });
+
+    // all the retries should be done before next tick
+    let deadline = tok_time::Instant::now() + self.until_next_fire();
+    let groups = self.active_groups();
+    let delay = self.send_delay.unwrap_or(DEFAULT_SEND_DELAY);
+    let policy = &self.retry_policy;
+    let concurrency = self.max_concurrency;
+    let editing = if self.edit_in_place {
+      self.last_messages.clone()
+    } else {
+      HashMap::new()
+    };
+    let editing = &editing;
+    // like the stale messages, each previous poll is stopped by the first attempt only
+    let polls = parking_lot::Mutex::new(if self.stop_previous_poll {
+      std::mem::take(&mut self.last_polls)
+    } else {
+      HashMap::new()
+    });
+    let (sent, failed) = broadcast(
+      id,
+      &groups,
+      policy,
+      delay,
+      concurrency,
+      deadline,
+      |group_id| {
+        let prev = stale.lock().remove(&group_id);
+        let prev_poll = polls.lock().remove(&group_id);
+        let value = overrides.get(&group_id);
+        let notification = value.map_or(notification, |v| &v.content);
+        let options = SendOptions {
+          thread_id: topics.get(&group_id).copied(),
+          buttons: value
+            .and_then(|v| v.buttons.clone())
+            .or_else(|| options.buttons.clone()),
+          ..options.clone()
+        };
+        async move {
+          let options = &options;
+          if let Some(poll) = prev_poll {
+            if let Err(e) = bot.close_poll(group_id, poll).await {
+              warn!("Task {id} fail to stop poll {poll} in {}: {e}", group_id.0);
+            }
+          }
+          let mut vars = vars.clone();
+          if notification.contains_placeholder(GROUP_TITLE_PLACEHOLDER) {
+            vars.group_title = bot.chat_title(group_id).await.unwrap_or_else(|e| {
+              warn!("Task {id} fail to get the title of {}: {e}", group_id.0);
+              group_id.0.to_string()
+            });
+          }
+          let notification = &notification.render(&vars, options.parse_mode);
+          tracing::trace!("Going to send {:?} to {:?}", notification, group_id);
+          if let Some(&[msg_id]) = editing.get(&group_id).map(Vec::as_slice) {
+            match bot
+              .edit_notification(group_id, msg_id, notification, options)
+              .await
+            {
+              Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => {
+                return Ok(vec![msg_id])
+              }
+              // the message might be deleted, or can't be edited into the new content
+              Err(RequestError::Api(e)) => {
+                warn!(
+                  "Task {id} fail to edit message {msg_id} in {}: {e}, send a new one",
+                  group_id.0
+                );
+              }
+              Err(e) => return Err(e),
+            }
+          }
+          for prev in prev.unwrap_or_default() {
+            if let Err(e) = bot.delete(group_id, prev).await {
+              warn!(
+                "Task {id} fail to delete message {prev} in {}: {e}",
+                group_id.0
+              );
+            }
+          }
+          bot.send_notification(group_id, notification, options).await
+        }
+      },
+    )
+    .await;
+
+    self.stats.write().record(&groups, &failed);
+    // the migrated groups are not broken, they are rewritten by the pool
+    let mut failed = failed;
+    failed.retain(|&(gid, ref e)| match e {
+      RequestError::MigrateToChatId(to) => {
+        warn!("Task {id} found group {} is migrated to {to}", gid.0);
+        self.report(TaskEvent::Migrated {
+          from: gid,
+          to: ChatId(*to),
+        });
+        false
+      }
+      _ => true,
+    });
+    self.timing.write().last_sent = Some(clock::now());
+    if failed.iter().any(|(_, e)| is_copy_source_gone(e)) {
+      // the group is not to blame, the task can never succeed until the source is fixed
+      warn!("Task {id} fail to copy the source message, pausing the task");
+      self
+        .notify_creator(
+          bot,
+          format!("任务 {id} 的原消息已被删除或无法访问，复制失败，任务已暂停。"),
+        )
+        .await;
+      self.pause_by_self(id);
+    } else {
+      for (gid, reason) in self.count_failures(&groups, &failed) {
+        self.disable_group(id, gid, &reason, bot).await;
+      }
+    }
+    if self.delete_previous {
+      // forget the deleted messages, only the untouched one are still in the group
+      let stale = stale.into_inner();
+      self.last_messages.retain(|gid, _| stale.contains_key(gid));
+    }
+    if self.pin {
+      self.pin_sent(id, &sent, bot).await;
+    }
+    // the polls of the groups not reached are still open
+    self.last_polls.extend(polls.into_inner());
+    self.last_polls.extend(
+      sent
+        .iter()
+        .filter(|(gid, _)| {
+          let sent = overrides.get(gid).map_or(notification, |v| &v.content);
+          matches!(sent, Notification::Poll { .. })
+        })
+        .map(|(gid, ids)| (*gid, ids[0])),
+    );
+    let report = SendReport {
+      sent: sent.len(),
+      failed: failed
+        .iter()
+        .map(|(gid, e)| (*gid, e.to_string()))
+        .collect(),
+    };
+    self.last_messages.extend(sent);
+    report
+  }
+
+  /// Update the next fire time from the schedule
+  fn set_next_fire(&self) {
+    let now = clock::now();
+    let mut next = self.schedule.next_fire(now);
+    // skip the fire times on the disabled weekdays, but don't look ahead more than a week
+    while let Some(time) = next {
+      if self.weekdays.contains(time.weekday()) || time - now > chrono::Duration::weeks(1) {
+        break;
+      }
+      next = self.schedule.next_fire(time);
+    }
+    self.timing.write().next_fire = next;
+  }
+
+  /// Return the duration from now to the next fire time. The one-shot task has no next fire
+  /// time once it fires, so it gets the total backoff of the retry policy instead.
+  fn until_next_fire(&self) -> Duration {
+    let now = clock::now();
+    match self.schedule.next_fire(now) {
+      Some(next) => (next - now).to_std().unwrap_or_default(),
+      None => self.retry_policy.max_total_wait(),
+    }
+  }
+
+  /// Return the groups that are not disabled. The failure counter of the disabled groups is
+  /// cleared, so they can start over when they get enabled again.
+  fn active_groups(&mut self) -> Vec<ChatId> {
+    let wt = self.whitelist.read();
+    let (disabled, active): (Vec<ChatId>, Vec<ChatId>) =
+      self.groups.iter().partition(|&&g| wt.is_disabled(g));
+    for gid in disabled {
+      self.failures.remove(&gid);
+    }
+    active
+  }
+
+  /// Update the consecutive failure counter of each group after a broadcast. Return the
+  /// groups that should be disabled, with the reason.
+  fn count_failures(
+    &mut self,
+    groups: &[ChatId],
+    failed: &[(ChatId, RequestError)],
+  ) -> Vec<(ChatId, String)> {
+    let mut to_disable = Vec::new();
+    for gid in groups {
+      match failed.iter().find(|(g, _)| g == gid) {
+        Some((_, e)) => {
+          let count = self.failures.entry(*gid).or_insert(0);
+          *count += 1;
+          if is_permanent_error(e) || *count >= self.retry_policy.max_failures {
+            to_disable.push((*gid, format!("连续 {count} 次发送失败，最后一次错误：{e}")));
+          }
+        }
+        None => {
+          self.failures.remove(gid);
+        }
+      }
+    }
+    to_disable
+  }
+
+  /// Tell the pool about the state change made by this task
+  fn report(&self, event: TaskEvent) {
+    if let Some(events) = &self.events {
+      let _ = events.send(event);
+    }
+  }
+
+  /// Deregister this task from the pool
+  fn finish(&self, id: u32) {
+    self.report(TaskEvent::Finished(id));
+  }
+
+  /// A one-shot task never fire again after skipping its tick, so it is finished and the creator
+  /// is told. Return `true` if the task is finished.
+  async fn finish_skipped_once<M: Messenger>(&self, id: u32, bot: &M, reason: &str) -> bool {
+    if !matches!(self.schedule, Schedule::Once(_)) {
+      return false;
+    }
+    tracing::info!("One-shot task {} skipped its only tick, finishing it", id);
+    self
+      .notify_creator(
+        bot,
+        format!("一次性任务 {id} 到达发送时间时{reason}，通知没有发送，任务已自动删除。"),
+      )
+      .await;
+    self.finish(id);
+    true
+  }
+
+  /// Stop sending notification, and tell the pool that this task is paused
+  fn pause_by_self(&mut self, id: u32) {
+    self.paused = true;
+    self.report(TaskEvent::Paused(id));
+  }
+
+  /// Send a message to the creator of this task
+  async fn notify_creator<M: Messenger>(&self, bot: &M, text: String) {
+    if let Some(creator) = self.creator {
+      if let Err(e) = bot.send_text(ChatId(creator.0 as i64), &text).await {
+        error!("Fail to notify the creator {}: {}", creator, e);
+      }
+    }
+  }
+
+  /// Mark the group as disabled in the whitelist and alert the maintainers
+  async fn disable_group<M: Messenger>(&mut self, id: u32, gid: ChatId, reason: &str, bot: &M) {
+    self.failures.remove(&gid);
+    let maintainers = {
+      let mut wt = self.whitelist.write();
+      if wt.is_disabled(gid) {
+        // other task has already disable it
+        return;
+      }
+      wt.disabled_groups.push(gid);
+      wt.maintainers.clone()
+    };
+
+    error!("Task {id} disable group {}: {reason}", gid.0);
+    let text = format!(
+      "群组 {} 已被停用：{reason}\n\n请检查 bot 是否还在群内，确认后可以用 /enablegroup {} 重新启用。",
+      gid.0, gid.0
+    );
+    alert_maintainers(bot, &maintainers, &text).await;
+  }
+
+  /// Pin the sent notifications, and unpin the previous notification of this task in the same
+  /// group. Failure is logged and reported to maintainers once for each group, it never stop
+  /// the task.
+  async fn pin_sent<M: Messenger>(&mut self, id: u32, sent: &[(ChatId, Vec<i32>)], bot: &M) {
+    for (gid, ids) in sent {
+      let (gid, msg_id) = match ids.first() {
+        Some(&msg_id) => (*gid, msg_id),
+        None => continue,
+      };
+      let prev = self
+        .last_messages
+        .get(&gid)
+        .and_then(|ids| ids.first())
+        .copied();
+      if prev == Some(msg_id) {
+        // the message is edited in place, it is pinned already
+        continue;
+      }
+      let e = match bot.pin(gid, msg_id).await {
+        Ok(_) => {
+          if let Some(prev) = prev {
+            if let Err(e) = bot.unpin(gid, prev).await {
+              warn!("Task {id} fail to unpin message {prev} in {}: {e}", gid.0);
+            }
+          }
+          continue;
+        }
+        Err(e) => e,
+      };
+
+      error!("Task {id} fail to pin message {msg_id} in {}: {e}", gid.0);
+      if !self.pin_failure_reported.insert(gid) {
+        continue;
+      }
+      let maintainers = self.whitelist.read().maintainers.clone();
+      let text = format!(
+        "任务 {id} 无法在群组 {} 置顶通知：{e}\n\n请检查 bot 是否有置顶消息的权限。",
+        gid.0
+      );
+      alert_maintainers(bot, &maintainers, &text).await;
+    }
+  }
+}
+
+/// Send the alert text to every maintainer, failure is logged only
+pub async fn alert_maintainers<M: Messenger>(bot: &M, maintainers: &[UserId], text: &str) {
+  for &user in maintainers {
+    if let Err(e) = bot.send_text(ChatId(user.0 as i64), text).await {
+      error!("Fail to alert maintainer {user}: {e}");
+    }
+  }
+}
+
+/// Send the notification to every group by the given sender. Failure of one group is logged
+/// and skipped, so it doesn't affect the other groups. Return the output of the succeeded
+/// sends, and the groups that failed with the last error.
+/// Each send is retried by the retry policy, and all the retries should be finished before
+/// the deadline. The sends are started one by one with the delay between them, and at most
+/// `concurrency` sends are in flight at the same time.
+async fn broadcast<T, F, Fut>(
+  id: u32,
+  groups: &[ChatId],
+  policy: &RetryPolicy,
+  delay: Duration,
+  concurrency: usize,
+  deadline: tok_time::Instant,
+  send: F,
+) -> (Vec<(ChatId, T)>, Vec<(ChatId, RequestError)>)
+where
+  F: Fn(ChatId) -> Fut,
+  Fut: Future<Output = Result<T, RequestError>>,
+{
+  // the delays (with jitter) take at most 3/4 of the time before deadline, leave the rest
+  // for sending and retrying
+  let budget = deadline.saturating_duration_since(tok_time::Instant::now()) / 2;
+  let delay = delay.min(budget / (groups.len().max(1) as u32));
+
+  let send = &send;
+  stream::iter(groups.iter().copied().enumerate())
+    .then(|(i, gid)| async move {
+      if i > 0 && !delay.is_zero() {
+        // random jitter avoid aligning the bursts of the tasks that fire in the same time
+        let jitter = delay.mul_f64(rand::random::<f64>() / 2.0);
+        tok_time::sleep(delay + jitter).await;
+      }
+      gid
+    })
+    .map(|gid| async move { (gid, send_with_retry(id, gid, policy, deadline, send).await) })
+    .buffer_unordered(concurrency.max(1))
+    .fold(
+      (Vec::new(), Vec::new()),
+      |(mut sent, mut failed), (gid, result)| async move {
+        match result {
+          Ok(output) => sent.push((gid, output)),
+          Err(e) => {
+            error!("Task {id} fail to send notification to {}: {e}", gid.0);
+            failed.push((gid, e));
+          }
+        }
+        (sent, failed)
+      },
+    )
+    .await
+}
+
+/// Send to the group, retry when Telegram ask us to wait or there is a transient network
+/// error. Give up when the next retry can't be finished before the deadline.
+async fn send_with_retry<T, F, Fut>(
+  id: u32,
+  gid: ChatId,
+  policy: &RetryPolicy,
+  deadline: tok_time::Instant,
+  send: &F,
+) -> Result<T, RequestError>
+where
+  F: Fn(ChatId) -> Fut,
+  Fut: Future<Output = Result<T, RequestError>>,
+{
+  let mut attempt = 0;
+  loop {
+    let e = match send(gid).await {
+      Ok(output) => return Ok(output),
+      Err(e) => e,
+    };
+
+    let wait = match &e {
+      RequestError::RetryAfter(d) => *d,
+      RequestError::Network(_) | RequestError::Io(_) if attempt < policy.max_retries => {
+        let d = policy
+          .base_delay
+          .saturating_mul(2_u32.saturating_pow(attempt));
+        attempt += 1;
+        d
+      }
+      _ => return Err(e),
+    };
+
+    if tok_time::Instant::now() + wait > deadline {
+      return Err(e);
+    }
+
+    warn!(
+      "Task {id} fail to send notification to {}: {e}, retry after {wait:?}",
+      gid.0
+    );
+    tok_time::sleep(wait).await;
+  }
+}
+
+/// Validate the notification index, the error message contains the available index range.
+fn check_notification_index(pool: &[Notification], index: usize) -> Result<()> {
+  if index >= pool.len() {
+    return Err(Error::NotificationIndex {
+      index,
+      max: pool.len().saturating_sub(1),
+    });
+  }
+  Ok(())
+}
+
+/// Create a skim of the notification pool for describing the task. When there are
+/// multiple notifications, the pool size will be shown before the first notification.
+fn skim_content(pool: &[Notification]) -> String {
+  match pool.len() {
+    0 => String::new(),
+    1 => pool[0].to_string(),
+    n => format!(
+      "{n} 条轮播消息: {}",
+      pool[0].to_string().chars().take(30).collect::<String>()
+    ),
+  }
+}
+
+/// Messenger recording the sent notifications instead of calling the Bot API
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct RecordingBot {
+  pub(crate) sent: Arc<Mutex<Vec<(ChatId, String)>>>,
+  /// Number of the next sends failing with a short `RetryAfter`
+  fail_next: Arc<AtomicU32>,
+}
+
+#[cfg(test)]
+impl Messenger for RecordingBot {
+  fn send_notification<'a>(
+    &'a self,
+    chat_id: ChatId,
+    notification: &'a Notification,
+    _: &'a SendOptions,
+  ) -> BoxFuture<'a, Result<Vec<i32>, RequestError>> {
+    let failing = self
+      .fail_next
+      .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+    if failing.is_ok() {
+      return Box::pin(async { Err(RequestError::RetryAfter(Duration::from_millis(50))) });
+    }
+    let mut sent = self.sent.lock();
+    sent.push((chat_id, notification.to_string()));
+    let id = sent.len() as i32;
+    Box::pin(async move { Ok(vec![id]) })
+  }
+
+  fn edit_notification<'a>(
+    &'a self,
+    _: ChatId,
+    _: i32,
+    _: &'a Notification,
+    _: &'a SendOptions,
+  ) -> BoxFuture<'a, Result<(), RequestError>> {
+    Box::pin(async { Ok(()) })
+  }
+
+  fn send_text<'a>(&'a self, _: ChatId, _: &'a str) -> BoxFuture<'a, Result<(), RequestError>> {
+    Box::pin(async { Ok(()) })
+  }
+
+  fn delete(&self, _: ChatId, _: i32) -> BoxFuture<'_, Result<(), RequestError>> {
+    Box::pin(async { Ok(()) })
+  }
+
+  fn close_poll(&self, _: ChatId, _: i32) -> BoxFuture<'_, Result<(), RequestError>> {
+    Box::pin(async { Ok(()) })
+  }
+
+  fn pin(&self, _: ChatId, _: i32) -> BoxFuture<'_, Result<(), RequestError>> {
+    Box::pin(async { Ok(()) })
+  }
+
+  fn unpin(&self, _: ChatId, _: i32) -> BoxFuture<'_, Result<(), RequestError>> {
+    Box::pin(async { Ok(()) })
+  }
+
+  fn chat_title(&self, chat_id: ChatId) -> BoxFuture<'_, Result<String, RequestError>> {
+    Box::pin(async move { Ok(format!("群组{}", chat_id.0)) })
+  }
+}
+
+/// Task pool on the recording bot, without whitelist and quiet hours. The returned bot shares
+/// the record with the pool.
+#[cfg(test)]
+pub(crate) fn test_pool(
+  shutdown_sig: watch::Receiver<u8>,
+) -> (TaskPool<RecordingBot>, RecordingBot) {
+  let bot = RecordingBot::default();
+  let pool = TaskPool::new(
+    bot.clone(),
+    Arc::default(),
+    Arc::default(),
+    None,
+    shutdown_sig,
+  );
+  (pool, bot)
+}
+
+#[tokio::test]
+async fn run_task_with_recording_bot() {
+  let bot = RecordingBot::default();
+  let (_tx, rx) = watch::channel(0);
+  let task = ScheduleTask::new(rx)
+    .schedule(Schedule::Every(Duration::from_millis(200)))
+    .send_immediately(true)
+    .pending_notification(vec![
+      Notification::Text("A".to_string()),
+      Notification::Text("B".to_string()),
+    ])
+    .groups(vec![ChatId(-1001), ChatId(-1002)])
+    .send_delay(Duration::ZERO)
+    .max_concurrency(1)
+    .run(0, bot.clone());
+
+  // the immediate tick and one more after a period
+  tok_time::sleep(Duration::from_millis(300)).await;
+  task.editor.shutdown().await;
+  assert!(task.wait_stopped().await);
+
+  let sent = bot.sent.lock().clone();
+  let expect = [(-1001, "A"), (-1002, "A"), (-1001, "B"), (-1002, "B")]
+    .iter()
+    .map(|&(chat, text)| (ChatId(chat), text.to_string()))
+    .collect::<Vec<_>>();
+  assert_eq!(sent, expect);
+}
+
+#[tokio::test]
+async fn paused_one_shot_task_finish() {
+  let bot = RecordingBot::default();
+  let (_tx, rx) = watch::channel(0);
+  let (events, mut finished) = mpsc::unbounded_channel();
+  let task = ScheduleTask::new(rx)
+    .creator(UserId(1))
+    .schedule(Schedule::Once(
+      clock::now() + chrono::Duration::milliseconds(100),
+    ))
+    .paused(true)
+    .pending_notification(vec![Notification::Text("A".to_string())])
+    .groups(vec![ChatId(-1001)])
+    .events(events)
+    .run(3, bot.clone());
+
+  // the skipped tick finish the task instead of waiting forever
+  let event = tok_time::timeout(Duration::from_secs(2), finished.recv())
+    .await
+    .unwrap();
+  assert!(matches!(event, Some(TaskEvent::Finished(3))));
+  assert!(task.wait_stopped().await);
+  assert!(bot.sent.lock().is_empty());
+}
+
+#[tokio::test]
+async fn one_shot_send_retry() {
+  let bot = RecordingBot::default();
+  bot.fail_next.store(1, Ordering::SeqCst);
+  let (_tx, rx) = watch::channel(0);
+  let (events, mut finished) = mpsc::unbounded_channel();
+  let task = ScheduleTask::new(rx)
+    .schedule(Schedule::Once(
+      clock::now() + chrono::Duration::milliseconds(100),
+    ))
+    .pending_notification(vec![Notification::Text("A".to_string())])
+    .groups(vec![ChatId(-1001)])
+    .events(events)
+    .run(4, bot.clone());
+
+  // the one-shot task has no next fire time, but the failed send still get its retry
+  let event = tok_time::timeout(Duration::from_secs(2), finished.recv())
+    .await
+    .unwrap();
+  assert!(matches!(event, Some(TaskEvent::Finished(4))));
+  assert!(task.wait_stopped().await);
+  assert_eq!(*bot.sent.lock(), vec![(ChatId(-1001), "A".to_string())]);
+}
+
+#[tokio::test]
+async fn trigger_now_keep_schedule() {
+  let bot = RecordingBot::default();
+  let (_tx, rx) = watch::channel(0);
+  let task = ScheduleTask::new(rx)
+    .schedule(Schedule::Every(Duration::from_secs(3600)))
+    .pending_notification(vec![Notification::Text("A".to_string())])
+    .groups(vec![ChatId(-1001), ChatId(-1002)])
+    .send_delay(Duration::ZERO)
+    .run(0, bot.clone());
+  let next_fire = task.timing.read().next_fire;
+
+  let report = task.editor.trigger_now().await.unwrap();
+  assert_eq!(report.sent, 2);
+  assert!(report.failed.is_empty());
+  assert_eq!(bot.sent.lock().len(), 2);
+  assert_eq!(task.stats.read().total.successes, 2);
+  // the regular send is still an hour later
+  assert_eq!(task.timing.read().next_fire, next_fire);
+
+  task.editor.shutdown().await;
+  assert!(task.wait_stopped().await);
+}
+
+#[tokio::test]
+async fn render_template_per_group() {
+  let bot = RecordingBot::default();
+  let (_tx, rx) = watch::channel(0);
+  let task = ScheduleTask::new(rx)
+    .schedule(Schedule::Every(Duration::from_secs(3600)))
+    .pending_notification(vec![Notification::Text(
+      "{group_title} 第 {count} 期 {unknown}".to_string(),
+    )])
+    .groups(vec![ChatId(-1001), ChatId(-1002)])
+    .send_delay(Duration::ZERO)
+    .run(0, bot.clone());
+
+  task.editor.trigger_now().await.unwrap();
+  task.editor.trigger_now().await.unwrap();
+  let mut sent = bot.sent.lock().clone();
+  sent.sort();
+  assert_eq!(
+    sent,
+    vec![
+      (ChatId(-1002), "群组-1002 第 1 期 {unknown}".to_string()),
+      (ChatId(-1002), "群组-1002 第 2 期 {unknown}".to_string()),
+      (ChatId(-1001), "群组-1001 第 1 期 {unknown}".to_string()),
+      (ChatId(-1001), "群组-1001 第 2 期 {unknown}".to_string()),
+    ]
+  );
+  assert_eq!(task.timing.read().fired, 2);
+
+  task.editor.shutdown().await;
+  assert!(task.wait_stopped().await);
+}
+
+#[tokio::test]
+async fn group_override_replace_content() {
+  let bot = RecordingBot::default();
+  let (_tx, rx) = watch::channel(0);
+  let task = ScheduleTask::new(rx)
+    .schedule(Schedule::Every(Duration::from_secs(3600)))
+    .pending_notification(vec![Notification::Text("早上好".to_string())])
+    .groups(vec![ChatId(-1001), ChatId(-1002)])
+    .overrides(BTreeMap::from([(
+      ChatId(-1002),
+      GroupOverride {
+        content: Notification::Text("Good morning".to_string()),
+        buttons: None,
+      },
+    )]))
+    .send_delay(Duration::ZERO)
+    .run(0, bot.clone());
+
+  task.editor.trigger_now().await.unwrap();
+  task
+    .editor
+    .change_override(ChatId(-1002), None)
+    .await
+    .unwrap();
+  task.editor.trigger_now().await.unwrap();
+  let mut sent = bot.sent.lock().clone();
+  sent.sort();
+  let expect = [
+    (-1002, "Good morning"),
+    (-1002, "早上好"),
+    (-1001, "早上好"),
+    (-1001, "早上好"),
+  ]
+  .iter()
+  .map(|&(chat, text)| (ChatId(chat), text.to_string()))
+  .collect::<Vec<_>>();
+  assert_eq!(sent, expect);
+
+  task.editor.shutdown().await;
+  assert!(task.wait_stopped().await);
+}
+
+#[tokio::test]
+async fn next_notification_follow_cursor() {
+  let bot = RecordingBot::default();
+  let (_tx, rx) = watch::channel(0);
+  let task = ScheduleTask::new(rx)
+    .schedule(Schedule::Every(Duration::from_secs(3600)))
+    .pending_notification(vec![
+      Notification::Text("A".to_string()),
+      Notification::Text("B".to_string()),
+    ])
+    .groups(vec![ChatId(-1001)])
+    .send_delay(Duration::ZERO)
+    .run(0, bot.clone());
+
+  assert_eq!(task.next_notification().unwrap().to_string(), "A");
+  task.editor.trigger_now().await.unwrap();
+  assert_eq!(task.next_notification().unwrap().to_string(), "B");
+
+  task.editor.shutdown().await;
+  assert!(task.wait_stopped().await);
+}
+
+#[test]
+fn rotation_mode_next_index() {
+  let mut rng = StdRng::seed_from_u64(42);
+  let equal = [1, 1, 1];
+  let mut slot = 0;
+  assert_eq!(
+    RotationMode::Sequential.next_index(&mut rng, 0, &equal, &mut slot),
+    1
+  );
+  slot = 2;
+  assert_eq!(
+    RotationMode::Sequential.next_index(&mut rng, 2, &equal, &mut slot),
+    0
+  );
+
+  // consecutive picks never repeat, and every notification is picked
+  let mut seen = HashSet::new();
+  let mut current = 0;
+  for _ in 0..200 {
+    let next = RotationMode::RandomNoRepeat.next_index(&mut rng, current, &equal, &mut slot);
+    assert_ne!(next, current);
+    assert!(next < 3);
+    seen.insert(next);
+    current = next;
+  }
+  assert_eq!(seen.len(), 3);
+  assert_eq!(
+    RotationMode::RandomNoRepeat.next_index(&mut rng, 1, &[1, 1], &mut slot),
+    0
+  );
+
+  // pool with a single notification always pick it
+  for mode in [RotationMode::Random, RotationMode::RandomNoRepeat] {
+    assert_eq!(mode.next_index(&mut rng, 0, &[1], &mut slot), 0);
+  }
+  assert!((0..50).all(|_| RotationMode::Random.next_index(&mut rng, 0, &equal, &mut slot) < 3));
+}
+
+#[test]
+fn weighted_cycle_test() {
+  assert_eq!(weighted_cycle(&[1, 1, 1]), vec![0, 1, 2]);
+  // the sponsor message once for every four regular posts, spread evenly
+  assert_eq!(weighted_cycle(&[4, 1]), vec![0, 0, 1, 0, 0]);
+  assert_eq!(weighted_cycle(&[2, 1, 1]), vec![0, 1, 2, 0]);
+  assert!(validate_weight(0).is_err());
+  assert!(validate_weight(MAX_NOTIFICATION_WEIGHT).is_ok());
+  assert!(validate_weight(MAX_NOTIFICATION_WEIGHT + 1).is_err());
+}
+
+#[test]
+fn weighted_rotation_frequency() {
+  let mut rng = StdRng::seed_from_u64(7);
+  let weights = [3, 1, 6];
+  let total: u32 = weights.iter().sum();
+
+  // the sequential mode match the weights exactly in every round
+  let cycle = weighted_cycle(&weights);
+  let (mut current, mut slot) = (cycle[0], 0);
+  let mut counts = [0u32; 3];
+  for _ in 0..total * 50 {
+    counts[current] += 1;
+    current = RotationMode::Sequential.next_index(&mut rng, current, &weights, &mut slot);
+  }
+  assert_eq!(counts, [150, 50, 300]);
+
+  // the random mode match the weights roughly in the long run
+  let rounds = 20000;
+  let mut counts = [0u32; 3];
+  for _ in 0..rounds {
+    counts[RotationMode::Random.next_index(&mut rng, 0, &weights, &mut slot)] += 1;
+  }
+  for (count, weight) in counts.iter().zip(weights) {
+    let expected = rounds as f64 * weight as f64 / total as f64;
+    assert!(
+      (*count as f64 - expected).abs() < expected * 0.1,
+      "{count} is too far from {expected}"
+    );
+  }
+}
+
+#[tokio::test]
+async fn random_no_repeat_rotation_sends_different() {
+  let bot = RecordingBot::default();
+  let (_tx, rx) = watch::channel(0);
+  let task = ScheduleTask::new(rx)
+    .schedule(Schedule::Every(Duration::from_secs(3600)))
+    .pending_notification(vec![
+      Notification::Text("A".to_string()),
+      Notification::Text("B".to_string()),
+      Notification::Text("C".to_string()),
+    ])
+    .rotation(RotationMode::RandomNoRepeat)
+    .groups(vec![ChatId(-1001)])
+    .send_delay(Duration::ZERO)
+    .run(0, bot.clone());
+
+  let mut last = None;
+  for _ in 0..10 {
+    let next = task.next_notification().unwrap().to_string();
+    assert_ne!(Some(&next), last.as_ref());
+    task.editor.trigger_now().await.unwrap();
+    last = Some(next);
+  }
+
+  task.editor.shutdown().await;
+  assert!(task.wait_stopped().await);
+}
+
+#[tokio::test(start_paused = true)]
+async fn slow_tick_does_not_burst() {
+  let period = Duration::from_millis(50);
+  let mut ticker = Ticker::new(
+    &Schedule::Every(period),
+    true,
+    MissedTickBehavior::Delay,
+    Duration::ZERO,
+  );
+  ticker.tick().await;
+  // a slow send miss several ticks
+  tok_time::sleep(period * 4).await;
+  // one tick for the missed ones, then wait for a whole period again
+  ticker.tick().await;
+  assert!(tok_time::timeout(period / 2, ticker.tick()).await.is_err());
+
+  let mut ticker = Ticker::new(
+    &Schedule::Every(period),
+    true,
+    MissedTickBehavior::Burst,
+    Duration::ZERO,
+  );
+  ticker.tick().await;
+  tok_time::sleep(period * 4).await;
+  ticker.tick().await;
+  assert!(tok_time::timeout(period / 2, ticker.tick()).await.is_ok());
+}
+
+#[tokio::test(start_paused = true)]
+async fn same_interval_tasks_fire_apart() {
+  let period = Duration::from_secs(3600);
+  // the paused clock jumps to the next timer, so each ticker sees its exact fire time
+  let fire_times = |phases: Vec<Duration>| {
+    futures::future::join_all(phases.into_iter().map(|phase| async move {
+      let start = tok_time::Instant::now();
+      let mut ticker = Ticker::new(
+        &Schedule::Every(period),
+        false,
+        MissedTickBehavior::Delay,
+        phase,
+      );
+      ticker.tick().await;
+      tok_time::Instant::now() - start
+    }))
+  };
+
+  let mut staggered = fire_times((0..8).map(|id| start_phase(id, period)).collect()).await;
+  assert!(staggered.iter().all(|&t| !t.is_zero() && t <= period));
+  staggered.sort();
+  staggered.dedup();
+  assert_eq!(staggered.len(), 8);
+
+  let synchronized = fire_times(vec![Duration::ZERO; 8]).await;
+  assert!(synchronized.iter().all(|&t| t == period));
+
+  // the running tasks report the shifted fire time
+  let (_tx, rx) = watch::channel(0);
+  let tasks = (0..4)
+    .map(|id| {
+      ScheduleTask::new(rx.clone())
+        .schedule(Schedule::Every(period))
+        .pending_notification(vec![Notification::Text("A".to_string())])
+        .groups(vec![ChatId(-1001)])
+        .run(id, RecordingBot::default())
+    })
+    .collect::<Vec<_>>();
+  let mut next_fires = tasks
+    .iter()
+    .map(|task| task.timing.read().next_fire.unwrap())
+    .collect::<Vec<_>>();
+  next_fires.sort();
+  next_fires.dedup();
+  assert_eq!(next_fires.len(), 4);
+
+  // the tasks keep apart after changing to the same new interval
+  let period = period * 2;
+  for task in &tasks {
+    task
+      .editor
+      .change_schedule(Schedule::Every(period))
+      .await
+      .unwrap();
+  }
+  // let the tasks handle the edit
+  tok_time::sleep(Duration::from_millis(1)).await;
+  let latest = clock::now() + chrono::Duration::from_std(period).unwrap();
+  let mut next_fires = tasks
+    .iter()
+    .map(|task| task.timing.read().next_fire.unwrap())
+    .collect::<Vec<_>>();
+  assert!(next_fires.iter().all(|&next| next <= latest));
+  next_fires.sort();
+  next_fires.dedup();
+  assert_eq!(next_fires.len(), 4);
+  for task in tasks {
+    task.editor.shutdown().await;
+    assert!(task.wait_stopped().await);
+  }
+}
+
+#[tokio::test]
+async fn pools_assign_independent_ids() {
+  let bot = Bot::new("0:test")
+    .throttle(teloxide::adaptors::throttle::Limits::default())
+    .auto_send();
+  let (_tx, rx) = watch::channel(0);
+  let new_pool = || {
+    TaskPool::new(
+      bot.clone(),
+      Arc::default(),
+      Arc::default(),
+      None,
+      rx.clone(),
+    )
+  };
+  let task = || {
+    ScheduleTask::new(rx.clone())
+      .schedule(Schedule::Every(Duration::from_secs(3600)))
+      .pending_notification(vec![Notification::Text("A".to_string())])
+      .groups(vec![ChatId(-1001)])
+  };
+
+  let mut first = new_pool();
+  let mut second = new_pool().with_start_id(10);
+  assert_eq!(first.add_task(task()).await.unwrap(), 0);
+  assert_eq!(second.add_task(task()).await.unwrap(), 10);
+  assert_eq!(first.add_task(task()).await.unwrap(), 1);
+  assert_eq!(second.add_task(task()).await.unwrap(), 11);
+  first.shutdown_all().await.unwrap();
+  second.shutdown_all().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn remove_wait_task_stopped() {
+  let (_tx, rx) = watch::channel(0);
+  let (mut pool, bot) = test_pool(rx.clone());
+  let period = Duration::from_secs(1);
+  let task = ScheduleTask::new(rx)
+    .schedule(Schedule::Every(period))
+    .send_immediately(true)
+    .send_delay(Duration::ZERO)
+    .pending_notification(vec![Notification::Text("A".to_string())])
+    .groups(vec![ChatId(-1001)]);
+  let id = pool.add_task(task).await.unwrap();
+  let handle = Arc::clone(&pool.get_task(id).unwrap().handle);
+  // let the immediate send go out
+  tok_time::sleep(Duration::from_millis(10)).await;
+  let sent = bot.sent.lock().len();
+  assert!(sent > 0);
+
+  pool.remove(id).await.unwrap();
+  // the task is stopped once remove return, nothing can be sent anymore
+  assert!(handle.lock().is_none());
+  assert!(pool.running.try_write().is_ok());
+  tok_time::sleep(period * 5).await;
+  assert_eq!(bot.sent.lock().len(), sent);
+  assert!(matches!(
+    pool.remove(id).await,
+    Err(Error::TaskNotFound(i)) if i == id
+  ));
+}
+
+#[tokio::test]
+async fn clone_task_test() {
+  let (_tx, rx) = watch::channel(0);
+  let (mut pool, _) = test_pool(rx.clone());
+  let task = ScheduleTask::new(rx)
+    .name(Some("早报".to_string()))
+    .creator(UserId(1))
+    .schedule(Schedule::Every(Duration::from_secs(3600)))
+    .pending_notification(vec![
+      Notification::Text("A".to_string()),
+      Notification::Text("B".to_string()),
+    ])
+    .parse_mode(Some(ParseMode::Html))
+    .silent(true)
+    .groups(vec![ChatId(-1001), ChatId(-1002)]);
+  let id = pool.add_task(task).await.unwrap();
+
+  let clone = pool.clone_task(id, UserId(2)).await.unwrap();
+  assert_ne!(clone, id);
+  let (source, cloned) = (pool.get_task(id).unwrap(), pool.get_task(clone).unwrap());
+  assert_eq!(cloned.name, None);
+  assert_eq!(cloned.creator, Some(UserId(2)));
+  assert!(cloned.paused);
+  assert!(!source.paused);
+  assert_eq!(cloned.schedule.to_string(), source.schedule.to_string());
+  assert_eq!(cloned.notifications, source.notifications);
+  assert_eq!(cloned.parse_mode, source.parse_mode);
+  assert_eq!(cloned.groups, source.groups);
+  assert!(cloned.silent);
+  assert!(matches!(
+    pool.clone_task(42, UserId(2)).await,
+    Err(Error::TaskNotFound(42))
+  ));
+}
+
+#[tokio::test]
+async fn group_override_test() {
+  let (_tx, rx) = watch::channel(0);
+  let (mut pool, bot) = test_pool(rx.clone());
+  let task = ScheduleTask::new(rx)
+    .schedule(Schedule::Every(Duration::from_secs(3600)))
+    .send_delay(Duration::ZERO)
+    .pending_notification(vec![Notification::Text("早上好".to_string())])
+    .groups(vec![ChatId(-1001), ChatId(-1002)]);
+  let id = pool.add_task(task).await.unwrap();
+  let value = GroupOverride {
+    content: Notification::Text("Good morning".to_string()),
+    buttons: None,
+  };
+
+  assert!(matches!(
+    pool.set_override(id, ChatId(-1003), value.clone()).await,
+    Err(Error::GroupNotInTask { .. })
+  ));
+  pool
+    .set_override(id, ChatId(-1002), value.clone())
+    .await
+    .unwrap();
+  assert_eq!(
+    pool.get_task(id).unwrap().overrides.get(&ChatId(-1002)),
+    Some(&value)
+  );
+  // the overridden group receives its own text, the others keep the pool
+  pool.send_now(id).await.unwrap();
+  let mut sent = bot.sent.lock().clone();
+  sent.sort();
+  assert_eq!(
+    sent,
+    vec![
+      (ChatId(-1002), "Good morning".to_string()),
+      (ChatId(-1001), "早上好".to_string()),
+    ]
+  );
+  // the clone keeps the overrides
+  let clone = pool.clone_task(id, UserId(2)).await.unwrap();
+  assert_eq!(pool.get_task(clone).unwrap().overrides.len(), 1);
+
+  // removing the group drops its override
+  pool.set_groups(id, vec![ChatId(-1001)]).await.unwrap();
+  assert!(pool.get_task(id).unwrap().overrides.is_empty());
+  assert!(matches!(
+    pool.remove_override(id, ChatId(-1002)).await,
+    Err(Error::OverrideNotFound { .. })
+  ));
+
+  pool.remove_override(clone, ChatId(-1002)).await.unwrap();
+  assert!(pool.get_task(clone).unwrap().overrides.is_empty());
+}
+
+#[tokio::test]
+async fn removed_group_task_needs_new_groups() {
+  let (_tx, rx) = watch::channel(0);
+  let (mut pool, _) = test_pool(rx.clone());
+  let task = || {
+    ScheduleTask::new(rx.clone())
+      .schedule(Schedule::Every(Duration::from_secs(3600)))
+      .pending_notification(vec![Notification::Text("A".to_string())])
+  };
+  let only = pool
+    .add_task(task().groups(vec![ChatId(-1001)]))
+    .await
+    .unwrap();
+  let shared = pool
+    .add_task(task().groups(vec![ChatId(-1001), ChatId(-1002)]))
+    .await
+    .unwrap();
+  let value = GroupOverride {
+    content: Notification::Text("B".to_string()),
+    buttons: None,
+  };
+  pool
+    .set_override(only, ChatId(-1001), value.clone())
+    .await
+    .unwrap();
+  pool
+    .set_override(shared, ChatId(-1001), value)
+    .await
+    .unwrap();
+
+  assert_eq!(pool.remove_group(ChatId(-1001)).await.unwrap(), vec![only]);
+  let task = pool.get_task(only).unwrap();
+  assert!(task.paused);
+  assert!(task.groups.is_empty());
+  assert!(task.overrides.is_empty());
+  let task = pool.get_task(shared).unwrap();
+  assert_eq!(task.groups, vec![ChatId(-1002)]);
+  assert!(task.overrides.is_empty());
+
+  // the dropped group doesn't come back by resuming, the admin set new groups first
+  assert!(matches!(pool.resume(only).await, Err(Error::NoGroup)));
+  assert!(pool.get_task(only).unwrap().paused);
+  pool.set_groups(only, vec![ChatId(-1002)]).await.unwrap();
+  assert!(pool.resume(only).await.unwrap());
+  pool.shutdown_all().await.unwrap();
+}
+
+#[tokio::test]
+async fn export_import_round_trip() {
+  use crate::store::{export_tasks, parse_exported_tasks};
+
+  let (_tx, rx) = watch::channel(0);
+  let (mut pool, _) = test_pool(rx.clone());
+  *pool.whitelist.write() = Whitelist {
+    groups: vec![ChatId(-1001), ChatId(-1002)],
+    ..Default::default()
+  };
+  let task = ScheduleTask::new(rx.clone())
+    .name(Some("早报".to_string()))
+    .schedule(Schedule::Every(Duration::from_secs(3600)))
+    .pending_notification(vec![
+      Notification::Text("A".to_string()),
+      Notification::Text("B".to_string()),
+    ])
+    .parse_mode(Some(ParseMode::Html))
+    .pin(true)
+    .paused(true)
+    .groups(vec![ChatId(-1001), ChatId(-1002)]);
+  pool.add_task(task).await.unwrap();
+  let task = ScheduleTask::new(rx.clone())
+    .schedule(Schedule::Every(Duration::from_secs(600)))
+    .pending_notification(vec![Notification::Text("C".to_string())])
+    .groups(vec![ChatId(-1002)]);
+  pool.add_task(task).await.unwrap();
+  let exported = export_tasks(&pool.snapshots()).unwrap();
+
+  // the new server only knows one of the groups
+  let (imported, _) = test_pool(rx.clone());
+  let mut imported = imported.with_start_id(100);
+  *imported.whitelist.write() = Whitelist {
+    groups: vec![ChatId(-1001)],
+    ..Default::default()
+  };
+  let outcomes = imported
+    .import_tasks(parse_exported_tasks(exported.as_bytes()).unwrap())
+    .await;
+  assert_eq!(outcomes.len(), 2);
+  assert_eq!(outcomes[0].result.as_ref().unwrap(), &100);
+  assert_eq!(outcomes[0].skipped_groups, vec![ChatId(-1002)]);
+  assert!(matches!(outcomes[1].result, Err(Error::NoGroup)));
+  assert_eq!(outcomes[1].skipped_groups, vec![ChatId(-1002)]);
+
+  let task = imported.get_task(100).unwrap();
+  assert_eq!(task.name.as_deref(), Some("早报"));
+  assert_eq!(
+    task.schedule.to_string(),
+    Schedule::Every(Duration::from_secs(3600)).to_string()
+  );
+  assert_eq!(task.notifications.len(), 2);
+  assert_eq!(task.parse_mode, Some(ParseMode::Html));
+  assert_eq!(task.groups, vec![ChatId(-1001)]);
+  assert!(task.pin);
+  assert!(task.paused);
+
+  // a broken entry doesn't stop the others, and the used name is rejected
+  let json = format!("[{{\"id\": 1}}, {}]", &exported[1..exported.len() - 1]);
+  let outcomes = imported
+    .import_tasks(parse_exported_tasks(json.as_bytes()).unwrap())
+    .await;
+  assert!(matches!(outcomes[0].result, Err(Error::Json(_))));
+  assert!(matches!(&outcomes[1].result, Err(Error::TaskNameUsed(name)) if name == "早报"));
+  assert!(parse_exported_tasks(b"{}").is_err());
+}
+
+#[tokio::test]
+async fn shutdown_all_clear_pool() {
+  let bot = Bot::new("0:test")
+    .throttle(teloxide::adaptors::throttle::Limits::default())
+    .auto_send();
+  let (_tx, rx) = watch::channel(0);
+  let mut pool = TaskPool::new(bot, Arc::default(), Arc::default(), None, rx.clone());
+  let mut handles = Vec::new();
+  for _ in 0..3 {
+    let task = ScheduleTask::new(rx.clone())
+      .schedule(Schedule::Every(Duration::from_secs(3600)))
+      .pending_notification(vec![Notification::Text("A".to_string())])
+      .groups(vec![ChatId(-1001)]);
+    let id = pool.add_task(task).await.unwrap();
+    handles.push(Arc::clone(&pool.get_task(id).unwrap().handle));
+  }
+
+  assert_eq!(pool.shutdown_all().await.unwrap(), 3);
+  assert!(pool.list_id().is_empty());
+  assert!(handles.iter().all(|h| h.lock().is_none()));
+  assert!(pool.running.try_write().is_ok());
+  assert_eq!(pool.shutdown_all().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn shutdown_stop_all_tasks() {
+  let bot = Bot::new("0:test")
+    .throttle(teloxide::adaptors::throttle::Limits::default())
+    .auto_send();
+  let (tx, rx) = watch::channel(0);
+  let mut pool = TaskPool::new(bot, Arc::default(), Arc::default(), None, rx.clone());
+  for _ in 0..3 {
+    let task = ScheduleTask::new(rx.clone())
+      .schedule(Schedule::Every(Duration::from_secs(3600)))
+      .pending_notification(vec![Notification::Text("A".to_string())])
+      .groups(vec![ChatId(-1001)]);
+    pool.add_task(task).await.unwrap();
+  }
+
+  // the tasks are running
+  let running = tok_time::timeout(Duration::from_millis(100), pool.wait_stopped()).await;
+  assert!(running.is_err());
+
+  tx.send(1).unwrap();
+  tok_time::timeout(Duration::from_secs(1), pool.wait_stopped())
+    .await
+    .expect("tasks should stop after the shutdown signal");
+}
+
+#[tokio::test]
+async fn restore_tasks_from_store() {
+  let (_tx, rx) = watch::channel(0);
+  let path = std::env::temp_dir().join(format!("notify-bot-restore-{}.json", std::process::id()));
+  let store: Arc<dyn TaskStore> = Arc::new(crate::store::JsonFileStore::new(&path));
+
+  let (pool, _) = test_pool(rx.clone());
+  let mut pool = pool.with_store(Arc::clone(&store));
+  let task = ScheduleTask::new(rx.clone())
+    .name(Some("daily".to_string()))
+    .schedule(Schedule::Daily(NaiveTime::from_hms(9, 0, 0)))
+    .pending_notification(vec![Notification::Text("A".to_string())])
+    .groups(vec![ChatId(-1001)]);
+  let id = pool.add_task(task).await.unwrap();
+  pool.pause(id).await.unwrap();
+  // shutdown keeps the tasks in the store
+  pool.shutdown_all().await.unwrap();
+
+  let (restored, _) = test_pool(rx.clone());
+  let mut restored = restored.with_store(Arc::clone(&store));
+  assert_eq!(restored.restore().await.unwrap(), 1);
+  let task = restored.get_task(id).unwrap();
+  assert_eq!(task.name.as_deref(), Some("daily"));
+  assert!(task.paused);
+  assert_eq!(task.schedule.to_string(), "每天 09:00");
+
+  // the new task doesn't reuse the restored id
+  let task = ScheduleTask::new(rx)
+    .schedule(Schedule::Every(Duration::from_secs(3600)))
+    .pending_notification(vec![Notification::Text("B".to_string())])
+    .groups(vec![ChatId(-1001)]);
+  assert_eq!(restored.add_task(task).await.unwrap(), id + 1);
+
+  restored.remove(id).await.unwrap();
+  let saved = store.load_all().await.unwrap();
+  assert_eq!(
+    saved.iter().map(|t| t.id).collect::<Vec<u32>>(),
+    vec![id + 1]
+  );
+  restored.shutdown_all().await.unwrap();
+  tokio::fs::remove_file(&path).await.unwrap();
+}
+
+#[test]
+fn rotate_notification_pool() {
+  let (_tx, rx) = watch::channel(0);
+  let mut task = ScheduleTask::new(rx).pending_notification(vec![
+    Notification::Text("A".to_string()),
+    Notification::Text("B".to_string()),
+    Notification::Text("C".to_string()),
+  ]);
+  let sent: Vec<String> = (0..4)
+    .map(|_| task.next_notification().to_string())
+    .collect();
+  assert_eq!(sent, vec!["A", "B", "C", "A"]);
+
+  // appending new notification should not reset the cursor
+  task
+    .pending_notification
+    .push(Notification::Text("D".to_string()));
+  assert_eq!(task.next_notification().to_string(), "B");
+  assert_eq!(skim_content(&task.pending_notification), "4 条轮播消息: A");
+
+  // removing a sent notification should keep the next notification unchanged
+  task.remove_notification(0);
+  assert_eq!(task.next_notification().to_string(), "C");
+  task.remove_notification(2);
+  task.remove_notification(1);
+  task.remove_notification(0);
+  assert_eq!(
+    task.pending_notification,
+    vec![Notification::Text("B".to_string())]
+  );
+}
+
+#[test]
+fn parse_cron_schedule() {
+  use chrono::{Datelike, TimeZone, Timelike, Weekday};
+
+  let schedule = Schedule::from_cron("30 9 * * 1-5").unwrap();
+  assert_eq!(schedule.to_string(), "cron `30 9 * * 1-5`");
+
+  // 2022-06-04 is Saturday, the next fire time should be Monday
+  let now = Tz::UTC.ymd(2022, 6, 4).and_hms(12, 0, 0);
+  let next = schedule.next_fire(now).unwrap();
+  assert_eq!(next.weekday(), Weekday::Mon);
+  assert_eq!((next.hour(), next.minute()), (9, 30));
+
+  // both 0 and 7 are Sunday
+  let sunday = Schedule::from_cron("0 8 * * 5-7").unwrap();
+  let next = sunday.next_fire(now).unwrap();
+  assert_eq!(next.weekday(), Weekday::Sun);
+
+  let err = Schedule::from_cron("30 25 * * *").unwrap_err();
+  assert!(err.to_string().contains("hour"));
+  assert!(Schedule::from_cron("30 9 * *").is_err());
+}
+
+#[test]
+fn daily_schedule() {
+  use chrono::TimeZone;
+
+  let schedule = Schedule::Daily(NaiveTime::from_hms(10, 0, 0));
+  assert_eq!(schedule.to_string(), "每天 10:00");
+
+  let before = Tz::UTC.ymd(2022, 6, 4).and_hms(9, 59, 0);
+  assert_eq!(
+    schedule.next_fire(before),
+    Some(Tz::UTC.ymd(2022, 6, 4).and_hms(10, 0, 0))
+  );
+  // exactly at the fire time should wait for the next day
+  let at = Tz::UTC.ymd(2022, 6, 4).and_hms(10, 0, 0);
+  assert_eq!(
+    schedule.next_fire(at),
+    Some(Tz::UTC.ymd(2022, 6, 5).and_hms(10, 0, 0))
+  );
+
+  // one-shot schedule never fire again after the time
+  let once = Schedule::Once(at);
+  assert_eq!(once.to_string(), "单次 2022-06-04 10:00");
+  assert_eq!(once.next_fire(before), Some(at));
+  assert_eq!(once.next_fire(at), None);
+  assert!(!once.is_valid());
+}
+
+#[test]
+fn parse_weekday_set() {
+  let set = "一三五".parse::<WeekdaySet>().unwrap();
+  assert_eq!(set, "mon,wed,fri".parse().unwrap());
+  assert_eq!(set, "周一、周三、周五".parse().unwrap());
+  assert_eq!(set, "1 3 5".parse().unwrap());
+  assert!(set.contains(Weekday::Wed));
+  assert!(!set.contains(Weekday::Sun));
+  assert_eq!(set.to_string(), "周一、三、五");
+
+  assert!("日".parse::<WeekdaySet>().unwrap().contains(Weekday::Sun));
+  assert!("一二三四五六日".parse::<WeekdaySet>().unwrap().is_all());
+  assert!("八".parse::<WeekdaySet>().is_err());
+  assert!("".parse::<WeekdaySet>().is_err());
+}
+
+#[test]
+fn quiet_hours_cross_midnight() {
+  let t = |h, m| NaiveTime::from_hms(h, m, 0);
+
+  let night = "23:00-08:00".parse::<QuietHours>().unwrap();
+  assert!(night.contains(t(23, 0)));
+  assert!(night.contains(t(3, 0)));
+  assert!(!night.contains(t(8, 0)));
+  assert!(!night.contains(t(12, 0)));
+  assert_eq!(night.to_string(), "23:00-08:00");
+
+  let noon = "12:00-14:00".parse::<QuietHours>().unwrap();
+  assert!(noon.contains(t(13, 59)));
+  assert!(!noon.contains(t(3, 0)));
+
+  assert!("23:00".parse::<QuietHours>().is_err());
+}
+
+#[tokio::test]
+async fn reject_one_shot_in_quiet_hours() {
+  let at = clock::now() + chrono::Duration::hours(1);
+  let quiet = format!(
+    "{}-{}",
+    (at - chrono::Duration::minutes(30)).format("%H:%M"),
+    (at + chrono::Duration::minutes(30)).format("%H:%M")
+  )
+  .parse::<QuietHours>()
+  .unwrap();
+  let (_tx, rx) = watch::channel(0);
+  let mut pool = TaskPool::new(
+    RecordingBot::default(),
+    Arc::default(),
+    Arc::default(),
+    Some(quiet),
+    rx.clone(),
+  );
+  let task = |schedule| {
+    ScheduleTask::new(rx.clone())
+      .schedule(schedule)
+      .pending_notification(vec![Notification::Text("A".to_string())])
+      .groups(vec![ChatId(-1001)])
+  };
+
+  assert!(matches!(
+    pool.add_task(task(Schedule::Once(at))).await,
+    Err(Error::InQuietHours(_))
+  ));
+  // the repeating task just skip the ticks in the quiet hours
+  let id = pool
+    .add_task(task(Schedule::Every(Duration::from_secs(3600))))
+    .await
+    .unwrap();
+  assert!(matches!(
+    pool.set_schedule(id, Schedule::Once(at)).await,
+    Err(Error::InQuietHours(_))
+  ));
+  let later = at + chrono::Duration::hours(2);
+  pool.set_schedule(id, Schedule::Once(later)).await.unwrap();
+}
+
+#[tokio::test]
+async fn broadcast_skip_failed_group() {
+  use std::cell::RefCell;
+
+  let groups = vec![ChatId(1), ChatId(2), ChatId(3)];
+  let received = RefCell::new(Vec::new());
+  let received_ref = &received;
+
+  let deadline = tok_time::Instant::now() + Duration::from_secs(1);
+  let (_, failed) = broadcast(
+    0,
+    &groups,
+    &RetryPolicy::default(),
+    Duration::from_millis(10),
+    1,
+    deadline,
+    |gid| async move {
+      if gid == ChatId(2) {
+        return Err(RequestError::Api(ApiError::BotKicked));
+      }
+      received_ref.borrow_mut().push(gid);
+      Ok(())
+    },
+  )
+  .await;
+
+  assert_eq!(failed.len(), 1);
+  assert_eq!(failed[0].0, ChatId(2));
+  assert_eq!(received.into_inner(), vec![ChatId(1), ChatId(3)]);
+
+  // bot kicked is a permanent error, the group should be disabled immediately
+  let (_tx, rx) = watch::channel(0);
+  let mut task = ScheduleTask::new(rx).groups(groups.clone());
+  let to_disable = task.count_failures(&groups, &failed);
+  assert_eq!(to_disable.len(), 1);
+  assert_eq!(to_disable[0].0, ChatId(2));
+
+  // transient error should be counted until max failures
+  let failed = vec![(ChatId(3), RequestError::RetryAfter(Duration::from_secs(1)))];
+  for _ in 1..task.retry_policy.max_failures {
+    assert!(task.count_failures(&groups, &failed).is_empty());
+  }
+  assert_eq!(task.count_failures(&groups, &failed)[0].0, ChatId(3));
+  task.count_failures(&groups, &[]);
+  assert!(task.failures.is_empty());
+}
+
+#[tokio::test]
+async fn broadcast_concurrency_is_bounded() {
+  use std::sync::atomic::AtomicUsize;
+
+  let groups = (0..10).map(ChatId).collect::<Vec<ChatId>>();
+  let in_flight = AtomicUsize::new(0);
+  let max_in_flight = AtomicUsize::new(0);
+  let started = parking_lot::Mutex::new(Vec::new());
+  let (in_flight, max_in_flight, started_ref) = (&in_flight, &max_in_flight, &started);
+
+  let deadline = tok_time::Instant::now() + Duration::from_secs(5);
+  let (_, failed) = broadcast(
+    0,
+    &groups,
+    &RetryPolicy::default(),
+    Duration::ZERO,
+    3,
+    deadline,
+    |gid| async move {
+      started_ref.lock().push((gid, tok_time::Instant::now()));
+      let n = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+      max_in_flight.fetch_max(n, Ordering::SeqCst);
+      tok_time::sleep(Duration::from_millis(20)).await;
+      in_flight.fetch_sub(1, Ordering::SeqCst);
+      Ok(())
+    },
+  )
+  .await;
+
+  assert!(failed.is_empty());
+  // sends overlap, but never exceed the cap
+  assert_eq!(max_in_flight.load(Ordering::SeqCst), 3);
+  let started = started.into_inner();
+  assert_eq!(started.len(), groups.len());
+  // sends are started in the order of the groups
+  assert!(started
+    .iter()
+    .map(|(gid, _)| *gid)
+    .eq(groups.iter().copied()));
+  // the first three sends start together, the fourth one wait for a free slot
+  assert!(started[2].1 - started[0].1 < Duration::from_millis(10));
+  assert!(started[3].1 - started[0].1 >= Duration::from_millis(20));
+}
+
+#[tokio::test]
+async fn retry_after_is_respected() {
+  use std::cell::Cell;
+
+  let calls = Cell::new(0);
+  let calls_ref = &calls;
+  let policy = RetryPolicy::default();
+
+  // first call ask us to wait, second call succeed
+  let deadline = tok_time::Instant::now() + Duration::from_secs(1);
+  let send = |_: ChatId| async move {
+    calls_ref.set(calls_ref.get() + 1);
+    if calls_ref.get() == 1 {
+      Err(RequestError::RetryAfter(Duration::from_millis(10)))
+    } else {
+      Ok(())
+    }
+  };
+  let result = send_with_retry(0, ChatId(1), &policy, deadline, &send).await;
+  assert!(result.is_ok());
+  assert_eq!(calls.get(), 2);
+
+  // wait longer than the deadline should give up
+  calls.set(0);
+  let send = |_: ChatId| async move {
+    calls_ref.set(calls_ref.get() + 1);
+    Err::<(), _>(RequestError::RetryAfter(Duration::from_secs(60)))
+  };
+  let result = send_with_retry(0, ChatId(1), &policy, deadline, &send).await;
+  assert!(matches!(result, Err(RequestError::RetryAfter(_))));
+  assert_eq!(calls.get(), 1);
 }
@@ -0,0 +1,470 @@
+//! SQLite backend of the tasks and the whitelist, enabled by the `sqlite` feature. The schema is
+//! migrated on open, the version is kept in `PRAGMA user_version`.
+
+use crate::{
+  clock,
+  error::{Error, Result},
+  runtime::Whitelist,
+  schedule::{GroupOverride, Notification, WeekdaySet},
+  store::{TaskSnapshot, TaskStore, WhitelistStore},
+};
+use chrono::DateTime;
+use chrono_tz::Tz;
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::{path::Path, sync::Arc};
+use teloxide::types::{ChatId, UserId};
+
+/// Each entry upgrades the schema by one version
+const MIGRATIONS: &[&str] = &[
+  r#"
+CREATE TABLE tasks (
+  id INTEGER PRIMARY KEY,
+  name TEXT,
+  creator INTEGER,
+  schedule TEXT NOT NULL,
+  weekdays INTEGER NOT NULL,
+  ends_at TEXT,
+  buttons TEXT,
+  parse_mode TEXT,
+  disable_web_page_preview INTEGER NOT NULL,
+  silent INTEGER NOT NULL,
+  protect_content INTEGER NOT NULL,
+  pin INTEGER NOT NULL,
+  delete_previous INTEGER NOT NULL,
+  edit_in_place INTEGER NOT NULL,
+  stop_previous_poll INTEGER NOT NULL,
+  paused INTEGER NOT NULL,
+  created_at TEXT NOT NULL
+);
+CREATE TABLE task_notifications (
+  task_id INTEGER NOT NULL REFERENCES tasks(id) ON DELETE CASCADE,
+  position INTEGER NOT NULL,
+  content TEXT NOT NULL,
+  PRIMARY KEY (task_id, position)
+);
+CREATE TABLE task_groups (
+  task_id INTEGER NOT NULL REFERENCES tasks(id) ON DELETE CASCADE,
+  position INTEGER NOT NULL,
+  chat_id INTEGER NOT NULL,
+  PRIMARY KEY (task_id, position)
+);
+CREATE TABLE whitelist_users (
+  user_id INTEGER NOT NULL,
+  role TEXT NOT NULL,
+  PRIMARY KEY (user_id, role)
+);
+CREATE TABLE whitelist_groups (
+  chat_id INTEGER PRIMARY KEY,
+  listed INTEGER NOT NULL,
+  disabled INTEGER NOT NULL,
+  topic INTEGER
+);
+CREATE TABLE admin_scopes (
+  admin_id INTEGER NOT NULL,
+  chat_id INTEGER NOT NULL,
+  PRIMARY KEY (admin_id, chat_id)
+);
+"#,
+  r#"
+ALTER TABLE tasks ADD COLUMN rotation TEXT NOT NULL DEFAULT '"sequential"';
+"#,
+  r#"
+ALTER TABLE task_notifications ADD COLUMN weight INTEGER NOT NULL DEFAULT 1;
+"#,
+  r#"
+CREATE TABLE task_overrides (
+  task_id INTEGER NOT NULL REFERENCES tasks(id) ON DELETE CASCADE,
+  chat_id INTEGER NOT NULL,
+  content TEXT NOT NULL,
+  buttons TEXT,
+  PRIMARY KEY (task_id, chat_id)
+);
+"#,
+  r#"
+ALTER TABLE tasks ADD COLUMN synchronized INTEGER NOT NULL DEFAULT 0;
+"#,
+];
+
+/// Keep the tasks and the whitelist in a SQLite database. The queries run on the blocking
+/// threads, the connection is shared by all of them.
+#[derive(Clone)]
+pub struct SqliteStore {
+  conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+  /// Open or create the database file and migrate it to the latest schema
+  pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+    Self::from_connection(Connection::open(path)?)
+  }
+
+  /// Open a database living in memory, lost when the store is dropped
+  pub fn open_in_memory() -> Result<Self> {
+    Self::from_connection(Connection::open_in_memory()?)
+  }
+
+  fn from_connection(mut conn: Connection) -> Result<Self> {
+    conn.pragma_update(None, "foreign_keys", true)?;
+    migrate(&mut conn)?;
+    Ok(Self {
+      conn: Arc::new(Mutex::new(conn)),
+    })
+  }
+
+  /// Run the query on a blocking thread
+  async fn run<T, F>(&self, f: F) -> Result<T>
+  where
+    T: Send + 'static,
+    F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+  {
+    let conn = self.conn.clone();
+    tokio::task::spawn_blocking(move || f(&mut conn.lock()))
+      .await
+      .map_err(|e| Error::Storage(e.into()))?
+  }
+}
+
+impl From<rusqlite::Error> for Error {
+  fn from(e: rusqlite::Error) -> Self {
+    Self::Storage(e.into())
+  }
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+  let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+  for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+    let tx = conn.transaction()?;
+    tx.execute_batch(migration)?;
+    tx.pragma_update(None, "user_version", i as i64 + 1)?;
+    tx.commit()?;
+  }
+  Ok(())
+}
+
+fn parse_time(text: &str) -> Result<DateTime<Tz>> {
+  DateTime::parse_from_rfc3339(text)
+    .map(|t| t.with_timezone(&*clock::TIMEZONE))
+    .map_err(|e| Error::Storage(e.into()))
+}
+
+fn insert_task(conn: &Connection, task: &TaskSnapshot) -> Result<()> {
+  conn.execute(
+    "INSERT INTO tasks VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, \
+     ?16, ?17, ?18, ?19)",
+    params![
+      task.id,
+      task.name,
+      task.creator.map(|u| u.0 as i64),
+      serde_json::to_string(&task.schedule)?,
+      task.weekdays.bits(),
+      task.ends_at.map(|t| t.to_rfc3339()),
+      task
+        .buttons
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?,
+      task
+        .parse_mode
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?,
+      task.disable_web_page_preview,
+      task.silent,
+      task.protect_content,
+      task.pin,
+      task.delete_previous,
+      task.edit_in_place,
+      task.stop_previous_poll,
+      task.paused,
+      task.created_at.to_rfc3339(),
+      serde_json::to_string(&task.rotation)?,
+      task.synchronized,
+    ],
+  )?;
+  for (position, notification) in task.notifications.iter().enumerate() {
+    conn.execute(
+      "INSERT INTO task_notifications VALUES (?1, ?2, ?3, ?4)",
+      params![
+        task.id,
+        position as i64,
+        serde_json::to_string(notification)?,
+        task.weights.get(position).copied().unwrap_or(1),
+      ],
+    )?;
+  }
+  for (position, group) in task.groups.iter().enumerate() {
+    conn.execute(
+      "INSERT INTO task_groups VALUES (?1, ?2, ?3)",
+      params![task.id, position as i64, group.0],
+    )?;
+  }
+  for (group, value) in &task.overrides {
+    conn.execute(
+      "INSERT INTO task_overrides VALUES (?1, ?2, ?3, ?4)",
+      params![
+        task.id,
+        group.0,
+        serde_json::to_string(&value.content)?,
+        value
+          .buttons
+          .as_ref()
+          .map(serde_json::to_string)
+          .transpose()?,
+      ],
+    )?;
+  }
+  Ok(())
+}
+
+fn read_task(conn: &Connection, row: &Row) -> Result<TaskSnapshot> {
+  let id: u32 = row.get("id")?;
+  let schedule: String = row.get("schedule")?;
+  let ends_at: Option<String> = row.get("ends_at")?;
+  let buttons: Option<String> = row.get("buttons")?;
+  let parse_mode: Option<String> = row.get("parse_mode")?;
+  let created_at: String = row.get("created_at")?;
+  let rotation: String = row.get("rotation")?;
+
+  let (notifications, weights) = conn
+    .prepare("SELECT content, weight FROM task_notifications WHERE task_id = ?1 ORDER BY position")?
+    .query_map([id], |row| {
+      Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?))
+    })?
+    .map(|row| {
+      let (content, weight) = row?;
+      Ok((serde_json::from_str(&content)?, weight))
+    })
+    .collect::<Result<Vec<(Notification, u32)>>>()?
+    .into_iter()
+    .unzip();
+  let groups = conn
+    .prepare("SELECT chat_id FROM task_groups WHERE task_id = ?1 ORDER BY position")?
+    .query_map([id], |row| row.get(0).map(ChatId))?
+    .collect::<rusqlite::Result<Vec<ChatId>>>()?;
+  let overrides = conn
+    .prepare("SELECT chat_id, content, buttons FROM task_overrides WHERE task_id = ?1")?
+    .query_map([id], |row| {
+      Ok((
+        ChatId(row.get(0)?),
+        row.get::<_, String>(1)?,
+        row.get::<_, Option<String>>(2)?,
+      ))
+    })?
+    .map(|row| {
+      let (group, content, buttons) = row?;
+      let value = GroupOverride {
+        content: serde_json::from_str(&content)?,
+        buttons: buttons.as_deref().map(serde_json::from_str).transpose()?,
+      };
+      Ok((group, value))
+    })
+    .collect::<Result<_>>()?;
+
+  Ok(TaskSnapshot {
+    id,
+    name: row.get("name")?,
+    creator: row
+      .get::<_, Option<i64>>("creator")?
+      .map(|u| UserId(u as u64)),
+    schedule: serde_json::from_str(&schedule)?,
+    weekdays: WeekdaySet::from_bits(row.get("weekdays")?),
+    ends_at: ends_at.as_deref().map(parse_time).transpose()?,
+    notifications,
+    weights,
+    buttons: buttons.as_deref().map(serde_json::from_str).transpose()?,
+    parse_mode: parse_mode
+      .as_deref()
+      .map(serde_json::from_str)
+      .transpose()?,
+    rotation: serde_json::from_str(&rotation)?,
+    disable_web_page_preview: row.get("disable_web_page_preview")?,
+    silent: row.get("silent")?,
+    protect_content: row.get("protect_content")?,
+    pin: row.get("pin")?,
+    delete_previous: row.get("delete_previous")?,
+    edit_in_place: row.get("edit_in_place")?,
+    stop_previous_poll: row.get("stop_previous_poll")?,
+    synchronized: row.get("synchronized")?,
+    groups,
+    overrides,
+    paused: row.get("paused")?,
+    created_at: parse_time(&created_at)?,
+  })
+}
+
+impl TaskStore for SqliteStore {
+  fn save_all<'a>(&'a self, tasks: &'a [TaskSnapshot]) -> BoxFuture<'a, Result<()>> {
+    let tasks = tasks.to_vec();
+    Box::pin(self.run(move |conn| {
+      let tx = conn.transaction()?;
+      tx.execute("DELETE FROM tasks", [])?;
+      for task in &tasks {
+        insert_task(&tx, task)?;
+      }
+      tx.commit()?;
+      Ok(())
+    }))
+  }
+
+  fn load_all(&self) -> BoxFuture<'_, Result<Vec<TaskSnapshot>>> {
+    Box::pin(self.run(|conn| {
+      let mut stmt = conn.prepare("SELECT * FROM tasks ORDER BY id")?;
+      let mut rows = stmt.query([])?;
+      let mut tasks = Vec::new();
+      while let Some(row) = rows.next()? {
+        tasks.push(read_task(conn, row)?);
+      }
+      Ok(tasks)
+    }))
+  }
+
+  fn delete(&self, id: u32) -> BoxFuture<'_, Result<()>> {
+    Box::pin(self.run(move |conn| {
+      conn.execute("DELETE FROM tasks WHERE id = ?1", [id])?;
+      Ok(())
+    }))
+  }
+}
+
+impl WhitelistStore for SqliteStore {
+  fn save_whitelist<'a>(&'a self, whitelist: &'a Whitelist) -> BoxFuture<'a, Result<()>> {
+    let wt = whitelist.clone();
+    Box::pin(self.run(move |conn| {
+      let tx = conn.transaction()?;
+      tx.execute_batch(
+        "DELETE FROM whitelist_users; DELETE FROM whitelist_groups; DELETE FROM admin_scopes;",
+      )?;
+      for (role, users) in [
+        ("maintainer", &wt.maintainers),
+        ("admin", &wt.admins),
+        ("banned", &wt.banned),
+      ] {
+        for user in users {
+          tx.execute(
+            "INSERT OR IGNORE INTO whitelist_users VALUES (?1, ?2)",
+            params![user.0 as i64, role],
+          )?;
+        }
+      }
+      let chats = wt
+        .groups
+        .iter()
+        .chain(&wt.disabled_groups)
+        .chain(wt.topics.keys());
+      for &chat in chats {
+        tx.execute(
+          "INSERT OR REPLACE INTO whitelist_groups VALUES (?1, ?2, ?3, ?4)",
+          params![
+            chat.0,
+            wt.groups.contains(&chat),
+            wt.disabled_groups.contains(&chat),
+            wt.topics.get(&chat).copied(),
+          ],
+        )?;
+      }
+      for (admin, scope) in &wt.admin_scopes {
+        for chat in scope {
+          tx.execute(
+            "INSERT OR IGNORE INTO admin_scopes VALUES (?1, ?2)",
+            params![admin.0 as i64, chat.0],
+          )?;
+        }
+      }
+      tx.commit()?;
+      Ok(())
+    }))
+  }
+
+  fn load_whitelist(&self) -> BoxFuture<'_, Result<Option<Whitelist>>> {
+    Box::pin(self.run(|conn| {
+      let saved = conn
+        .query_row("SELECT 1 FROM whitelist_users LIMIT 1", [], |_| Ok(()))
+        .optional()?;
+      if saved.is_none() {
+        return Ok(None);
+      }
+
+      let mut wt = Whitelist::default();
+      let mut stmt = conn.prepare("SELECT user_id, role FROM whitelist_users")?;
+      let mut rows = stmt.query([])?;
+      while let Some(row) = rows.next()? {
+        let user = UserId(row.get::<_, i64>(0)? as u64);
+        match row.get::<_, String>(1)?.as_str() {
+          "maintainer" => wt.maintainers.push(user),
+          "admin" => wt.admins.push(user),
+          "banned" => wt.banned.push(user),
+          role => return Err(Error::Storage(anyhow::anyhow!("unknown role {role}"))),
+        }
+      }
+
+      let mut stmt =
+        conn.prepare("SELECT chat_id, listed, disabled, topic FROM whitelist_groups")?;
+      let mut rows = stmt.query([])?;
+      while let Some(row) = rows.next()? {
+        let chat = ChatId(row.get(0)?);
+        if row.get(1)? {
+          wt.groups.push(chat);
+        }
+        if row.get(2)? {
+          wt.disabled_groups.push(chat);
+        }
+        if let Some(topic) = row.get(3)? {
+          wt.topics.insert(chat, topic);
+        }
+      }
+
+      let mut stmt = conn.prepare("SELECT admin_id, chat_id FROM admin_scopes")?;
+      let mut rows = stmt.query([])?;
+      while let Some(row) = rows.next()? {
+        let admin = UserId(row.get::<_, i64>(0)? as u64);
+        wt.admin_scopes
+          .entry(admin)
+          .or_default()
+          .push(ChatId(row.get(1)?));
+      }
+
+      Ok(Some(wt.dedup()))
+    }))
+  }
+}
+
+#[tokio::test]
+async fn sqlite_task_store_test() {
+  let store = SqliteStore::open_in_memory().unwrap();
+  assert!(store.load_all().await.unwrap().is_empty());
+
+  let first = crate::store::snapshot();
+  let second = TaskSnapshot {
+    id: 4,
+    name: None,
+    ends_at: None,
+    buttons: None,
+    parse_mode: None,
+    ..crate::store::snapshot()
+  };
+  store.save_all(&[first.clone(), second]).await.unwrap();
+  store.delete(4).await.unwrap();
+  store.delete(10).await.unwrap();
+
+  let tasks = store.load_all().await.unwrap();
+  assert_eq!(tasks.len(), 1);
+  assert_eq!(
+    serde_json::to_string(&tasks[0]).unwrap(),
+    serde_json::to_string(&first).unwrap()
+  );
+}
+
+#[tokio::test]
+async fn sqlite_whitelist_store_test() {
+  let store = SqliteStore::open_in_memory().unwrap();
+  assert_eq!(store.load_whitelist().await.unwrap(), None);
+
+  let wt = Whitelist::fixture().dedup();
+  store.save_whitelist(&wt).await.unwrap();
+  assert_eq!(store.load_whitelist().await.unwrap(), Some(wt));
+
+  // migrating again is a no-op
+  migrate(&mut store.conn.lock()).unwrap();
+}
@@ -0,0 +1,412 @@
+//! Persistence of the tasks. The pool saves a snapshot of the tasks into a [`TaskStore`] after
+//! every change, and restores the tasks from it on startup. Implement [`TaskStore`] to keep the
+//! tasks in another place, like a database.
+
+use crate::{
+  clock,
+  error::Result,
+  runtime::{with_suffix, Whitelist},
+  schedule::{
+    GroupOverride, Notification, RotationMode, Schedule, ScheduleTask, TaskInfo, WeekdaySet,
+  },
+};
+use chrono::DateTime;
+use chrono_tz::Tz;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::BTreeMap,
+  path::{Path, PathBuf},
+};
+use teloxide::types::{ChatId, InlineKeyboardMarkup, ParseMode, UserId};
+use tokio::{fs, io::AsyncWriteExt, sync::watch};
+
+/// The persistent part of a task. The delivery statistics and the position in the
+/// notification pool are not saved.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaskSnapshot {
+  pub id: u32,
+  #[serde(default)]
+  pub name: Option<String>,
+  #[serde(default)]
+  pub creator: Option<UserId>,
+  pub schedule: Schedule,
+  #[serde(default)]
+  pub weekdays: WeekdaySet,
+  #[serde(default, with = "rfc3339::option")]
+  pub ends_at: Option<DateTime<Tz>>,
+  pub notifications: Vec<Notification>,
+  /// The weight of each notification, missing ones are 1
+  #[serde(default)]
+  pub weights: Vec<u32>,
+  #[serde(default)]
+  pub buttons: Option<InlineKeyboardMarkup>,
+  #[serde(default)]
+  pub parse_mode: Option<ParseMode>,
+  #[serde(default)]
+  pub rotation: RotationMode,
+  #[serde(default)]
+  pub disable_web_page_preview: bool,
+  #[serde(default)]
+  pub silent: bool,
+  #[serde(default)]
+  pub protect_content: bool,
+  #[serde(default)]
+  pub pin: bool,
+  #[serde(default)]
+  pub delete_previous: bool,
+  #[serde(default)]
+  pub edit_in_place: bool,
+  #[serde(default)]
+  pub stop_previous_poll: bool,
+  #[serde(default)]
+  pub synchronized: bool,
+  pub groups: Vec<ChatId>,
+  /// The content sent to the specific groups instead of the notification pool
+  #[serde(default)]
+  pub overrides: BTreeMap<ChatId, GroupOverride>,
+  #[serde(default)]
+  pub paused: bool,
+  #[serde(with = "rfc3339")]
+  pub created_at: DateTime<Tz>,
+}
+
+impl TaskSnapshot {
+  pub fn new(id: u32, task: &TaskInfo) -> Self {
+    Self {
+      id,
+      name: task.name.clone(),
+      creator: task.creator,
+      schedule: task.schedule.clone(),
+      weekdays: task.weekdays,
+      ends_at: task.ends_at,
+      notifications: task.notifications.clone(),
+      weights: task.weights.clone(),
+      buttons: task.buttons.clone(),
+      parse_mode: task.parse_mode,
+      rotation: task.rotation,
+      disable_web_page_preview: task.disable_web_page_preview,
+      silent: task.silent,
+      protect_content: task.protect_content,
+      pin: task.pin,
+      delete_previous: task.delete_previous,
+      edit_in_place: task.edit_in_place,
+      stop_previous_poll: task.stop_previous_poll,
+      synchronized: task.synchronized,
+      groups: task.groups.clone(),
+      overrides: task.overrides.clone(),
+      paused: task.paused,
+      created_at: task.created_at,
+    }
+  }
+
+  /// Test if the task will never send again, like the one-shot task in the past
+  pub fn is_expired(&self) -> bool {
+    !self.schedule.is_valid() || self.ends_at.map_or(false, |t| t <= clock::now())
+  }
+
+  /// Build the task back, the pool fills in the shared states when it is added
+  pub(crate) fn into_task(self, shutdown_sig: watch::Receiver<u8>) -> ScheduleTask {
+    let mut task = ScheduleTask::new(shutdown_sig)
+      .name(self.name)
+      .schedule(self.schedule)
+      .weekdays(self.weekdays)
+      .pending_notification(self.notifications)
+      .weights(self.weights)
+      .parse_mode(self.parse_mode)
+      .rotation(self.rotation)
+      .disable_web_page_preview(self.disable_web_page_preview)
+      .silent(self.silent)
+      .protect_content(self.protect_content)
+      .pin(self.pin)
+      .delete_previous(self.delete_previous)
+      .edit_in_place(self.edit_in_place)
+      .stop_previous_poll(self.stop_previous_poll)
+      .synchronized(self.synchronized)
+      .groups(self.groups)
+      .overrides(self.overrides)
+      .paused(self.paused)
+      .created_at(self.created_at);
+    if let Some(creator) = self.creator {
+      task = task.creator(creator);
+    }
+    if let Some(ends_at) = self.ends_at {
+      task = task.ends_at(ends_at);
+    }
+    if let Some(buttons) = self.buttons {
+      task = task.msg_buttons(buttons);
+    }
+    task
+  }
+}
+
+/// Serialize the tasks into the document made by /exporttasks
+pub fn export_tasks(snapshots: &[TaskSnapshot]) -> Result<String> {
+  Ok(serde_json::to_string_pretty(snapshots)?)
+}
+
+/// Parse the document made by [`export_tasks`]. The document must be a JSON array, but each
+/// entry is parsed on its own, so a broken entry doesn't stop the others from being imported.
+pub fn parse_exported_tasks(json: &[u8]) -> Result<Vec<Result<TaskSnapshot>>> {
+  let entries: Vec<serde_json::Value> = serde_json::from_slice(json)?;
+  Ok(
+    entries
+      .into_iter()
+      .map(|entry| Ok(serde_json::from_value(entry)?))
+      .collect(),
+  )
+}
+
+/// Backend keeping the task snapshots
+pub trait TaskStore: Send + Sync {
+  /// Replace all the saved tasks with the given ones
+  fn save_all<'a>(&'a self, tasks: &'a [TaskSnapshot]) -> BoxFuture<'a, Result<()>>;
+
+  /// Return all the saved tasks
+  fn load_all(&self) -> BoxFuture<'_, Result<Vec<TaskSnapshot>>>;
+
+  /// Remove the saved task, removing a task not saved is not an error
+  fn delete(&self, id: u32) -> BoxFuture<'_, Result<()>>;
+}
+
+/// Backend keeping the whitelist instead of the whitelist file
+pub trait WhitelistStore: Send + Sync {
+  /// Replace the saved whitelist
+  fn save_whitelist<'a>(&'a self, whitelist: &'a Whitelist) -> BoxFuture<'a, Result<()>>;
+
+  /// Return the saved whitelist, `None` if it is never saved
+  fn load_whitelist(&self) -> BoxFuture<'_, Result<Option<Whitelist>>>;
+}
+
+/// Save the tasks into a JSON file. The file is replaced atomically like the whitelist file.
+#[derive(Debug)]
+pub struct JsonFileStore {
+  path: PathBuf,
+  /// Serialize the writes, so the concurrent writes don't share the temporary file
+  lock: tokio::sync::Mutex<()>,
+}
+
+impl Default for JsonFileStore {
+  fn default() -> Self {
+    Self::new("tasks.json")
+  }
+}
+
+impl JsonFileStore {
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    Self {
+      path: path.into(),
+      lock: tokio::sync::Mutex::default(),
+    }
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// Missing or empty file means no task is saved
+  async fn read(&self) -> Result<Vec<TaskSnapshot>> {
+    let content = match fs::read_to_string(&self.path).await {
+      Ok(content) => content,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(e) => return Err(e.into()),
+    };
+    if content.trim().is_empty() {
+      return Ok(Vec::new());
+    }
+    Ok(serde_json::from_str(&content)?)
+  }
+
+  async fn write(&self, tasks: &[TaskSnapshot]) -> Result<()> {
+    let content = serde_json::to_string_pretty(tasks)?;
+
+    let tmp = with_suffix(&self.path, "tmp");
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(content.as_bytes()).await?;
+    file.sync_all().await?;
+    drop(file);
+    fs::rename(&tmp, &self.path).await?;
+    Ok(())
+  }
+}
+
+impl TaskStore for JsonFileStore {
+  fn save_all<'a>(&'a self, tasks: &'a [TaskSnapshot]) -> BoxFuture<'a, Result<()>> {
+    Box::pin(async move {
+      let _guard = self.lock.lock().await;
+      self.write(tasks).await
+    })
+  }
+
+  fn load_all(&self) -> BoxFuture<'_, Result<Vec<TaskSnapshot>>> {
+    Box::pin(async move {
+      let _guard = self.lock.lock().await;
+      self.read().await
+    })
+  }
+
+  fn delete(&self, id: u32) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+      let _guard = self.lock.lock().await;
+      let mut tasks = self.read().await?;
+      let len = tasks.len();
+      tasks.retain(|t| t.id != id);
+      if tasks.len() != len {
+        self.write(&tasks).await?;
+      }
+      Ok(())
+    })
+  }
+}
+
+/// Keep nothing, the tasks are lost after restart
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopStore;
+
+impl TaskStore for NoopStore {
+  fn save_all<'a>(&'a self, _: &'a [TaskSnapshot]) -> BoxFuture<'a, Result<()>> {
+    Box::pin(async { Ok(()) })
+  }
+
+  fn load_all(&self) -> BoxFuture<'_, Result<Vec<TaskSnapshot>>> {
+    Box::pin(async { Ok(Vec::new()) })
+  }
+
+  fn delete(&self, _: u32) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async { Ok(()) })
+  }
+}
+
+/// Save the time as RFC 3339 string, and read it back in the configured timezone
+mod rfc3339 {
+  use crate::clock;
+  use chrono::DateTime;
+  use chrono_tz::Tz;
+  use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+  pub fn serialize<S: Serializer>(time: &DateTime<Tz>, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&time.to_rfc3339())
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<DateTime<Tz>, D::Error> {
+    let text = String::deserialize(d)?;
+    DateTime::parse_from_rfc3339(&text)
+      .map(|t| t.with_timezone(&*clock::TIMEZONE))
+      .map_err(D::Error::custom)
+  }
+
+  pub mod option {
+    use chrono::DateTime;
+    use chrono_tz::Tz;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &Option<DateTime<Tz>>, s: S) -> Result<S::Ok, S::Error> {
+      match time {
+        Some(time) => super::serialize(time, s),
+        None => s.serialize_none(),
+      }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<DateTime<Tz>>, D::Error> {
+      #[derive(Deserialize)]
+      struct Wrapper(#[serde(with = "super")] DateTime<Tz>);
+
+      let time = Option::<Wrapper>::deserialize(d)?;
+      Ok(time.map(|Wrapper(t)| t))
+    }
+  }
+}
+
+#[cfg(test)]
+pub(crate) fn snapshot() -> TaskSnapshot {
+  use teloxide::types::InlineKeyboardButton;
+
+  TaskSnapshot {
+    id: 3,
+    name: Some("早报".to_string()),
+    creator: Some(UserId(1234)),
+    schedule: Schedule::from_cron("30 9 * * 1-5").unwrap(),
+    weekdays: "一三五".parse().unwrap(),
+    ends_at: Some(clock::now() + chrono::Duration::days(1)),
+    notifications: vec![
+      Notification::Text("早上好".to_string()),
+      Notification::Copy {
+        from_chat: ChatId(-1001),
+        message_id: 42,
+      },
+    ],
+    weights: vec![1, 4],
+    buttons: Some(InlineKeyboardMarkup::new(vec![vec![
+      InlineKeyboardButton::url("Button".to_string(), "https://example.com".parse().unwrap()),
+    ]])),
+    parse_mode: Some(ParseMode::Html),
+    rotation: RotationMode::RandomNoRepeat,
+    disable_web_page_preview: true,
+    silent: true,
+    protect_content: false,
+    pin: true,
+    delete_previous: false,
+    edit_in_place: false,
+    stop_previous_poll: true,
+    synchronized: true,
+    groups: vec![ChatId(-1001), ChatId(-1002)],
+    overrides: BTreeMap::from([(
+      ChatId(-1002),
+      GroupOverride {
+        content: Notification::Text("Good morning".to_string()),
+        buttons: None,
+      },
+    )]),
+    paused: true,
+    created_at: clock::now(),
+  }
+}
+
+#[test]
+fn task_snapshot_round_trip_test() {
+  let snapshot = snapshot();
+  let json = serde_json::to_string(&snapshot).unwrap();
+  let back: TaskSnapshot = serde_json::from_str(&json).unwrap();
+
+  assert_eq!(back.buttons, snapshot.buttons);
+  assert_eq!(back.notifications, snapshot.notifications);
+  assert_eq!(back.schedule.to_string(), "cron `30 9 * * 1-5`");
+  assert_eq!(back.ends_at, snapshot.ends_at);
+  assert_eq!(back.rotation, RotationMode::RandomNoRepeat);
+  assert_eq!(back.weights, vec![1, 4]);
+  assert_eq!(back.overrides, snapshot.overrides);
+  assert_eq!(back.created_at, snapshot.created_at);
+  assert_eq!(serde_json::to_string(&back).unwrap(), json);
+
+  for schedule in [
+    Schedule::Every(std::time::Duration::from_secs(5400)),
+    Schedule::Daily(chrono::NaiveTime::from_hms(9, 30, 0)),
+    Schedule::Once(clock::now() + chrono::Duration::hours(1)),
+  ] {
+    let json = serde_json::to_string(&schedule).unwrap();
+    let back: Schedule = serde_json::from_str(&json).unwrap();
+    assert_eq!(back.to_string(), schedule.to_string());
+  }
+}
+
+#[tokio::test]
+async fn json_file_store_test() {
+  let path = std::env::temp_dir().join(format!("notify-bot-tasks-{}.json", std::process::id()));
+  let store = JsonFileStore::new(&path);
+  assert!(store.load_all().await.unwrap().is_empty());
+
+  let first = snapshot();
+  let second = TaskSnapshot {
+    id: 4,
+    ..snapshot()
+  };
+  store.save_all(&[first, second]).await.unwrap();
+  store.delete(3).await.unwrap();
+  store.delete(10).await.unwrap();
+
+  let tasks = store.load_all().await.unwrap();
+  assert_eq!(tasks.iter().map(|t| t.id).collect::<Vec<u32>>(), vec![4]);
+  assert_eq!(tasks[0].buttons, snapshot().buttons);
+
+  fs::remove_file(&path).await.unwrap();
+}
@@ -0,0 +1,184 @@
+//! Reusable message templates. A template keeps the notification, the parse mode and the
+//! buttons of a task, so a new task can start from it instead of typing them again. All the
+//! templates are saved in one JSON file keyed by the template name.
+
+use crate::{
+  error::{Error, Result},
+  runtime::with_suffix,
+  schedule::{Notification, TaskInfo},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::PathBuf};
+use teloxide::types::{InlineKeyboardMarkup, ParseMode};
+use tokio::{fs, io::AsyncWriteExt};
+
+/// Max characters of the template name
+pub const NAME_MAX_LEN: usize = 16;
+
+/// The template name is embedded in the callback data, which is limited to 64 bytes
+const NAME_MAX_BYTES: usize = 48;
+
+/// The reusable part of a task
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MessageTemplate {
+  pub content: Notification,
+  #[serde(default)]
+  pub parse_mode: Option<ParseMode>,
+  #[serde(default)]
+  pub buttons: Option<InlineKeyboardMarkup>,
+}
+
+impl MessageTemplate {
+  /// Take the first notification of the task, with its parse mode and buttons
+  pub fn from_task(task: &TaskInfo) -> Option<Self> {
+    Some(Self {
+      content: task.notifications.first()?.clone(),
+      parse_mode: task.parse_mode,
+      buttons: task.buttons.clone(),
+    })
+  }
+}
+
+/// Read the path of the template file from `NOTIFY_BOT_TEMPLATE_FILE`, default to
+/// `templates.json`
+pub fn path_from_env() -> PathBuf {
+  std::env::var("NOTIFY_BOT_TEMPLATE_FILE")
+    .map(PathBuf::from)
+    .unwrap_or_else(|_| PathBuf::from("templates.json"))
+}
+
+/// Test if the name can be used for a template: 1 to [`NAME_MAX_LEN`] characters without
+/// whitespace
+pub fn validate_name(name: &str) -> Result<()> {
+  let len = name.chars().count();
+  if len == 0
+    || len > NAME_MAX_LEN
+    || name.len() > NAME_MAX_BYTES
+    || name.chars().any(char::is_whitespace)
+  {
+    return Err(Error::InvalidTemplateName(name.to_string()));
+  }
+  Ok(())
+}
+
+/// The file keeping the templates. It is read on every access and replaced atomically on every
+/// change, like the tasks file.
+#[derive(Debug)]
+pub struct TemplateFile {
+  path: PathBuf,
+  /// Serialize the accesses, so a change is never lost between the read and the write
+  lock: tokio::sync::Mutex<()>,
+}
+
+impl TemplateFile {
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    Self {
+      path: path.into(),
+      lock: tokio::sync::Mutex::default(),
+    }
+  }
+
+  /// Missing or empty file means no template is saved
+  async fn read(&self) -> Result<BTreeMap<String, MessageTemplate>> {
+    let content = match fs::read_to_string(&self.path).await {
+      Ok(content) => content,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+      Err(e) => return Err(e.into()),
+    };
+    if content.trim().is_empty() {
+      return Ok(BTreeMap::new());
+    }
+    Ok(serde_json::from_str(&content)?)
+  }
+
+  async fn write(&self, templates: &BTreeMap<String, MessageTemplate>) -> Result<()> {
+    let content = serde_json::to_string_pretty(templates)?;
+
+    let tmp = with_suffix(&self.path, "tmp");
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(content.as_bytes()).await?;
+    file.sync_all().await?;
+    drop(file);
+    fs::rename(&tmp, &self.path).await?;
+    Ok(())
+  }
+
+  /// Return all the templates sorted by the name
+  pub async fn list(&self) -> Result<BTreeMap<String, MessageTemplate>> {
+    let _guard = self.lock.lock().await;
+    self.read().await
+  }
+
+  pub async fn get(&self, name: &str) -> Result<MessageTemplate> {
+    let _guard = self.lock.lock().await;
+    self
+      .read()
+      .await?
+      .remove(name)
+      .ok_or_else(|| Error::TemplateNotFound(name.to_string()))
+  }
+
+  /// Save the template under a new name, the existing template is never replaced
+  pub async fn insert(&self, name: &str, template: MessageTemplate) -> Result<()> {
+    validate_name(name)?;
+    let _guard = self.lock.lock().await;
+    let mut templates = self.read().await?;
+    if templates.contains_key(name) {
+      return Err(Error::TemplateNameUsed(name.to_string()));
+    }
+    templates.insert(name.to_string(), template);
+    self.write(&templates).await
+  }
+
+  pub async fn remove(&self, name: &str) -> Result<()> {
+    let _guard = self.lock.lock().await;
+    let mut templates = self.read().await?;
+    if templates.remove(name).is_none() {
+      return Err(Error::TemplateNotFound(name.to_string()));
+    }
+    self.write(&templates).await
+  }
+}
+
+#[test]
+fn validate_name_test() {
+  assert!(validate_name("周报").is_ok());
+  assert!(validate_name(&"长".repeat(NAME_MAX_LEN)).is_ok());
+  assert!(validate_name(&"长".repeat(NAME_MAX_LEN + 1)).is_err());
+  assert!(validate_name("").is_err());
+  assert!(validate_name("weekly report").is_err());
+  // too long for the callback data
+  assert!(validate_name(&"🎉".repeat(NAME_MAX_LEN)).is_err());
+}
+
+#[tokio::test]
+async fn template_file_test() {
+  let path = std::env::temp_dir().join(format!("notify-bot-templates-{}.json", std::process::id()));
+  let file = TemplateFile::new(&path);
+  assert!(file.list().await.unwrap().is_empty());
+
+  let template = MessageTemplate {
+    content: Notification::Text("本周例会".to_string()),
+    parse_mode: Some(ParseMode::Html),
+    buttons: None,
+  };
+  file.insert("weekly", template.clone()).await.unwrap();
+  file.insert("daily", template.clone()).await.unwrap();
+  assert!(matches!(
+    file.insert("weekly", template.clone()).await,
+    Err(Error::TemplateNameUsed(_))
+  ));
+  let names = file.list().await.unwrap().into_keys().collect::<Vec<_>>();
+  assert_eq!(names, vec!["daily", "weekly"]);
+  assert_eq!(file.get("weekly").await.unwrap(), template);
+
+  file.remove("daily").await.unwrap();
+  assert!(matches!(
+    file.remove("daily").await,
+    Err(Error::TemplateNotFound(_))
+  ));
+  assert!(file.list().await.unwrap().contains_key("weekly"));
+  assert_eq!(file.list().await.unwrap().len(), 1);
+
+  let _ = std::fs::remove_file(&path);
+}